@@ -1,8 +1,6 @@
-use std::collections::HashMap;
+use std::{cell::OnceCell, collections::HashMap, rc::Rc};
 
-use itertools::Itertools;
-
-use crate::value::Value;
+use crate::value::{FuncObj, Value};
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
@@ -15,6 +13,8 @@ pub enum ByteCode {
     Nil = 3,
     True = 4,
     False = 5,
+    // Marks a variable as not yet assigned, see Value::Uninit
+    Uninit = 6,
 
     // Arith
     Negate = 0x10,
@@ -22,16 +22,41 @@ pub enum ByteCode {
     Sub,
     Mul,
     Div,
+    // Add or Sub with the constant at the index as the right operand
+    AddConst(u8),
+    SubConst(u8),
+    // Pops that many values and pushes them concatenated into a string, for interpolation
+    Format(u32),
+    // Divides and rounds the quotient down
+    FloorDiv,
+    // AddConst and SubConst for constants past the first 256
+    AddConstLong(u32),
+    SubConstLong(u32),
 
     // Bool
     Not = 0x20,
     Eq,
     Gt,
     Lt,
+    // Compare the top of the stack against a literal, fusing e.g. True and Eq
+    EqTrue,
+    EqFalse,
+    EqNil,
+    // Whether both are the same object, rather than just equal
+    RefEq,
+    // A comparison followed by Not, made by the optimizer
+    NotEq,
+    NotGt,
+    NotLt,
+    // `>=` and `<=`, which aren't Lt and Gt followed by Not since comparing with NaN is false
+    Ge,
+    Le,
 
     // Stack mutations
     Pop = 0x40,
     Dup,
+    // Pushes a copy of the value n slots below the top, so DupAt(0) is Dup
+    DupAt(u8),
 
     // Variables
     SetGlobal(u32) = 0x60,
@@ -39,29 +64,233 @@ pub enum ByteCode {
 
     SetLocal(u32),
     GetLocal(u32),
+    // Same as SetLocal and GetLocal, for the common case of slots that fit in a byte
+    SetLocalByte(u8),
+    GetLocalByte(u8),
+    // Pushes the function being run, which sits just below its frame
+    GetCallee,
 
     // Temporary, will remove eventually...
     Print = 0x80,
+    PrintErr = 0x81,
 
     // Control Flow
     JumpF(i16) = 0xA0,
     JumpRelative(i16),
+    // Calls the function below the given number of arguments on the stack. Before the call the
+    // stack ends with the callee followed by the n arguments, so the callee is at len - n - 1 and
+    // the callee's frame starts right above it. Once the call returns, its result replaces the
+    // callee and all of the arguments.
+    Call(u8),
+    // Calls with the given number of positional arguments followed by that many name and value
+    // pairs of named arguments
+    CallNamed(u8, u8),
+    // Suspends the running generator, returning the value on top of the stack to its caller
+    Yield,
+    // Reports a failed assertion with the message on top of the stack (nil for none)
+    AssertFailed,
+    // A Call whose result is immediately returned, so the callee replaces the caller's frame.
+    // Always followed by a Return, which other paths may jump to.
+    TailCall(u8),
+    // Calls with the arguments spread from the given number of lists
+    CallSpread(u8),
+    // Pop followed by JumpRelative, for moving on to the next arm of a match
+    PopJump(i16),
+    // Until the matching PopHandler, a runtime error unwinds to the given offset, with the stack
+    // as it was here plus the error's message
+    PushHandler(i16),
+    PopHandler,
+    // With an iterable and a position on top of the stack, pushes the element at the position
+    // and advances it, or jumps to the given offset once there are no elements left
+    IterNext(i16),
+    // Does nothing, filling the space left by an instruction rewritten in place
+    Nop,
+    // Pops a for loop's counter after and then before its increment, failing if it's a number
+    // too large for the increment to change it
+    CheckCounter,
+    // JumpTable(min, len) is followed by a table of len + 2 JumpRelatives, and continues at the
+    // entry for the value on top of the stack: entry n - min for an integer n from min to
+    // min + len - 1, entry len for any other number and the last entry for anything else
+    JumpTable(i32, u16),
+
+    // Collections
+    BuildList(u32) = 0xC0,
+    BuildMap(u32),
+    GetIndex,
+    SetIndex,
+    // Pops the end and then the start of a range, which includes the end if set
+    MakeRange(bool),
+    // Pops a list, which must have exactly the given length, and pushes its elements in order
+    Unpack(u32),
 }
 
 impl ByteCode {
     pub fn from_constant_index(index: u32) -> ByteCode {
         u8::try_from(index)
-            .map(|idx| Self::Constant(idx))
+            .map(Self::Constant)
             .unwrap_or(Self::ConstantLong(index))
     }
+
+    pub fn set_local(slot: u32) -> ByteCode {
+        u8::try_from(slot)
+            .map(Self::SetLocalByte)
+            .unwrap_or(Self::SetLocal(slot))
+    }
+
+    pub fn get_local(slot: u32) -> ByteCode {
+        u8::try_from(slot)
+            .map(Self::GetLocalByte)
+            .unwrap_or(Self::GetLocal(slot))
+    }
 }
 
 type LabelId = usize;
 
+/// Constants of a whole program, shared by the chunks of all of its functions so that a literal
+/// used by several of them is stored once. Instructions refer to constants by their index here.
+#[derive(Debug, Default, Clone)]
+pub struct ConstantTable {
+    values: Vec<Value>,
+    /// Index of each literal, to find identical ones without comparing against every constant
+    literals: HashMap<Literal, u32>,
+    /// Slots emptied by `release`. Pushes fill them in order, starting from `reused`.
+    free: Vec<u32>,
+    reused: usize,
+}
+
+/// Where a `ConstantTable` was at some point, to find the slots filled since
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantMark {
+    len: usize,
+    reused: usize,
+}
+
+/// A constant that is shared when added again. Numbers are kept by their bits, since -0 equals 0
+/// and NaN doesn't equal itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Literal {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Str(Rc<str>),
+    Symbol(Rc<str>),
+}
+
+impl Literal {
+    fn of(value: &Value) -> Option<Literal> {
+        match value {
+            Value::Nil => Some(Literal::Nil),
+            Value::Bool(b) => Some(Literal::Bool(*b)),
+            Value::Number(n) => Some(Literal::Number(n.to_bits())),
+            Value::Str(s) => Some(Literal::Str(Rc::clone(s))),
+            Value::Symbol(s) => Some(Literal::Symbol(Rc::clone(s))),
+            _ => None,
+        }
+    }
+}
+
+impl ConstantTable {
+    /// A table holding the values at their indices. Identical literals keep their own indices,
+    /// later pushes share the first of them.
+    pub fn from_values(values: Vec<Value>) -> ConstantTable {
+        let mut literals = HashMap::new();
+        for (idx, value) in values.iter().enumerate() {
+            if let Some(literal) = Literal::of(value) {
+                literals.entry(literal).or_insert(idx as u32);
+            }
+        }
+        ConstantTable {
+            values,
+            literals,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the value unless an identical literal is already there, returning its index either
+    /// way
+    pub fn push(&mut self, value: Value) -> u32 {
+        let literal = Literal::of(&value);
+        if let Some(&idx) = literal.as_ref().and_then(|l| self.literals.get(l)) {
+            return idx;
+        }
+        let idx = match self.free.get(self.reused) {
+            Some(&idx) => {
+                self.reused += 1;
+                self.values[idx as usize] = value;
+                idx
+            }
+            None => {
+                self.values.push(value);
+                // TODO, do safe casting
+                (self.values.len() as u32) - 1
+            }
+        };
+        if let Some(literal) = literal {
+            self.literals.insert(literal, idx);
+        }
+        idx
+    }
+
+    /// Index of the literal identical to the value, if there is one
+    pub fn find(&self, value: &Value) -> Option<u32> {
+        self.literals.get(&Literal::of(value)?).copied()
+    }
+
+    pub fn get(&self, idx: u32) -> Value {
+        // TODO: remove clone since we wouldn't want to clone a str
+        self.values[idx as usize].clone()
+    }
+
+    /// Replaces the constant at the index, for swapping in an optimized function
+    pub fn replace(&mut self, idx: u32, value: Value) {
+        let old = std::mem::replace(&mut self.values[idx as usize], value);
+        if let Some(literal) = Literal::of(&old) {
+            if self.literals.get(&literal) == Some(&idx) {
+                self.literals.remove(&literal);
+            }
+        }
+    }
+
+    pub fn mark(&self) -> ConstantMark {
+        ConstantMark {
+            len: self.values.len(),
+            reused: self.reused,
+        }
+    }
+
+    /// Indices of the slots pushes filled since the mark was taken. Marks taken before a
+    /// `release` can't be used after it.
+    pub fn filled_since(&self, mark: ConstantMark) -> impl Iterator<Item = u32> + '_ {
+        let reused = self.free[mark.reused..self.reused].iter().copied();
+        reused.chain(mark.len as u32..self.values.len() as u32)
+    }
+
+    /// Empties the slot for a later push to fill. Only for constants no instruction that can
+    /// still run refers to, such as the functions of a script that was replaced.
+    pub fn release(&mut self, idx: u32) {
+        self.replace(idx, Value::Nil);
+        self.free.drain(..self.reused);
+        self.reused = 0;
+        self.free.push(idx);
+    }
+
+    /// All constants, by index
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
 #[derive(Debug)]
 pub struct Chunk {
     pub bytecode: Vec<u8>,
-    constants: Vec<Value>,
     pub global_slots: u32,
     // Vec of line number to start
     line_info: Vec<(usize, usize)>,
@@ -69,18 +298,27 @@ pub struct Chunk {
     label_count: usize,
     labels: HashMap<LabelId, usize>,
     to_patch: Vec<(usize, LabelId)>,
+    // Offset of the last instruction pushed
+    last_instruction: Option<usize>,
+    // Offset of the last label pushed
+    last_label: Option<usize>,
+
+    // Computed on first use, once the chunk is no longer being written to
+    instruction_starts: OnceCell<Vec<bool>>,
 }
 
 impl Default for Chunk {
     fn default() -> Self {
         Chunk {
             bytecode: vec![],
-            constants: vec![],
             line_info: vec![(0, 0)],
             global_slots: 0,
             labels: HashMap::default(),
             to_patch: vec![],
             label_count: 0,
+            last_instruction: None,
+            last_label: None,
+            instruction_starts: OnceCell::new(),
         }
     }
 }
@@ -116,41 +354,134 @@ impl Chunk {
         }
     }
 
+    /// The line of the instruction at `offset`. Offsets past the end get the last line, and
+    /// chunks without any instructions get 0.
     pub fn get_line(&self, offset: usize) -> usize {
-        self.line_info
-            .iter()
-            .take_while_ref(|(_, o)| o <= &offset)
-            .last()
-            .map(|l| l.1)
-            .unwrap_or(0)
+        // Entries are in offset order, so this finds the last one starting at or before the
+        // offset. The first instruction always starts an entry at 0, after the (0, 0) placeholder.
+        let end = self
+            .line_info
+            .partition_point(|&(_, start)| start <= offset);
+        self.line_info[..end].last().map_or(0, |&(line, _)| line)
+    }
+
+    /// Size of the bytecode in bytes
+    pub fn size(&self) -> usize {
+        self.bytecode.len()
     }
 
-    pub fn push_constant(&mut self, value: Value) -> u32 {
-        if let Some(pos) = self.constants.iter().position(|v| v == &value) {
-            return pos as u32;
+    /// Whether an instruction starts at `offset`. The end of the chunk also counts, since
+    /// jumping there ends execution of the chunk.
+    pub fn is_instruction_start(&self, offset: usize) -> bool {
+        let starts = self.instruction_starts.get_or_init(|| {
+            let mut starts = vec![false; self.bytecode.len() + 1];
+            self.into_iter()
+                .for_each(|(offset, _)| starts[offset] = true);
+            starts[self.bytecode.len()] = true;
+            starts
+        });
+        starts.get(offset).copied().unwrap_or(false)
+    }
+
+    /// An empty chunk with the same global slots, for rewriting this chunk's code
+    pub fn empty_like(&self) -> Chunk {
+        Chunk {
+            global_slots: self.global_slots,
+            ..Chunk::default()
         }
-        self.constants.push(value);
-        // TODO, do safe casting
-        (self.constants.len() as u32) - 1
     }
 
-    pub fn get_constant(&self, idx: u32) -> Value {
-        // TODO: remove clone since we wouldn't want to clone a str
-        self.constants[idx as usize].clone()
+    /// Indices in the constant table of the constants the chunk's instructions use, in order
+    /// and without duplicates
+    pub fn used_constants(&self) -> Vec<u32> {
+        use ByteCode::*;
+        let mut used: Vec<u32> = self
+            .into_iter()
+            .filter_map(|(_, code)| match code {
+                Constant(idx) | AddConst(idx) | SubConst(idx) => Some(idx as u32),
+                ConstantLong(idx) | AddConstLong(idx) | SubConstLong(idx) => Some(idx),
+                _ => None,
+            })
+            .collect();
+        used.sort_unstable();
+        used.dedup();
+        used
+    }
+
+    /// Print the chunk, resolving constants to their values in `constants` and global slots to
+    /// the names in `global_names` (indexed by slot). Functions among the constants it uses are
+    /// printed after it, indented under it.
+    pub fn disassemble(&self, global_names: &[&str], constants: &ConstantTable) {
+        self.disassemble_indented(global_names, constants, "");
+    }
+
+    fn disassemble_indented(&self, global_names: &[&str], constants: &ConstantTable, indent: &str) {
+        println!("{indent}== CONSTANTS ==");
+        // Functions are listed by name, their code follows
+        let used = self.used_constants();
+        for &i in &used {
+            match &constants.get(i) {
+                Value::Func(func) => println!("{indent}{i:#06x}: {func}"),
+                v => println!("{indent}{i:#06x}: {v:?}"),
+            }
+        }
+        // Globals are shared, so they're only listed for the outermost chunk
+        if indent.is_empty() {
+            println!("=== GLOBALS ===");
+            println!("{} slots used", self.global_slots);
+            global_names
+                .iter()
+                .enumerate()
+                .for_each(|(i, name)| println!("{i:#06x}: {name}"));
+        }
+        println!("{indent}===============");
+        self.into_iter().for_each(|(offset, code)| {
+            println!(
+                "{indent}{offset:#06x}: {}",
+                self.describe_instruction(code, global_names, constants)
+            )
+        });
+        println!("{indent}===============");
+
+        let nested_indent = format!("{indent}    ");
+        for &i in &used {
+            if let Value::Func(func) = &constants.get(i) {
+                println!(
+                    "{nested_indent}== fn {} (arity {}) ==",
+                    func.name.as_deref().unwrap_or("<anonymous>"),
+                    func.describe_arity()
+                );
+                func.chunk
+                    .disassemble_indented(global_names, constants, &nested_indent);
+            }
+        }
     }
 
-    pub fn disassemble(&self) {
-        println!("== CONSTANTS ==");
-        self.constants
-            .iter()
-            .enumerate()
-            .for_each(|(i, v)| println!("{i:#06x}: {v:?}"));
-        println!("=== GLOBALS ===");
-        println!("{} slots used", self.global_slots);
-        println!("===============");
-        self.into_iter()
-            .for_each(|(offset, code)| println!("{offset:#06x}: {code:?}"));
-        println!("===============");
+    /// Describes an instruction as the disassembly does. Globals without a name in
+    /// `global_names` are shown by slot only.
+    pub fn describe_instruction(
+        &self,
+        bytecode: ByteCode,
+        global_names: &[&str],
+        constants: &ConstantTable,
+    ) -> String {
+        use ByteCode::*;
+        let resolved = match bytecode {
+            Constant(idx) | AddConst(idx) | SubConst(idx) => {
+                constants.values().get(idx as usize).map(describe_constant)
+            }
+            ConstantLong(idx) | AddConstLong(idx) | SubConstLong(idx) => {
+                constants.values().get(idx as usize).map(describe_constant)
+            }
+            SetGlobal(slot) | GetGlobal(slot) => {
+                global_names.get(slot as usize).map(|n| n.to_string())
+            }
+            _ => return format!("{bytecode:?}"),
+        };
+        match resolved {
+            Some(resolved) => format!("{bytecode:?} ({resolved})"),
+            None => format!("{bytecode:?}"),
+        }
     }
 
     fn push_raw_slice(&mut self, raw: &[u8]) {
@@ -175,6 +506,7 @@ impl Chunk {
             Nil => self.push_raw(3),
             True => self.push_raw(4),
             False => self.push_raw(5),
+            Uninit => self.push_raw(6),
             Negate => self.push_raw(0x10),
             Add => self.push_raw(0x11),
             Sub => self.push_raw(0x12),
@@ -184,10 +516,35 @@ impl Chunk {
             Not => self.push_raw(0x20),
             Eq => self.push_raw(0x21),
             Gt => self.push_raw(0x22),
+            AddConst(idx) => self.push_raw_slice(&[0x15, idx]),
+            SubConst(idx) => self.push_raw_slice(&[0x16, idx]),
+            FloorDiv => self.push_raw(0x18),
+            AddConstLong(idx) => {
+                self.push_raw(0x19);
+                self.push_raw_slice(&idx.to_le_bytes());
+            }
+            SubConstLong(idx) => {
+                self.push_raw(0x1A);
+                self.push_raw_slice(&idx.to_le_bytes());
+            }
+            Format(len) => {
+                self.push_raw(0x17);
+                self.push_raw_slice(&len.to_le_bytes());
+            }
             Lt => self.push_raw(0x23),
+            EqTrue => self.push_raw(0x24),
+            EqFalse => self.push_raw(0x25),
+            EqNil => self.push_raw(0x26),
+            RefEq => self.push_raw(0x27),
+            NotEq => self.push_raw(0x28),
+            NotGt => self.push_raw(0x29),
+            NotLt => self.push_raw(0x2A),
+            Ge => self.push_raw(0x2B),
+            Le => self.push_raw(0x2C),
 
             Pop => self.push_raw(0x40),
             Dup => self.push_raw(0x41),
+            DupAt(n) => self.push_raw_slice(&[0x42, n]),
             SetGlobal(slot) => {
                 self.push_raw(0x60);
                 self.push_raw_slice(&slot.to_le_bytes());
@@ -204,8 +561,12 @@ impl Chunk {
                 self.push_raw(0x63);
                 self.push_raw_slice(&slot.to_le_bytes());
             }
+            SetLocalByte(slot) => self.push_raw_slice(&[0x64, slot]),
+            GetLocalByte(slot) => self.push_raw_slice(&[0x65, slot]),
+            GetCallee => self.push_raw(0x66),
 
             Print => self.push_raw(0x80),
+            PrintErr => self.push_raw(0x81),
             JumpF(offset) => {
                 self.push_raw(0xA0);
                 self.push_raw_slice(&offset.to_le_bytes());
@@ -214,10 +575,141 @@ impl Chunk {
                 self.push_raw(0xA1);
                 self.push_raw_slice(&offset.to_le_bytes());
             }
+            Call(arg_count) => self.push_raw_slice(&[0xA2, arg_count]),
+            CallNamed(positional, named) => self.push_raw_slice(&[0xA6, positional, named]),
+            Yield => self.push_raw(0xA3),
+            AssertFailed => self.push_raw(0xA5),
+            TailCall(arg_count) => self.push_raw_slice(&[0xA7, arg_count]),
+            CallSpread(list_count) => self.push_raw_slice(&[0xA8, list_count]),
+            PopJump(offset) => {
+                self.push_raw(0xA9);
+                self.push_raw_slice(&offset.to_le_bytes());
+            }
+            PushHandler(offset) => {
+                self.push_raw(0xAA);
+                self.push_raw_slice(&offset.to_le_bytes());
+            }
+            PopHandler => self.push_raw(0xAB),
+            IterNext(offset) => {
+                self.push_raw(0xAC);
+                self.push_raw_slice(&offset.to_le_bytes());
+            }
+            Nop => self.push_raw(0xAD),
+            CheckCounter => self.push_raw(0xAE),
+            JumpTable(min, len) => {
+                self.push_raw(0xAF);
+                self.push_raw_slice(&min.to_le_bytes());
+                self.push_raw_slice(&len.to_le_bytes());
+            }
+
+            BuildList(len) => {
+                self.push_raw(0xC0);
+                self.push_raw_slice(&len.to_le_bytes());
+            }
+            BuildMap(len) => {
+                self.push_raw(0xC1);
+                self.push_raw_slice(&len.to_le_bytes());
+            }
+            GetIndex => self.push_raw(0xC2),
+            SetIndex => self.push_raw(0xC3),
+            MakeRange(inclusive) => self.push_raw_slice(&[0xC4, inclusive as u8]),
+            Unpack(len) => {
+                self.push_raw(0xC5);
+                self.push_raw_slice(&len.to_le_bytes());
+            }
         }
+        self.last_instruction = Some(offset);
         self.extend_line_info(line, offset);
     }
 
+    /// Whether the last instruction pushed is a call, in which case a Return right after it
+    /// makes it a tail call
+    pub fn is_tail_position(&self) -> bool {
+        self.last_instruction
+            .is_some_and(|offset| matches!(self.bytecode[offset], 0xA2))
+    }
+
+    /// Turns the last instruction into a tail call, see `is_tail_position`. It has the same size
+    /// so jumps past it stay valid.
+    pub fn make_tail_call(&mut self) {
+        let offset = self.last_instruction.unwrap();
+        debug_assert!(self.is_tail_position());
+        self.bytecode[offset] = 0xA7;
+    }
+
+    /// Whether the code from `start` on only computes a single value, with no side effects and no
+    /// way to fail, so that it can be dropped if the value goes unused
+    pub fn is_pure_from(&self, start: usize, constants: &ConstantTable) -> bool {
+        use ByteCode::*;
+
+        /// What is known about a value the code pushed
+        #[derive(Clone, Copy, PartialEq)]
+        enum Known {
+            /// A constant number that is neither NaN nor infinite
+            FiniteNumber,
+            Number,
+            NotNumber,
+        }
+
+        let mut instructions = self.into_iter();
+        instructions.ptr = start;
+        let mut stack: Vec<Known> = vec![];
+        let constant = |idx: u32| match constants.values()[idx as usize] {
+            Value::Number(n) if n.is_finite() => Known::FiniteNumber,
+            Value::Number(_) => Known::Number,
+            _ => Known::NotNumber,
+        };
+        let is_number = |idx: u32| constant(idx) != Known::NotNumber;
+        for (_, bytecode) in instructions {
+            // How many values it pops, whether they must be numbers to not fail, and what it
+            // pushes
+            let (pops, needs_numbers, pushes) = match bytecode {
+                Constant(idx) => (0, false, constant(idx as u32)),
+                ConstantLong(idx) => (0, false, constant(idx)),
+                Nil | True | False => (0, false, Known::NotNumber),
+                Negate => (1, true, Known::Number),
+                AddConst(idx) | SubConst(idx) if is_number(idx as u32) => (1, true, Known::Number),
+                AddConstLong(idx) | SubConstLong(idx) if is_number(idx) => (1, true, Known::Number),
+                Add | Sub | Mul | Div | FloorDiv => (2, true, Known::Number),
+                Gt | Lt | Ge | Le => (2, true, Known::NotNumber),
+                // Comparing with nil, and by identity, works on any values. Other equality can
+                // fail on mixed types with `MixedEquality::Error`, so needs numbers.
+                Not | EqNil => (1, false, Known::NotNumber),
+                RefEq => (2, false, Known::NotNumber),
+                Eq => (2, true, Known::NotNumber),
+                Format(n) | BuildList(n) => (n as usize, false, Known::NotNumber),
+                BuildMap(n) => (2 * n as usize, false, Known::NotNumber),
+                _ => return false,
+            };
+            let Some(rest) = stack.len().checked_sub(pops) else {
+                return false;
+            };
+            let popped = &stack[rest..];
+            if needs_numbers && popped.contains(&Known::NotNumber) {
+                return false;
+            }
+            // Numeric keys that might be NaN or infinite are rejected
+            if matches!(bytecode, BuildMap(_))
+                && popped.iter().step_by(2).any(|&key| key == Known::Number)
+            {
+                return false;
+            }
+            stack.truncate(rest);
+            stack.push(pushes);
+        }
+        stack.len() == 1
+    }
+
+    /// Drops the code from `start` on, which nothing may jump into, along with any jumps in it
+    pub fn truncate(&mut self, start: usize) {
+        self.bytecode.truncate(start);
+        self.to_patch.retain(|&(offset, _)| offset < start);
+        while self.line_info.len() > 1 && self.line_info.last().unwrap().1 >= start {
+            self.line_info.pop();
+        }
+        self.last_instruction = None;
+    }
+
     pub fn push_monkey_patch(&mut self, bytecode: ByteCode, line: usize, label: usize) {
         let offset = self.bytecode.len();
         self.push(bytecode, line);
@@ -226,6 +718,25 @@ impl Chunk {
 
     pub fn push_label(&mut self, l: usize) {
         self.labels.insert(l, self.bytecode.len());
+        self.last_label = Some(self.bytecode.len());
+    }
+
+    /// The last instruction pushed, if the next one is sure to run right after it, i.e. no
+    /// label was pushed since
+    pub fn last_instruction(&self) -> Option<ByteCode> {
+        let offset = self.last_instruction?;
+        if self.last_label == Some(self.bytecode.len()) {
+            return None;
+        }
+        let mut instructions = self.into_iter();
+        instructions.ptr = offset;
+        instructions.next().map(|(_, bytecode)| bytecode)
+    }
+
+    /// Places label `l` where `other` was pushed
+    pub fn alias_label(&mut self, l: usize, other: usize) {
+        let location = self.labels[&other];
+        self.labels.insert(l, location);
     }
 
     pub fn allocate_new_label(&mut self) -> usize {
@@ -239,16 +750,365 @@ impl Chunk {
             let resolved_location = *self
                 .labels
                 .get(&label)
-                .expect(&format!("Unresolved label {label}"));
+                .unwrap_or_else(|| panic!("Unresolved label {label}"));
 
             let delta: i16 = (resolved_location as isize - offset as isize)
                 .try_into()
-                .expect(&format!(
-                    "Jump from {offset} to {resolved_location} is too far"
-                ));
+                .unwrap_or_else(|_| panic!("Jump from {offset} to {resolved_location} is too far"));
 
             self.bytecode[offset + 1..offset + 3].copy_from_slice(&delta.to_le_bytes())
         }
+        self.thread_jumps();
+        self.erase_jumps_to_next();
+    }
+
+    /// Where the jump at `offset` lands
+    fn jump_target(&self, offset: usize) -> usize {
+        let delta = i16::from_le_bytes([self.bytecode[offset + 1], self.bytecode[offset + 2]]);
+        (offset as isize + delta as isize) as usize
+    }
+
+    /// Makes patched jumps that land on an unconditional jump go straight to where that one
+    /// goes, following chains of them but stopping at a cycle
+    fn thread_jumps(&mut self) {
+        for &(offset, _) in self.to_patch.iter() {
+            // JumpF, JumpRelative and PopJump, but not PushHandler
+            if !matches!(self.bytecode[offset], 0xA0 | 0xA1 | 0xA9) {
+                continue;
+            }
+            let mut target = self.jump_target(offset);
+            let mut seen = vec![offset];
+            while self.bytecode.get(target) == Some(&0xA1) && !seen.contains(&target) {
+                seen.push(target);
+                target = self.jump_target(target);
+            }
+            if let Ok(delta) = i16::try_from(target as isize - offset as isize) {
+                self.bytecode[offset + 1..offset + 3].copy_from_slice(&delta.to_le_bytes())
+            }
+        }
+    }
+
+    /// Replaces patched jumps that land on the next instruction with `Nop`s, keeping a
+    /// `PopJump`'s pop, so that no offsets move
+    fn erase_jumps_to_next(&mut self) {
+        for &(offset, _) in self.to_patch.iter() {
+            let erased = match self.bytecode[offset] {
+                0xA1 => [0xAD, 0xAD, 0xAD],
+                0xA9 => [0x40, 0xAD, 0xAD],
+                _ => continue,
+            };
+            if self.jump_target(offset) == offset + 3 {
+                self.bytecode[offset..offset + 3].copy_from_slice(&erased);
+            }
+        }
+    }
+}
+
+// Serialization
+//
+// A serialized chunk is its global slot count, line info and bytecode, with all integers little
+// endian and lengths as u32. Labels are not kept, so chunks must have their monkey patches
+// resolved before being serialized. The outermost chunk is preceded by MAGIC, FORMAT_VERSION and
+// the constant table, in which functions embed their own chunk.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializeError {
+    /// Only literals and functions can be stored as constants
+    UnsupportedConstant(&'static str),
+    UnexpectedEnd,
+    InvalidConstantTag(u8),
+    InvalidUtf8,
+    /// The bytes don't start with `MAGIC`, so they aren't a serialized chunk
+    InvalidMagic,
+    /// Serialized by a different version of the format, see `FORMAT_VERSION`
+    UnsupportedVersion(u8),
+    /// The bytecode has a byte that isn't an opcode where an instruction should start
+    InvalidOpcode(u8),
+    ConstantOutOfRange(u32),
+    GlobalOutOfRange(u32),
+    /// The jump at the offset doesn't land on an instruction, or its jump table is incomplete
+    InvalidJump(usize),
+    /// A function starts running at an offset that isn't an instruction
+    InvalidEntryPoint(usize),
+}
+
+const MAGIC: [u8; 4] = *b"LOXC";
+/// Bumped whenever the encoding or the meaning of any opcode changes
+const FORMAT_VERSION: u8 = 2;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STR: u8 = 3;
+const TAG_FUNC: u8 = 4;
+const TAG_SYMBOL: u8 = 5;
+
+impl Chunk {
+    /// Serializes the chunk along with the constant table its instructions refer to
+    pub fn to_bytes(&self, constants: &ConstantTable) -> Result<Vec<u8>, SerializeError> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        write_len(&mut out, constants.len());
+        for constant in constants.values() {
+            write_constant(&mut out, constant)?;
+        }
+        self.write_bytes(&mut out);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Chunk, ConstantTable), SerializeError> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+        if reader.read_array().ok() != Some(MAGIC) {
+            return Err(SerializeError::InvalidMagic);
+        }
+        match reader.read_u8()? {
+            FORMAT_VERSION => {
+                // Read as is rather than pushed, since equal constants must keep their indices
+                let values: Vec<Value> = (0..reader.read_u32()?)
+                    .map(|_| read_constant(&mut reader))
+                    .collect::<Result<_, _>>()?;
+                let chunk = Chunk::read_bytes(&mut reader)?;
+
+                // Nothing checks instructions as they run, so check them all now
+                chunk.validate(values.len(), chunk.global_slots)?;
+                for value in values.iter() {
+                    if let Value::Func(func) = value {
+                        func.chunk.validate(values.len(), chunk.global_slots)?;
+                        if let Some(&entry) = func
+                            .entry_points
+                            .iter()
+                            .find(|&&entry| !func.chunk.is_instruction_start(entry))
+                        {
+                            return Err(SerializeError::InvalidEntryPoint(entry));
+                        }
+                    }
+                }
+                Ok((chunk, ConstantTable::from_values(values)))
+            }
+            version => Err(SerializeError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.global_slots.to_le_bytes());
+
+        write_len(out, self.line_info.len());
+        for &(line, offset) in self.line_info.iter() {
+            write_len(out, line);
+            write_len(out, offset);
+        }
+
+        write_len(out, self.bytecode.len());
+        out.extend_from_slice(&self.bytecode);
+    }
+
+    fn read_bytes(reader: &mut ByteReader) -> Result<Chunk, SerializeError> {
+        let global_slots = reader.read_u32()?;
+
+        let line_info = (0..reader.read_u32()?)
+            .map(|_| Ok((reader.read_u32()? as usize, reader.read_u32()? as usize)))
+            .collect::<Result<_, _>>()?;
+
+        let len = reader.read_u32()? as usize;
+        let bytecode = reader.read_slice(len)?.to_vec();
+
+        Ok(Chunk {
+            bytecode,
+            global_slots,
+            line_info,
+            ..Default::default()
+        })
+    }
+
+    /// Checks that the bytecode is made of whole instructions whose constants, globals and jump
+    /// targets exist, given the number of each there are
+    fn validate(&self, constants: usize, global_slots: u32) -> Result<(), SerializeError> {
+        use ByteCode::*;
+        let len = self.bytecode.len();
+        // Decoding doesn't check that the operands are all there, so decode a padded copy
+        let mut padded = Chunk {
+            bytecode: self.bytecode.clone(),
+            ..Default::default()
+        };
+        padded.bytecode.extend_from_slice(&[0; 8]);
+        let mut instructions = vec![];
+        let mut iterator = padded.into_iter();
+        while iterator.ptr < len {
+            let Some(instruction) = iterator.next() else {
+                return Err(SerializeError::InvalidOpcode(
+                    self.bytecode[iterator.ptr - 1],
+                ));
+            };
+            if iterator.ptr > len {
+                return Err(SerializeError::UnexpectedEnd);
+            }
+            instructions.push(instruction);
+        }
+
+        let lands = |offset: usize, j_offset: i16| {
+            usize::try_from(offset as isize + j_offset as isize)
+                .is_ok_and(|target| target <= len && self.is_instruction_start(target))
+        };
+        for (i, &(offset, bytecode)) in instructions.iter().enumerate() {
+            match bytecode {
+                Constant(idx) | AddConst(idx) | SubConst(idx) if idx as usize >= constants => {
+                    return Err(SerializeError::ConstantOutOfRange(idx as u32))
+                }
+                ConstantLong(idx) | AddConstLong(idx) | SubConstLong(idx)
+                    if idx as usize >= constants =>
+                {
+                    return Err(SerializeError::ConstantOutOfRange(idx))
+                }
+                SetGlobal(slot) | GetGlobal(slot) if slot >= global_slots => {
+                    return Err(SerializeError::GlobalOutOfRange(slot))
+                }
+                JumpF(j) | JumpRelative(j) | PopJump(j) | PushHandler(j) | IterNext(j)
+                    if !lands(offset, j) =>
+                {
+                    return Err(SerializeError::InvalidJump(offset))
+                }
+                JumpTable(_, entries) => {
+                    let table = instructions.get(i + 1..i + entries as usize + 3);
+                    if !table.is_some_and(|t| t.iter().all(|(_, b)| matches!(b, JumpRelative(_)))) {
+                        return Err(SerializeError::InvalidJump(offset));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    // TODO: safe convert
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_constant(out: &mut Vec<u8>, value: &Value) -> Result<(), SerializeError> {
+    match value {
+        Value::Nil => out.push(TAG_NIL),
+        Value::Bool(b) => out.extend_from_slice(&[TAG_BOOL, *b as u8]),
+        Value::Number(n) => {
+            out.push(TAG_NUMBER);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        Value::Str(s) => {
+            out.push(TAG_STR);
+            write_str(out, s);
+        }
+        Value::Symbol(name) => {
+            out.push(TAG_SYMBOL);
+            write_str(out, name);
+        }
+        Value::Func(func) => {
+            // Functions embed their own chunk, whose constants are in the same table
+            out.push(TAG_FUNC);
+            match &func.name {
+                Some(name) => {
+                    out.push(1);
+                    write_str(out, name);
+                }
+                None => out.push(0),
+            }
+            write_len(out, func.def_line);
+            write_len(out, func.arity);
+            for param in func.params.iter() {
+                write_str(out, param);
+            }
+            write_len(out, func.entry_points.len());
+            for &entry in func.entry_points.iter() {
+                write_len(out, entry);
+            }
+            out.push(func.is_generator as u8);
+            func.chunk.write_bytes(out);
+        }
+        v => return Err(SerializeError::UnsupportedConstant(v.type_name())),
+    }
+    Ok(())
+}
+
+fn read_constant(reader: &mut ByteReader) -> Result<Value, SerializeError> {
+    let value = match reader.read_u8()? {
+        TAG_NIL => Value::Nil,
+        TAG_BOOL => Value::Bool(reader.read_u8()? != 0),
+        TAG_NUMBER => Value::Number(f64::from_le_bytes(reader.read_array()?)),
+        TAG_STR => Value::Str(reader.read_str()?.into()),
+        TAG_SYMBOL => Value::Symbol(reader.read_str()?.into()),
+        TAG_FUNC => {
+            let name = match reader.read_u8()? {
+                0 => None,
+                _ => Some(reader.read_str()?.into()),
+            };
+            let def_line = reader.read_u32()? as usize;
+            let arity = reader.read_u32()? as usize;
+            let params = (0..arity)
+                .map(|_| Ok(reader.read_str()?.into()))
+                .collect::<Result<_, _>>()?;
+            let entry_points = (0..reader.read_u32()?)
+                .map(|_| Ok(reader.read_u32()? as usize))
+                .collect::<Result<_, _>>()?;
+            let is_generator = reader.read_u8()? != 0;
+            let chunk = Chunk::read_bytes(reader)?;
+            Value::Func(Rc::new(FuncObj {
+                arity,
+                chunk,
+                name,
+                def_line,
+                params,
+                entry_points,
+                is_generator,
+            }))
+        }
+        tag => return Err(SerializeError::InvalidConstantTag(tag)),
+    };
+    Ok(value)
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], SerializeError> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(SerializeError::UnexpectedEnd)?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], SerializeError> {
+        // unwrap since read_slice returns exactly N bytes
+        Ok(self.read_slice(N)?.try_into().unwrap())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, SerializeError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SerializeError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_str(&mut self) -> Result<&'a str, SerializeError> {
+        let len = self.read_u32()? as usize;
+        std::str::from_utf8(self.read_slice(len)?).map_err(|_| SerializeError::InvalidUtf8)
+    }
+}
+
+fn describe_constant(value: &Value) -> String {
+    match value {
+        Value::Str(s) => format!("{s:?}"),
+        Value::Func(func) => format!("{func}, declared on line {}", func.def_line),
+        v => v.to_string(),
     }
 }
 
@@ -280,18 +1140,61 @@ impl<'a> Iterator for ChunkIterator<'a> {
             3 => ByteCode::Nil,
             4 => ByteCode::True,
             5 => ByteCode::False,
+            6 => ByteCode::Uninit,
             0x10 => ByteCode::Negate,
             0x11 => ByteCode::Add,
             0x12 => ByteCode::Sub,
             0x13 => ByteCode::Mul,
             0x14 => ByteCode::Div,
+            0x18 => ByteCode::FloorDiv,
+            0x19 | 0x1A => {
+                self.ptr += 4;
+                let idx = u32::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 5]
+                        .try_into()
+                        .unwrap(),
+                );
+                match opcode {
+                    0x19 => ByteCode::AddConstLong(idx),
+                    _ => ByteCode::SubConstLong(idx),
+                }
+            }
             0x20 => ByteCode::Not,
             0x21 => ByteCode::Eq,
             0x22 => ByteCode::Gt,
+            0x15 => {
+                self.ptr += 1;
+                ByteCode::AddConst(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0x16 => {
+                self.ptr += 1;
+                ByteCode::SubConst(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0x17 => {
+                self.ptr += 4;
+                ByteCode::Format(u32::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 5]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
             0x23 => ByteCode::Lt,
+            0x24 => ByteCode::EqTrue,
+            0x25 => ByteCode::EqFalse,
+            0x26 => ByteCode::EqNil,
+            0x27 => ByteCode::RefEq,
+            0x28 => ByteCode::NotEq,
+            0x29 => ByteCode::NotGt,
+            0x2A => ByteCode::NotLt,
+            0x2B => ByteCode::Ge,
+            0x2C => ByteCode::Le,
 
             0x40 => ByteCode::Pop,
             0x41 => ByteCode::Dup,
+            0x42 => {
+                self.ptr += 1;
+                ByteCode::DupAt(self.inner.bytecode[opcode_ptr + 1])
+            }
 
             0x60 => {
                 self.ptr += 4;
@@ -325,8 +1228,18 @@ impl<'a> Iterator for ChunkIterator<'a> {
                         .unwrap(),
                 ))
             }
+            0x64 => {
+                self.ptr += 1;
+                ByteCode::SetLocalByte(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0x65 => {
+                self.ptr += 1;
+                ByteCode::GetLocalByte(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0x66 => ByteCode::GetCallee,
 
             0x80 => ByteCode::Print,
+            0x81 => ByteCode::PrintErr,
 
             0xA0 => {
                 self.ptr += 2;
@@ -344,6 +1257,93 @@ impl<'a> Iterator for ChunkIterator<'a> {
                         .unwrap(),
                 ))
             }
+            0xA2 => {
+                self.ptr += 1;
+                ByteCode::Call(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0xA7 => {
+                self.ptr += 1;
+                ByteCode::TailCall(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0xA8 => {
+                self.ptr += 1;
+                ByteCode::CallSpread(self.inner.bytecode[opcode_ptr + 1])
+            }
+            0xA9 => {
+                self.ptr += 2;
+                ByteCode::PopJump(i16::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 3]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
+            0xAA => {
+                self.ptr += 2;
+                ByteCode::PushHandler(i16::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 3]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
+            0xAB => ByteCode::PopHandler,
+            0xAC => {
+                self.ptr += 2;
+                ByteCode::IterNext(i16::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 3]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
+            0xAD => ByteCode::Nop,
+            0xAE => ByteCode::CheckCounter,
+            0xAF => {
+                self.ptr += 6;
+                let operands = &self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 7];
+                ByteCode::JumpTable(
+                    i32::from_le_bytes(operands[..4].try_into().unwrap()),
+                    u16::from_le_bytes(operands[4..].try_into().unwrap()),
+                )
+            }
+            0xA3 => ByteCode::Yield,
+            0xA5 => ByteCode::AssertFailed,
+            0xA6 => {
+                self.ptr += 2;
+                ByteCode::CallNamed(
+                    self.inner.bytecode[opcode_ptr + 1],
+                    self.inner.bytecode[opcode_ptr + 2],
+                )
+            }
+
+            0xC0 => {
+                self.ptr += 4;
+                ByteCode::BuildList(u32::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 5]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
+            0xC1 => {
+                self.ptr += 4;
+                ByteCode::BuildMap(u32::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 5]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
+            0xC2 => ByteCode::GetIndex,
+            0xC3 => ByteCode::SetIndex,
+            0xC4 => {
+                self.ptr += 1;
+                ByteCode::MakeRange(self.inner.bytecode[opcode_ptr + 1] != 0)
+            }
+            0xC5 => {
+                self.ptr += 4;
+                ByteCode::Unpack(u32::from_le_bytes(
+                    self.inner.bytecode[opcode_ptr + 1..opcode_ptr + 5]
+                        .try_into()
+                        .unwrap(),
+                ))
+            }
 
             // throw an error!
             _ => return None,
@@ -1,13 +1,19 @@
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    rc::Rc,
+};
 
 use itertools::Itertools;
 use num_traits::FromPrimitive;
 
 use crate::{
-    chunk::Chunk,
-    scanner::{Token, TokenScanner, TokenType},
+    chunk::{Chunk, ConstantTable},
+    diagnostics::{self, Severity},
+    native::NATIVES,
+    scanner::{ScanError, Token, TokenScanner, TokenType},
     util::PrevPeekable,
-    value::FuncObj,
+    value::{FuncObj, Value},
     vm::InterpretError,
 };
 
@@ -20,6 +26,11 @@ pub enum Precedence {
     And,
     Equality,
     Comparison,
+    /// `a..b` and `a..=b`, so `0..n + 1` is `0..(n + 1)`
+    Range,
+    /// Binds looser than arithmetic but tighter than comparisons (as in Kotlin), so
+    /// `1 + 2 ?: 3` is `(1 + 2) ?: 3`, `nil ?: 1 + 2` is `nil ?: (1 + 2)` and `a ?: b == c` is
+    /// `(a ?: b) == c`. It is right associative.
     Elvis,
     Term,
     Factor,
@@ -36,21 +47,26 @@ impl Precedence {
 
     pub fn of(ttype: TokenType) -> Precedence {
         match ttype {
-            TokenType::LParen => Precedence::None,
+            TokenType::LParen => Precedence::Call,
             TokenType::RParen => Precedence::None,
             TokenType::LBrace => Precedence::None,
             TokenType::RBrace => Precedence::None,
+            TokenType::LBracket => Precedence::Call,
+            TokenType::RBracket => Precedence::None,
             TokenType::Comma => Precedence::None,
             TokenType::Dot => Precedence::None,
             TokenType::Minus => Precedence::Term,
             TokenType::Plus => Precedence::Term,
             TokenType::Semi => Precedence::None,
             TokenType::Slash => Precedence::Factor,
+            TokenType::TildeSlash => Precedence::Factor,
             TokenType::Star => Precedence::Factor,
             TokenType::Bang => Precedence::None,
-            TokenType::BangEqual => Precedence::None,
+            TokenType::BangEqual => Precedence::Equality,
             TokenType::Equal => Precedence::None,
             TokenType::EqualEqual => Precedence::Equality,
+            TokenType::EqualEqualEqual => Precedence::Equality,
+            TokenType::BangEqualEqual => Precedence::Equality,
             TokenType::Greater => Precedence::Comparison,
             TokenType::GreaterEqual => Precedence::Comparison,
             TokenType::Less => Precedence::Comparison,
@@ -69,6 +85,7 @@ impl Precedence {
             TokenType::Nil => Precedence::None,
             TokenType::Or => Precedence::Or,
             TokenType::Print => Precedence::None,
+            TokenType::EPrint => Precedence::None,
             TokenType::Return => Precedence::None,
             TokenType::Super => Precedence::None,
             TokenType::This => Precedence::None,
@@ -77,45 +94,70 @@ impl Precedence {
             TokenType::Val => Precedence::None,
             TokenType::While => Precedence::None,
             TokenType::Error => Precedence::None,
+            TokenType::Comment => Precedence::None,
             TokenType::Bar => Precedence::None,
             TokenType::FatArrow => Precedence::None,
             TokenType::Match => Precedence::None,
-            TokenType::Question => Precedence::None,
+            TokenType::Yield => Precedence::None,
+            TokenType::Assert => Precedence::None,
+            TokenType::Enum => Precedence::None,
+            TokenType::Try => Precedence::None,
+            TokenType::Catch => Precedence::None,
+            TokenType::Break => Precedence::None,
+            TokenType::Continue => Precedence::None,
+            TokenType::Question => Precedence::Elvis,
             TokenType::Colon => Precedence::None,
             TokenType::QuestionColon => Precedence::Elvis,
+            TokenType::QuestionColonEqual => Precedence::None,
+            TokenType::PlusEqual => Precedence::None,
+            TokenType::MinusEqual => Precedence::None,
+            TokenType::StarEqual => Precedence::None,
+            TokenType::SlashEqual => Precedence::None,
+            TokenType::DotDot => Precedence::Range,
+            TokenType::DotDotEqual => Precedence::Range,
+            TokenType::DotDotDot => Precedence::None,
         }
     }
 }
 pub fn report_error(token: &Token, msg: &str) {
+    diagnostics::push(Severity::Error, Some(token.line), msg);
     println!(
         "Error at line {}, token '{}': {msg}",
         token.line, token.lexeme
     );
 }
 
+pub fn report_warning(token: &Token, msg: &str) {
+    diagnostics::push(Severity::Warning, Some(token.line), msg);
+    println!(
+        "Warning at line {}, token '{}': {msg}",
+        token.line, token.lexeme
+    );
+}
+
 pub fn report_error_eof(msg: &str) {
+    diagnostics::push(Severity::Error, None, msg);
     println!("Error at end of file: {msg}");
 }
 
+/// Skips error tokens, reporting the scanner's errors as they are found. The errors are also
+/// collected into `errors`, which is shared with the compiler so that compiling fails.
 pub struct ErrorIgnoreTokenScanner<'a> {
     pub inner: TokenScanner<'a>,
+    pub errors: Rc<RefCell<Vec<ScanError<'a>>>>,
 }
 
 impl<'a> Iterator for ErrorIgnoreTokenScanner<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut takewhile = self
-            .inner
-            .take_while_ref(|tok| tok.ttype == TokenType::Error);
-        let err = takewhile.next();
-        takewhile.last();
-
-        // TODO, bubble this up somewhere higher instead of reporting it from here
-        if let Some(err) = err {
-            report_error(&err, &format!("Unexpected Token '{}'", err.lexeme));
+        let tok = self.inner.find(|tok| tok.ttype != TokenType::Error);
+
+        for err in self.inner.errors.drain(..) {
+            report_error(&err.token, &err.to_string());
+            self.errors.borrow_mut().push(err);
         }
-        self.inner.next()
+        tok
     }
 }
 
@@ -125,52 +167,130 @@ impl<'a> PrevPeekable<ErrorIgnoreTokenScanner<'a>> {
         ttype: TokenType,
         msg: &str,
     ) -> Result<Token<'a>, InterpretError> {
-        if let Some(tok) = self.next() {
+        // A mismatched token is left for recovering from the error, see `Compiler::synchronize`
+        if let Some(&tok) = self.peek() {
             if tok.ttype != ttype {
                 report_error(&tok, msg);
                 return Err(InterpretError::Compiler);
             } else {
-                return Ok(tok.clone());
+                self.next();
+                return Ok(tok);
             }
         }
         report_error_eof(msg);
         Err(InterpretError::Compiler)
     }
+
+    /// Consumes the ';' terminating `construct`, telling apart a file that ends without one
+    pub fn consume_semi(&mut self, construct: &str) -> Result<Token<'a>, InterpretError> {
+        if self.peek().is_none() {
+            report_error_eof(&format!(
+                "Expected ';' after {construct}, but the file ended"
+            ));
+            return Err(InterpretError::Compiler);
+        }
+        self.consume_token(TokenType::Semi, &format!("Expected ';' after {construct}"))
+    }
 }
 
 pub type CompilerResult<T> = Result<T, InterpretError>;
 
-#[derive(Debug, Default)]
-pub struct GlobalBindings<'a> {
-    pub global_slots: BTreeMap<&'a str, u32>,
-    pub undeclared_globals: HashSet<&'a str>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalBindings {
+    pub global_slots: BTreeMap<String, u32>,
+    pub undeclared_globals: HashSet<String>,
+    /// Natives the script hasn't declared a global of the same name over. Declaring one takes
+    /// over the native's slot, so natives like `next` don't take the name from scripts.
+    pub natives: HashSet<String>,
+    /// Members of each declared enum. Members are compiled to symbol constants, so enums have
+    /// no runtime binding.
+    pub enums: HashMap<String, Vec<String>>,
+    /// Values of global `val`s, which are initialized with a literal and inlined wherever they
+    /// are used instead of having a slot
+    pub constants: HashMap<String, Value>,
+    /// Parameters of each top-level function, for checking the names of named arguments
+    pub function_params: HashMap<String, Vec<Rc<str>>>,
+    /// Globals assigned to after their declaration, which may no longer hold the function they
+    /// were declared as
+    pub assigned_globals: HashSet<String>,
 }
 
-impl<'a> GlobalBindings<'a> {
-    fn next_undeclared_slot(&mut self) -> u32 {
-        self.global_slots
-            .last_entry()
-            .map(|e| e.get() + 1)
-            .unwrap_or(0)
+impl Default for GlobalBindings {
+    /// Bindings with the natives declared, matching the globals of a default VM
+    fn default() -> Self {
+        let global_slots = NATIVES
+            .iter()
+            .enumerate()
+            .map(|(slot, native)| (native.name.to_owned(), slot as u32))
+            .collect();
+        GlobalBindings {
+            global_slots,
+            undeclared_globals: HashSet::new(),
+            natives: NATIVES
+                .iter()
+                .map(|native| native.name.to_owned())
+                .collect(),
+            enums: HashMap::new(),
+            constants: HashMap::new(),
+            function_params: HashMap::new(),
+            assigned_globals: HashSet::new(),
+        }
+    }
+}
+
+impl GlobalBindings {
+    fn next_undeclared_slot(&self) -> u32 {
+        self.global_slots.len() as u32
     }
 
-    pub fn use_binding(&mut self, name: &'a str) -> u32 {
+    pub fn use_binding(&mut self, name: &str) -> u32 {
         let next_idx = self.next_undeclared_slot();
         self.global_slots.get(name).cloned().unwrap_or_else(|| {
-            self.global_slots.insert(name, next_idx);
-            self.undeclared_globals.insert(name);
+            self.global_slots.insert(name.to_owned(), next_idx);
+            self.undeclared_globals.insert(name.to_owned());
             next_idx
         })
     }
 
-    pub fn declare_binding(&mut self, name: &'a str) -> Option<u32> {
-        self.undeclared_globals.remove(name);
-        if self.global_slots.contains_key(name) {
+    /// Reports the globals that were used but never declared, failing if there are any
+    pub fn check_all_declared(&self) -> CompilerResult<()> {
+        if self.undeclared_globals.is_empty() {
+            return Ok(());
+        }
+        report_error_eof(&format!(
+            "The following global bindings were not declared but were used: {}",
+            self.undeclared_globals
+                .iter()
+                .map(|n| format!("'{n}'"))
+                .join(", ")
+        ));
+        Err(InterpretError::Compiler)
+    }
+
+    /// Global names indexed by their slot
+    pub fn names_by_slot(&self) -> Vec<&str> {
+        self.global_slots
+            .iter()
+            .sorted_by_key(|(_, &slot)| slot)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    pub fn declare_binding(&mut self, name: &str) -> Option<u32> {
+        if self.undeclared_globals.remove(name) {
+            // Already used before being declared (e.g. by a function calling another function
+            // declared after it), so the slot was allocated by use_binding
+            return self.global_slots.get(name).cloned();
+        }
+        if self.natives.remove(name) {
+            return self.global_slots.get(name).cloned();
+        }
+        if self.global_slots.contains_key(name) || self.constants.contains_key(name) {
             // can't redeclare
             None
         } else {
             let next_idx = self.next_undeclared_slot();
-            self.global_slots.insert(name, next_idx);
+            self.global_slots.insert(name.to_owned(), next_idx);
             Some(next_idx)
         }
     }
@@ -183,6 +303,18 @@ pub enum ChunkType {
     Function,
 }
 
+/// A loop being compiled, which `break` and `continue` can jump out of
+#[derive(Debug)]
+pub struct Loop<'a> {
+    pub label: Option<&'a str>,
+    pub break_label: usize,
+    pub continue_label: usize,
+    /// Locals deeper than this are popped before jumping out
+    pub depth: isize,
+    /// Handlers pushed beyond this many are popped before jumping out
+    pub try_depth: usize,
+}
+
 #[derive(Debug, Default)]
 pub struct Scope<'a> {
     pub chunk_type: ChunkType,
@@ -193,19 +325,74 @@ pub struct Scope<'a> {
     // Depth, Token, Mutable
     pub locals: Vec<(isize, Token<'a>, bool)>,
     pub depth: isize,
+
+    /// Local `val`s initialized with a literal, which are inlined like global ones instead of
+    /// taking a slot
+    pub constants: Vec<(isize, Token<'a>, Value)>,
+
+    /// Number of try blocks being compiled in this function
+    pub try_depth: usize,
+
+    /// Indices of the distinct constants this function's chunk refers to, see
+    /// `Compiler::max_constants`
+    pub constants_used: HashSet<u32>,
+
+    /// Loops being compiled in this function, innermost last
+    pub loops: Vec<Loop<'a>>,
+
+    /// Lines of the first `return` with a value and the first without one in this function,
+    /// see `Compiler::warn_mixed_returns`
+    pub value_return_line: Option<usize>,
+    pub bare_return_line: Option<usize>,
+
+    /// Name of a function declared in a local scope, which refers to the function itself in its
+    /// body since the function can't see the enclosing function's locals
+    pub self_name: Option<&'a str>,
 }
 
 impl<'a> Scope<'a> {
+    /// Scope for compiling the body of a function. Parameters and top-level locals of the body
+    /// live at depth 1.
+    pub fn function(name: &str, def_line: usize) -> Scope<'a> {
+        Scope {
+            chunk_type: ChunkType::Function,
+            func: FuncObj {
+                name: Some(name.into()),
+                def_line,
+                ..Default::default()
+            },
+            depth: 1,
+            ..Default::default()
+        }
+    }
+
     pub fn curr_chunk(&mut self) -> &mut Chunk {
         &mut self.func.chunk
     }
 
     /// Finds the highest depth local
     pub fn find(&self, name: &str) -> Option<&(isize, Token<'a>, bool)> {
-        self.locals
+        self.locals.iter().rfind(|(_, t, _)| t.lexeme == name)
+    }
+
+    /// The value of the local `val` the name refers to, unless it's shadowed by another local
+    pub fn find_constant(&self, name: &str) -> Option<Value> {
+        let (depth, _, value) = self.constants.iter().rfind(|(_, t, _)| t.lexeme == name)?;
+        match self.find(name) {
+            Some((local_depth, _, _)) if local_depth > depth => None,
+            _ => Some(value.clone()),
+        }
+    }
+
+    /// The depth and token of the innermost local or local constant by that name
+    pub fn find_declaration(&self, name: &str) -> Option<(isize, &Token<'a>)> {
+        let local = self.find(name).map(|(d, t, _)| (*d, t));
+        let constant = self
+            .constants
             .iter()
-            .filter(|(_, t, _)| t.lexeme == name)
-            .last()
+            .rfind(|(_, t, _)| t.lexeme == name)
+            .map(|(d, t, _)| (*d, t));
+        local.into_iter().chain(constant).max_by_key(|(d, _)| *d)
     }
 
     /// Finds the highest index
@@ -215,7 +402,7 @@ impl<'a> Scope<'a> {
             .enumerate()
             .filter(|(_, (_, t, _))| t.lexeme == name)
             .map(|(i, (_, _, b))| (i, *b))
-            .last()
+            .next_back()
     }
 
     pub fn increment_depth(&mut self) {
@@ -225,75 +412,318 @@ impl<'a> Scope<'a> {
     pub fn decrement_depth(&mut self) -> usize {
         let prev_size = self.locals.len();
         self.locals.retain(|(d, _, _)| d < &self.depth);
+        self.constants.retain(|(d, _, _)| d < &self.depth);
         self.depth -= 1;
         prev_size - self.locals.len()
     }
 
-    /// Returns success
+    /// Returns success. `_` is a throwaway name, so it can be redeclared in the same scope.
     pub fn add_local(&mut self, token: Token<'a>, mutable: bool) -> bool {
-        if let Some((depth, _, _)) = self.find(&token.lexeme) {
-            if depth >= &self.depth && depth != &-1 {
-                return false;
-            }
+        if self.is_declared_here(token.lexeme) {
+            return false;
         }
-
         self.locals.push((self.depth, token, mutable));
         true
     }
+
+    /// Returns success, like `add_local`
+    pub fn add_constant(&mut self, token: Token<'a>, value: Value) -> bool {
+        if self.is_declared_here(token.lexeme) {
+            return false;
+        }
+        self.constants.push((self.depth, token, value));
+        true
+    }
+
+    /// Whether declaring the name again in the current scope would be a redeclaration
+    fn is_declared_here(&self, name: &str) -> bool {
+        match self.find_declaration(name) {
+            Some((depth, _)) => depth >= self.depth && depth != -1 && name != "_",
+            None => false,
+        }
+    }
 }
 
+/// Default for `Compiler::max_expression_depth`
+pub const DEFAULT_MAX_EXPRESSION_DEPTH: usize = 256;
+
+/// Default for `Compiler::max_constants`
+pub const DEFAULT_MAX_CONSTANTS: usize = 65536;
+
+/// Default for `Compiler::max_locals`, the number of slots byte-wide local opcodes can address
+pub const DEFAULT_MAX_LOCALS: usize = 256;
+
 pub struct Compiler<'a> {
     pub scanner: PrevPeekable<ErrorIgnoreTokenScanner<'a>>,
-    pub global_bindings: GlobalBindings<'a>,
+    pub global_bindings: &'a mut GlobalBindings,
+    /// Where the constants of every function compiled are added
+    pub constants: &'a mut ConstantTable,
     pub scope: Scope<'a>,
+    /// Errors from scanning the source so far
+    pub scan_errors: Rc<RefCell<Vec<ScanError<'a>>>>,
+
+    /// Expressions are compiled recursively, so bound how deeply they may nest to avoid
+    /// overflowing the stack on pathological input
+    pub max_expression_depth: usize,
+    pub expression_depth: usize,
+
+    /// Limit on the number of distinct constants a single function's chunk can refer to. The
+    /// constant table is shared by every function, so it can grow past this as a whole.
+    pub max_constants: usize,
+    /// Set once too many constants were reported, so that every constant after the limit
+    /// doesn't report it again
+    pub reported_too_many_constants: bool,
+
+    /// Limit on the number of locals in scope at once in a single function
+    pub max_locals: usize,
+
+    /// Allow the script to end in an expression without a ';', whose value is left on the stack
+    /// as the result of the script. Used by the REPL to echo expressions.
+    pub allow_expression_result: bool,
+    /// Set once compiled if the script ended in such an expression
+    pub has_expression_result: bool,
+
+    /// Warn when a local shadows an outer local or a global
+    pub warn_shadowing: bool,
+
+    /// Warn when a function has both `return value;` and `return;`
+    pub warn_mixed_returns: bool,
+
+    /// Emit no code for `assert` statements, so their arguments aren't evaluated
+    pub strip_assertions: bool,
+
+    /// Variables declared without an initializer can't be read until they are assigned, instead
+    /// of defaulting to nil
+    pub strict: bool,
+
+    /// Dialect option accepting `elif` as shorthand for `else if`. Otherwise `elif` is an
+    /// ordinary identifier.
+    pub allow_elif: bool,
+
+    /// Print the disassembly of the script once compiled
+    pub disassemble: bool,
+
+    /// Leave globals that are used but not declared for the caller to check with
+    /// `GlobalBindings::check_all_declared`, for compiling a program one piece at a time
+    pub allow_undeclared: bool,
+
+    /// Set once an error was reported, compiling then continues only to report more errors
+    pub had_error: bool,
+
+    /// Report warnings as errors, so that the compile fails if any are found
+    pub warnings_as_errors: bool,
+
+    /// Number of warnings reported so far, including those reported as errors
+    pub warning_count: usize,
+
+    /// The global about to be called, when the callee is just its name
+    pub callee: Option<Token<'a>>,
+    /// Calls on globals with named arguments, checked once the whole script is compiled, as the
+    /// function may be declared or assigned to after the call
+    pub named_calls: Vec<(Token<'a>, Vec<Token<'a>>)>,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(source: &'a str) -> Compiler<'a> {
+    pub fn new(
+        source: &'a str,
+        global_bindings: &'a mut GlobalBindings,
+        constants: &'a mut ConstantTable,
+    ) -> Compiler<'a> {
+        Self::new_at_line(source, 1, global_bindings, constants)
+    }
+
+    /// Compiles source that starts on the given line of a larger file
+    pub fn new_at_line(
+        source: &'a str,
+        first_line: usize,
+        global_bindings: &'a mut GlobalBindings,
+        constants: &'a mut ConstantTable,
+    ) -> Compiler<'a> {
+        let scan_errors = Rc::default();
         let scanner = PrevPeekable::from(ErrorIgnoreTokenScanner {
-            inner: TokenScanner::from_source(source),
+            inner: TokenScanner::from_source_at_line(source, first_line),
+            errors: Rc::clone(&scan_errors),
         });
         Compiler {
             scanner,
-            global_bindings: GlobalBindings::default(),
+            scan_errors,
+            global_bindings,
+            constants,
             scope: Scope::default(),
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            expression_depth: 0,
+            max_constants: DEFAULT_MAX_CONSTANTS,
+            reported_too_many_constants: false,
+            max_locals: DEFAULT_MAX_LOCALS,
+            allow_expression_result: false,
+            has_expression_result: false,
+            warn_shadowing: false,
+            warn_mixed_returns: false,
+            strip_assertions: false,
+            strict: false,
+            allow_elif: false,
+            disassemble: true,
+            allow_undeclared: false,
+            had_error: false,
+            warnings_as_errors: false,
+            warning_count: 0,
+            callee: None,
+            named_calls: vec![],
+        }
+    }
+
+    /// Reports named arguments that the called function has no parameter for. Only functions
+    /// declared at the top level and never assigned to are known, others are checked when called.
+    fn check_named_calls(&mut self) {
+        for (callee, named) in std::mem::take(&mut self.named_calls) {
+            if self
+                .global_bindings
+                .assigned_globals
+                .contains(callee.lexeme)
+            {
+                continue;
+            }
+            let Some(params) = self.global_bindings.function_params.get(callee.lexeme) else {
+                continue;
+            };
+            for name in named {
+                if !params.iter().any(|p| **p == *name.lexeme) {
+                    let msg = format!(
+                        "Unknown parameter '{}' for function '{}'",
+                        name.lexeme, callee.lexeme
+                    );
+                    report_error(&name, &msg);
+                    self.had_error = true;
+                }
+            }
+        }
+    }
+
+    /// Reports a warning, or an error if `warnings_as_errors` is set
+    pub fn warn(&mut self, token: &Token, msg: &str) {
+        self.warning_count += 1;
+        if self.warnings_as_errors {
+            report_error(token, msg);
+            self.had_error = true;
+        } else {
+            report_warning(token, msg);
+        }
+    }
+
+    /// Should be called before declaring `token` as a local
+    pub fn check_shadowing(&mut self, token: &Token<'a>) {
+        if !self.warn_shadowing {
+            return;
+        }
+
+        let name = token.lexeme;
+        if let Some((depth, outer)) = self.scope.find_declaration(name) {
+            if depth == self.scope.depth {
+                // Redeclaration, which is an error instead
+                return;
+            }
+            let msg = format!(
+                "Local '{name}' shadows a local declared on line {}",
+                outer.line
+            );
+            self.warn(token, &msg);
+        } else if self.global_bindings.global_slots.contains_key(name) {
+            self.warn(token, &format!("Local '{name}' shadows a global"));
         }
     }
 
-    pub fn compile(mut self) -> CompilerResult<FuncObj> {
+    /// Should be called before declaring `token` as a local
+    pub fn check_locals_limit(&self, token: &Token<'a>) -> CompilerResult<()> {
+        if self.scope.locals.len() < self.max_locals {
+            return Ok(());
+        }
+        report_error(
+            token,
+            &format!(
+                "Too many locals, '{}' would be local number {} of a function limited to {}. \
+                 Consider splitting it into smaller functions.",
+                token.lexeme,
+                self.scope.locals.len() + 1,
+                self.max_locals
+            ),
+        );
+        Err(InterpretError::Compiler)
+    }
+
+    /// Called after a statement failed to compile. Skips to what is likely the start of the next
+    /// statement in the same block, so that compiling can continue and report further errors.
+    pub fn synchronize(&mut self) {
+        use TokenType::*;
+        self.had_error = true;
+
+        // Braces opened while skipping, so that the '}' closing the block can be told apart
+        let mut open_braces = 0;
+        loop {
+            // Reading past the end of the source leaves no previous token
+            let after_semi = self.scanner.prev().is_some_and(|t| t.ttype == Semi);
+            if after_semi && open_braces == 0 {
+                return;
+            }
+            match self.scanner.peek().map(|t| t.ttype) {
+                None => return,
+                Some(RBrace) if open_braces == 0 => return,
+                Some(
+                    Var | Val | Fun | Enum | Print | EPrint | If | While | For | Match | Return
+                    | Assert | Try | Break | Continue,
+                ) if open_braces == 0 => return,
+                Some(LBrace) => open_braces += 1,
+                Some(RBrace) => open_braces -= 1,
+                _ => {}
+            }
+            self.scanner.next();
+        }
+    }
+
+    pub fn compile(&mut self) -> CompilerResult<FuncObj> {
         // self.compile_expression()?;
 
-        while let Some(_) = self.scanner.peek() {
-            self.compile_decl()?;
+        while self.scanner.peek().is_some() {
+            if self.compile_top_level_decl().is_err() {
+                self.scope.depth = 0;
+                self.scope.locals.clear();
+                self.scope.constants.clear();
+                self.synchronize();
+            }
         }
 
-        if !self.global_bindings.undeclared_globals.is_empty() {
-            report_error_eof(&format!(
-                "The following global bindings were not declared but were used: {}",
-                self.global_bindings
-                    .undeclared_globals
-                    .iter()
-                    .map(|n| format!("'{n}'"))
-                    .join(", ")
-            ));
+        self.check_named_calls();
 
+        // Already reported, but the invalid tokens were skipped so the program can't be run
+        if self.had_error || !self.scan_errors.borrow().is_empty() {
             return Err(InterpretError::Compiler);
         }
 
+        if !self.allow_undeclared {
+            self.global_bindings.check_all_declared()?;
+        }
+
         // TODO: safe convert
+        // The implicit return belongs to the last line, or the first for an empty script
+        let line = self.scanner.prev().map_or(1, |t| t.line);
         self.scope
             .curr_chunk()
-            .push(crate::chunk::ByteCode::Return, 0);
+            .push(crate::chunk::ByteCode::Return, line);
 
         self.scope.curr_chunk().global_slots =
             self.global_bindings.global_slots.keys().count() as u32;
         self.scope.curr_chunk().resolve_monkey_patches();
-        self.scope.curr_chunk().disassemble();
+        if self.disassemble {
+            let global_names = self.global_bindings.names_by_slot();
+            self.scope
+                .func
+                .chunk
+                .disassemble(&global_names, self.constants);
+        }
         if let Some(t) = self.scanner.peek() {
             report_error(t, "Expected EOF");
             Err(InterpretError::Compiler)
         } else {
-            Ok(self.scope.func)
+            Ok(std::mem::take(&mut self.scope.func))
         }
 
         // self.check_eof()?;
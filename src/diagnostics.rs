@@ -0,0 +1,50 @@
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning reported while compiling or running a script, as it was printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// None for errors at the end of the file
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+thread_local! {
+    // Errors are reported from wherever they are found, which often has no access to the
+    // compiler or VM, so they're collected here while `collect` runs
+    static COLLECTED: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, returning along with its result the diagnostics reported while it ran. They are
+/// still printed as usual.
+pub fn collect<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    let outer = COLLECTED.replace(Some(vec![]));
+    let result = f();
+    let collected = COLLECTED.replace(outer).unwrap_or_default();
+    // Nested calls also report to the outer one
+    COLLECTED.with_borrow_mut(|outer| {
+        if let Some(outer) = outer {
+            outer.extend(collected.iter().cloned());
+        }
+    });
+    (result, collected)
+}
+
+/// Records a diagnostic if `collect` is running, should be called wherever one is printed
+pub(crate) fn push(severity: Severity, line: Option<usize>, message: &str) {
+    COLLECTED.with_borrow_mut(|collected| {
+        if let Some(collected) = collected {
+            collected.push(Diagnostic {
+                severity,
+                line,
+                message: message.to_owned(),
+            });
+        }
+    });
+}
@@ -7,14 +7,61 @@ use crate::{
 };
 
 impl<'a> Compiler<'a> {
-    fn emit_constant(&mut self, token: &Token, value: Value) {
-        let idx = self.scope.curr_chunk().push_constant(value);
+    pub fn emit_constant(&mut self, token: &Token, value: Value) -> CompilerResult<()> {
+        let idx = self.add_constant(token, value)?;
         self.scope
             .curr_chunk()
-            .push(ByteCode::from_constant_index(idx), token.line)
+            .push(ByteCode::from_constant_index(idx), token.line);
+        Ok(())
+    }
+
+    /// Adds the value to the constant table for the current function to refer to, returning its
+    /// index. Fails if the function would refer to more than `max_constants` distinct constants.
+    fn add_constant(&mut self, token: &Token, value: Value) -> CompilerResult<u32> {
+        let idx = self.constants.push(value);
+        if !self.has_room_for_constant(idx) {
+            if !self.reported_too_many_constants {
+                self.reported_too_many_constants = true;
+                report_error(
+                    token,
+                    &format!(
+                        "Too many constants in one chunk (limit is {})",
+                        self.max_constants
+                    ),
+                );
+            }
+            return Err(InterpretError::Compiler);
+        }
+        self.scope.constants_used.insert(idx);
+        Ok(idx)
+    }
+
+    /// Whether the current function already refers to the constant, or can refer to another
+    fn has_room_for_constant(&self, idx: u32) -> bool {
+        self.scope.constants_used.contains(&idx)
+            || self.scope.constants_used.len() < self.max_constants
     }
 
     fn compile_precedence(&mut self, precedence: Precedence) -> CompilerResult<()> {
+        if self.expression_depth >= self.max_expression_depth {
+            let msg = format!(
+                "Expression nested too deeply (limit is {})",
+                self.max_expression_depth
+            );
+            match self.scanner.peek() {
+                Some(t) => report_error(t, &msg),
+                None => report_error_eof(&msg),
+            }
+            return Err(InterpretError::Compiler);
+        }
+
+        self.expression_depth += 1;
+        let res = self.compile_precedence_nested(precedence);
+        self.expression_depth -= 1;
+        res
+    }
+
+    fn compile_precedence_nested(&mut self, precedence: Precedence) -> CompilerResult<()> {
         use TokenType::*;
 
         let can_assign = precedence <= Precedence::Assignment;
@@ -23,6 +70,8 @@ impl<'a> Compiler<'a> {
         match self.scanner.next() {
             Some(tok) => match tok.ttype {
                 LParen => self.compile_grouping(),
+                LBracket => self.compile_list(),
+                LBrace => self.compile_map(),
                 Minus => self.compile_unary(),
                 Number => self.compile_number(),
                 Str => self.compile_string(),
@@ -50,12 +99,30 @@ impl<'a> Compiler<'a> {
 
             match self.scanner.next() {
                 Some(tok) => match tok.ttype {
-                    Minus | Plus | Slash | Star | EqualEqual | Greater | GreaterEqual | Less
+                    Minus | Plus | Slash | TildeSlash | Star | EqualEqual | BangEqual
+                    | EqualEqualEqual | BangEqualEqual | Greater | GreaterEqual | Less
                     | LessEqual => self.compile_binary(),
                     And => self.compile_and(),
                     Or => self.compile_or(),
                     QuestionColon => self.compile_elvis(),
-                    _ => Ok(()),
+                    DotDot | DotDotEqual => self.compile_range(),
+                    LParen => self.compile_call(),
+                    LBracket => self.compile_index(can_assign),
+                    Question => {
+                        report_error(
+                            &tok,
+                            "The conditional operator '?' is not supported, did you mean '?:'?",
+                        );
+                        Err(InterpretError::Compiler)
+                    }
+                    // Anything with an infix precedence must be handled above
+                    _ => {
+                        report_error(
+                            &tok,
+                            &format!("Operator '{}' is not implemented", tok.lexeme),
+                        );
+                        Err(InterpretError::Compiler)
+                    }
                 },
                 None => {
                     report_error_eof("EOF reached");
@@ -65,7 +132,11 @@ impl<'a> Compiler<'a> {
         }
 
         if can_assign {
-            if let Some(t) = self.scanner.advance_if_match(TokenType::Equal) {
+            let assignment = match self.scanner.advance_if_match(TokenType::Equal) {
+                Some(t) => Some(t),
+                None => self.scanner.advance_if_match(TokenType::QuestionColonEqual),
+            };
+            if let Some(t) = assignment {
                 report_error(&t, "Left hand side of the assignment is not assignable");
                 return Err(InterpretError::Compiler);
             }
@@ -163,35 +234,75 @@ impl<'a> Compiler<'a> {
 
     fn compile_number(&mut self) -> CompilerResult<()> {
         let token = self.scanner.prev_unwrap();
-        self.emit_constant(&token, Value::Number(token.lexeme.parse().unwrap()));
+        let value = self.parse_number(&token);
+        self.emit_constant(&token, Value::Number(value))?;
         Ok(())
     }
 
     fn compile_string(&mut self) -> CompilerResult<()> {
         let token = self.scanner.prev_unwrap();
-        self.emit_constant(&token, token.lexeme.to_owned().into());
+        self.emit_constant(&token, token.lexeme.to_owned().into())?;
         Ok(())
     }
 
     fn compile_string_interp(&mut self) -> CompilerResult<()> {
+        //   "a${x}b${y}c"
+        // compiles to
+        //   "a" x "b" y "c" format(5)
+        //
+        // so that the string is built in one go. Empty pieces are skipped.
         let token = self.scanner.prev_unwrap();
         let line = token.line;
-        self.emit_constant(&token, token.lexeme.to_owned().into());
+        let mut len: u32 = 0;
+        if !token.lexeme.is_empty() {
+            self.emit_constant(&token, token.lexeme.to_owned().into())?;
+            len += 1;
+        }
 
+        // The piece ending in the '${' being compiled
+        let mut open = token;
         loop {
+            if let Some(&close) = self.scanner.peek() {
+                if close.ttype == TokenType::RBrace {
+                    report_error(&close, "Expected an expression inside '${}'");
+                    // Skip the '}' so that recovery doesn't take it for the end of a block
+                    self.scanner.next();
+                    return Err(InterpretError::Compiler);
+                }
+            }
             self.compile_expression()?;
-            self.scope.curr_chunk().push(ByteCode::Add, line);
+            len += 1;
             self.scanner.consume_token(
                 TokenType::RBrace,
-                "Expecting '}' after String interpolation",
+                &format!(
+                    "Expected '}}' to close the interpolation opened on line {}",
+                    open.line
+                ),
             )?;
 
-            if let Some(s) = self.scanner.advance_if_match(TokenType::Str) {
-                self.emit_constant(&s, s.lexeme.to_owned().into());
-                self.scope.curr_chunk().push(ByteCode::Add, s.line);
+            // After the '}', the scanner continues the string until the closing quote (Str) or
+            // the next interpolation (StrInterp)
+            let (piece, last) = if let Some(s) = self.scanner.advance_if_match(TokenType::Str) {
+                (s, true)
+            } else {
+                let s = self.scanner.consume_token(
+                    TokenType::StrInterp,
+                    "Expecting the rest of the string after String interpolation",
+                )?;
+                (s, false)
+            };
+
+            if !piece.lexeme.is_empty() {
+                self.emit_constant(&piece, piece.lexeme.to_owned().into())?;
+                len += 1;
+            }
+
+            if last {
                 break;
             }
+            open = piece;
         }
+        self.scope.curr_chunk().push(ByteCode::Format(len), line);
         Ok(())
     }
 
@@ -208,34 +319,243 @@ impl<'a> Compiler<'a> {
     }
 
     fn compile_var(&mut self, can_assign: bool) -> CompilerResult<()> {
-        self.compile_named_var(&self.scanner.prev_unwrap(), can_assign)
+        let name = self.scanner.prev_unwrap();
+        // Pseudo-variables, replaced by a literal for where they are used
+        match name.lexeme {
+            "__line__" => {
+                let line = Value::Number(name.line as f64);
+                return self.compile_constant_var(&name, line, can_assign);
+            }
+            "__func__" => {
+                let func = self.scope.func.name.as_deref().unwrap_or("<script>");
+                let func = Value::Str(func.into());
+                return self.compile_constant_var(&name, func, can_assign);
+            }
+            _ => {}
+        }
+        if self.global_bindings.enums.contains_key(name.lexeme) {
+            return self.compile_enum_member(&name);
+        }
+        self.compile_named_var(&name, can_assign)
+    }
+
+    /// `Enum.Member`, which is a symbol constant
+    fn compile_enum_member(&mut self, enum_name: &Token<'a>) -> CompilerResult<()> {
+        self.scanner.consume_token(
+            TokenType::Dot,
+            &format!(
+                "Expected '.' and a member after enum '{}'",
+                enum_name.lexeme
+            ),
+        )?;
+        let member = self
+            .scanner
+            .consume_token(TokenType::Ident, "Expected enum member name after '.'")?;
+
+        let members = &self.global_bindings.enums[enum_name.lexeme];
+        if !members.iter().any(|m| m == member.lexeme) {
+            report_error(
+                &member,
+                &format!(
+                    "Enum '{}' has no member '{}'",
+                    enum_name.lexeme, member.lexeme
+                ),
+            );
+            return Err(InterpretError::Compiler);
+        }
+
+        let symbol = format!("{}.{}", enum_name.lexeme, member.lexeme);
+        self.emit_constant(&member, Value::Symbol(symbol.into()))
+    }
+
+    /// Global `val`s, and local ones initialized with a literal, are inlined as constants
+    fn compile_constant_var(
+        &mut self,
+        name: &Token<'a>,
+        value: Value,
+        can_assign: bool,
+    ) -> CompilerResult<()> {
+        self.check_not_assigned(name, can_assign)?;
+        self.emit_constant(name, value)
+    }
+
+    /// Whether the token is an assignment operator, like `=` or `+=`
+    pub fn is_assignment(token: Option<&Token<'a>>) -> bool {
+        use TokenType::*;
+        matches!(
+            token.map(|t| t.ttype),
+            Some(Equal | QuestionColonEqual | PlusEqual | MinusEqual | StarEqual | SlashEqual)
+        )
+    }
+
+    /// For names that can't be assigned to, fails if the next token would assign to it
+    fn check_not_assigned(&mut self, name: &Token<'a>, can_assign: bool) -> CompilerResult<()> {
+        let assigns = Self::is_assignment(self.scanner.peek());
+        if can_assign && assigns {
+            report_error(name, &format!("Variable {} is not mutable", name.lexeme));
+            return Err(InterpretError::Compiler);
+        }
+        Ok(())
     }
 
-    fn compile_named_var(&mut self, name: &Token<'a>, can_assign: bool) -> CompilerResult<()> {
+    /// Fails if the variable isn't mutable. Otherwise notes that a global is assigned to, so
+    /// that it's no longer known to hold the function it may have been declared as.
+    fn check_assignable(
+        &mut self,
+        name: &Token<'a>,
+        mutable: bool,
+        setop: ByteCode,
+    ) -> CompilerResult<()> {
+        if !mutable {
+            report_error(name, &format!("Variable {} is not mutable", name.lexeme));
+            return Err(InterpretError::Compiler);
+        }
+        if let ByteCode::SetGlobal(_) = setop {
+            self.global_bindings
+                .assigned_globals
+                .insert(name.lexeme.to_owned());
+        }
+        Ok(())
+    }
+
+    /// `_` is a throwaway name that can only be assigned to
+    fn check_readable(&self, name: &Token<'a>) -> CompilerResult<()> {
+        if name.lexeme == "_" {
+            report_error(name, "'_' can only be assigned to, not read");
+            return Err(InterpretError::Compiler);
+        }
+        Ok(())
+    }
+
+    /// A local function's name in its own body, see `Scope::self_name`
+    fn compile_self_reference(&mut self, name: &Token<'a>, can_assign: bool) -> CompilerResult<()> {
+        self.check_not_assigned(name, can_assign)?;
+        self.scope.curr_chunk().push(ByteCode::GetCallee, name.line);
+        Ok(())
+    }
+
+    pub fn compile_named_var(&mut self, name: &Token<'a>, can_assign: bool) -> CompilerResult<()> {
+        if let Some(value) = self.scope.find_constant(name.lexeme) {
+            return self.compile_constant_var(name, value, can_assign);
+        }
+        if self.scope.find_declaration(name.lexeme).is_none() {
+            if self.scope.self_name == Some(name.lexeme) {
+                return self.compile_self_reference(name, can_assign);
+            }
+            if let Some(value) = self.global_bindings.constants.get(name.lexeme).cloned() {
+                return self.compile_constant_var(name, value, can_assign);
+            }
+        }
+
         // check if this is a local variable
-        let (setop, getop, mutable) =
-            if let Some((v, mutable)) = self.scope.find_index(&name.lexeme) {
-                (
-                    ByteCode::SetLocal(v as u32),
-                    ByteCode::GetLocal(v as u32),
-                    mutable,
-                )
-            } else {
-                let slot = self.global_bindings.use_binding(name.lexeme);
-                (ByteCode::SetGlobal(slot), ByteCode::GetGlobal(slot), true)
-            };
+        let (setop, getop, mutable) = if let Some((v, mutable)) = self.scope.find_index(name.lexeme)
+        {
+            (
+                ByteCode::set_local(v as u32),
+                ByteCode::get_local(v as u32),
+                mutable,
+            )
+        } else {
+            let slot = self.global_bindings.use_binding(name.lexeme);
+            (ByteCode::SetGlobal(slot), ByteCode::GetGlobal(slot), true)
+        };
 
         if can_assign && self.scanner.advance_if_match(TokenType::Equal).is_some() {
-            if !mutable {
-                report_error(name, &format!("Variable {} is not mutable", name.lexeme));
-                return Err(InterpretError::Compiler);
-            }
+            self.check_assignable(name, mutable, setop)?;
             self.compile_expression()?;
             self.scope.curr_chunk().push(setop, name.line);
+        } else if can_assign
+            && self
+                .scanner
+                .advance_if_match(TokenType::QuestionColonEqual)
+                .is_some()
+        {
+            self.check_assignable(name, mutable, setop)?;
+            self.check_readable(name)?;
+            self.compile_elvis_assign(getop, setop)?;
+        } else if let Some(op) = self.match_compound_assign(can_assign) {
+            self.check_assignable(name, mutable, setop)?;
+            self.check_readable(name)?;
+            let line = self.scanner.prev_unwrap().line;
+            self.scope.curr_chunk().push(getop, line);
+            self.compile_expression()?;
+            self.scope.curr_chunk().push(op, line);
+            self.scope.curr_chunk().push(setop, line);
         } else {
-            self.scope.curr_chunk().push(getop, name.line);
+            self.check_readable(name)?;
+            if matches!(getop, ByteCode::GetGlobal(_))
+                && matches!(self.scanner.peek(), Some(t) if t.ttype == TokenType::LParen)
+            {
+                self.callee = Some(*name);
+            }
+            let chunk = self.scope.curr_chunk();
+            // Reading a global right after reading it, as in `x + x`, reuses the value
+            let op = match (getop, chunk.last_instruction()) {
+                (ByteCode::GetGlobal(slot), Some(ByteCode::GetGlobal(last))) if slot == last => {
+                    ByteCode::Dup
+                }
+                _ => getop,
+            };
+            chunk.push(op, name.line);
+        }
+
+        Ok(())
+    }
+
+    /// `x ?:= value` assigns value to x only if x is nil
+    /// Consumes a compound assignment operator like `+=`, returning the operation it applies
+    fn match_compound_assign(&mut self, can_assign: bool) -> Option<ByteCode> {
+        if !can_assign {
+            return None;
         }
+        let op = match self.scanner.peek()?.ttype {
+            TokenType::PlusEqual => ByteCode::Add,
+            TokenType::MinusEqual => ByteCode::Sub,
+            TokenType::StarEqual => ByteCode::Mul,
+            TokenType::SlashEqual => ByteCode::Div,
+            _ => return None,
+        };
+        self.scanner.next();
+        Some(op)
+    }
+
+    fn compile_elvis_assign(&mut self, getop: ByteCode, setop: ByteCode) -> CompilerResult<()> {
+        use ByteCode::*;
+        //   get x
+        //   dup
+        //   nil
+        //   eq
+        //   jump_f .keep
+        //   pop
+        //   pop
+        //   value
+        //   set x
+        //   jump .exit
+        // .keep
+        //   pop
+        // .exit
+        let line = self.scanner.prev_unwrap().line;
+
+        let exit = self.scope.curr_chunk().allocate_new_label();
+        let keep = self.scope.curr_chunk().allocate_new_label();
 
+        self.scope.curr_chunk().push(getop, line);
+        self.scope.curr_chunk().push(Dup, line);
+        self.scope.curr_chunk().push(Nil, line);
+        self.scope.curr_chunk().push(Eq, line);
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(JumpF(0), line, keep);
+        self.scope.curr_chunk().push(Pop, line);
+        self.scope.curr_chunk().push(Pop, line);
+        self.compile_expression()?;
+        self.scope.curr_chunk().push(setop, line);
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(JumpRelative(0), line, exit);
+        self.scope.curr_chunk().push_label(keep);
+        self.scope.curr_chunk().push(Pop, line);
+        self.scope.curr_chunk().push_label(exit);
         Ok(())
     }
 
@@ -243,6 +563,16 @@ impl<'a> Compiler<'a> {
         use TokenType::*;
         let op = self.scanner.prev_unwrap();
 
+        // Fold negative number literals into a single constant, so they can be used anywhere a
+        // literal is expected (e.g. match patterns)
+        if op.ttype == Minus {
+            if let Some(number) = self.scanner.advance_if_match(Number) {
+                let value = -self.parse_number(&number);
+                self.emit_constant(&number, Value::Number(value))?;
+                return Ok(());
+            }
+        }
+
         // Compile operand
         self.compile_precedence(Precedence::Unary)?;
 
@@ -258,6 +588,12 @@ impl<'a> Compiler<'a> {
     fn compile_binary(&mut self) -> CompilerResult<()> {
         use TokenType::*;
         let op = self.scanner.prev_unwrap();
+        if matches!(op.ttype, EqualEqual | BangEqual) && self.compile_literal_comparison(&op) {
+            return Ok(());
+        }
+        if matches!(op.ttype, Plus | Minus) && self.compile_constant_operand(&op) {
+            return Ok(());
+        }
         self.compile_precedence(Precedence::of(op.ttype).next())?;
 
         match op.ttype {
@@ -265,27 +601,315 @@ impl<'a> Compiler<'a> {
             Minus => self.scope.curr_chunk().push(ByteCode::Sub, op.line),
             Star => self.scope.curr_chunk().push(ByteCode::Mul, op.line),
             Slash => self.scope.curr_chunk().push(ByteCode::Div, op.line),
+            TildeSlash => self.scope.curr_chunk().push(ByteCode::FloorDiv, op.line),
 
             EqualEqual | BangEqual => self.scope.curr_chunk().push(ByteCode::Eq, op.line),
-            Greater | GreaterEqual => self.scope.curr_chunk().push(ByteCode::Gt, op.line),
-            Less | LessEqual => self.scope.curr_chunk().push(ByteCode::Lt, op.line),
+            EqualEqualEqual | BangEqualEqual => {
+                self.scope.curr_chunk().push(ByteCode::RefEq, op.line)
+            }
+            Greater => self.scope.curr_chunk().push(ByteCode::Gt, op.line),
+            Less => self.scope.curr_chunk().push(ByteCode::Lt, op.line),
+            GreaterEqual => self.scope.curr_chunk().push(ByteCode::Ge, op.line),
+            LessEqual => self.scope.curr_chunk().push(ByteCode::Le, op.line),
             _ => panic!("Operation {op:?} not handled"),
         }
 
         match op.ttype {
-            BangEqual | GreaterEqual | LessEqual => {
-                self.scope.curr_chunk().push(ByteCode::Not, op.line)
-            }
+            BangEqual | BangEqualEqual => self.scope.curr_chunk().push(ByteCode::Not, op.line),
             _ => {}
         }
 
         Ok(())
     }
 
+    /// Compiles `== true`, `== false` and `== nil` (or their `!=`) to a single fused comparison.
+    /// Returns false without consuming anything if the right operand is not just the literal.
+    fn compile_literal_comparison(&mut self, op: &Token<'a>) -> bool {
+        let fused = match self.scanner.peek().map(|t| t.ttype) {
+            Some(TokenType::True) => ByteCode::EqTrue,
+            Some(TokenType::False) => ByteCode::EqFalse,
+            Some(TokenType::Nil) => ByteCode::EqNil,
+            _ => return false,
+        };
+        // The literal is only the whole operand if nothing after it binds tighter than `==`
+        if let Some(t) = self.scanner.peek_second() {
+            if Precedence::of(t.ttype) > Precedence::Equality {
+                return false;
+            }
+        }
+
+        self.scanner.next();
+        self.scope.curr_chunk().push(fused, op.line);
+        if op.ttype == TokenType::BangEqual {
+            self.scope.curr_chunk().push(ByteCode::Not, op.line);
+        }
+        true
+    }
+
+    /// Compiles `+ 1` or `- 1` to a single fused instruction with the number as a constant.
+    /// Returns false without consuming anything if the right operand is not just the number, or
+    /// the constant doesn't fit in the fused instruction.
+    fn compile_constant_operand(&mut self, op: &Token<'a>) -> bool {
+        let Some(&number) = self.scanner.peek() else {
+            return false;
+        };
+        if number.ttype != TokenType::Number {
+            return false;
+        }
+        // The number is only the whole operand if nothing after it binds tighter than `+`
+        if let Some(t) = self.scanner.peek_second() {
+            if Precedence::of(t.ttype) > Precedence::Term {
+                return false;
+            }
+        }
+
+        // Not warned about until it is certain to be fused, as the regular path would warn again
+        let value: f64 = number.lexeme.parse().unwrap();
+        // Past the function's limit, leave it to the regular path to report too many constants
+        let existing = self.constants.find(&Value::Number(value));
+        let has_room = match existing {
+            Some(idx) => self.has_room_for_constant(idx),
+            None => self.scope.constants_used.len() < self.max_constants,
+        };
+        if !has_room {
+            return false;
+        }
+        let idx = self.constants.push(Value::Number(value));
+        self.scope.constants_used.insert(idx);
+
+        self.scanner.next();
+        self.warn_if_imprecise(&number, value);
+        let fused = match (op.ttype, u8::try_from(idx)) {
+            (TokenType::Plus, Ok(idx)) => ByteCode::AddConst(idx),
+            (TokenType::Plus, Err(_)) => ByteCode::AddConstLong(idx),
+            (_, Ok(idx)) => ByteCode::SubConst(idx),
+            (_, Err(_)) => ByteCode::SubConstLong(idx),
+        };
+        self.scope.curr_chunk().push(fused, op.line);
+        true
+    }
+
+    /// `start..end` excludes the end, `start..=end` includes it
+    fn compile_range(&mut self) -> CompilerResult<()> {
+        let op = self.scanner.prev_unwrap();
+        self.compile_precedence(Precedence::Range.next())?;
+        let inclusive = op.ttype == TokenType::DotDotEqual;
+        self.scope
+            .curr_chunk()
+            .push(ByteCode::MakeRange(inclusive), op.line);
+        Ok(())
+    }
+
+    fn compile_call(&mut self) -> CompilerResult<()> {
+        // Named arguments `name: value` come after any positional ones, and are pushed as the name
+        // followed by the value.
+        // Once a spread argument `...list` is seen, the arguments so far are gathered into a list,
+        // and each later one is pushed as a list of its own or the spread list itself.
+        let paren = self.scanner.prev_unwrap();
+        let callee = self.callee.take();
+        let mut arg_count: usize = 0;
+        let mut named: Vec<Token> = vec![];
+        let mut spread_lists: Option<usize> = None;
+
+        if self.scanner.advance_if_match(TokenType::RParen).is_none() {
+            loop {
+                if let Some(dots) = self.scanner.advance_if_match(TokenType::DotDotDot) {
+                    if !named.is_empty() {
+                        report_error(&dots, "Cannot mix named and spread arguments");
+                        return Err(InterpretError::Compiler);
+                    }
+                    if spread_lists.is_none() {
+                        let gathered = u32::try_from(arg_count).unwrap();
+                        self.scope
+                            .curr_chunk()
+                            .push(ByteCode::BuildList(gathered), dots.line);
+                        spread_lists = Some(1);
+                    }
+                    self.compile_expression()?;
+                    spread_lists = spread_lists.map(|n| n + 1);
+                    if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                        break;
+                    }
+                    continue;
+                }
+
+                let is_named = matches!(self.scanner.peek(), Some(t) if t.ttype == TokenType::Ident)
+                    && matches!(self.scanner.peek_second(), Some(t) if t.ttype == TokenType::Colon);
+                if is_named {
+                    let name = self.scanner.next().unwrap();
+                    self.scanner.next();
+                    if named.iter().any(|n| n.lexeme == name.lexeme) {
+                        report_error(
+                            &name,
+                            &format!("Argument '{}' was given more than once", name.lexeme),
+                        );
+                        return Err(InterpretError::Compiler);
+                    }
+                    self.emit_constant(&name, name.lexeme.to_owned().into())?;
+                    named.push(name);
+                } else if !named.is_empty() {
+                    match self.scanner.peek() {
+                        Some(t) => {
+                            report_error(t, "Positional arguments cannot follow named arguments")
+                        }
+                        None => report_error_eof("Expected named argument"),
+                    }
+                    return Err(InterpretError::Compiler);
+                }
+
+                self.compile_expression()?;
+                arg_count += 1;
+                if let Some(lists) = &mut spread_lists {
+                    if is_named {
+                        report_error(
+                            named.last().unwrap(),
+                            "Cannot mix named and spread arguments",
+                        );
+                        return Err(InterpretError::Compiler);
+                    }
+                    self.scope
+                        .curr_chunk()
+                        .push(ByteCode::BuildList(1), paren.line);
+                    *lists += 1;
+                }
+                if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                    break;
+                }
+            }
+            self.scanner
+                .consume_token(TokenType::RParen, "Expected ')' after arguments")?;
+        }
+
+        let Ok(arg_count) = u8::try_from(arg_count) else {
+            report_error(&paren, "Cannot have more than 255 arguments");
+            return Err(InterpretError::Compiler);
+        };
+
+        if let Some(lists) = spread_lists {
+            let Ok(lists) = u8::try_from(lists) else {
+                report_error(&paren, "Cannot have more than 255 arguments");
+                return Err(InterpretError::Compiler);
+            };
+            self.scope
+                .curr_chunk()
+                .push(ByteCode::CallSpread(lists), paren.line);
+            return Ok(());
+        }
+
+        let call = if named.is_empty() {
+            ByteCode::Call(arg_count)
+        } else {
+            // Fits since there are no more named than total arguments
+            ByteCode::CallNamed(arg_count - named.len() as u8, named.len() as u8)
+        };
+        self.scope.curr_chunk().push(call, paren.line);
+        if let Some(callee) = callee.filter(|_| !named.is_empty()) {
+            self.named_calls.push((callee, named));
+        }
+        Ok(())
+    }
+
+    fn compile_list(&mut self) -> CompilerResult<()> {
+        let bracket = self.scanner.prev_unwrap();
+        let mut len: u32 = 0;
+
+        if self.scanner.advance_if_match(TokenType::RBracket).is_none() {
+            loop {
+                self.compile_expression()?;
+                len += 1;
+                if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                    break;
+                }
+            }
+            self.scanner
+                .consume_token(TokenType::RBracket, "Expected ']' after list elements")?;
+        }
+
+        self.scope
+            .curr_chunk()
+            .push(ByteCode::BuildList(len), bracket.line);
+        Ok(())
+    }
+
+    fn compile_map(&mut self) -> CompilerResult<()> {
+        let brace = self.scanner.prev_unwrap();
+        let mut len: u32 = 0;
+
+        if self.scanner.advance_if_match(TokenType::RBrace).is_none() {
+            loop {
+                self.compile_expression()?;
+                self.scanner
+                    .consume_token(TokenType::Colon, "Expected ':' after map key")?;
+                self.compile_expression()?;
+                len += 1;
+                if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                    break;
+                }
+            }
+            self.scanner
+                .consume_token(TokenType::RBrace, "Expected '}' after map entries")?;
+        }
+
+        self.scope
+            .curr_chunk()
+            .push(ByteCode::BuildMap(len), brace.line);
+        Ok(())
+    }
+
+    fn compile_index(&mut self, can_assign: bool) -> CompilerResult<()> {
+        let bracket = self.scanner.prev_unwrap();
+        self.compile_expression()?;
+        self.scanner
+            .consume_token(TokenType::RBracket, "Expected ']' after index")?;
+
+        if can_assign && self.scanner.advance_if_match(TokenType::Equal).is_some() {
+            self.compile_expression()?;
+            self.scope
+                .curr_chunk()
+                .push(ByteCode::SetIndex, bracket.line);
+        } else if let Some(op) = self.match_compound_assign(can_assign) {
+            // The target and index are only evaluated once, then copied to read the old value
+            //   target index dupat(1) dupat(1) get_index value op set_index
+            let line = self.scanner.prev_unwrap().line;
+            self.scope.curr_chunk().push(ByteCode::DupAt(1), line);
+            self.scope.curr_chunk().push(ByteCode::DupAt(1), line);
+            self.scope.curr_chunk().push(ByteCode::GetIndex, line);
+            self.compile_expression()?;
+            self.scope.curr_chunk().push(op, line);
+            self.scope.curr_chunk().push(ByteCode::SetIndex, line);
+        } else {
+            self.scope
+                .curr_chunk()
+                .push(ByteCode::GetIndex, bracket.line);
+        }
+        Ok(())
+    }
+
     fn compile_grouping(&mut self) -> CompilerResult<()> {
         self.compile_expression()?;
         self.scanner
             .consume_token(TokenType::RParen, "Expected ')' after expression")?;
         Ok(())
     }
+
+    /// Parses a number literal, warning if it is an integer too large to be represented exactly
+    pub fn parse_number(&mut self, token: &Token) -> f64 {
+        let value: f64 = token.lexeme.parse().unwrap();
+        self.warn_if_imprecise(token, value);
+        value
+    }
+
+    /// Warns if the integer literal parsed to `value` is not exactly that integer
+    fn warn_if_imprecise(&mut self, token: &Token, value: f64) {
+        if !token.lexeme.contains('.') {
+            // Formatting a float without decimals prints its exact integer value
+            let digits = token.lexeme.trim_start_matches('0');
+            if format!("{value:.0}") != digits && !digits.is_empty() {
+                self.warn(
+                    token,
+                    &format!("Integer literal can't be represented exactly, it is {value:.0}"),
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,156 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use crate::value::{Map, Value};
+
+type List = Rc<RefCell<Vec<Value>>>;
+
+/// A list or map, held weakly so that tracking it doesn't keep it alive
+enum Tracked {
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<Map>>),
+}
+
+/// A tracked list or map that is still alive
+enum Container {
+    List(List),
+    Map(Rc<RefCell<Map>>),
+}
+
+/// Dead entries are pruned once there are this many entries, and again each time the count
+/// doubles
+const MIN_PRUNE_AT: usize = 1024;
+
+thread_local! {
+    // Every list and map made by the program, since only they can form cycles
+    static TRACKED: RefCell<(Vec<Tracked>, usize)> = const { RefCell::new((vec![], MIN_PRUNE_AT)) };
+}
+
+pub(crate) fn track_list(list: &List) {
+    track(Tracked::List(Rc::downgrade(list)));
+}
+
+pub(crate) fn track_map(map: &Rc<RefCell<Map>>) {
+    track(Tracked::Map(Rc::downgrade(map)));
+}
+
+fn track(tracked: Tracked) {
+    TRACKED.with_borrow_mut(|(entries, prune_at)| {
+        entries.push(tracked);
+        if entries.len() >= *prune_at {
+            entries.retain(|tracked| tracked.upgrade().is_some());
+            *prune_at = (entries.len() * 2).max(MIN_PRUNE_AT);
+        }
+    });
+}
+
+fn container_ptr(value: &Value) -> Option<*const ()> {
+    match value {
+        Value::List(list) => Some(Rc::as_ptr(list) as *const ()),
+        Value::Map(map) => Some(Rc::as_ptr(map) as *const ()),
+        _ => None,
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Container> {
+        match self {
+            Tracked::List(list) => list.upgrade().map(Container::List),
+            Tracked::Map(map) => map.upgrade().map(Container::Map),
+        }
+    }
+}
+
+impl Container {
+    fn ptr(&self) -> *const () {
+        match self {
+            Container::List(list) => Rc::as_ptr(list) as *const (),
+            Container::Map(map) => Rc::as_ptr(map) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Container::List(list) => Rc::strong_count(list),
+            Container::Map(map) => Rc::strong_count(map),
+        }
+    }
+
+    /// The lists and maps this one holds, as pointers comparable with `ptr`
+    fn children(&self) -> Vec<*const ()> {
+        match self {
+            Container::List(list) => list.borrow().iter().filter_map(container_ptr).collect(),
+            Container::Map(map) => map
+                .borrow()
+                .iter()
+                .flat_map(|(k, v)| [k, v])
+                .filter_map(container_ptr)
+                .collect(),
+        }
+    }
+
+    /// Drops the contents, breaking any cycles through them
+    fn clear(&self) -> Vec<Value> {
+        match self {
+            Container::List(list) => std::mem::take(&mut *list.borrow_mut()),
+            Container::Map(map) => {
+                let map = std::mem::take(&mut *map.borrow_mut());
+                map.iter()
+                    .flat_map(|(k, v)| [k.clone(), v.clone()])
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Frees lists and maps that are only reachable from each other, returning how many there were.
+///
+/// Nothing outside of the tracked containers is inspected. Instead, any container with more
+/// references than the other containers account for is held from somewhere else, like the
+/// stack or a global, so it and everything it reaches is kept.
+pub(crate) fn collect_cycles() -> usize {
+    let alive: Vec<Container> = TRACKED.with_borrow_mut(|(entries, _)| {
+        entries.retain(|tracked| tracked.upgrade().is_some());
+        entries.iter().filter_map(Tracked::upgrade).collect()
+    });
+    let index: HashMap<*const (), usize> = alive
+        .iter()
+        .enumerate()
+        .map(|(i, container)| (container.ptr(), i))
+        .collect();
+    let children: Vec<Vec<usize>> = alive
+        .iter()
+        .map(|container| {
+            container
+                .children()
+                .iter()
+                .filter_map(|child| index.get(child).copied())
+                .collect()
+        })
+        .collect();
+
+    // References from outside the containers, not counting the one in `alive`
+    let mut outside: Vec<usize> = alive.iter().map(|c| c.strong_count() - 1).collect();
+    for &child in children.iter().flatten() {
+        outside[child] -= 1;
+    }
+
+    let mut reachable = vec![false; alive.len()];
+    let mut pending: Vec<usize> = (0..alive.len()).filter(|&i| outside[i] > 0).collect();
+    while let Some(i) = pending.pop() {
+        if !reachable[i] {
+            reachable[i] = true;
+            pending.extend(&children[i]);
+        }
+    }
+
+    // Dropped only once everything is cleared, since dropping may free other containers
+    let mut garbage = vec![];
+    for (container, _) in alive.iter().zip(&reachable).filter(|(_, &r)| !r) {
+        garbage.extend(container.clear());
+    }
+    reachable.iter().filter(|&&r| !r).count()
+}
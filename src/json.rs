@@ -0,0 +1,290 @@
+use crate::value::{Map, Value};
+
+/// Structures nested deeper than this are rejected, which also catches lists and maps that
+/// contain themselves
+const MAX_DEPTH: usize = 512;
+
+/// Converts nil, booleans, numbers, strings, and lists and maps of those to JSON. Map keys must
+/// be strings.
+pub fn to_json(value: &Value) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(&mut out, value, 0)?;
+    Ok(out)
+}
+
+fn write_value(out: &mut String, value: &Value, depth: usize) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err("Value is nested too deeply, it may contain itself".to_owned());
+    }
+
+    match value {
+        Value::Nil => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) if n.is_finite() => out.push_str(&n.to_string()),
+        Value::Number(n) => return Err(format!("{n} can't be represented in JSON")),
+        Value::Str(s) => write_str(out, s),
+        Value::List(items) => {
+            out.push('[');
+            for (i, item) in items.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, item, depth + 1)?;
+            }
+            out.push(']');
+        }
+        Value::Map(map) => {
+            out.push('{');
+            for (i, (key, item)) in map.borrow().iter().enumerate() {
+                let Value::Str(key) = key else {
+                    return Err(format!(
+                        "Map keys must be strings, found {} '{key}'",
+                        key.type_name()
+                    ));
+                };
+                if i > 0 {
+                    out.push(',');
+                }
+                write_str(out, key);
+                out.push(':');
+                write_value(out, item, depth + 1)?;
+            }
+            out.push('}');
+        }
+        v => return Err(format!("Can't convert {} '{v}' to JSON", v.type_name())),
+    }
+    Ok(())
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Parses JSON, with objects becoming maps, arrays lists, and null nil
+pub fn from_json(source: &str) -> Result<Value, String> {
+    let mut parser = Parser { source, pos: 0 };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < source.len() {
+        return Err(parser.unexpected());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    /// Byte offset into `source`
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn advance_if(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("Unexpected '{c}' at offset {}", self.pos),
+            None => "Unexpected end of JSON".to_owned(),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.advance_if(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("JSON is nested too deeply".to_owned());
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.parse_keyword("null", Value::Nil),
+            Some('t') => self.parse_keyword("true", Value::Bool(true)),
+            Some('f') => self.parse_keyword("false", Value::Bool(false)),
+            Some('"') => Ok(self.parse_str()?.into()),
+            Some('[') => self.parse_list(depth),
+            Some('{') => self.parse_map(depth),
+            Some('-' | '0'..='9') => self.parse_number(),
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_keyword(&mut self, keyword: &str, value: Value) -> Result<Value, String> {
+        if self.source[self.pos..].starts_with(keyword) {
+            self.pos += keyword.len();
+            Ok(value)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn skip_digits(&mut self) -> Result<(), String> {
+        if !matches!(self.peek(), Some('0'..='9')) {
+            return Err(self.unexpected());
+        }
+        while matches!(self.peek(), Some('0'..='9')) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        //   -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?
+        let start = self.pos;
+        self.advance_if('-');
+        if !self.advance_if('0') {
+            self.skip_digits()?;
+        }
+        if self.advance_if('.') {
+            self.skip_digits()?;
+        }
+        if self.advance_if('e') || self.advance_if('E') {
+            if !self.advance_if('+') {
+                self.advance_if('-');
+            }
+            self.skip_digits()?;
+        }
+        // Checked against the grammar above, so this can't fail
+        Ok(Value::Number(self.source[start..self.pos].parse().unwrap()))
+    }
+
+    fn parse_str(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.parse_escape()?),
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(format!(
+                        "Unescaped control character in string at offset {}",
+                        self.pos - 1
+                    ))
+                }
+                Some(c) => s.push(c),
+                None => return Err("Unterminated string in JSON".to_owned()),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, String> {
+        let c = match self.advance() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let unit = self.parse_hex4()?;
+                // Characters outside the BMP are escaped as a surrogate pair
+                let code = if (0xD800..0xDC00).contains(&unit)
+                    && self.source[self.pos..].starts_with("\\u")
+                {
+                    self.pos += 2;
+                    let low = self.parse_hex4()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(format!("Invalid surrogate pair at offset {}", self.pos));
+                    }
+                    0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    unit
+                };
+                char::from_u32(code)
+                    .ok_or_else(|| format!("Invalid character escape at offset {}", self.pos))?
+            }
+            _ => return Err(format!("Invalid escape at offset {}", self.pos - 1)),
+        };
+        Ok(c)
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .source
+            .get(self.pos..self.pos + 4)
+            .filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("Expected 4 hex digits at offset {}", self.pos))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn parse_list(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if !self.advance_if(']') {
+            loop {
+                items.push(self.parse_value(depth + 1)?);
+                self.skip_whitespace();
+                if self.advance_if(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Value::new_list(items))
+    }
+
+    fn parse_map(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut map = Map::default();
+        self.skip_whitespace();
+        if !self.advance_if('}') {
+            loop {
+                self.skip_whitespace();
+                let key = self.parse_str()?;
+                self.skip_whitespace();
+                self.expect(':')?;
+                let value = self.parse_value(depth + 1)?;
+                map.insert(key.into(), value);
+                self.skip_whitespace();
+                if self.advance_if('}') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Value::new_map(map))
+    }
+}
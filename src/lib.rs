@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate num_derive;
+extern crate num_traits;
+
+pub mod chunk;
+pub mod compiler;
+pub mod diagnostics;
+mod expression;
+mod gc;
+mod json;
+pub mod native;
+mod object;
+pub mod optimizer;
+pub mod pipeline;
+pub mod scanner;
+mod statement;
+pub mod util;
+pub mod value;
+pub mod vm;
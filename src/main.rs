@@ -1,39 +1,112 @@
-#![feature(coroutines)]
-
-#[macro_use]
-extern crate num_derive;
-extern crate num_traits;
-
-mod chunk;
-mod compiler;
-mod expression;
-mod object;
-mod pipeline;
-mod scanner;
-mod statement;
-mod util;
-mod value;
-mod vm;
-
-use std::{env, fs, io};
+use std::{
+    env,
+    fs::File,
+    io::{self, Read},
+};
+
+use lox_rs::{
+    pipeline::Pipeline,
+    value::{MixedEquality, Value},
+};
+
+const REPL_HELP: &str = "\
+:help   Show this message
+:env    List all globals and their values
+:clear  Forget all globals declared so far
+:echo   Toggle showing the globals each line declares";
+
+/// Handles a REPL meta-command, i.e. a line starting with ':'
+fn run_repl_command(pipeline: &mut Pipeline, command: &str, echo: &mut bool) {
+    match command {
+        ":help" => println!("{REPL_HELP}"),
+        ":echo" => {
+            *echo = !*echo;
+            println!(
+                "Echoing declarations is {}",
+                if *echo { "on" } else { "off" }
+            );
+        }
+        ":env" => {
+            for (name, value) in pipeline.globals() {
+                println!("{name} = {value}");
+            }
+        }
+        ":clear" => pipeline.reset(),
+        _ => println!("Unknown command '{command}', try :help"),
+    }
+}
 
-use crate::pipeline::Pipeline;
+/// Shows each global declared by the last line, like `x = 5`, or `<fn f>` for functions
+fn echo_declarations(pipeline: &Pipeline) {
+    for (name, value) in pipeline.declared_by_last_script() {
+        match value {
+            Value::Func(_) => println!("<fn {name}>"),
+            value => println!("{name} = {value}"),
+        }
+    }
+}
 
-fn run_repl(pipeline: &mut Pipeline) {
+fn run_repl(pipeline: &mut Pipeline, mut echo: bool) {
     for line in io::stdin().lines() {
-        if let Ok(line) = line {
-            if let Err(e) = pipeline.interpret_source(&line) {
-                println!("Error: {e:?}");
-            }
-        } else {
+        let Ok(line) = line else {
             break;
+        };
+
+        if line.trim_start().starts_with(':') {
+            run_repl_command(pipeline, line.trim(), &mut echo);
+            continue;
+        }
+        let result = pipeline.interpret_repl_line(&line);
+        if echo && result.is_ok() {
+            echo_declarations(pipeline);
+        }
+        match result {
+            Ok(Some(value)) => println!("=> {value}"),
+            Ok(None) => {}
+            Err(e) => println!("Error: {e:?}"),
         }
     }
 }
 
-fn run_file(pipeline: &mut Pipeline, filepath: &str) -> Result<(), i32> {
-    let source = fs::read_to_string(filepath).map_err(|e| {
-        println!("Error: {e:?}");
+/// Default for the largest source file `run_file` will load, in bytes
+const DEFAULT_MAX_SOURCE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads the file, refusing to load more than `max_size` bytes
+fn read_source(filepath: &str, max_size: u64) -> io::Result<String> {
+    let file = File::open(filepath)?;
+    let size = file.metadata()?.len();
+    if size > max_size {
+        return Err(io::Error::other(format!(
+            "Source is {size} bytes, which is over the limit of {max_size} bytes"
+        )));
+    }
+
+    // The file could grow after checking its size, so also bound the read
+    let mut source = String::new();
+    file.take(max_size + 1).read_to_string(&mut source)?;
+    if source.len() as u64 > max_size {
+        return Err(io::Error::other(format!(
+            "Source is over the limit of {max_size} bytes"
+        )));
+    }
+    Ok(source)
+}
+
+/// Only compiles the file, reporting any errors without running it
+fn check_file(pipeline: &mut Pipeline, filepath: &str, max_size: u64) -> Result<(), i32> {
+    let source = read_source(filepath, max_size).map_err(|e| {
+        println!("Error: {e}");
+        1
+    })?;
+
+    pipeline.hide_disassembly = true;
+    pipeline.compile(&source).map_err(|_| 1)?;
+    Ok(())
+}
+
+fn run_file(pipeline: &mut Pipeline, filepath: &str, max_size: u64) -> Result<(), i32> {
+    let source = read_source(filepath, max_size).map_err(|e| {
+        println!("Error: {e}");
         1
     })?;
 
@@ -46,14 +119,68 @@ fn run_file(pipeline: &mut Pipeline, filepath: &str) -> Result<(), i32> {
 }
 
 fn main() -> Result<(), i32> {
-    let args: Vec<String> = env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().partition(|arg| arg.starts_with("--"));
+
+    let mut pipeline = Pipeline::default();
+    let mut max_source_size = DEFAULT_MAX_SOURCE_SIZE;
+    let mut check_only = false;
+    let mut echo = false;
+    for flag in flags {
+        if let Some(size) = flag.strip_prefix("--max-source-size=") {
+            let Ok(size) = size.parse() else {
+                println!("Invalid source size '{size}'");
+                return Err(1);
+            };
+            max_source_size = size;
+            continue;
+        }
+
+        if let Some(equality) = flag.strip_prefix("--equality=") {
+            pipeline.equality = match equality {
+                "false" => MixedEquality::False,
+                "coerce" => MixedEquality::Coerce,
+                "error" => MixedEquality::Error,
+                _ => {
+                    println!("Invalid equality '{equality}', expected false, coerce or error");
+                    return Err(1);
+                }
+            };
+            continue;
+        }
+
+        match flag.as_str() {
+            "--strict" => pipeline.strict = true,
+            "--warnings-as-errors" => pipeline.warnings_as_errors = true,
+            "--warn-shadowing" => pipeline.warn_shadowing = true,
+            "--warn-mixed-returns" => pipeline.warn_mixed_returns = true,
+            "--allow-elif" => pipeline.allow_elif = true,
+            "--strip-asserts" => pipeline.strip_assertions = true,
+            "--trace" => pipeline.trace = true,
+            "--profile" => pipeline.profile = true,
+            "--optimize" => pipeline.optimize = true,
+            "--allow-fs" => pipeline.allow_filesystem = true,
+            "--buffer-output" => pipeline.buffer_output(),
+            "--check" => check_only = true,
+            "--echo" => echo = true,
+            _ => {
+                println!("Unknown flag '{flag}'");
+                return Err(1);
+            }
+        }
+    }
 
     if args.len() == 1 {
-        run_repl(&mut Pipeline {});
+        run_repl(&mut pipeline, echo);
+    } else if args.len() == 2 && check_only {
+        check_file(&mut pipeline, &args[1], max_source_size)?
     } else if args.len() == 2 {
-        run_file(&mut Pipeline {}, &args[1])?
+        run_file(&mut pipeline, &args[1], max_source_size)?
     } else {
-        println!("Usage: {} [path]", args[0]);
+        println!(
+            "Usage: {} [--strict] [--warnings-as-errors] [--warn-shadowing] [--warn-mixed-returns] [--allow-elif] [--strip-asserts] [--trace] [--profile] [--optimize] [--equality=false|coerce|error] [--allow-fs] [--buffer-output] [--check] [--echo] [--max-source-size=BYTES] [path]",
+            args[0]
+        );
         return Err(1);
     }
 
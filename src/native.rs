@@ -0,0 +1,700 @@
+use std::{cmp::Ordering, fs};
+
+use crate::{
+    gc, json,
+    value::Value,
+    vm::{raise_error, InterpretError},
+};
+
+pub type NativeResult = Result<Value, InterpretError>;
+
+/// A function implemented in Rust. Natives are predeclared as the first globals, in the order of
+/// `NATIVES`.
+#[derive(Debug)]
+pub struct NativeFn {
+    pub name: &'static str,
+    /// Arguments after the first `min_arity` are optional
+    pub min_arity: usize,
+    /// Either the most arguments accepted or `VARIADIC`
+    pub arity: usize,
+    /// Called with between `min_arity` and `arity` arguments
+    pub func: fn(&[Value]) -> NativeResult,
+}
+
+/// The `arity` of natives that take any number of arguments after the first `min_arity`
+pub const VARIADIC: usize = usize::MAX;
+
+impl NativeFn {
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        (self.min_arity..=self.arity).contains(&arg_count)
+    }
+
+    /// The number of arguments accepted, for error messages
+    pub fn describe_arity(&self) -> String {
+        if self.arity == VARIADIC {
+            format!("at least {}", self.min_arity)
+        } else if self.min_arity == self.arity {
+            self.arity.to_string()
+        } else {
+            format!("{} to {}", self.min_arity, self.arity)
+        }
+    }
+}
+
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+pub static NATIVES: &[NativeFn] = &[
+    NativeFn {
+        name: "abs",
+        min_arity: 1,
+        arity: 1,
+        func: abs,
+    },
+    NativeFn {
+        name: "sign",
+        min_arity: 1,
+        arity: 1,
+        func: sign,
+    },
+    NativeFn {
+        name: "clamp",
+        min_arity: 3,
+        arity: 3,
+        func: clamp,
+    },
+    NativeFn {
+        name: "round_to",
+        min_arity: 2,
+        arity: 2,
+        func: round_to,
+    },
+    NativeFn {
+        name: "is_integer",
+        min_arity: 1,
+        arity: 1,
+        func: is_integer,
+    },
+    NativeFn {
+        name: "is_even",
+        min_arity: 1,
+        arity: 1,
+        func: is_even,
+    },
+    NativeFn {
+        name: "is_odd",
+        min_arity: 1,
+        arity: 1,
+        func: is_odd,
+    },
+    NativeFn {
+        name: "lower",
+        min_arity: 1,
+        arity: 1,
+        func: lower,
+    },
+    NativeFn {
+        name: "upper",
+        min_arity: 1,
+        arity: 1,
+        func: upper,
+    },
+    NativeFn {
+        name: "eq_ignore_case",
+        min_arity: 2,
+        arity: 2,
+        func: eq_ignore_case,
+    },
+    NativeFn {
+        name: "pad_left",
+        min_arity: 2,
+        arity: 3,
+        func: pad_left,
+    },
+    NativeFn {
+        name: "pad_right",
+        min_arity: 2,
+        arity: 3,
+        func: pad_right,
+    },
+    NativeFn {
+        name: "replace",
+        min_arity: 3,
+        arity: 3,
+        func: replace,
+    },
+    NativeFn {
+        name: "trim",
+        min_arity: 1,
+        arity: 1,
+        func: trim,
+    },
+    NativeFn {
+        name: "parse_int",
+        min_arity: 2,
+        arity: 2,
+        func: parse_int,
+    },
+    NativeFn {
+        name: "parse_float",
+        min_arity: 1,
+        arity: 1,
+        func: parse_float,
+    },
+    NativeFn {
+        name: "len",
+        min_arity: 1,
+        arity: 1,
+        func: len,
+    },
+    NativeFn {
+        name: "contains",
+        min_arity: 2,
+        arity: 2,
+        func: contains,
+    },
+    NativeFn {
+        name: "to_json",
+        min_arity: 1,
+        arity: 1,
+        func: to_json,
+    },
+    NativeFn {
+        name: "from_json",
+        min_arity: 1,
+        arity: 1,
+        func: from_json,
+    },
+    NativeFn {
+        name: "gc",
+        min_arity: 0,
+        arity: 0,
+        func: gc,
+    },
+    NativeFn {
+        name: "approx_eq",
+        min_arity: 2,
+        arity: 3,
+        func: approx_eq,
+    },
+    NativeFn {
+        name: "format",
+        min_arity: 1,
+        arity: VARIADIC,
+        func: format,
+    },
+    NativeFn {
+        name: "sort",
+        min_arity: 1,
+        arity: 1,
+        func: sort,
+    },
+    NativeFn {
+        name: "sorted",
+        min_arity: 1,
+        arity: 1,
+        func: sorted,
+    },
+    NativeFn {
+        name: "binary_search",
+        min_arity: 2,
+        arity: 2,
+        func: binary_search,
+    },
+    NativeFn {
+        name: "eval",
+        min_arity: 1,
+        arity: 1,
+        func: eval,
+    },
+    NativeFn {
+        name: "next",
+        min_arity: 1,
+        arity: 1,
+        func: next,
+    },
+    NativeFn {
+        name: "pretty",
+        min_arity: 1,
+        arity: 2,
+        func: pretty,
+    },
+];
+
+/// Natives with access to the filesystem, only declared when allowed, see
+/// `Pipeline::allow_filesystem`
+pub static FILESYSTEM_NATIVES: &[NativeFn] = &[
+    NativeFn {
+        name: "read_file",
+        min_arity: 1,
+        arity: 1,
+        func: read_file,
+    },
+    NativeFn {
+        name: "write_file",
+        min_arity: 2,
+        arity: 2,
+        func: write_file,
+    },
+];
+
+/// Fails the native call with a runtime error mentioning the native's name. Also meant for
+/// natives registered with `Pipeline::register_native`.
+pub fn report_native_error<T>(name: &str, msg: &str) -> Result<T, InterpretError> {
+    raise_error(
+        format!("Error in '{name}': {msg}"),
+        format!("{name}: {msg}"),
+    )
+}
+
+fn abs(args: &[Value]) -> NativeResult {
+    Ok(args[0].as_number()?.abs().into())
+}
+
+/// -1, 0 or 1. NaN stays NaN.
+fn sign(args: &[Value]) -> NativeResult {
+    let x = args[0].as_number()?;
+    let sign = if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else if x == 0.0 {
+        0.0
+    } else {
+        x
+    };
+    Ok(sign.into())
+}
+
+fn clamp(args: &[Value]) -> NativeResult {
+    let x = args[0].as_number()?;
+    let lo = args[1].as_number()?;
+    let hi = args[2].as_number()?;
+    // Also rejects NaN bounds, which f64::clamp would panic on
+    if lo.partial_cmp(&hi).is_none_or(|o| o.is_gt()) {
+        return report_native_error(
+            "clamp",
+            &format!("Lower bound {lo} is above upper bound {hi}"),
+        );
+    }
+    Ok(x.clamp(lo, hi).into())
+}
+
+/// Used by `approx_eq` when no tolerance is given
+const DEFAULT_TOLERANCE: f64 = 1e-9;
+
+/// Whether two numbers differ by at most the tolerance
+fn approx_eq(args: &[Value]) -> NativeResult {
+    let a = args[0].as_number()?;
+    let b = args[1].as_number()?;
+    let tolerance = match args.get(2) {
+        Some(tolerance) => tolerance.as_number()?,
+        None => DEFAULT_TOLERANCE,
+    };
+    if tolerance.is_nan() || tolerance < 0.0 {
+        return report_native_error(
+            "approx_eq",
+            &format!("Tolerance must not be negative, found {tolerance}"),
+        );
+    }
+    // Equal infinities differ by NaN
+    Ok((a == b || (a - b).abs() <= tolerance).into())
+}
+
+/// Replaces each `{}` in the format string with the next argument, as `print` would show it.
+/// `{{` and `}}` stand for literal braces.
+fn format(args: &[Value]) -> NativeResult {
+    let fmt = args[0].as_str()?;
+    let mut values = args[1..].iter();
+    let mut placeholders = 0;
+    let mut formatted = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                formatted.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                placeholders += 1;
+                if let Some(value) = values.next() {
+                    formatted += &value.to_string();
+                }
+            }
+            ('{' | '}', _) => {
+                return report_native_error(
+                    "format",
+                    &format!("Unmatched '{c}' in format string, use '{c}{c}' for a literal one"),
+                );
+            }
+            _ => formatted.push(c),
+        }
+    }
+    if placeholders != args.len() - 1 {
+        return report_native_error(
+            "format",
+            &format!(
+                "Format string has {placeholders} placeholders but {} arguments were given",
+                args.len() - 1
+            ),
+        );
+    }
+    Ok(formatted.into())
+}
+
+/// Rounds to the given number of decimal places, with ties to even. Negative digits round to
+/// tens, hundreds and so on.
+fn round_to(args: &[Value]) -> NativeResult {
+    let x = args[0].as_number()?;
+    let digits = args[1].as_number()?;
+    if !digits.is_finite() || digits.fract() != 0.0 {
+        return report_native_error(
+            "round_to",
+            &format!("Digits must be an integer, found {digits}"),
+        );
+    }
+
+    // Scale by dividing for negative digits, since 10^-n isn't exact
+    let scale = 10f64.powi(digits.abs().min(i32::MAX as f64) as i32);
+    let rounded = if digits >= 0.0 {
+        let scaled = x * scale;
+        if scaled.is_finite() {
+            scaled.round_ties_even() / scale
+        } else {
+            // Too many digits for it to make a difference
+            x
+        }
+    } else if scale.is_finite() {
+        (x / scale).round_ties_even() * scale
+    } else {
+        0.0
+    };
+    Ok(rounded.into())
+}
+
+fn is_integer(args: &[Value]) -> NativeResult {
+    let x = args[0].as_number()?;
+    Ok((x.is_finite() && x.fract() == 0.0).into())
+}
+
+/// Whether a whole number is even, erroring for numbers with a fractional part
+fn is_whole_even(name: &str, args: &[Value]) -> Result<bool, InterpretError> {
+    let x = args[0].as_number()?;
+    if !x.is_finite() || x.fract() != 0.0 {
+        return report_native_error(name, &format!("Expected a whole number, found {x}"));
+    }
+    Ok(x % 2.0 == 0.0)
+}
+
+fn is_even(args: &[Value]) -> NativeResult {
+    Ok(is_whole_even("is_even", args)?.into())
+}
+
+fn is_odd(args: &[Value]) -> NativeResult {
+    Ok((!is_whole_even("is_odd", args)?).into())
+}
+
+// String casing uses full Unicode case mapping, so e.g. `upper("straße")` is "STRASSE"
+
+fn lower(args: &[Value]) -> NativeResult {
+    Ok(args[0].as_str()?.to_lowercase().into())
+}
+
+fn upper(args: &[Value]) -> NativeResult {
+    Ok(args[0].as_str()?.to_uppercase().into())
+}
+
+fn eq_ignore_case(args: &[Value]) -> NativeResult {
+    let a = args[0].as_str()?;
+    let b = args[1].as_str()?;
+    Ok((a.to_lowercase() == b.to_lowercase()).into())
+}
+
+fn pad_left(args: &[Value]) -> NativeResult {
+    pad("pad_left", args, true)
+}
+
+fn pad_right(args: &[Value]) -> NativeResult {
+    pad("pad_right", args, false)
+}
+
+/// Widest string `pad_left` and `pad_right` will pad to, in characters
+const MAX_PAD_WIDTH: f64 = 1_000_000.0;
+
+/// Pads a string with `fill`, or spaces if no fill is given, up to `width` characters, leaving
+/// longer strings alone
+fn pad(name: &str, args: &[Value], left: bool) -> NativeResult {
+    let s = args[0].as_str()?;
+    let width = args[1].as_number()?;
+    if !(width >= 0.0 && width.fract() == 0.0) {
+        return report_native_error(
+            name,
+            &format!("Width must be a non-negative integer, found {width}"),
+        );
+    }
+    if width > MAX_PAD_WIDTH {
+        return report_native_error(
+            name,
+            &format!("Width must be at most {MAX_PAD_WIDTH}, found {width}"),
+        );
+    }
+    let fill = match args.get(2) {
+        Some(fill) => {
+            let fill = fill.as_str()?;
+            let mut fill_chars = fill.chars();
+            let (Some(fill), None) = (fill_chars.next(), fill_chars.next()) else {
+                return report_native_error(
+                    name,
+                    &format!("Fill must be a single character, found '{fill}'"),
+                );
+            };
+            fill
+        }
+        None => ' ',
+    };
+
+    let padding = (width as usize).saturating_sub(s.chars().count());
+    let padding: String = std::iter::repeat_n(fill, padding).collect();
+    let padded = if left {
+        padding + &s
+    } else {
+        s.to_string() + &padding
+    };
+    Ok(padded.into())
+}
+
+/// Replaces every occurrence of `from`. An empty `from` is an error, as it would match between
+/// every character.
+fn replace(args: &[Value]) -> NativeResult {
+    let s = args[0].as_str()?;
+    let from = args[1].as_str()?;
+    let to = args[2].as_str()?;
+    if from.is_empty() {
+        return report_native_error("replace", "Can't replace an empty string");
+    }
+    Ok(s.replace(from.as_ref(), &to).into())
+}
+
+/// Strips whitespace from both ends
+fn trim(args: &[Value]) -> NativeResult {
+    Ok(args[0].as_str()?.trim().to_owned().into())
+}
+
+/// Parses an integer in the given radix (2 to 36), with an optional sign. Returns nil if the
+/// string is anything else, including if it has surrounding whitespace.
+fn parse_int(args: &[Value]) -> NativeResult {
+    let s = args[0].as_str()?;
+    let radix = args[1].as_number()?;
+    if !(2.0..=36.0).contains(&radix) || radix.fract() != 0.0 {
+        return report_native_error(
+            "parse_int",
+            &format!("Radix must be an integer from 2 to 36, found {radix}"),
+        );
+    }
+
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(&s)),
+    };
+    if digits.is_empty() {
+        return Ok(Value::Nil);
+    }
+    let mut value = 0.0;
+    for c in digits.chars() {
+        let Some(digit) = c.to_digit(radix as u32) else {
+            return Ok(Value::Nil);
+        };
+        value = value * radix + digit as f64;
+    }
+    Ok(if negative { -value } else { value }.into())
+}
+
+/// Parses a decimal number like `-1.5e3`. Returns nil if the string is anything else, including
+/// the names of infinity and NaN that Rust would accept, or if it has surrounding whitespace.
+fn parse_float(args: &[Value]) -> NativeResult {
+    let s = args[0].as_str()?;
+    let is_decimal = s.chars().any(|c| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if !is_decimal {
+        return Ok(Value::Nil);
+    }
+    Ok(s.parse::<f64>().map_or(Value::Nil, Value::Number))
+}
+
+/// Number of characters of a string, or items of a list, map or range
+fn len(args: &[Value]) -> NativeResult {
+    let len = match &args[0] {
+        Value::Str(s) => s.chars().count(),
+        Value::List(list) => list.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::Range(range) => range.len(),
+        v => {
+            return report_native_error(
+                "len",
+                &format!("Expected a string, list, map or range, found {v}"),
+            )
+        }
+    };
+    Ok((len as f64).into())
+}
+
+/// Whether a string contains a substring, a list an item, a map a key, or a range a number
+fn contains(args: &[Value]) -> NativeResult {
+    let found = match (&args[0], &args[1]) {
+        (Value::Str(s), Value::Str(sub)) => s.contains(sub.as_ref()),
+        (Value::List(list), item) => list.borrow().contains(item),
+        (Value::Map(map), key) => map.borrow().get(key).is_some(),
+        (Value::Range(range), Value::Number(x)) => range.contains(*x),
+        (Value::Range(_), _) => false,
+        (v, _) => {
+            return report_native_error(
+                "contains",
+                &format!("Expected a string, list, map or range, found {v}"),
+            )
+        }
+    };
+    Ok(found.into())
+}
+
+/// Fails unless every item can be compared with every other, see `Value::compare`
+fn check_comparable(name: &str, items: &[Value]) -> Result<(), InterpretError> {
+    let Some(first) = items.first() else {
+        return Ok(());
+    };
+    match items.iter().find(|item| first.compare(item).is_none()) {
+        Some(item) => report_native_error(
+            name,
+            &format!(
+                "Can't compare {} '{first}' with {} '{item}'",
+                first.type_name(),
+                item.type_name()
+            ),
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Sorts a list of numbers or of strings in place, in ascending order
+fn sort(args: &[Value]) -> NativeResult {
+    let list = args[0].as_list()?;
+    check_comparable("sort", &list.borrow())?;
+    list.borrow_mut().sort_by(|a, b| a.compare(b).unwrap());
+    Ok(Value::Nil)
+}
+
+/// Like `sort`, but returns a sorted copy, leaving the list alone
+fn sorted(args: &[Value]) -> NativeResult {
+    let mut items = args[0].as_list()?.borrow().clone();
+    check_comparable("sorted", &items)?;
+    items.sort_by(|a, b| a.compare(b).unwrap());
+    Ok(Value::new_list(items))
+}
+
+/// The index of an item equal to the value in a sorted list, or -1 if there's none
+fn binary_search(args: &[Value]) -> NativeResult {
+    let list = args[0].as_list()?;
+    let items = list.borrow();
+    let (mut lo, mut hi) = (0, items.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match items[mid].compare(&args[1]) {
+            Some(Ordering::Less) => lo = mid + 1,
+            Some(Ordering::Greater) => hi = mid,
+            Some(Ordering::Equal) => return Ok((mid as f64).into()),
+            None => {
+                return report_native_error(
+                    "binary_search",
+                    &format!(
+                        "Can't compare {} '{}' with {} '{}'",
+                        items[mid].type_name(),
+                        items[mid],
+                        args[1].type_name(),
+                        args[1]
+                    ),
+                )
+            }
+        }
+    }
+    Ok((-1.0).into())
+}
+
+/// Compiles and runs a string of source code, sharing the program's globals. It needs the VM, so
+/// `VM::call` runs it instead of calling this.
+fn eval(_: &[Value]) -> NativeResult {
+    report_native_error("eval", "Can only be called by the VM")
+}
+
+/// Resumes a generator, returning the next value it yields, or nil once it has returned. It runs
+/// the generator's frame, so `VM::call` resumes it instead of calling this.
+fn next(_: &[Value]) -> NativeResult {
+    report_native_error("next", "Can only be called by the VM")
+}
+
+/// The widest indent `pretty` accepts
+const MAX_PRETTY_INDENT: f64 = 16.0;
+
+/// Like `print` shows the value, but with the items of nested lists and maps on their own lines,
+/// indented by `indent` spaces (2 by default, at most `MAX_PRETTY_INDENT`) per level
+fn pretty(args: &[Value]) -> NativeResult {
+    let indent = match args.get(1) {
+        Some(indent) => indent.as_number()?,
+        None => 2.0,
+    };
+    if !(indent >= 0.0 && indent.fract() == 0.0) {
+        return report_native_error(
+            "pretty",
+            &format!("Indent must be a non-negative integer, found {indent}"),
+        );
+    }
+    if indent > MAX_PRETTY_INDENT {
+        return report_native_error(
+            "pretty",
+            &format!("Indent must be at most {MAX_PRETTY_INDENT}, found {indent}"),
+        );
+    }
+    match args[0].pretty(indent as usize) {
+        Some(s) => Ok(Value::Str(s.into())),
+        None => report_native_error("pretty", "Value is nested too deeply to indent"),
+    }
+}
+
+fn to_json(args: &[Value]) -> NativeResult {
+    match json::to_json(&args[0]) {
+        Ok(s) => Ok(s.into()),
+        Err(e) => report_native_error("to_json", &e),
+    }
+}
+
+fn from_json(args: &[Value]) -> NativeResult {
+    match json::from_json(&args[0].as_str()?) {
+        Ok(v) => Ok(v),
+        Err(e) => report_native_error("from_json", &e),
+    }
+}
+
+/// Frees lists and maps that only reference each other, returning how many were freed
+fn gc(_args: &[Value]) -> NativeResult {
+    Ok(Value::Number(gc::collect_cycles() as f64))
+}
+
+fn read_file(args: &[Value]) -> NativeResult {
+    let path = args[0].as_str()?;
+    match fs::read_to_string(path.as_ref()) {
+        Ok(contents) => Ok(contents.into()),
+        Err(e) => report_native_error("read_file", &format!("Could not read '{path}': {e}")),
+    }
+}
+
+fn write_file(args: &[Value]) -> NativeResult {
+    let path = args[0].as_str()?;
+    let contents = args[1].as_str()?;
+    match fs::write(path.as_ref(), contents.as_bytes()) {
+        Ok(()) => Ok(Value::Nil),
+        Err(e) => report_native_error("write_file", &format!("Could not write '{path}': {e}")),
+    }
+}
@@ -0,0 +1,414 @@
+use std::{collections::HashSet, rc::Rc};
+
+use crate::{
+    chunk::{ByteCode, Chunk, ConstantTable},
+    value::{FuncObj, Value},
+};
+
+/// Peephole passes over compiled chunks. Each pass can be turned off on its own, and all are on
+/// by default.
+#[derive(Debug, Clone, Copy)]
+pub struct Optimizer {
+    /// Drop values that are pushed only to be popped, like the `1` in `1;`
+    pub dead_pops: bool,
+    /// Evaluate arithmetic on number constants, so `2 * 3` becomes `6`
+    pub fold_constants: bool,
+    /// Fuse a comparison followed by `Not` into a single instruction, like `!=` into `NotEq`
+    pub fuse_not: bool,
+    /// Make jumps that land on an unconditional jump go straight to its target, and drop jumps
+    /// to the next instruction
+    pub thread_jumps: bool,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer {
+            dead_pops: true,
+            fold_constants: true,
+            fuse_not: true,
+            thread_jumps: true,
+        }
+    }
+}
+
+/// An instruction with its jump target, if any, as an instruction index rather than an offset.
+/// The end of the chunk is the index one past the last instruction.
+#[derive(Debug, Clone, Copy)]
+struct Instr {
+    code: ByteCode,
+    line: usize,
+    target: Option<usize>,
+}
+
+/// A chunk being optimized. Removed instructions are left as `None` until the chunk is
+/// rebuilt, so that indices stay put. They never do anything, so a jump to one continues at the
+/// next instruction that's left.
+struct Program<'a> {
+    instrs: Vec<Option<Instr>>,
+    /// Offset of each instruction in the original chunk, and of the end
+    offsets: Vec<usize>,
+    /// Instructions that must be kept as something to land on, besides jump targets, e.g. the
+    /// entry points of a function
+    pinned: Vec<usize>,
+    /// Entries of jump tables, which are found by their position so must all be kept
+    table_entries: HashSet<usize>,
+    /// The rebuilt chunk
+    chunk: Chunk,
+    /// Where constants made by folding are added
+    constants: &'a mut ConstantTable,
+}
+
+fn jump_offset(code: ByteCode) -> Option<i16> {
+    use ByteCode::*;
+    match code {
+        JumpF(j) | JumpRelative(j) | PopJump(j) | PushHandler(j) | IterNext(j) => Some(j),
+        _ => None,
+    }
+}
+
+impl Optimizer {
+    /// Optimizes the function's chunk and those of the functions it declares, replacing them in
+    /// the constant table
+    pub fn optimize_function(&self, func: &FuncObj, constants: &mut ConstantTable) -> FuncObj {
+        let (chunk, entry_points) = self.optimize_chunk(&func.chunk, &func.entry_points, constants);
+        FuncObj {
+            arity: func.arity,
+            chunk,
+            name: func.name.clone(),
+            def_line: func.def_line,
+            params: func.params.clone(),
+            entry_points,
+            is_generator: func.is_generator,
+        }
+    }
+
+    /// Optimizes the chunk, returning it along with where each of `entry_points` ended up
+    fn optimize_chunk(
+        &self,
+        chunk: &Chunk,
+        entry_points: &[usize],
+        constants: &mut ConstantTable,
+    ) -> (Chunk, Vec<usize>) {
+        for idx in chunk.used_constants() {
+            if let Value::Func(func) = &constants.get(idx) {
+                let optimized = self.optimize_function(func, constants);
+                constants.replace(idx, Value::Func(Rc::new(optimized)));
+            }
+        }
+
+        let mut program = Program::decode(chunk, entry_points, constants);
+        loop {
+            let mut changed = false;
+            if self.dead_pops {
+                changed |= program.remove_dead_pops();
+            }
+            if self.fold_constants {
+                changed |= program.fold_constants();
+            }
+            if self.fuse_not {
+                changed |= program.fuse_not();
+            }
+            if self.thread_jumps {
+                changed |= program.thread_jumps();
+            }
+            if !changed {
+                break;
+            }
+        }
+        program.encode()
+    }
+}
+
+impl<'a> Program<'a> {
+    fn decode(
+        chunk: &Chunk,
+        entry_points: &[usize],
+        constants: &'a mut ConstantTable,
+    ) -> Program<'a> {
+        let decoded: Vec<(usize, ByteCode)> = chunk.into_iter().collect();
+        let mut offsets: Vec<usize> = decoded.iter().map(|&(offset, _)| offset).collect();
+        offsets.push(chunk.size());
+        let index_of = |offset: usize| offsets.binary_search(&offset).ok();
+
+        // Nops are left as holes, so the rebuilt chunk drops them
+        let instrs = decoded
+            .iter()
+            .map(|&(offset, code)| {
+                if let ByteCode::Nop = code {
+                    return None;
+                }
+                Some(Instr {
+                    code,
+                    line: chunk.get_line(offset),
+                    target: jump_offset(code)
+                        .and_then(|j| index_of((offset as isize + j as isize) as usize)),
+                })
+            })
+            .collect();
+        let pinned = entry_points.iter().filter_map(|&o| index_of(o)).collect();
+        let table_entries = decoded
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &(_, code))| match code {
+                ByteCode::JumpTable(_, len) => i + 1..i + len as usize + 3,
+                _ => 0..0,
+            })
+            .collect();
+
+        Program {
+            instrs,
+            pinned,
+            table_entries,
+            chunk: chunk.empty_like(),
+            constants,
+            offsets,
+        }
+    }
+
+    /// Builds the optimized chunk, returning it along with the new offsets of the pinned
+    /// instructions
+    fn encode(mut self) -> (Chunk, Vec<usize>) {
+        let len = self.instrs.len();
+        let labels: Vec<usize> = (0..=len).map(|_| self.chunk.allocate_new_label()).collect();
+        let mut new_offsets = vec![0; len + 1];
+        for (i, instr) in self.instrs.iter().enumerate() {
+            new_offsets[i] = self.chunk.size();
+            self.chunk.push_label(labels[i]);
+            let Some(instr) = instr else {
+                continue;
+            };
+            match instr.target {
+                Some(target) => {
+                    self.chunk
+                        .push_monkey_patch(instr.code, instr.line, labels[target])
+                }
+                None => self.chunk.push(instr.code, instr.line),
+            }
+        }
+        new_offsets[len] = self.chunk.size();
+        self.chunk.push_label(labels[len]);
+        self.chunk.resolve_monkey_patches();
+
+        let entry_points = self.pinned.iter().map(|&i| new_offsets[i]).collect();
+        (self.chunk, entry_points)
+    }
+
+    /// The instruction execution continues at after running `i`, skipping removed ones
+    fn next_live(&self, i: usize) -> Option<usize> {
+        (i + 1..self.instrs.len()).find(|&j| self.instrs[j].is_some())
+    }
+
+    /// Where a jump to `i` ends up, past any removed instructions
+    fn resolve(&self, i: usize) -> usize {
+        (i..self.instrs.len())
+            .find(|&j| self.instrs[j].is_some())
+            .unwrap_or(self.instrs.len())
+    }
+
+    /// Instructions that something may jump to, so they can't be merged into the one before
+    fn landing_sites(&self) -> HashSet<usize> {
+        let targets = self.instrs.iter().flatten().filter_map(|i| i.target);
+        self.pinned
+            .iter()
+            .copied()
+            .chain(targets)
+            .map(|i| self.resolve(i))
+            .collect()
+    }
+
+    fn code(&self, i: usize) -> Option<ByteCode> {
+        self.instrs.get(i)?.map(|instr| instr.code)
+    }
+
+    fn set_code(&mut self, i: usize, code: ByteCode) {
+        if let Some(instr) = &mut self.instrs[i] {
+            instr.code = code;
+        }
+    }
+
+    /// The instruction after `i`, unless something jumps to it
+    fn follower(&self, i: usize, sites: &HashSet<usize>) -> Option<usize> {
+        self.next_live(i).filter(|next| !sites.contains(next))
+    }
+
+    fn number_constant(&self, code: Option<ByteCode>) -> Option<f64> {
+        let idx = match code? {
+            ByteCode::Constant(idx) => idx as u32,
+            ByteCode::ConstantLong(idx) => idx,
+            _ => return None,
+        };
+        match self.constants.values().get(idx as usize) {
+            Some(&Value::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The instruction pushing `n`, if it can be done without growing the code
+    fn push_number(&mut self, n: f64) -> Option<ByteCode> {
+        let idx = self.constants.push(Value::Number(n));
+        u8::try_from(idx).ok().map(ByteCode::Constant)
+    }
+
+    fn remove_dead_pops(&mut self) -> bool {
+        use ByteCode::*;
+        let mut sites = self.landing_sites();
+        let mut changed = false;
+        let mut i = self.resolve(0);
+        while i < self.instrs.len() {
+            let is_push = matches!(
+                self.code(i),
+                Some(Constant(_) | ConstantLong(_) | Nil | True | False | Uninit | Dup)
+            );
+            match self.follower(i, &sites) {
+                Some(pop) if is_push && matches!(self.code(pop), Some(Pop)) => {
+                    self.instrs[i] = None;
+                    self.instrs[pop] = None;
+                    // Jumps to the pair now land after it
+                    if sites.contains(&i) {
+                        sites.insert(self.resolve(pop));
+                    }
+                    changed = true;
+                    i = self.resolve(pop);
+                }
+                _ => i = self.resolve(i + 1),
+            }
+        }
+        changed
+    }
+
+    fn fold_constants(&mut self) -> bool {
+        use ByteCode::*;
+        let sites = self.landing_sites();
+        let mut changed = false;
+        let mut i = self.resolve(0);
+        while i < self.instrs.len() {
+            let Some(l) = self.number_constant(self.code(i)) else {
+                i = self.resolve(i + 1);
+                continue;
+            };
+            let next = self.follower(i, &sites);
+
+            // A constant followed by Negate, or AddConst or SubConst of either width
+            let unary = next.and_then(|next| match self.code(next)? {
+                Negate => Some(-l),
+                AddConst(idx) => self.number_constant(Some(Constant(idx))).map(|r| l + r),
+                SubConst(idx) => self.number_constant(Some(Constant(idx))).map(|r| l - r),
+                AddConstLong(idx) => self.number_constant(Some(ConstantLong(idx))).map(|r| l + r),
+                SubConstLong(idx) => self.number_constant(Some(ConstantLong(idx))).map(|r| l - r),
+                _ => None,
+            });
+            if let (Some(next), Some(folded)) = (next, unary) {
+                if let Some(code) = self.push_number(folded) {
+                    self.set_code(i, code);
+                    self.instrs[next] = None;
+                    changed = true;
+                    // The result may fold with what follows it
+                    continue;
+                }
+            }
+
+            // Two constants followed by an arithmetic instruction
+            let r = next.and_then(|next| self.number_constant(self.code(next)));
+            let op = next.and_then(|next| self.follower(next, &sites));
+            if let (Some(next), Some(r), Some(op)) = (next, r, op) {
+                let folded = match self.code(op) {
+                    Some(Add) => Some(l + r),
+                    Some(Sub) => Some(l - r),
+                    Some(Mul) => Some(l * r),
+                    Some(Div) => Some(l / r),
+                    Some(FloorDiv) => Some((l / r).floor()),
+                    _ => None,
+                };
+                if let Some(code) = folded.and_then(|folded| self.push_number(folded)) {
+                    self.set_code(i, code);
+                    self.instrs[next] = None;
+                    self.instrs[op] = None;
+                    changed = true;
+                    continue;
+                }
+            }
+            i = self.resolve(i + 1);
+        }
+        changed
+    }
+
+    fn fuse_not(&mut self) -> bool {
+        use ByteCode::*;
+        let sites = self.landing_sites();
+        let mut changed = false;
+        let mut i = self.resolve(0);
+        while i < self.instrs.len() {
+            let fused = match self.code(i) {
+                Some(Eq) => Some(NotEq),
+                Some(Gt) => Some(NotGt),
+                Some(Lt) => Some(NotLt),
+                _ => None,
+            };
+            if let (Some(fused), Some(not)) = (fused, self.follower(i, &sites)) {
+                if matches!(self.code(not), Some(Not)) {
+                    self.set_code(i, fused);
+                    self.instrs[not] = None;
+                    changed = true;
+                }
+            }
+            i = self.resolve(i + 1);
+        }
+        changed
+    }
+
+    fn thread_jumps(&mut self) -> bool {
+        use ByteCode::*;
+        let mut changed = false;
+        for i in 0..self.instrs.len() {
+            let Some(
+                instr @ Instr {
+                    code: JumpF(_) | JumpRelative(_) | PopJump(_),
+                    target: Some(first),
+                    ..
+                },
+            ) = self.instrs[i]
+            else {
+                continue;
+            };
+            // Follow chains of jumps, stopping at a cycle
+            let mut target = self.resolve(first);
+            let mut seen = HashSet::from([i]);
+            while let Some(Some(Instr {
+                code: JumpRelative(_),
+                target: Some(next),
+                ..
+            })) = self.instrs.get(target)
+            {
+                if !seen.insert(target) {
+                    break;
+                }
+                target = self.resolve(*next);
+            }
+            // A jump to what runs next anyway does nothing but its pop
+            if target == self.resolve(i + 1)
+                && !matches!(instr.code, JumpF(_))
+                && !self.table_entries.contains(&i)
+            {
+                self.instrs[i] = match instr.code {
+                    PopJump(_) => Some(Instr {
+                        code: Pop,
+                        target: None,
+                        ..instr
+                    }),
+                    _ => None,
+                };
+                changed = true;
+                continue;
+            }
+            // The chunk only shrinks, so a jump that fit before still fits
+            let distance = self.offsets[target] as isize - self.offsets[i] as isize;
+            if target != self.resolve(first) && i16::try_from(distance).is_ok() {
+                if let Some(instr) = &mut self.instrs[i] {
+                    instr.target = Some(target);
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+}
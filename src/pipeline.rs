@@ -1,23 +1,468 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufWriter, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+
 use crate::{
-    compiler::Compiler,
+    chunk::{ConstantMark, ConstantTable},
+    compiler::{Compiler, GlobalBindings},
+    diagnostics::{self, Diagnostic},
+    native::{NativeFn, NativeResult, FILESYSTEM_NATIVES},
+    optimizer::Optimizer,
+    scanner::{TokenScanner, TokenType},
+    value::{FuncObj, MixedEquality, Value},
     vm::{InterpretError, VM},
 };
 
-pub struct Pipeline;
+/// Compiles and runs source, persisting globals across calls so that consecutive REPL lines
+/// can see each other's declarations.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    vm: VM,
+    /// Added with `register_native`
+    host_natives: Vec<&'static NativeFn>,
+    /// Globals and global `val`s declared by the last compiled script, see
+    /// `declared_by_last_script`
+    last_declared: Vec<String>,
+    /// Bindings from before the first `compile_incremental`, which each call compiles against
+    incremental_base: Option<GlobalBindings>,
+    /// Declarations compiled by the last `compile_incremental`, keyed by their first line and
+    /// source text
+    declaration_cache: HashMap<(usize, String), Vec<CachedDeclaration>>,
+    /// Declarations the last `compile_incremental` had to compile
+    recompiled: usize,
+    /// Reported since the last `take_diagnostics`, if `collect_diagnostics` is set
+    diagnostics: Vec<Diagnostic>,
+
+    /// Compile in strict mode, see `Compiler::strict`
+    pub strict: bool,
+    /// Fail compiling if there are any warnings, see `Compiler::warnings_as_errors`
+    pub warnings_as_errors: bool,
+    /// Warn about locals shadowing other variables, see `Compiler::warn_shadowing`
+    pub warn_shadowing: bool,
+    /// Warn about functions returning both with and without a value, see
+    /// `Compiler::warn_mixed_returns`
+    pub warn_mixed_returns: bool,
+    /// Accept `elif` for `else if`, see `Compiler::allow_elif`
+    pub allow_elif: bool,
+    /// Compile `assert` statements to nothing, see `Compiler::strip_assertions`
+    pub strip_assertions: bool,
+    /// Trace execution, see `VM::trace`
+    pub trace: bool,
+    /// How `==` treats operands of different types, see `MixedEquality`
+    pub equality: MixedEquality,
+    /// Count executed opcodes, see `VM::profile`
+    pub profile: bool,
+    /// Run compiled scripts through the optimizer's passes
+    pub optimize: bool,
+    /// Declare natives that read and write files
+    pub allow_filesystem: bool,
+    /// Don't print the disassembly of compiled scripts
+    pub hide_disassembly: bool,
+    /// Keep the errors and warnings reported while compiling and running, see
+    /// `take_diagnostics`
+    pub collect_diagnostics: bool,
+}
+
+/// A top-level declaration compiled on its own by `compile_incremental`
+#[derive(Debug)]
+struct CachedDeclaration {
+    /// The script is only reused when compiling against the same bindings
+    bindings_before: GlobalBindings,
+    bindings_after: GlobalBindings,
+    script: Rc<FuncObj>,
+    /// Constant slots of the functions the script declares, freed once it's no longer cached
+    functions: Vec<u32>,
+}
 
 impl Pipeline {
     pub fn interpret_source(&mut self, source: &str) -> Result<(), InterpretError> {
-        // let mut scanner = ErrorIgnoreTokenScanner {
-        //     inner: TokenScanner::from_source(source),
-        // };
+        let script = self.compile(source)?;
+        self.run(&script)?;
+        Ok(())
+    }
 
-        // while let Some(t) = scanner.next() {
-        //     println!("{t:?}");
-        // }
+    /// Compiles the source into a script that can be run any number of times with `run`. Globals
+    /// it declares stay declared for later sources even if it is never run.
+    pub fn compile(&mut self, source: &str) -> Result<Rc<FuncObj>, InterpretError> {
+        let (script, _) = self.collecting(|pipeline| pipeline.compile_script(source, false))?;
+        Ok(script)
+    }
 
-        let compiler = Compiler::new(source);
+    /// Runs a script compiled by this pipeline, returning the value it left on the stack, if any
+    pub fn run(&mut self, script: &Rc<FuncObj>) -> Result<Value, InterpretError> {
+        self.vm.trace = self.trace;
+        self.vm.profile = self.profile;
+        self.vm.equality = self.equality;
+        self.collecting(|pipeline| pipeline.vm.interpret(Rc::clone(script)))
+    }
 
-        VM::interpret(&compiler.compile()?.chunk)?;
-        Ok(())
+    /// Like `run`, but fails with a runtime error once the script has run for longer than the
+    /// timeout. The script's try blocks can't catch the error.
+    pub fn run_with_timeout(
+        &mut self,
+        script: &Rc<FuncObj>,
+        timeout: Duration,
+    ) -> Result<Value, InterpretError> {
+        // A timeout too long to represent never passes
+        self.vm.deadline = Instant::now().checked_add(timeout);
+        let result = self.run(script);
+        self.vm.deadline = None;
+        result
+    }
+
+    /// Like `interpret_source`, but if the line is (or ends with) an expression without a ';',
+    /// returns its value
+    pub fn interpret_repl_line(&mut self, line: &str) -> Result<Option<Value>, InterpretError> {
+        self.interpret(line, true)
+    }
+
+    fn interpret(
+        &mut self,
+        source: &str,
+        allow_expression_result: bool,
+    ) -> Result<Option<Value>, InterpretError> {
+        let (script, has_result) =
+            self.collecting(|pipeline| pipeline.compile_script(source, allow_expression_result))?;
+        let result = self.run(&script)?;
+        Ok(has_result.then_some(result))
+    }
+
+    /// Also returns whether the script ends in an expression whose value it leaves as its result
+    fn compile_script(
+        &mut self,
+        source: &str,
+        allow_expression_result: bool,
+    ) -> Result<(Rc<FuncObj>, bool), InterpretError> {
+        if self.allow_filesystem {
+            self.define_natives(FILESYSTEM_NATIVES);
+        }
+        self.define_natives(self.host_natives.clone());
+
+        // Compile against a copy of the bindings, so that a failed compile doesn't leave
+        // half-declared globals behind
+        let mut global_bindings = self.vm.global_bindings.clone();
+        let first_new_slot = global_bindings.global_slots.len() as u32;
+        let mut compiler = self.new_compiler(source, 1, &mut global_bindings);
+        compiler.allow_expression_result = allow_expression_result;
+        let mut func = compiler.compile()?;
+        let has_result = compiler.has_expression_result;
+        if self.optimize {
+            func = Optimizer::default().optimize_function(&func, &mut self.vm.constants);
+        }
+
+        let new_globals = global_bindings
+            .global_slots
+            .iter()
+            .filter(|(_, &slot)| slot >= first_new_slot)
+            .sorted_by_key(|(_, &slot)| slot)
+            .map(|(name, _)| name);
+        let new_constants = global_bindings
+            .constants
+            .keys()
+            .filter(|name| !self.vm.global_bindings.constants.contains_key(*name))
+            .sorted();
+        self.last_declared = new_globals.chain(new_constants).cloned().collect();
+        self.vm.global_bindings = global_bindings;
+        Ok((Rc::new(func), has_result))
+    }
+
+    /// A compiler for the source, which starts on `first_line`, with the pipeline's options
+    fn new_compiler<'a>(
+        &'a mut self,
+        source: &'a str,
+        first_line: usize,
+        global_bindings: &'a mut GlobalBindings,
+    ) -> Compiler<'a> {
+        let mut compiler =
+            Compiler::new_at_line(source, first_line, global_bindings, &mut self.vm.constants);
+        compiler.strict = self.strict;
+        compiler.warnings_as_errors = self.warnings_as_errors;
+        compiler.warn_shadowing = self.warn_shadowing;
+        compiler.warn_mixed_returns = self.warn_mixed_returns;
+        compiler.allow_elif = self.allow_elif;
+        compiler.strip_assertions = self.strip_assertions;
+        compiler.disassemble = !self.hide_disassembly;
+        compiler
+    }
+
+    /// Compiles the source one top-level declaration at a time into scripts to be run in order
+    /// with `run`, for editors that recompile a file on every change. Declarations whose text,
+    /// first line and preceding declarations are unchanged since the last call reuse their
+    /// scripts instead of being compiled again, so their warnings aren't reported again.
+    ///
+    /// Each call replaces the program compiled by the previous one: it compiles against the
+    /// globals declared before the first call, rather than the ones the previous call declared.
+    /// The constants of functions in declarations that are no longer reused are freed, so scripts
+    /// and functions from earlier calls must not be run once the new scripts are compiled.
+    pub fn compile_incremental(
+        &mut self,
+        source: &str,
+    ) -> Result<Vec<Rc<FuncObj>>, InterpretError> {
+        self.collecting(|pipeline| pipeline.compile_declarations(source))
+    }
+
+    fn compile_declarations(&mut self, source: &str) -> Result<Vec<Rc<FuncObj>>, InterpretError> {
+        if self.allow_filesystem {
+            self.define_natives(FILESYSTEM_NATIVES);
+        }
+        self.define_natives(self.host_natives.clone());
+
+        let mut bindings = self
+            .incremental_base
+            .get_or_insert_with(|| self.vm.global_bindings.clone())
+            .clone();
+        let mut previous = std::mem::take(&mut self.declaration_cache);
+        let mut scripts = vec![];
+        let mut failed = false;
+        self.recompiled = 0;
+
+        for (line, text) in top_level_declarations(source) {
+            let key = (line, text.to_owned());
+            let cached = previous.get_mut(&key).and_then(|entries| {
+                let index = entries.iter().position(|e| e.bindings_before == bindings)?;
+                Some(entries.swap_remove(index))
+            });
+            let declaration = match cached {
+                Some(declaration) => declaration,
+                None => {
+                    self.recompiled += 1;
+                    let mark = self.vm.constants.mark();
+                    let mut bindings_after = bindings.clone();
+                    let mut compiler = self.new_compiler(text, line, &mut bindings_after);
+                    compiler.allow_undeclared = true;
+                    let compiled = compiler.compile();
+                    let functions = self.new_functions(mark);
+                    let Ok(mut func) = compiled else {
+                        // Keep going to report errors in the later declarations too
+                        self.release_functions(&functions);
+                        failed = true;
+                        bindings = bindings_after;
+                        continue;
+                    };
+                    if self.optimize {
+                        // Optimized functions replace the originals in their slots
+                        func =
+                            Optimizer::default().optimize_function(&func, &mut self.vm.constants);
+                    }
+                    CachedDeclaration {
+                        bindings_before: bindings,
+                        bindings_after,
+                        script: Rc::new(func),
+                        functions,
+                    }
+                }
+            };
+
+            bindings = declaration.bindings_after.clone();
+            scripts.push(Rc::clone(&declaration.script));
+            self.declaration_cache
+                .entry(key)
+                .or_default()
+                .push(declaration);
+        }
+
+        for evicted in previous.into_values().flatten() {
+            self.release_functions(&evicted.functions);
+        }
+
+        if failed {
+            return Err(InterpretError::Compiler);
+        }
+        bindings.check_all_declared()?;
+        self.last_declared.clear();
+        self.vm.global_bindings = bindings;
+        Ok(scripts)
+    }
+
+    /// Slots of the functions compiled since the mark was taken
+    fn new_functions(&self, mark: ConstantMark) -> Vec<u32> {
+        let constants = &self.vm.constants;
+        constants
+            .filled_since(mark)
+            .filter(|&idx| matches!(constants.values()[idx as usize], Value::Func(_)))
+            .collect()
+    }
+
+    fn release_functions(&mut self, functions: &[u32]) {
+        for &idx in functions {
+            self.vm.constants.release(idx);
+        }
+    }
+
+    /// How many declarations the last `compile_incremental` compiled rather than reusing
+    pub fn last_recompiled(&self) -> usize {
+        self.recompiled
+    }
+
+    /// The errors and warnings reported since the last call, in the order they were printed.
+    /// Only collected while `collect_diagnostics` is set.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Runs `f`, keeping the diagnostics it reports if `collect_diagnostics` is set
+    fn collecting<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        if !self.collect_diagnostics {
+            return f(self);
+        }
+        let (result, collected) = diagnostics::collect(|| f(self));
+        self.diagnostics.extend(collected);
+        result
+    }
+
+    /// Makes a Rust function callable from scripts compiled afterwards, as a global named
+    /// `name` taking exactly `arity` arguments. Like the built-in natives, it fails by returning
+    /// `native::report_native_error`. Registered natives are kept across `reset`.
+    ///
+    /// Returns false, without registering anything, if a global by that name is already
+    /// declared.
+    pub fn register_native(
+        &mut self,
+        name: &'static str,
+        arity: usize,
+        func: fn(&[Value]) -> NativeResult,
+    ) -> bool {
+        let registered = self.host_natives.iter().any(|native| native.name == name);
+        if registered || self.vm.global_bindings.global_slots.contains_key(name) {
+            return false;
+        }
+        // Natives are referenced by values for as long as the program runs
+        let native: &'static NativeFn = Box::leak(Box::new(NativeFn {
+            name,
+            min_arity: arity,
+            arity,
+            func,
+        }));
+        self.host_natives.push(native);
+        self.define_natives([native]);
+        true
+    }
+
+    /// Declares natives as globals, skipping any whose name is already taken
+    fn define_natives(&mut self, natives: impl IntoIterator<Item = &'static NativeFn>) {
+        for native in natives {
+            let bindings = &mut self.vm.global_bindings;
+            if let Some(slot) = bindings.declare_binding(native.name) {
+                bindings.natives.insert(native.name.to_owned());
+                self.vm.define_global(slot, Value::Native(native));
+            }
+        }
+    }
+
+    /// Constants of every script compiled so far, shared by all of their functions
+    pub fn constants(&self) -> &ConstantTable {
+        &self.vm.constants
+    }
+
+    /// All declared globals and their current values, in slot order
+    pub fn globals(&self) -> Vec<(&str, &Value)> {
+        const NIL: &Value = &Value::Nil;
+        self.vm
+            .global_bindings
+            .global_slots
+            .iter()
+            .sorted_by_key(|(_, &slot)| slot)
+            .map(|(name, &slot)| {
+                (
+                    name.as_str(),
+                    self.vm.globals.get(slot as usize).unwrap_or(NIL),
+                )
+            })
+            .collect()
+    }
+
+    /// The globals and global `val`s declared by the last compiled script and their current
+    /// values, globals first in slot order. Used by the REPL to echo declarations.
+    pub fn declared_by_last_script(&self) -> Vec<(&str, Value)> {
+        self.last_declared
+            .iter()
+            .map(|name| {
+                let value = match self.vm.global_bindings.global_slots.get(name) {
+                    Some(&slot) => self.vm.globals.get(slot as usize).cloned(),
+                    None => self.vm.global_bindings.constants.get(name).cloned(),
+                };
+                (name.as_str(), value.unwrap_or(Value::Nil))
+            })
+            .collect()
+    }
+
+    /// Write what scripts print to `out` instead of stdout
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.vm.out = Box::new(out);
+    }
+
+    /// Write what scripts `eprint` to `err` instead of stderr
+    pub fn set_error_output(&mut self, err: impl Write + 'static) {
+        self.vm.err = Box::new(err);
+    }
+
+    /// Buffer what the script prints instead of writing each line as it is printed, which is
+    /// much faster for scripts that print a lot. Output is flushed at the end of each run. Error
+    /// messages are not buffered, so they can show up ahead of output printed before them.
+    pub fn buffer_output(&mut self) {
+        self.vm.out = Box::new(BufWriter::new(io::stdout()));
+    }
+
+    /// Forget all declared globals
+    pub fn reset(&mut self) {
+        self.last_declared.clear();
+        self.incremental_base = None;
+        self.declaration_cache.clear();
+        self.vm.reset();
+    }
+}
+
+/// Splits the source into its top-level declarations, each with the line its text starts on. A
+/// declaration's text starts with the whitespace and comments before it, so that the texts cover
+/// the source.
+fn top_level_declarations(source: &str) -> Vec<(usize, &str)> {
+    use TokenType::*;
+    let tokens: Vec<_> = TokenScanner::from_source(source).collect();
+    let mut declarations = vec![];
+    let mut start = 0;
+    let mut line = 1;
+    let mut first_token = None;
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let first = *first_token.get_or_insert(i);
+        match token.ttype {
+            LParen | LBrace | LBracket | StrInterp => depth += 1,
+            RParen | RBrace | RBracket => depth -= 1,
+            _ => {}
+        }
+        if depth > 0 {
+            continue;
+        }
+
+        // Statements with a block body end at its '}', others at their ';'
+        let ends_with_block = matches!(
+            tokens[first].ttype,
+            Fun | Enum | If | While | For | Match | Try | LBrace
+        ) || tokens.get(first + 1).is_some_and(|t| t.ttype == Colon);
+        let ends = match token.ttype {
+            Semi => true,
+            RBrace => ends_with_block,
+            _ => false,
+        };
+        let continues = tokens.get(i + 1).is_some_and(|next| {
+            matches!(next.ttype, Else | Catch) || (next.ttype == Ident && next.lexeme == "elif")
+        });
+        if ends && !continues {
+            let text = &source[start..token.span.end];
+            declarations.push((line, text));
+            line += text.matches('\n').count();
+            start = token.span.end;
+            first_token = None;
+            depth = 0;
+        }
+    }
+    if first_token.is_some() {
+        declarations.push((line, &source[start..]));
     }
+    declarations
 }
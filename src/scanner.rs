@@ -1,9 +1,7 @@
-use std::{iter::Peekable, str::Chars};
+use std::{fmt::Display, iter::Peekable, str::Chars};
 
 use itertools::Itertools;
 
-use crate::compiler::report_error;
-
 /// Scanner scans individual bytes
 #[derive(Debug, Clone)]
 struct Scanner<'a> {
@@ -32,6 +30,40 @@ pub struct TokenScanner<'a> {
 
     // Force the next token to be a Str, or StrInter
     force_str: bool,
+
+    /// Errors encountered so far, in source order
+    pub errors: Vec<ScanError<'a>>,
+
+    // Where the whitespace and comments before the current token start
+    trivia_start: usize,
+
+    /// Emit comments as `Comment` tokens instead of skipping them, for tools that need them.
+    /// The compiler doesn't expect them, so this is off by default.
+    pub keep_comments: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanErrorKind {
+    UnexpectedCharacter,
+    UnterminatedString,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScanError<'a> {
+    pub kind: ScanErrorKind,
+    /// The offending token. Unexpected characters are also emitted as `TokenType::Error`
+    pub token: Token<'a>,
+}
+
+impl Display for ScanError<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ScanErrorKind::UnexpectedCharacter => {
+                write!(f, "Unexpected character '{}'", self.token.lexeme)
+            }
+            ScanErrorKind::UnterminatedString => write!(f, "Unterminated string"),
+        }
+    }
 }
 
 impl<'a> Iterator for Scanner<'a> {
@@ -39,7 +71,8 @@ impl<'a> Iterator for Scanner<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(c) = self.source_iterator.next() {
-            self.current += 1;
+            // start and current are byte offsets, so they can be used to slice the source
+            self.current += c.len_utf8();
             if c == '\n' {
                 self.line += 1;
             }
@@ -68,6 +101,11 @@ impl<'a> Scanner<'a> {
         false
     }
 
+    /// The source that hasn't been scanned yet
+    pub fn rest(&self) -> &'a str {
+        &self.source[self.current..]
+    }
+
     pub fn make_lexeme(&mut self) -> &'a str {
         self.make_lexeme_strip(0)
     }
@@ -79,9 +117,48 @@ impl<'a> Scanner<'a> {
     }
 }
 
+/// Tokens that are always a single character, by ASCII code. These skip the `match` in `next`.
+const SINGLE_CHAR_TOKENS: [Option<TokenType>; 128] = {
+    let mut table = [None; 128];
+    table[b'(' as usize] = Some(TokenType::LParen);
+    table[b')' as usize] = Some(TokenType::RParen);
+    table[b'[' as usize] = Some(TokenType::LBracket);
+    table[b']' as usize] = Some(TokenType::RBracket);
+    table[b';' as usize] = Some(TokenType::Semi);
+    table[b',' as usize] = Some(TokenType::Comma);
+    table[b'|' as usize] = Some(TokenType::Bar);
+    table[b':' as usize] = Some(TokenType::Colon);
+    table
+};
+
+/// Keywords by the ASCII code of their first letter, so most identifiers are ruled out without
+/// comparing against any keyword
+const KEYWORDS: [&[(&str, TokenType)]; 128] = {
+    use TokenType::*;
+    let mut table: [&[(&str, TokenType)]; 128] = [&[]; 128];
+    table[b'a' as usize] = &[("and", And), ("assert", Assert)];
+    table[b'b' as usize] = &[("break", Break)];
+    table[b'c' as usize] = &[("class", Class), ("catch", Catch), ("continue", Continue)];
+    table[b'e' as usize] = &[("else", Else), ("eprint", EPrint), ("enum", Enum)];
+    table[b'f' as usize] = &[("false", False), ("for", For), ("fun", Fun)];
+    table[b'i' as usize] = &[("if", If)];
+    table[b'm' as usize] = &[("match", Match)];
+    table[b'n' as usize] = &[("nil", Nil)];
+    table[b'o' as usize] = &[("or", Or)];
+    table[b'p' as usize] = &[("print", Print)];
+    table[b'r' as usize] = &[("return", Return)];
+    table[b's' as usize] = &[("super", Super)];
+    table[b't' as usize] = &[("this", This), ("true", True), ("try", Try)];
+    table[b'v' as usize] = &[("var", Var), ("val", Val)];
+    table[b'w' as usize] = &[("while", While)];
+    table[b'y' as usize] = &[("yield", Yield)];
+    table
+};
+
 impl<'a> Iterator for TokenScanner<'a> {
     type Item = Token<'a>;
     fn next(&mut self) -> Option<Self::Item> {
+        self.trivia_start = self.chars.start;
         loop {
             if self.force_str {
                 self.force_str = false;
@@ -94,6 +171,10 @@ impl<'a> Iterator for TokenScanner<'a> {
             }
             let c = c.unwrap();
 
+            if let Some(&Some(ttype)) = SINGLE_CHAR_TOKENS.get(c as usize) {
+                return Some(self.make_token(ttype));
+            }
+
             let tok = match c {
                 c if c.is_ascii_digit() => self.take_numeric(),
                 c if is_valid_identifier_first(c) => self.take_identifier_or_keyword(),
@@ -102,38 +183,93 @@ impl<'a> Iterator for TokenScanner<'a> {
                     self.chars.make_lexeme();
                     self.take_string()
                 }
-                '(' => self.make_token(TokenType::LParen),
-                ')' => self.make_token(TokenType::RParen),
+                '`' => {
+                    // Ignore the `
+                    self.chars.make_lexeme();
+                    self.take_raw_string()
+                }
                 '{' => {
                     self.state.push(ScannerState::General);
                     self.make_token(TokenType::LBrace)
                 }
                 '}' => {
-                    // TODO: handle this properly!
-                    // For example if the program looks like
-                    //   print "hi"; }
-                    let top = self.state.pop().expect("");
-                    self.force_str = top == ScannerState::StrInterp;
+                    // A stray `}`, as in `print "hi"; }`, has nothing to pop and is left for the
+                    // compiler to report
+                    let top = self.state.pop();
+                    self.force_str = top == Some(ScannerState::StrInterp);
                     self.make_token(TokenType::RBrace)
                 }
-                ';' => self.make_token(TokenType::Semi),
-                ',' => self.make_token(TokenType::Comma),
-                '.' => self.make_token(TokenType::Dot),
-                '-' => self.make_token(TokenType::Minus),
-                '+' => self.make_token(TokenType::Plus),
-                '|' => self.make_token(TokenType::Bar),
-                '*' => self.make_token(TokenType::Star),
+                // A number without its leading zero, e.g. `.5`
+                '.' if self.chars.rest().starts_with(|c: char| c.is_ascii_digit()) => {
+                    self.chars.take_while_ref(|&c| c.is_ascii_digit()).count();
+                    self.make_token(TokenType::Number)
+                }
+                '.' => {
+                    let t = if !self.chars.next_if_match('.') {
+                        TokenType::Dot
+                    } else if self.chars.next_if_match('=') {
+                        TokenType::DotDotEqual
+                    } else if self.chars.next_if_match('.') {
+                        TokenType::DotDotDot
+                    } else {
+                        TokenType::DotDot
+                    };
+                    self.make_token(t)
+                }
+                '-' => {
+                    let t = if self.chars.next_if_match('=') {
+                        TokenType::MinusEqual
+                    } else {
+                        TokenType::Minus
+                    };
+                    self.make_token(t)
+                }
+                '+' => {
+                    let t = if self.chars.next_if_match('=') {
+                        TokenType::PlusEqual
+                    } else {
+                        TokenType::Plus
+                    };
+                    self.make_token(t)
+                }
+                '*' => {
+                    let t = if self.chars.next_if_match('=') {
+                        TokenType::StarEqual
+                    } else {
+                        TokenType::Star
+                    };
+                    self.make_token(t)
+                }
                 '/' => {
                     if self.chars.next_if_match('/') {
+                        if self.keep_comments {
+                            self.chars.take_while_ref(|&c| c != '\n').count();
+                            return Some(self.make_token(TokenType::Comment));
+                        }
                         self.take_until_newline();
                         continue;
+                    } else if self.chars.next_if_match('=') {
+                        self.make_token(TokenType::SlashEqual)
                     } else {
                         self.make_token(TokenType::Slash)
                     }
                 }
+                // A `#!` line at the very start, so that scripts can be made executable
+                '#' if self.chars.current == 1 && self.chars.next_if_match('!') => {
+                    if self.keep_comments {
+                        self.chars.take_while_ref(|&c| c != '\n').count();
+                        return Some(self.make_token(TokenType::Comment));
+                    }
+                    self.take_until_newline();
+                    continue;
+                }
                 '!' => {
                     let t = if self.chars.next_if_match('=') {
-                        TokenType::BangEqual
+                        if self.chars.next_if_match('=') {
+                            TokenType::BangEqualEqual
+                        } else {
+                            TokenType::BangEqual
+                        }
                     } else {
                         TokenType::Bang
                     };
@@ -142,7 +278,11 @@ impl<'a> Iterator for TokenScanner<'a> {
 
                 '=' => {
                     let t = if self.chars.next_if_match('=') {
-                        TokenType::EqualEqual
+                        if self.chars.next_if_match('=') {
+                            TokenType::EqualEqualEqual
+                        } else {
+                            TokenType::EqualEqual
+                        }
                     } else if self.chars.next_if_match('>') {
                         TokenType::FatArrow
                     } else {
@@ -170,14 +310,26 @@ impl<'a> Iterator for TokenScanner<'a> {
                 }
                 '?' => {
                     let t = if self.chars.next_if_match(':') {
-                        TokenType::QuestionColon
+                        if self.chars.next_if_match('=') {
+                            TokenType::QuestionColonEqual
+                        } else {
+                            TokenType::QuestionColon
+                        }
                     } else {
                         TokenType::Question
                     };
                     self.make_token(t)
                 }
-                ':' => self.make_token(TokenType::Colon),
-                _ => self.make_token(TokenType::Error),
+                // `//` starts a comment, so floor division is spelled `~/`
+                '~' if self.chars.next_if_match('/') => self.make_token(TokenType::TildeSlash),
+                _ => {
+                    let t = self.make_token(TokenType::Error);
+                    self.errors.push(ScanError {
+                        kind: ScanErrorKind::UnexpectedCharacter,
+                        token: t,
+                    });
+                    t
+                }
             };
             return Some(tok);
         }
@@ -187,22 +339,37 @@ impl<'a> Iterator for TokenScanner<'a> {
 }
 
 impl<'a> TokenScanner<'a> {
-    pub fn from_source(source: &str) -> TokenScanner {
+    pub fn from_source(source: &str) -> TokenScanner<'_> {
+        Self::from_source_at_line(source, 1)
+    }
+
+    /// Scans source that starts on the given line of a larger file
+    pub fn from_source_at_line(source: &str, line: usize) -> TokenScanner<'_> {
         let scanner = Scanner {
             source,
             source_iterator: source.chars().peekable(),
             start: 0,
             current: 0,
-            line: 1,
+            line,
         };
 
         TokenScanner {
             chars: scanner,
             force_str: false,
             state: vec![],
+            errors: vec![],
+            trivia_start: 0,
+            keep_comments: false,
         }
     }
 
+    /// Scans the whole source, collecting errors instead of reporting them
+    pub fn scan_all(source: &str) -> (Vec<Token<'_>>, Vec<ScanError<'_>>) {
+        let mut scanner = TokenScanner::from_source(source);
+        let tokens = scanner.by_ref().collect();
+        (tokens, scanner.errors)
+    }
+
     fn take_until_newline(&mut self) {
         self.chars.take_while_ref(|&c| c != '\n').count();
         self.chars.next();
@@ -228,7 +395,27 @@ impl<'a> TokenScanner<'a> {
 
         // unclosed string!
         let t = self.make_token(TokenType::Str);
-        report_error(&t, "Unterminated string!");
+        self.errors.push(ScanError {
+            kind: ScanErrorKind::UnterminatedString,
+            token: t,
+        });
+        t
+    }
+
+    /// Continue taking a raw string until the closing `, which can span lines and contain " or
+    /// ${ without them being treated specially
+    fn take_raw_string(&mut self) -> Token<'a> {
+        self.chars.take_while_ref(|&c| c != '`').count();
+        if self.chars.next_if_match('`') {
+            return self.make_token_strip(TokenType::Str, 1);
+        }
+
+        // unclosed string!
+        let t = self.make_token(TokenType::Str);
+        self.errors.push(ScanError {
+            kind: ScanErrorKind::UnterminatedString,
+            token: t,
+        });
         t
     }
 
@@ -236,8 +423,8 @@ impl<'a> TokenScanner<'a> {
     fn take_numeric(&mut self) -> Token<'a> {
         self.chars.take_while_ref(|&c| c.is_ascii_digit()).count();
 
-        // allow fractional
-        if self.chars.next_if_match('.') {
+        // allow fractional, including a bare trailing dot like `5.`, but not in a range like `0..5`
+        if !self.chars.rest().starts_with("..") && self.chars.next_if_match('.') {
             self.chars.take_while_ref(|&c| c.is_ascii_digit()).count();
         }
         self.make_token(TokenType::Number)
@@ -253,33 +440,12 @@ impl<'a> TokenScanner<'a> {
     }
 
     fn make_identifier_or_keyword(&mut self) -> Token<'a> {
-        let lexeme = self.chars.make_lexeme();
-        let ttype = match lexeme {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "if" => TokenType::If,
-            "nil" => TokenType::Nil,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "val" => TokenType::Val,
-            "while" => TokenType::While,
-            "match" => TokenType::Match,
-            _ => TokenType::Ident,
-        };
-        Token {
-            lexeme,
-            ttype,
-            line: self.chars.line,
-        }
+        let lexeme = &self.chars.source[self.chars.start..self.chars.current];
+        let ttype = KEYWORDS
+            .get(lexeme.as_bytes()[0] as usize)
+            .and_then(|keywords| keywords.iter().find(|&&(keyword, _)| keyword == lexeme))
+            .map_or(TokenType::Ident, |&(_, ttype)| ttype);
+        self.make_token(ttype)
     }
 
     fn make_token(&mut self, ttype: TokenType) -> Token<'a> {
@@ -287,20 +453,47 @@ impl<'a> TokenScanner<'a> {
     }
 
     fn make_token_strip(&mut self, ttype: TokenType, end_strip: usize) -> Token<'a> {
+        let start = self.chars.start;
         let lexeme = self.chars.make_lexeme_strip(end_strip);
         Token {
             lexeme,
             ttype,
             line: self.chars.line,
+            span: Span {
+                trivia_start: self.trivia_start,
+                start,
+                end: start + lexeme.len(),
+            },
         }
     }
 }
 
+/// Byte offsets of a token's lexeme in the source. For strings, this excludes the quotes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Start of the whitespace and comments preceding the token
+    pub trivia_start: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn text<'s>(&self, source: &'s str) -> &'s str {
+        &source[self.start..self.end]
+    }
+
+    /// The token's text along with the whitespace and comments before it
+    pub fn text_with_trivia<'s>(&self, source: &'s str) -> &'s str {
+        &source[self.trivia_start..self.end]
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Token<'a> {
     pub lexeme: &'a str,
     pub ttype: TokenType,
     pub line: usize,
+    pub span: Span,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -310,12 +503,15 @@ pub enum TokenType {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Comma,
     Dot,
     Minus,
     Plus,
     Semi,
     Slash,
+    TildeSlash,
     Star,
     Bar,
 
@@ -324,13 +520,24 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    // Three char
+    EqualEqualEqual,
+    BangEqualEqual,
     FatArrow,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    DotDot,
+    DotDotEqual,
+    DotDotDot,
 
     Question,
     Colon,
     // TODO: add support for ?, :, and ?: (true ? 1 : 0) and (nil ?: 0)
     //       where the statements after ?, :, and ?: are lazily evaluated.
     QuestionColon,
+    QuestionColonEqual,
 
     Greater,
     GreaterEqual,
@@ -354,6 +561,7 @@ pub enum TokenType {
     Nil,
     Or,
     Print,
+    EPrint,
     Return,
     Super,
     This,
@@ -362,9 +570,19 @@ pub enum TokenType {
     Val,
     While,
     Match,
+    Yield,
+    Assert,
+    Enum,
+    Try,
+    Catch,
+    Break,
+    Continue,
 
     // Misc
     Error,
+    /// A `//` comment or leading `#!` line, up to but not including the newline. Only emitted with
+    /// `TokenScanner::keep_comments`.
+    Comment,
 }
 
 fn is_valid_identifier_first(c: char) -> bool {
@@ -1,37 +1,146 @@
+use std::rc::Rc;
+
 use crate::{
     chunk::ByteCode,
-    compiler::{report_error, Compiler, CompilerResult},
+    compiler::{report_error, ChunkType, Compiler, CompilerResult, Loop, Scope},
     scanner::{Token, TokenType},
     util::PrevPeekable,
+    value::{FuncObj, Value},
     vm::InterpretError,
 };
 
 impl<'a> Compiler<'a> {
     //declaration    → varDecl
+    //               | funDecl
     //               | statement ;
     //statement      → exprStmt
-    //               | printStmt ;
+    //               | printStmt
+    //               | returnStmt ;
+
+    pub fn compile_top_level_decl(&mut self) -> CompilerResult<()> {
+        use TokenType::*;
+        let starts_statement = matches!(
+            self.scanner.peek().map(|t| t.ttype),
+            Some(
+                Var | Val
+                    | Fun
+                    | Enum
+                    | Print
+                    | EPrint
+                    | If
+                    | While
+                    | For
+                    | Match
+                    | Return
+                    | Yield
+                    | Assert
+                    | Try
+                    | Break
+                    | Continue
+                    | LBrace
+            )
+        );
+        if !self.allow_expression_result || starts_statement || self.at_loop_label() {
+            return self.compile_decl();
+        }
+
+        let start = self.scope.curr_chunk().size();
+        self.compile_expression()?;
+        if self.scanner.peek().is_none() {
+            // The value of the last expression is the result of the script
+            self.has_expression_result = true;
+            return Ok(());
+        }
+
+        self.scanner
+            .consume_token(Semi, "Expected ';' after expression")?;
+        self.discard_expression(start);
+        Ok(())
+    }
 
     pub fn compile_decl(&mut self) -> CompilerResult<()> {
-        if let Some(_) = self.scanner.advance_if_match(TokenType::Var) {
+        if self.scanner.advance_if_match(TokenType::Var).is_some() {
             self.compile_var_decl(true)
-        } else if let Some(_) = self.scanner.advance_if_match(TokenType::Val) {
+        } else if self.scanner.advance_if_match(TokenType::Val).is_some() {
             self.compile_var_decl(false)
+        } else if self.scanner.advance_if_match(TokenType::Fun).is_some() {
+            self.compile_fun_decl()
+        } else if self.scanner.advance_if_match(TokenType::Enum).is_some() {
+            self.compile_enum_decl()
         } else {
             self.compile_statement()
         }
     }
 
+    /// `enum Name { A, B }` only declares the members for the compiler, emitting no code
+    fn compile_enum_decl(&mut self) -> CompilerResult<()> {
+        let tok = self
+            .scanner
+            .consume_token(TokenType::Ident, "Expected identifier after 'enum'")?;
+        let name = tok.lexeme;
+        if self.scope.depth > 0 {
+            report_error(&tok, "Enums must be declared at the top level");
+            return Err(InterpretError::Compiler);
+        }
+        if self.global_bindings.enums.contains_key(name) {
+            report_error(&tok, &format!("Enum '{name}' already declared"));
+            return Err(InterpretError::Compiler);
+        }
+
+        self.scanner
+            .consume_token(TokenType::LBrace, "Expected '{' after enum name")?;
+        let mut members: Vec<String> = vec![];
+        while self.scanner.advance_if_match(TokenType::RBrace).is_none() {
+            let member = self
+                .scanner
+                .consume_token(TokenType::Ident, "Expected enum member name")?;
+            if members.iter().any(|m| m == member.lexeme) {
+                report_error(
+                    &member,
+                    &format!("Duplicate member '{}' in enum '{name}'", member.lexeme),
+                );
+                return Err(InterpretError::Compiler);
+            }
+            members.push(member.lexeme.to_owned());
+
+            if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                self.scanner
+                    .consume_token(TokenType::RBrace, "Expected '}' after enum members")?;
+                break;
+            }
+        }
+
+        self.global_bindings.enums.insert(name.to_owned(), members);
+        Ok(())
+    }
+
     fn compile_var_decl(&mut self, mutable: bool) -> CompilerResult<()> {
         let tok = self
             .scanner
             .consume_token(TokenType::Ident, "Expected identifier after 'var'")?;
 
         let name = tok.lexeme;
+        if self
+            .scanner
+            .peek()
+            .is_some_and(|t| t.ttype == TokenType::Comma)
+        {
+            return self.compile_unpacking_var_decl(tok, mutable);
+        }
+        if !mutable && self.scope.depth == 0 {
+            return self.compile_global_val(&tok);
+        }
 
         // Compile expression if needed
-        if let Some(_) = self.scanner.advance_if_match(TokenType::Equal) {
+        if self.scanner.advance_if_match(TokenType::Equal).is_some() {
+            if !mutable && name != "_" {
+                if let Some(value) = self.take_literal_initializer() {
+                    return self.compile_local_val(tok, value);
+                }
+            }
             self.compile_expression()?;
+        } else if self.strict {
+            self.scope.curr_chunk().push(ByteCode::Uninit, tok.line);
         } else {
             self.scope.curr_chunk().push(ByteCode::Nil, tok.line);
         }
@@ -40,9 +149,79 @@ impl<'a> Compiler<'a> {
         // the following can work:
         //   var a = "hello";
         //   { var a = a + ", world!"; }
-        let slot = if self.scope.depth > 0 {
+        let slot = self.declare_variable(tok, mutable)?;
+
+        self.scanner.consume_semi("variable declaration")?;
+
+        if self.scope.depth == 0 {
+            self.scope
+                .curr_chunk()
+                .push(ByteCode::SetGlobal(slot), tok.line);
+            self.scope.curr_chunk().push(ByteCode::Pop, tok.line);
+        }
+
+        Ok(())
+    }
+
+    /// Declares the variables of `var x, y = list;` from the elements of the list, which must
+    /// have one element per variable
+    fn compile_unpacking_var_decl(
+        &mut self,
+        first: Token<'a>,
+        mutable: bool,
+    ) -> CompilerResult<()> {
+        let mut names = vec![first];
+        while self.scanner.advance_if_match(TokenType::Comma).is_some() {
+            let tok = self
+                .scanner
+                .consume_token(TokenType::Ident, "Expected identifier after ','")?;
+            names.push(tok);
+        }
+        if !mutable && self.scope.depth == 0 {
+            report_error(
+                &first,
+                "Global vals can't be unpacked, as they must be initialized with a literal",
+            );
+            return Err(InterpretError::Compiler);
+        }
+
+        self.scanner
+            .consume_token(TokenType::Equal, "Expected '=' after variables to unpack")?;
+        self.compile_expression()?;
+        let len = names.len() as u32;
+        self.scope
+            .curr_chunk()
+            .push(ByteCode::Unpack(len), first.line);
+
+        // The elements are pushed in order, so locals take their slots as they are declared
+        let slots = names
+            .into_iter()
+            .map(|tok| self.declare_variable(tok, mutable))
+            .collect::<CompilerResult<Vec<_>>>()?;
+
+        self.scanner.consume_semi("variable declaration")?;
+
+        if self.scope.depth == 0 {
+            for &slot in slots.iter().rev() {
+                self.scope
+                    .curr_chunk()
+                    .push(ByteCode::SetGlobal(slot), first.line);
+                self.scope.curr_chunk().push(ByteCode::Pop, first.line);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a local, or a global binding at the top level, whose value is on top of the stack.
+    /// Returns the slot of a global.
+    fn declare_variable(&mut self, tok: Token<'a>, mutable: bool) -> CompilerResult<u32> {
+        let name = tok.lexeme;
+        if self.scope.depth > 0 {
             // local
-            if !self.scope.add_local(tok.clone(), mutable) {
+            self.check_shadowing(&tok);
+            self.check_locals_limit(&tok)?;
+            if !self.scope.add_local(tok, mutable) {
                 report_error(
                     &tok,
                     &format!("Cannot redeclare variable '{name}' in the same scope"),
@@ -50,15 +229,107 @@ impl<'a> Compiler<'a> {
                 return Err(InterpretError::Compiler);
             }
             // We dont actually care here
-            0u32
+            Ok(0)
         } else {
             // global
-            if !mutable {
-                report_error(&tok, "Immutable global variables are not allowed");
+            match self.global_bindings.declare_binding(name) {
+                Some(slot) => Ok(slot),
+                None => {
+                    report_error(&tok, &format!("Variable '{name}' already declared"));
+                    Err(InterpretError::Compiler)
+                }
+            }
+        }
+    }
+
+    /// Global `val`s must be initialized with a literal, which is then inlined wherever they are
+    /// used, so they take no slot
+    fn compile_global_val(&mut self, tok: &Token<'a>) -> CompilerResult<()> {
+        let name = tok.lexeme;
+        if self.global_bindings.global_slots.contains_key(name)
+            || self.global_bindings.constants.contains_key(name)
+        {
+            let msg = if self.global_bindings.undeclared_globals.contains(name) {
+                format!("Global val '{name}' must be declared before it is used")
+            } else {
+                format!("Variable '{name}' already declared")
+            };
+            report_error(tok, &msg);
+            return Err(InterpretError::Compiler);
+        }
+
+        let msg = "Global vals must be initialized with a literal";
+        self.scanner.consume_token(TokenType::Equal, msg)?;
+        let Some(value) = self.take_literal_initializer() else {
+            report_error(tok, msg);
+            return Err(InterpretError::Compiler);
+        };
+        self.scanner.consume_semi("variable declaration")?;
+
+        self.global_bindings
+            .constants
+            .insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    /// Local `val`s initialized with a literal are inlined like global ones, so they take no
+    /// slot
+    fn compile_local_val(&mut self, tok: Token<'a>, value: Value) -> CompilerResult<()> {
+        self.check_shadowing(&tok);
+        if !self.scope.add_constant(tok, value) {
+            report_error(
+                &tok,
+                &format!(
+                    "Cannot redeclare variable '{}' in the same scope",
+                    tok.lexeme
+                ),
+            );
+            return Err(InterpretError::Compiler);
+        }
+        self.scanner.consume_semi("variable declaration")?;
+        Ok(())
+    }
+
+    /// Consumes the initializer if it's a lone literal, like the `2` in `val x = 2;`
+    fn take_literal_initializer(&mut self) -> Option<Value> {
+        if !matches!(self.scanner.peek_second(), Some(t) if t.ttype == TokenType::Semi) {
+            return None;
+        }
+        let t = *self.scanner.peek()?;
+        let value = match t.ttype {
+            TokenType::Number => Value::Number(self.parse_number(&t)),
+            TokenType::Str => t.lexeme.to_owned().into(),
+            TokenType::True => Value::Bool(true),
+            TokenType::False => Value::Bool(false),
+            TokenType::Nil => Value::Nil,
+            _ => return None,
+        };
+        self.scanner.next();
+        Some(value)
+    }
+
+    fn compile_fun_decl(&mut self) -> CompilerResult<()> {
+        let tok = self
+            .scanner
+            .consume_token(TokenType::Ident, "Expected identifier after 'fun'")?;
+        let name = tok.lexeme;
+
+        // Unlike variables, bind the name before compiling the body so that the function can
+        // call itself recursively
+        let slot = if self.scope.depth > 0 {
+            self.check_shadowing(&tok);
+            self.check_locals_limit(&tok)?;
+            if !self.scope.add_local(tok, false) {
+                report_error(
+                    &tok,
+                    &format!("Cannot redeclare variable '{name}' in the same scope"),
+                );
                 return Err(InterpretError::Compiler);
             }
+            None
+        } else {
             match self.global_bindings.declare_binding(name) {
-                Some(slot) => slot,
+                Some(slot) => Some(slot),
                 None => {
                     report_error(&tok, &format!("Variable '{name}' already declared"));
                     return Err(InterpretError::Compiler);
@@ -66,10 +337,15 @@ impl<'a> Compiler<'a> {
             }
         };
 
-        self.scanner
-            .consume_token(TokenType::Semi, "Expected ';' after variable declaration")?;
+        let func = self.compile_function(&tok)?;
+        if slot.is_some() {
+            self.global_bindings
+                .function_params
+                .insert(name.to_owned(), func.params.clone());
+        }
+        self.emit_constant(&tok, Value::Func(Rc::new(func)))?;
 
-        if self.scope.depth == 0 {
+        if let Some(slot) = slot {
             self.scope
                 .curr_chunk()
                 .push(ByteCode::SetGlobal(slot), tok.line);
@@ -79,17 +355,123 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    /// Compiles the parameters and body of a function in a fresh scope
+    fn compile_function(&mut self, name: &Token<'a>) -> CompilerResult<FuncObj> {
+        let mut scope = Scope::function(name.lexeme, name.line);
+        if self.scope.depth > 0 {
+            scope.self_name = Some(name.lexeme);
+        }
+        let enclosing = std::mem::replace(&mut self.scope, scope);
+        let res = self.compile_function_body();
+        let scope = std::mem::replace(&mut self.scope, enclosing);
+        res?;
+
+        let mut func = scope.func;
+        // A statement that failed to compile may have left jumps to labels it never placed. The
+        // program can't be run then, so there is no need to resolve them.
+        if !self.had_error {
+            func.chunk.resolve_monkey_patches();
+        }
+        Ok(func)
+    }
+
+    fn compile_function_body(&mut self) -> CompilerResult<()> {
+        self.scanner
+            .consume_token(TokenType::LParen, "Expected '(' after function name")?;
+
+        if self.scanner.advance_if_match(TokenType::RParen).is_none() {
+            loop {
+                let param = self
+                    .scanner
+                    .consume_token(TokenType::Ident, "Expected parameter name")?;
+
+                // Default values are evaluated in the prologue, leaving them in the parameter's
+                // slot. Calls omitting trailing arguments start at the first missing default.
+                if self.scanner.advance_if_match(TokenType::Equal).is_some() {
+                    let entry = self.scope.curr_chunk().bytecode.len();
+                    self.scope.func.entry_points.push(entry);
+                    self.compile_expression()?;
+                } else if !self.scope.func.entry_points.is_empty() {
+                    report_error(
+                        &param,
+                        &format!(
+                            "Parameter '{}' must have a default since an earlier one does",
+                            param.lexeme
+                        ),
+                    );
+                    return Err(InterpretError::Compiler);
+                }
+
+                self.check_shadowing(&param);
+
+                self.check_locals_limit(&param)?;
+                if !self.scope.add_local(param, true) {
+                    report_error(&param, &format!("Duplicate parameter '{}'", param.lexeme));
+                    return Err(InterpretError::Compiler);
+                }
+                self.scope.func.arity += 1;
+                self.scope.func.params.push(param.lexeme.into());
+
+                if self.scanner.advance_if_match(TokenType::Comma).is_none() {
+                    break;
+                }
+            }
+            self.scanner
+                .consume_token(TokenType::RParen, "Expected ')' after parameters")?;
+        }
+
+        if !self.scope.func.entry_points.is_empty() {
+            // Calls with all arguments skip the whole prologue
+            let body = self.scope.curr_chunk().bytecode.len();
+            self.scope.func.entry_points.push(body);
+        }
+
+        if self.scope.func.arity > u8::MAX as usize {
+            report_error(
+                &self.scanner.prev_unwrap(),
+                "Cannot have more than 255 parameters",
+            );
+            return Err(InterpretError::Compiler);
+        }
+
+        self.scanner
+            .consume_token(TokenType::LBrace, "Expected '{' before function body")?;
+        self.compile_block()?;
+
+        // Functions that fall off the end return nil
+        let line = self.scanner.prev_unwrap().line;
+        self.scope.curr_chunk().push(ByteCode::Nil, line);
+        self.scope.curr_chunk().push(ByteCode::Return, line);
+        Ok(())
+    }
+
     fn compile_statement(&mut self) -> CompilerResult<()> {
         if self.scanner.advance_if_match(TokenType::Print).is_some() {
-            self.compile_print_statement()?;
+            self.compile_print_statement(ByteCode::Print)?;
+        } else if self.scanner.advance_if_match(TokenType::EPrint).is_some() {
+            self.compile_print_statement(ByteCode::PrintErr)?;
         } else if self.scanner.advance_if_match(TokenType::If).is_some() {
             self.compile_if_statement()?;
         } else if self.scanner.advance_if_match(TokenType::While).is_some() {
-            self.compile_while_statement()?;
+            self.compile_while_statement(None)?;
         } else if self.scanner.advance_if_match(TokenType::For).is_some() {
-            self.compile_for_statement()?;
+            self.compile_for_statement(None)?;
+        } else if self.at_loop_label() {
+            self.compile_labeled_loop()?;
+        } else if self.scanner.advance_if_match(TokenType::Break).is_some()
+            || self.scanner.advance_if_match(TokenType::Continue).is_some()
+        {
+            self.compile_loop_jump()?;
         } else if self.scanner.advance_if_match(TokenType::Match).is_some() {
             self.compile_match_statement()?;
+        } else if self.scanner.advance_if_match(TokenType::Return).is_some() {
+            self.compile_return_statement()?;
+        } else if self.scanner.advance_if_match(TokenType::Yield).is_some() {
+            self.compile_yield_statement()?;
+        } else if self.scanner.advance_if_match(TokenType::Assert).is_some() {
+            self.compile_assert_statement()?;
+        } else if self.scanner.advance_if_match(TokenType::Try).is_some() {
+            self.compile_try_statement()?;
         } else if let Some(t) = self.scanner.advance_if_match(TokenType::LBrace) {
             self.scope.increment_depth();
             self.compile_block()?;
@@ -100,19 +482,24 @@ impl<'a> Compiler<'a> {
         } else {
             // Must be an expression statement
             self.compile_expression_statement()?;
-            let line = self.scanner.prev_unwrap().line;
-            self.scope.curr_chunk().push(ByteCode::Pop, line);
         }
 
         Ok(())
     }
 
     fn compile_block(&mut self) -> CompilerResult<()> {
+        let depth = self.scope.depth;
         while let Some(t) = self.scanner.peek() {
             if t.ttype == TokenType::RBrace {
                 break;
             }
-            self.compile_decl()?;
+            if self.compile_decl().is_err() {
+                // Forget any scopes the failed statement didn't get to close
+                self.scope.depth = depth;
+                self.scope.locals.retain(|(d, _, _)| *d <= depth);
+                self.scope.constants.retain(|(d, _, _)| *d <= depth);
+                self.synchronize();
+            }
         }
 
         self.scanner
@@ -121,21 +508,273 @@ impl<'a> Compiler<'a> {
     }
 
     fn compile_expression_statement(&mut self) -> CompilerResult<()> {
+        let start = self.scope.curr_chunk().size();
+        self.compile_expression()?;
+        self.scanner.consume_semi("expression")?;
+        self.discard_expression(start);
+        Ok(())
+    }
+
+    /// Pops the value of the expression compiled from `start`, or drops its code altogether if
+    /// running it would have no effect
+    fn discard_expression(&mut self, start: usize) {
+        let chunk = self.scope.curr_chunk();
+        if chunk.is_pure_from(start, self.constants) {
+            chunk.truncate(start);
+        } else {
+            let line = self.scanner.prev_unwrap().line;
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
+    }
+
+    /// `print` and `eprint`, which differ only in the opcode
+    fn compile_print_statement(&mut self, opcode: ByteCode) -> CompilerResult<()> {
+        let line = self.scanner.prev_unwrap().line;
+        self.compile_expression()?;
+        self.scanner.consume_semi("value")?;
+        self.scope.curr_chunk().push(opcode, line);
+        Ok(())
+    }
+
+    fn compile_return_statement(&mut self) -> CompilerResult<()> {
+        let tok = self.scanner.prev_unwrap();
+        if let ChunkType::Script = self.scope.chunk_type {
+            report_error(&tok, "Cannot return from top-level code");
+            return Err(InterpretError::Compiler);
+        }
+
+        let has_value = self
+            .scanner
+            .peek()
+            .is_some_and(|t| t.ttype != TokenType::Semi);
+        self.check_mixed_returns(&tok, has_value);
+
+        if self.scanner.advance_if_match(TokenType::Semi).is_some() {
+            self.scope.curr_chunk().push(ByteCode::Nil, tok.line);
+        } else {
+            self.compile_expression()?;
+            // Several values are returned as a list, to be unpacked by the caller
+            let mut len = 1;
+            while self.scanner.advance_if_match(TokenType::Comma).is_some() {
+                self.compile_expression()?;
+                len += 1;
+            }
+            self.scanner.consume_semi("return value")?;
+            if len > 1 {
+                self.scope
+                    .curr_chunk()
+                    .push(ByteCode::BuildList(len), tok.line);
+            } else if self.scope.try_depth == 0 && self.scope.curr_chunk().is_tail_position() {
+                // A tail call would leave the try block before the callee runs
+                self.scope.curr_chunk().make_tail_call();
+            }
+        }
+        self.scope.curr_chunk().push(ByteCode::Return, tok.line);
+        Ok(())
+    }
+
+    /// Warns once per function about the first return that has a value when an earlier one
+    /// didn't, or the other way around
+    fn check_mixed_returns(&mut self, tok: &Token<'a>, has_value: bool) {
+        if !self.warn_mixed_returns {
+            return;
+        }
+        let (this, other) = if has_value {
+            (
+                &mut self.scope.value_return_line,
+                self.scope.bare_return_line,
+            )
+        } else {
+            (
+                &mut self.scope.bare_return_line,
+                self.scope.value_return_line,
+            )
+        };
+        if this.is_some() {
+            return;
+        }
+        *this = Some(tok.line);
+
+        if let Some(other_line) = other {
+            let name = self.scope.func.name.as_deref().unwrap_or("?").to_owned();
+            let (here, there) = match has_value {
+                true => ("returns a value", "returns nothing"),
+                false => ("returns nothing", "returns a value"),
+            };
+            self.warn(
+                tok,
+                &format!("Function '{name}' {here} here but {there} on line {other_line}"),
+            );
+        }
+    }
+
+    fn compile_yield_statement(&mut self) -> CompilerResult<()> {
+        let tok = self.scanner.prev_unwrap();
+        if let ChunkType::Script = self.scope.chunk_type {
+            report_error(&tok, "Cannot yield from top-level code");
+            return Err(InterpretError::Compiler);
+        }
+
+        if self.scope.try_depth > 0 {
+            // The try block's handler can't be suspended along with the generator
+            report_error(&tok, "Cannot yield inside a try block");
+            return Err(InterpretError::Compiler);
+        }
+
+        self.scope.func.is_generator = true;
         self.compile_expression()?;
+        self.scanner.consume_semi("yield value")?;
+        self.scope.curr_chunk().push(ByteCode::Yield, tok.line);
+        Ok(())
+    }
+
+    /// `try { ... } catch (e) { ... }` runs the catch block with the error's message bound to `e`
+    /// if a runtime error is raised while running the try block
+    fn compile_try_statement(&mut self) -> CompilerResult<()> {
+        //   push_handler .catch
+        //   try_block
+        //   pop_handler
+        //   jump .end
+        // catch:
+        //   (message pushed by the VM)
+        //   catch_block
+        //   pop
+        // end:
+        let line = self.scanner.prev_unwrap().line;
+        let catch_label = self.scope.curr_chunk().allocate_new_label();
+        let end_label = self.scope.curr_chunk().allocate_new_label();
+
+        self.scanner
+            .consume_token(TokenType::LBrace, "Expected '{' after try")?;
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::PushHandler(0), line, catch_label);
+        self.scope.try_depth += 1;
+        self.scope.increment_depth();
+        let result = self.compile_block();
+        let num_locals = self.scope.decrement_depth();
+        self.scope.try_depth -= 1;
+        result?;
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
+        self.scope.curr_chunk().push(ByteCode::PopHandler, line);
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpRelative(0), line, end_label);
+
+        self.scope.curr_chunk().push_label(catch_label);
+        self.scanner
+            .consume_token(TokenType::Catch, "Expected 'catch' after try block")?;
+        self.scanner
+            .consume_token(TokenType::LParen, "Expected '(' after catch")?;
+        let name = self
+            .scanner
+            .consume_token(TokenType::Ident, "Expected identifier to bind the error to")?;
+        self.scanner
+            .consume_token(TokenType::RParen, "Expected ')' after catch binding")?;
         self.scanner
-            .consume_token(TokenType::Semi, "Expected ';' after expression")?;
+            .consume_token(TokenType::LBrace, "Expected '{' after catch")?;
+
+        // The message is left on the stack as the binding's slot
+        self.scope.increment_depth();
+        self.check_shadowing(&name);
+        self.check_locals_limit(&name)?;
+        self.scope.add_local(name, true);
+        self.compile_block()?;
+        let num_locals = self.scope.decrement_depth();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
+
+        self.scope.curr_chunk().push_label(end_label);
         Ok(())
     }
 
-    fn compile_print_statement(&mut self) -> CompilerResult<()> {
+    fn compile_assert_statement(&mut self) -> CompilerResult<()> {
+        //   condition
+        //   jz fail
+        //   pop
+        //   jump end
+        // fail:
+        //   pop
+        //   message (or nil)
+        //   assert_failed
+        // end:
         let line = self.scanner.prev_unwrap().line;
+        let start = self.scope.curr_chunk().size();
+        let fail = self.scope.curr_chunk().allocate_new_label();
+        let end = self.scope.curr_chunk().allocate_new_label();
+
+        self.scanner
+            .consume_token(TokenType::LParen, "Expected '(' after assert")?;
         self.compile_expression()?;
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpF(0), line, fail);
+        self.scope.curr_chunk().push(ByteCode::Pop, line);
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpRelative(0), line, end);
+
+        // The message is only evaluated if the assertion fails
+        self.scope.curr_chunk().push_label(fail);
+        self.scope.curr_chunk().push(ByteCode::Pop, line);
+        if self.scanner.advance_if_match(TokenType::Comma).is_some() {
+            self.compile_expression()?;
+        } else {
+            self.scope.curr_chunk().push(ByteCode::Nil, line);
+        }
+        self.scope.curr_chunk().push(ByteCode::AssertFailed, line);
+        self.scope.curr_chunk().push_label(end);
+
         self.scanner
-            .consume_token(TokenType::Semi, "Expected ';' after value")?;
-        self.scope.curr_chunk().push(ByteCode::Print, line);
+            .consume_token(TokenType::RParen, "Expected ')' after assert arguments")?;
+        self.scanner.consume_semi("assert")?;
+
+        // Still compiled first, so that stripped asserts are checked for errors
+        if self.strip_assertions {
+            self.scope.curr_chunk().truncate(start);
+        }
         Ok(())
     }
 
+    /// Warns if the condition about to be compiled is a bare assignment, which is likely a typo
+    /// of `==`. Wrapping the assignment in parentheses silences this.
+    fn check_assignment_condition(&mut self) {
+        let is_ident = matches!(self.scanner.peek(), Some(t) if t.ttype == TokenType::Ident);
+        if let Some(&eq) = self.scanner.peek_second() {
+            if is_ident && eq.ttype == TokenType::Equal {
+                self.warn(
+                    &eq,
+                    "Assignment used as a condition, did you mean '=='? Wrap it in parentheses \
+                     if the assignment is intended",
+                );
+            }
+        }
+    }
+
+    /// Warns if the condition about to be compiled is a lone literal, so one branch can never
+    /// run. `while (true)` is the usual way to write an endless loop, so it's allowed.
+    fn check_constant_condition(&mut self, is_while: bool) {
+        let Some(&literal) = self.scanner.peek() else {
+            return;
+        };
+        if !matches!(self.scanner.peek_second(), Some(t) if t.ttype == TokenType::RParen) {
+            return;
+        }
+        let always = match literal.ttype {
+            TokenType::True if is_while => return,
+            TokenType::False | TokenType::Nil => "false",
+            TokenType::True | TokenType::Number | TokenType::Str => "true",
+            _ => return,
+        };
+        self.warn(
+            &literal,
+            &format!("Condition '{}' is always {always}", literal.lexeme),
+        );
+    }
+
     fn compile_if_statement(&mut self) -> CompilerResult<()> {
         let line = self.scanner.prev_unwrap().line;
         //   condition
@@ -150,6 +789,8 @@ impl<'a> Compiler<'a> {
 
         self.scanner
             .consume_token(TokenType::LParen, "Expected '(' after if")?;
+        self.check_assignment_condition();
+        self.check_constant_condition(false);
         self.compile_expression()?;
         self.scanner
             .consume_token(TokenType::RParen, "Expected ')' after condition")?;
@@ -169,8 +810,14 @@ impl<'a> Compiler<'a> {
         self.scope.curr_chunk().push_label(else_label);
         self.scope.curr_chunk().push(ByteCode::Pop, line);
 
-        if let Some(_) = self.scanner.advance_if_match(TokenType::Else) {
+        if self.scanner.advance_if_match(TokenType::Else).is_some() {
             self.compile_statement()?;
+        } else if self.allow_elif
+            && matches!(self.scanner.peek(), Some(t) if t.ttype == TokenType::Ident && t.lexeme == "elif")
+        {
+            // Same as `else if`
+            self.scanner.next();
+            self.compile_if_statement()?;
         }
 
         self.scope.curr_chunk().push_label(end_label);
@@ -178,7 +825,7 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn compile_while_statement(&mut self) -> CompilerResult<()> {
+    fn compile_while_statement(&mut self, label: Option<Token<'a>>) -> CompilerResult<()> {
         // cond:
         //   cond
         //   jump_f .end
@@ -187,15 +834,26 @@ impl<'a> Compiler<'a> {
         //   jump .cond
         // end:
         //   pop
+        // break:
 
         let line = self.scanner.prev_unwrap().line;
 
         let cond_label = self.scope.curr_chunk().allocate_new_label();
         let end_label = self.scope.curr_chunk().allocate_new_label();
+        let break_label = self.scope.curr_chunk().allocate_new_label();
 
         self.scanner
             .consume_token(TokenType::LParen, "Expected '(' after while")?;
+        if let Some(Token {
+            ttype: TokenType::Var | TokenType::Val,
+            ..
+        }) = self.scanner.peek()
+        {
+            return self.compile_while_binding(line, label, cond_label, end_label, break_label);
+        }
         self.scope.curr_chunk().push_label(cond_label);
+        self.check_assignment_condition();
+        self.check_constant_condition(true);
         self.compile_expression()?;
         self.scanner
             .consume_token(TokenType::RParen, "Expected ')' after condition")?;
@@ -206,25 +864,85 @@ impl<'a> Compiler<'a> {
         self.scope.curr_chunk().push(ByteCode::Pop, line);
 
         // compile body and jump back to cond
-        self.compile_statement()?;
+        let depth = self.scope.depth;
+        self.compile_loop_body(label, depth, break_label, cond_label)?;
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpRelative(0), line, cond_label);
+
+        self.scope.curr_chunk().push_label(end_label);
+        self.scope.curr_chunk().push(ByteCode::Pop, line);
+        self.scope.curr_chunk().push_label(break_label);
+        Ok(())
+    }
+
+    /// `while (var v = expr) body` loops while `expr` is truthy, binding its value to `v` for
+    /// each iteration
+    fn compile_while_binding(
+        &mut self,
+        line: usize,
+        label: Option<Token<'a>>,
+        cond_label: usize,
+        end_label: usize,
+        break_label: usize,
+    ) -> CompilerResult<()> {
+        // cond:
+        //   expr
+        //   jump_f .end
+        //   body
+        //   pop
+        //   jump .cond
+        // end:
+        //   pop
+        // break:
+        let mutable = self.scanner.next().unwrap().ttype == TokenType::Var;
+        let name = self
+            .scanner
+            .consume_token(TokenType::Ident, "Expected identifier to bind in while")?;
+        self.scanner
+            .consume_token(TokenType::Equal, "Expected '=' after while binding")?;
+
+        self.scope.curr_chunk().push_label(cond_label);
+        self.compile_expression()?;
+        self.scanner
+            .consume_token(TokenType::RParen, "Expected ')' after condition")?;
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpF(0), line, end_label);
+
+        // The value is left on the stack as the binding's slot
+        self.scope.increment_depth();
+        self.check_shadowing(&name);
+        self.check_locals_limit(&name)?;
+        self.scope.add_local(name, mutable);
+        let depth = self.scope.depth - 1;
+        self.compile_loop_body(label, depth, break_label, cond_label)?;
+        let num_locals = self.scope.decrement_depth();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
         self.scope
             .curr_chunk()
             .push_monkey_patch(ByteCode::JumpRelative(0), line, cond_label);
 
         self.scope.curr_chunk().push_label(end_label);
         self.scope.curr_chunk().push(ByteCode::Pop, line);
+        self.scope.curr_chunk().push_label(break_label);
         Ok(())
     }
 
-    fn compile_for_statement(&mut self) -> CompilerResult<()> {
+    fn compile_for_statement(&mut self, label: Option<Token<'a>>) -> CompilerResult<()> {
         //   init
         // cond:
         //   cond
         //   jump_f .end
         //   jump .body
         // post:
+        //   counter (if declared and assigned by post)
         //   post
         //   pop
+        //   counter
+        //   check_counter
         //   jump .cond
         // body:
         //   pop
@@ -232,6 +950,7 @@ impl<'a> Compiler<'a> {
         //   jump .post
         // end:
         //   pop
+        // break:
 
         let line = self.scanner.prev_unwrap().line;
 
@@ -239,11 +958,27 @@ impl<'a> Compiler<'a> {
         let post_label = self.scope.curr_chunk().allocate_new_label();
         let body_label = self.scope.curr_chunk().allocate_new_label();
         let end_label = self.scope.curr_chunk().allocate_new_label();
+        let break_label = self.scope.curr_chunk().allocate_new_label();
 
         self.scanner
             .consume_token(TokenType::LParen, "Expected '(' after 'for'")?;
+        let is_for_in = self
+            .scanner
+            .peek()
+            .is_some_and(|t| t.ttype == TokenType::Ident)
+            && self
+                .scanner
+                .peek_second()
+                .is_some_and(|t| t.ttype == TokenType::Ident && t.lexeme == "in");
+        if is_for_in {
+            return self.compile_for_in(line, label);
+        }
 
-        // ';' or decl
+        // ';' or decl. A variable declared here is taken to be the counter.
+        let counter = match self.scanner.peek() {
+            Some(t) if t.ttype == TokenType::Var => self.scanner.peek_second().copied(),
+            _ => None,
+        };
         if self.scanner.advance_if_match(TokenType::Semi).is_none() {
             self.compile_decl()?;
         }
@@ -253,8 +988,7 @@ impl<'a> Compiler<'a> {
             self.scope.curr_chunk().push(ByteCode::True, t.line);
         } else {
             self.compile_expression()?;
-            self.scanner
-                .consume_token(TokenType::Semi, "Expected ';' after for condition")?;
+            self.scanner.consume_semi("for condition")?;
         }
         self.scope
             .curr_chunk()
@@ -266,8 +1000,26 @@ impl<'a> Compiler<'a> {
         // ')' or post
         self.scope.curr_chunk().push_label(post_label);
         if self.scanner.advance_if_match(TokenType::RParen).is_none() {
+            // Catches counters that stop changing once they are too large to be incremented,
+            // which would otherwise loop forever. Only checked when the increment assigns it.
+            let assigns_counter =
+                self.scanner
+                    .peek()
+                    .zip(counter.as_ref())
+                    .is_some_and(|(t, counter)| {
+                        t.ttype == TokenType::Ident && t.lexeme == counter.lexeme
+                    })
+                    && Self::is_assignment(self.scanner.peek_second());
+            let counter = counter.filter(|_| assigns_counter);
+            if let Some(counter) = &counter {
+                self.compile_named_var(counter, false)?;
+            }
             self.compile_expression()?;
             self.scope.curr_chunk().push(ByteCode::Pop, line);
+            if let Some(counter) = &counter {
+                self.compile_named_var(counter, false)?;
+                self.scope.curr_chunk().push(ByteCode::CheckCounter, line);
+            }
             self.scanner
                 .consume_token(TokenType::RParen, "Expected ')' after for")?;
         }
@@ -278,17 +1030,202 @@ impl<'a> Compiler<'a> {
         // Body
         self.scope.curr_chunk().push_label(body_label);
         self.scope.curr_chunk().push(ByteCode::Pop, line);
-        self.compile_statement()?;
+        let depth = self.scope.depth;
+        self.compile_loop_body(label, depth, break_label, post_label)?;
         self.scope
             .curr_chunk()
             .push_monkey_patch(ByteCode::JumpRelative(0), line, post_label);
 
         self.scope.curr_chunk().push_label(end_label);
         self.scope.curr_chunk().push(ByteCode::Pop, line);
+        self.scope.curr_chunk().push_label(break_label);
 
         Ok(())
     }
 
+    /// `for (x in iterable) body` runs the body with `x` bound to each element of a list or
+    /// range, or each character of a string
+    fn compile_for_in(&mut self, line: usize, label: Option<Token<'a>>) -> CompilerResult<()> {
+        //   iterable
+        //   0
+        // next:
+        //   iter_next .end
+        //   body
+        //   pop
+        //   jump .next
+        // end:
+        //   pop
+        //   pop
+        let name = self.scanner.next().unwrap();
+        self.scanner.next();
+
+        let next_label = self.scope.curr_chunk().allocate_new_label();
+        let end_label = self.scope.curr_chunk().allocate_new_label();
+
+        // The iterable and the position in it are kept in locals that can't be named
+        self.scope.increment_depth();
+        self.compile_expression()?;
+        self.scanner
+            .consume_token(TokenType::RParen, "Expected ')' after for iterable")?;
+        self.emit_constant(&name, Value::Number(0.0))?;
+        for hidden in ["(iterable)", "(position)"] {
+            self.check_locals_limit(&name)?;
+            self.scope.add_local(
+                Token {
+                    lexeme: hidden,
+                    ..name
+                },
+                false,
+            );
+        }
+
+        self.scope.curr_chunk().push_label(next_label);
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::IterNext(0), line, end_label);
+
+        // The element is left on the stack as the binding's slot
+        self.scope.increment_depth();
+        self.check_shadowing(&name);
+        self.check_locals_limit(&name)?;
+        self.scope.add_local(name, true);
+        let depth = self.scope.depth - 1;
+        self.compile_loop_body(label, depth, end_label, next_label)?;
+        let num_locals = self.scope.decrement_depth();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpRelative(0), line, next_label);
+
+        self.scope.curr_chunk().push_label(end_label);
+        let num_locals = self.scope.decrement_depth();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
+        Ok(())
+    }
+
+    /// Whether the next tokens are a label like `outer:` in front of a loop
+    fn at_loop_label(&mut self) -> bool {
+        self.scanner
+            .peek()
+            .is_some_and(|t| t.ttype == TokenType::Ident)
+            && self
+                .scanner
+                .peek_second()
+                .is_some_and(|t| t.ttype == TokenType::Colon)
+    }
+
+    /// `label: while (...) body` or `label: for (...) body`, which `break label;` and
+    /// `continue label;` can refer to from nested loops
+    fn compile_labeled_loop(&mut self) -> CompilerResult<()> {
+        let label = self.scanner.next().unwrap();
+        self.scanner.next();
+        if self
+            .scope
+            .loops
+            .iter()
+            .any(|l| l.label == Some(label.lexeme))
+        {
+            report_error(
+                &label,
+                &format!(
+                    "Label '{}' is already used by an enclosing loop",
+                    label.lexeme
+                ),
+            );
+            return Err(InterpretError::Compiler);
+        }
+
+        if self.scanner.advance_if_match(TokenType::While).is_some() {
+            self.compile_while_statement(Some(label))
+        } else if self.scanner.advance_if_match(TokenType::For).is_some() {
+            self.compile_for_statement(Some(label))
+        } else {
+            report_error(
+                &label,
+                &format!("Expected a loop after label '{}'", label.lexeme),
+            );
+            Err(InterpretError::Compiler)
+        }
+    }
+
+    /// Compiles the body of a loop that `break` and `continue` can jump out of. Locals deeper
+    /// than `depth` belong to a single iteration.
+    fn compile_loop_body(
+        &mut self,
+        label: Option<Token<'a>>,
+        depth: isize,
+        break_label: usize,
+        continue_label: usize,
+    ) -> CompilerResult<()> {
+        self.scope.loops.push(Loop {
+            label: label.map(|t| t.lexeme),
+            break_label,
+            continue_label,
+            depth,
+            try_depth: self.scope.try_depth,
+        });
+        let result = self.compile_statement();
+        self.scope.loops.pop();
+        result
+    }
+
+    /// `break;` and `continue;` leave the innermost loop, or the one with the given label, after
+    /// dropping the locals and try blocks entered since the start of its iteration
+    fn compile_loop_jump(&mut self) -> CompilerResult<()> {
+        let tok = self.scanner.prev_unwrap();
+        let label = self.scanner.advance_if_match(TokenType::Ident);
+        self.scanner.consume_semi(tok.lexeme)?;
+
+        let target = match label {
+            Some(label) => self
+                .scope
+                .loops
+                .iter()
+                .rfind(|l| l.label == Some(label.lexeme)),
+            None => self.scope.loops.last(),
+        };
+        let Some(target) = target else {
+            match label {
+                Some(label) => report_error(
+                    &label,
+                    &format!("No enclosing loop is labeled '{}'", label.lexeme),
+                ),
+                None => report_error(
+                    &tok,
+                    &format!("Can't use '{}' outside of a loop", tok.lexeme),
+                ),
+            }
+            return Err(InterpretError::Compiler);
+        };
+        let jump_label = match tok.ttype {
+            TokenType::Break => target.break_label,
+            _ => target.continue_label,
+        };
+        let (depth, try_depth) = (target.depth, target.try_depth);
+
+        let num_locals = self
+            .scope
+            .locals
+            .iter()
+            .rev()
+            .take_while(|(d, _, _)| *d > depth)
+            .count();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, tok.line);
+        }
+        for _ in try_depth..self.scope.try_depth {
+            self.scope.curr_chunk().push(ByteCode::PopHandler, tok.line);
+        }
+        self.scope
+            .curr_chunk()
+            .push_monkey_patch(ByteCode::JumpRelative(0), tok.line, jump_label);
+        Ok(())
+    }
+
     fn compile_match_statement(&mut self) -> CompilerResult<()> {
         //   match_expr
         // branch_1:
@@ -305,9 +1242,8 @@ impl<'a> Compiler<'a> {
         //   eq
         //   not
         //   jz .statement_a
-        //   pop
+        //   pop_jump .branch_2
         //
-        //   jump .branch_2
         // statement_a:
         //   pop
         //   statement_a
@@ -319,8 +1255,13 @@ impl<'a> Compiler<'a> {
         // branch_n:
         // end:
         //   pop
+        //
+        // If every pattern is an integer and they are dense enough, the match starts with a jump
+        // to a jump table placed after the last arm, which goes straight to the arm for a number.
+        // Anything else is left to the checks above, so that equality works as usual.
 
-        let line = self.scanner.prev_unwrap().line;
+        let match_tok = self.scanner.prev_unwrap();
+        let line = match_tok.line;
 
         self.scanner
             .consume_token(TokenType::LParen, "Expected '(' after match")?;
@@ -328,21 +1269,60 @@ impl<'a> Compiler<'a> {
         self.scanner
             .consume_token(TokenType::RParen, "Expected ')' after match expression")?;
 
+        // The value is kept in a local that can't be named while the arms run, so that their
+        // own locals get the slots above it
+        self.scope.increment_depth();
+        self.check_locals_limit(&match_tok)?;
+        self.scope.add_local(
+            Token {
+                lexeme: "(match)",
+                ..match_tok
+            },
+            false,
+        );
+
         let end_label = self.scope.curr_chunk().allocate_new_label();
         let mut next_branch = self.scope.curr_chunk().allocate_new_label();
 
         self.scanner
             .consume_token(TokenType::LBrace, "Expected '{' after match expression")?;
 
-        while let None = self.scanner.advance_if_match(TokenType::RBrace) {
+        let first_branch = next_branch;
+        let dispatch = self.scope.curr_chunk().allocate_new_label();
+        // Each integer pattern, with the arm it goes to, while all patterns are integers
+        let mut cases = match self.scanner.peek().map(|t| t.ttype) {
+            Some(TokenType::Number | TokenType::Minus) => {
+                self.scope.curr_chunk().push_monkey_patch(
+                    ByteCode::JumpRelative(0),
+                    line,
+                    dispatch,
+                );
+                Some(vec![])
+            }
+            _ => None,
+        };
+        let uses_table = cases.is_some();
+        let mut else_body = None;
+
+        let mut else_arm: Option<Token> = None;
+        while self.scanner.advance_if_match(TokenType::RBrace).is_none() {
+            if let Some(else_tok) = else_arm.take() {
+                // Any arm after it would be unreachable. The arms are still compiled, so that
+                // compiling resumes after the match.
+                report_error(&else_tok, "The 'else' arm must be the last arm of a match");
+                self.had_error = true;
+            }
             let this_statement = self.scope.curr_chunk().allocate_new_label();
+            let this_body = self.scope.curr_chunk().allocate_new_label();
 
             self.scope.curr_chunk().push_label(next_branch);
             next_branch = self.scope.curr_chunk().allocate_new_label();
 
             // match each condition
             loop {
-                if self.scanner.advance_if_match(TokenType::Else).is_some() {
+                if let Some(else_tok) = self.scanner.advance_if_match(TokenType::Else) {
+                    else_arm = Some(else_tok);
+                    else_body = Some(this_body);
                     // it doesn't matter... it gets popped off the stack
                     self.scope.curr_chunk().push(ByteCode::Dup, line);
                     self.scope.curr_chunk().push_monkey_patch(
@@ -354,41 +1334,120 @@ impl<'a> Compiler<'a> {
                 }
 
                 self.scope.curr_chunk().push(ByteCode::Dup, line);
+                let pattern_start = self.scope.curr_chunk().size();
                 self.compile_expression()?;
+                if let Some(found) = &mut cases {
+                    match self.integer_constant_from(pattern_start) {
+                        Some(n) => found.push((n, this_body)),
+                        None => cases = None,
+                    }
+                }
                 self.scope.curr_chunk().push(ByteCode::Eq, line);
                 self.scope.curr_chunk().push(ByteCode::Not, line);
                 self.scope
                     .curr_chunk()
                     .push_monkey_patch(ByteCode::JumpF(0), line, this_statement);
-                self.scope.curr_chunk().push(ByteCode::Pop, line);
 
                 if self.scanner.advance_if_match(TokenType::Bar).is_none() {
+                    // None of the conditions matched
+                    self.scope.curr_chunk().push_monkey_patch(
+                        ByteCode::PopJump(0),
+                        line,
+                        next_branch,
+                    );
                     break;
                 }
+                self.scope.curr_chunk().push(ByteCode::Pop, line);
             }
 
             self.scanner
                 .consume_token(TokenType::FatArrow, "Expected '=>' after match conditions")?;
 
             // Compile branches
-            self.scope
-                .curr_chunk()
-                .push_monkey_patch(ByteCode::JumpRelative(0), line, next_branch);
 
             self.scope.curr_chunk().push_label(this_statement);
             self.scope.curr_chunk().push(ByteCode::Pop, line);
+            if uses_table {
+                self.scope.curr_chunk().push_label(this_body);
+            }
             self.compile_statement()?;
             self.scope
                 .curr_chunk()
                 .push_monkey_patch(ByteCode::JumpRelative(0), line, end_label);
         }
 
+        if uses_table {
+            // Nothing runs into the table, every arm ends in a jump
+            let miss = else_body.unwrap_or(end_label);
+            match cases.and_then(|cases| jump_table(&cases)) {
+                Some((min, entries)) => {
+                    let chunk = self.scope.curr_chunk();
+                    chunk.push_label(dispatch);
+                    chunk.push(ByteCode::JumpTable(min, entries.len() as u16), line);
+                    for target in entries.into_iter().map(|e| e.unwrap_or(miss)) {
+                        chunk.push_monkey_patch(ByteCode::JumpRelative(0), line, target);
+                    }
+                    chunk.push_monkey_patch(ByteCode::JumpRelative(0), line, miss);
+                    // This one jumps backwards, so no entry is erased as a jump to the next
+                    // instruction
+                    chunk.push_monkey_patch(ByteCode::JumpRelative(0), line, first_branch);
+                }
+                None => self.scope.curr_chunk().alias_label(dispatch, first_branch),
+            }
+        }
+
         self.scope.curr_chunk().push_label(end_label);
         self.scope.curr_chunk().push_label(next_branch);
-        self.scope.curr_chunk().push(ByteCode::Pop, line);
+        let num_locals = self.scope.decrement_depth();
+        for _ in 0..num_locals {
+            self.scope.curr_chunk().push(ByteCode::Pop, line);
+        }
 
         Ok(())
     }
+
+    /// The integer the code from `start` on pushes, if it is a single constant that fits a
+    /// jump table
+    fn integer_constant_from(&mut self, start: usize) -> Option<i32> {
+        let chunk = self.scope.curr_chunk();
+        let mut instructions = chunk.into_iter();
+        instructions.ptr = start;
+        let idx = match (instructions.next()?, instructions.next()) {
+            ((_, ByteCode::Constant(idx)), None) => idx as u32,
+            ((_, ByteCode::ConstantLong(idx)), None) => idx,
+            _ => return None,
+        };
+        match self.constants.get(idx) {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_JUMP_TABLE_CASE => {
+                Some(n as i32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Matches with fewer integer patterns than this are left to check them one by one
+const MIN_JUMP_TABLE_CASES: usize = 4;
+
+/// Largest integer pattern a jump table is used for, so that its entries can be found with an
+/// i32
+const MAX_JUMP_TABLE_CASE: f64 = 1e9;
+
+/// The first number of a jump table over the cases and the arm for each number from it on,
+/// if the cases are dense enough that the table isn't mostly empty. The first case for a
+/// number wins, as it would when checking them in order.
+fn jump_table(cases: &[(i32, usize)]) -> Option<(i32, Vec<Option<usize>>)> {
+    let min = cases.iter().map(|&(n, _)| n).min()?;
+    let max = cases.iter().map(|&(n, _)| n).max()?;
+    let len = (max as i64 - min as i64 + 1) as usize;
+    if cases.len() < MIN_JUMP_TABLE_CASES || len > 2 * cases.len() || len > u16::MAX as usize {
+        return None;
+    }
+    let mut entries = vec![None; len];
+    for &(n, arm) in cases {
+        entries[(n - min) as usize].get_or_insert(arm);
+    }
+    Some((min, entries))
 }
 
 impl<'a, I> PrevPeekable<I>
@@ -396,18 +1455,14 @@ where
     I: Iterator<Item = Token<'a>>,
 {
     pub fn advance_if_match(&mut self, ttype: TokenType) -> Option<Token<'a>> {
-        match self.peek() {
-            Some(Token {
-                ttype: next_ttype,
-                lexeme: _,
-                line: _,
-            }) => {
-                if next_ttype == &ttype {
-                    return self.next();
-                }
+        if let Some(Token {
+            ttype: next_ttype, ..
+        }) = self.peek()
+        {
+            if next_ttype == &ttype {
+                return self.next();
             }
-            None => {}
         }
-        return None;
+        None
     }
 }
@@ -1,4 +1,4 @@
-use std::iter::Peekable;
+use itertools::PeekNth;
 
 #[derive(Clone)]
 pub struct PrevPeekable<I>
@@ -6,7 +6,7 @@ where
     I: Iterator,
     <I as Iterator>::Item: Clone,
 {
-    inner: Peekable<I>,
+    inner: PeekNth<I>,
     previous: Option<I::Item>,
 }
 
@@ -17,7 +17,7 @@ where
 {
     pub fn from(inner: I) -> PrevPeekable<I> {
         PrevPeekable {
-            inner: inner.peekable(),
+            inner: itertools::peek_nth(inner),
             previous: None,
         }
     }
@@ -26,9 +26,24 @@ where
         self.previous.clone().unwrap()
     }
 
+    /// The last item returned, if any
+    pub fn prev(&self) -> Option<&I::Item> {
+        self.previous.as_ref()
+    }
+
     pub fn peek(&mut self) -> Option<&I::Item> {
         self.inner.peek()
     }
+
+    /// Peeks at the item after the next one
+    pub fn peek_second(&mut self) -> Option<&I::Item> {
+        self.peek_nth(1)
+    }
+
+    /// Peeks `n` items past the next one without consuming anything, so `peek_nth(0)` is `peek()`
+    pub fn peek_nth(&mut self, n: usize) -> Option<&I::Item> {
+        self.inner.peek_nth(n)
+    }
 }
 
 impl<I> Iterator for PrevPeekable<I>
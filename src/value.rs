@@ -1,8 +1,13 @@
-use std::{fmt::Display, rc::Rc};
+use std::{cell::RefCell, cmp::Ordering, fmt::Display, rc::Rc};
 
-use crate::chunk::Chunk;
+use crate::{
+    chunk::Chunk,
+    gc,
+    native::{NativeFn, VARIADIC},
+    vm::{raise_error, InterpretError},
+};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
@@ -10,6 +15,97 @@ pub enum Value {
     // TODO: String interning
     Str(Rc<str>),
     Func(Rc<FuncObj>),
+    Native(&'static NativeFn),
+    Generator(Rc<RefCell<Generator>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    Range(Range),
+    /// An enum member, named `Enum.Member`. Symbols are equal if their names are.
+    Symbol(Rc<str>),
+    /// Held by variables declared without an initializer in strict mode, and by globals whose
+    /// declaration hasn't run yet. Reading it is an error.
+    Uninit,
+}
+
+/// How `==` and `match` treat operands of different types. Comparing with nil is allowed in
+/// every mode, so that `x == nil` always works.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MixedEquality {
+    /// Values of different types are unequal, so `1 == "1"` is false
+    #[default]
+    False,
+    /// Numbers equal strings that parse as them and booleans as 1 or 0, so `1 == "1"` is true
+    Coerce,
+    /// Comparing values of different types is a runtime error
+    Error,
+}
+
+/// Insertion ordered map. Lookups are linear, which is fine for the small maps scripts build.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+}
+
+impl Map {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.entries.iter_mut().find(|(k, _)| k == &key) {
+            Some((_, v)) => *v = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Value, Value)> {
+        self.entries.iter()
+    }
+}
+
+/// Whole numbers from `start` up to `end`. Only ranges whose bounds are whole numbers can be
+/// made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    pub fn len(&self) -> usize {
+        let len = self.end - self.start + if self.inclusive { 1.0 } else { 0.0 };
+        len.max(0.0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `i`th number of the range, which must be less than `len()`
+    pub fn get(&self, i: usize) -> f64 {
+        self.start + i as f64
+    }
+
+    /// Whether `x` lies between the bounds, even if it isn't a whole number
+    pub fn contains(&self, x: f64) -> bool {
+        self.start <= x && (x < self.end || (self.inclusive && x == self.end))
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = if self.inclusive { "..=" } else { ".." };
+        write!(f, "{}{op}{}", self.start, self.end)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -17,6 +113,39 @@ pub struct FuncObj {
     pub arity: usize,
     pub chunk: Chunk,
     pub name: Option<Rc<str>>,
+    /// Line the function was declared on, 0 for the script
+    pub def_line: usize,
+    /// Parameter names, for binding named arguments
+    pub params: Vec<Rc<str>>,
+    /// Where to start executing when called with `min_arity() + i` arguments, or empty if no
+    /// parameters have defaults
+    pub entry_points: Vec<usize>,
+    /// Set if the body yields, in which case calling it returns a generator
+    pub is_generator: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+/// A suspended call to a generator function
+#[derive(Debug)]
+pub struct Generator {
+    pub func: Rc<FuncObj>,
+    pub ip: usize,
+    /// The generator's stack window while suspended, starting with the callee followed by the
+    /// arguments and locals
+    pub stack: Vec<Value>,
+    pub state: GeneratorState,
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
 }
 
 impl Display for FuncObj {
@@ -26,29 +155,69 @@ impl Display for FuncObj {
             "<fn {}#{} (size: {})>",
             self.name.as_ref().map(|n| n.as_ref()).unwrap_or("main"),
             self.arity,
-            self.chunk.bytecode.len()
+            self.chunk.size()
         )
     }
 }
 
+impl FuncObj {
+    /// Number of parameters without a default
+    pub fn min_arity(&self) -> usize {
+        self.arity + 1 - self.entry_points.len().max(1)
+    }
+
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        (self.min_arity()..=self.arity).contains(&arg_count)
+    }
+
+    pub fn entry_point(&self, arg_count: usize) -> usize {
+        self.entry_points
+            .get(arg_count - self.min_arity())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The number of arguments accepted, for error messages
+    pub fn describe_arity(&self) -> String {
+        if self.min_arity() == self.arity {
+            self.arity.to_string()
+        } else {
+            format!("{} to {}", self.min_arity(), self.arity)
+        }
+    }
+}
+
+/// Functions are equal only to themselves, so two functions that share a name are different
 impl PartialEq for FuncObj {
     fn eq(&self, other: &Self) -> bool {
-        return self.name == other.name;
+        std::ptr::eq(self, other)
     }
 }
 
-impl<'a> Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Bool(b) => write!(f, "{b}"),
-            Value::Number(n) => write!(f, "{n}"),
-            Value::Str(s) => write!(f, "{s}"),
-            Value::Func(func) => write!(f, "{func}"),
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_visiting(other, &mut vec![])
+    }
+}
+
+impl Drop for Value {
+    /// Lists and maps can be nested far deeper than the stack allows recursive drops, so the
+    /// items of the last reference to one are taken out and dropped in a loop instead
+    fn drop(&mut self) {
+        let mut pending = vec![];
+        self.take_last_items(&mut pending);
+        while let Some(mut item) = pending.pop() {
+            item.take_last_items(&mut pending);
         }
     }
 }
 
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_visiting(f, &mut vec![])
+    }
+}
+
 // #[derive(Debug, Clone)]
 // pub enum Constant {
 //     Nil,
@@ -58,13 +227,331 @@ impl<'a> Display for Value {
 // }
 
 impl Value {
-    pub fn is_truthy(self) -> bool {
+    /// A new list, tracked so that `gc` can free it if it ends up in a cycle
+    pub fn new_list(items: Vec<Value>) -> Value {
+        let list = Rc::new(RefCell::new(items));
+        gc::track_list(&list);
+        Value::List(list)
+    }
+
+    /// A new map, tracked so that `gc` can free it if it ends up in a cycle
+    pub fn new_map(map: Map) -> Value {
+        let map = Rc::new(RefCell::new(map));
+        gc::track_map(&map);
+        Value::Map(map)
+    }
+
+    /// Compares the values, where `visiting` holds the pairs of lists and maps being compared
+    /// further up. A pair met again is taken as equal, as any difference between them is found
+    /// where they were first compared.
+    fn eq_visiting(&self, other: &Value, visiting: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => {
+                let pair = (Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ());
+                if Rc::ptr_eq(a, b) || visiting.contains(&pair) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                if a.len() != b.len() {
+                    return false;
+                }
+                visiting.push(pair);
+                let equal = a
+                    .iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| a.eq_visiting(b, visiting));
+                visiting.pop();
+                equal
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                let pair = (Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ());
+                if Rc::ptr_eq(a, b) || visiting.contains(&pair) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                if a.len() != b.len() {
+                    return false;
+                }
+                visiting.push(pair);
+                let equal = a.iter().zip(b.iter()).all(|((ak, av), (bk, bv))| {
+                    ak.eq_visiting(bk, visiting) && av.eq_visiting(bv, visiting)
+                });
+                visiting.pop();
+                equal
+            }
+            (Value::Nil, Value::Nil) | (Value::Uninit, Value::Uninit) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) | (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Func(a), Value::Func(b)) => a == b,
+            (Value::Native(a), Value::Native(b)) => a == b,
+            (Value::Generator(a), Value::Generator(b)) => a == b,
+            (Value::Range(a), Value::Range(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Moves the items of a list or map into `pending` if this is its last reference, so that
+    /// dropping it doesn't drop them
+    fn take_last_items(&mut self, pending: &mut Vec<Value>) {
+        match self {
+            Value::List(list) if Rc::strong_count(list) == 1 => {
+                pending.append(&mut list.borrow_mut());
+            }
+            Value::Map(map) if Rc::strong_count(map) == 1 => {
+                let entries = std::mem::take(&mut map.borrow_mut().entries);
+                pending.extend(entries.into_iter().flat_map(|(k, v)| [k, v]));
+            }
+            _ => {}
+        }
+    }
+
+    /// Formats the value, where `visiting` holds the lists and maps it is nested in. A list or
+    /// map that contains itself is printed as `[...]` or `{...}` where it repeats.
+    fn fmt_visiting(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        visiting: &mut Vec<*const ()>,
+    ) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Func(func) => write!(f, "{func}"),
+            Value::Native(native) if native.arity == VARIADIC => {
+                write!(f, "<native fn {}#{}+>", native.name, native.min_arity)
+            }
+            Value::Native(native) => write!(f, "<native fn {}#{}>", native.name, native.arity),
+            Value::Generator(generator) => {
+                write!(f, "<generator {}>", generator.borrow().func)
+            }
+            Value::List(list) => {
+                let ptr = Rc::as_ptr(list) as *const ();
+                if visiting.contains(&ptr) {
+                    return write!(f, "[...]");
+                }
+                visiting.push(ptr);
+                write!(f, "[")?;
+                for (i, v) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.fmt_nested(f, visiting)?;
+                }
+                visiting.pop();
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                let ptr = Rc::as_ptr(map) as *const ();
+                if visiting.contains(&ptr) {
+                    return write!(f, "{{...}}");
+                }
+                visiting.push(ptr);
+                write!(f, "{{")?;
+                for (i, (k, v)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    k.fmt_nested(f, visiting)?;
+                    write!(f, ": ")?;
+                    v.fmt_nested(f, visiting)?;
+                }
+                visiting.pop();
+                write!(f, "}}")
+            }
+            Value::Range(range) => write!(f, "{range}"),
+            Value::Symbol(name) => write!(f, "{name}"),
+            Value::Uninit => write!(f, "<uninit>"),
+        }
+    }
+
+    /// Formats values contained in a list or map, where strings are quoted
+    fn fmt_nested(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        visiting: &mut Vec<*const ()>,
+    ) -> std::fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s:?}"),
+            v => v.fmt_visiting(f, visiting),
+        }
+    }
+
+    /// Formats the value like `Display`, but puts each item of a non-empty list or map on its
+    /// own line, indented by `indent` spaces per level of nesting. `None` if there isn't room
+    /// for the indentation of the deepest item.
+    pub fn pretty(&self, indent: usize) -> Option<String> {
+        let mut out = String::new();
+        self.write_pretty(&mut out, indent, 0, &mut vec![])?;
+        Some(out)
+    }
+
+    /// Like `fmt_visiting`, a list or map that contains itself is printed as `[...]` or `{...}`
+    fn write_pretty(
+        &self,
+        out: &mut String,
+        indent: usize,
+        depth: usize,
+        visiting: &mut Vec<*const ()>,
+    ) -> Option<()> {
+        let item_indent = indent.checked_mul(depth + 1)?;
+        match self {
+            Value::List(list) if visiting.contains(&(Rc::as_ptr(list) as *const ())) => {
+                out.push_str("[...]")
+            }
+            Value::Map(map) if visiting.contains(&(Rc::as_ptr(map) as *const ())) => {
+                out.push_str("{...}")
+            }
+            Value::List(list) if !list.borrow().is_empty() => {
+                visiting.push(Rc::as_ptr(list) as *const ());
+                out.push('[');
+                for (i, v) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    push_indented_line(out, item_indent)?;
+                    v.write_pretty_nested(out, indent, depth + 1, visiting)?;
+                }
+                visiting.pop();
+                push_indented_line(out, indent * depth)?;
+                out.push(']');
+            }
+            Value::Map(map) if !map.borrow().is_empty() => {
+                visiting.push(Rc::as_ptr(map) as *const ());
+                out.push('{');
+                for (i, (k, v)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    push_indented_line(out, item_indent)?;
+                    k.write_pretty_nested(out, indent, depth + 1, visiting)?;
+                    out.push_str(": ");
+                    v.write_pretty_nested(out, indent, depth + 1, visiting)?;
+                }
+                visiting.pop();
+                push_indented_line(out, indent * depth)?;
+                out.push('}');
+            }
+            v => *out += &v.to_string(),
+        }
+        Some(())
+    }
+
+    /// Like `fmt_nested`, strings in a list or map are quoted
+    fn write_pretty_nested(
+        &self,
+        out: &mut String,
+        indent: usize,
+        depth: usize,
+        visiting: &mut Vec<*const ()>,
+    ) -> Option<()> {
+        match self {
+            Value::Str(s) => *out += &format!("{s:?}"),
+            v => v.write_pretty(out, indent, depth, visiting)?,
+        }
+        Some(())
+    }
+
+    pub fn is_truthy(&self) -> bool {
         match self {
             Value::Nil => false,
-            Value::Bool(b) => b,
+            Value::Bool(b) => *b,
             _ => true,
         }
     }
+
+    /// Whether both are the same object, for values kept on the heap. Other values are the same
+    /// if they're equal.
+    pub fn is_same(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            (Value::Func(a), Value::Func(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => std::ptr::eq(*a, *b),
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (a, b) => a == b,
+        }
+    }
+
+    /// Equality for `MixedEquality::Coerce`
+    pub fn coerced_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(n), Value::Str(s)) | (Value::Str(s), Value::Number(n)) => {
+                s.trim().parse::<f64>().is_ok_and(|parsed| parsed == *n)
+            }
+            (Value::Number(n), Value::Bool(b)) | (Value::Bool(b), Value::Number(n)) => {
+                *n == if *b { 1.0 } else { 0.0 }
+            }
+            (l, r) => l == r,
+        }
+    }
+
+    /// Orders two numbers or two strings, or None for anything else, including NaN
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
+            (Value::Str(l), Value::Str(r)) => Some(l.cmp(r)),
+            _ => None,
+        }
+    }
+
+    /// Name of the value's type, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Func(_) | Value::Native(_) => "function",
+            Value::Generator(_) => "generator",
+            Value::Symbol(_) => "symbol",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Range(_) => "range",
+            Value::Uninit => "uninit",
+        }
+    }
+
+    // Argument helpers for native functions
+
+    pub fn as_number(&self) -> Result<f64, InterpretError> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            v => report_type_error("number", v),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<Rc<str>, InterpretError> {
+        match self {
+            Value::Str(s) => Ok(s.clone()),
+            v => report_type_error("string", v),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<Rc<RefCell<Vec<Value>>>, InterpretError> {
+        match self {
+            Value::List(list) => Ok(list.clone()),
+            v => report_type_error("list", v),
+        }
+    }
+}
+
+/// Starts a new line indented by `width` spaces, or `None` if there isn't room for them
+fn push_indented_line(out: &mut String, width: usize) -> Option<()> {
+    out.try_reserve(width.checked_add(1)?).ok()?;
+    out.push('\n');
+    out.extend(std::iter::repeat_n(' ', width));
+    Some(())
+}
+
+fn report_type_error<T>(expected: &str, found: &Value) -> Result<T, InterpretError> {
+    let msg = format!(
+        "Expected a {expected}, found {} '{found}'",
+        found.type_name()
+    );
+    raise_error(format!("Error: {msg}"), msg)
 }
 
 impl From<bool> for Value {
@@ -79,9 +566,27 @@ impl From<f64> for Value {
     }
 }
 
+thread_local! {
+    /// The empty string followed by every single ASCII character string. These come up often
+    /// from interpolation and concatenation, so they are shared instead of allocated each time.
+    static SMALL_STRS: Vec<Rc<str>> = std::iter::once(String::new())
+        .chain((0..128u8).map(|c| char::from(c).to_string()))
+        .map(Rc::from)
+        .collect();
+}
+
+fn small_str(s: &str) -> Option<Rc<str>> {
+    let idx = match s.as_bytes() {
+        [] => 0,
+        &[c] if c.is_ascii() => c as usize + 1,
+        _ => return None,
+    };
+    Some(SMALL_STRS.with(|strs| Rc::clone(&strs[idx])))
+}
+
 impl From<String> for Value {
     fn from(value: String) -> Self {
-        Value::Str(value.into())
+        Value::Str(small_str(&value).unwrap_or_else(|| value.into()))
     }
 }
 
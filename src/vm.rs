@@ -1,8 +1,20 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::Debug,
+    io::{self, Write},
+    rc::Rc,
+    time::Instant,
+};
 
+use itertools::Itertools;
 
 use crate::{
-    chunk::{ByteCode, Chunk},
-    value::Value,
+    chunk::{ByteCode, Chunk, ConstantTable},
+    compiler::{Compiler, GlobalBindings},
+    diagnostics::{self, Severity},
+    native::{report_native_error, NATIVES},
+    value::{FuncObj, Generator, GeneratorState, Map, MixedEquality, Range, Value},
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -11,95 +23,868 @@ pub enum InterpretError {
     Runtime,
 }
 
-pub struct VM<'a> {
-    _chunk: &'a Chunk,
+/// Calls deeper than this are reported as a stack overflow
+const MAX_FRAMES: usize = 1024;
+
+/// 2^53, from where numbers are too imprecise to hold every integer, so adding 1 may not change
+/// them
+const MAX_EXACT_INTEGER: f64 = 9007199254740992.0;
+
+/// Instructions run between checks of `VM::deadline`, as reading the clock takes far longer than
+/// most instructions
+const DEADLINE_CHECK_INTERVAL: u32 = 1024;
+
+pub struct VM {
     pub stack: Vec<Value>,
     pub globals: Vec<Value>,
-}
+    /// Names of the globals, which code run by `eval` is compiled against
+    pub global_bindings: GlobalBindings,
+    /// Constants of every script run, which their instructions refer to by index
+    pub constants: ConstantTable,
+    frames: Vec<CallFrame>,
+    /// Innermost last, see `ByteCode::PushHandler`
+    handlers: Vec<Handler>,
 
-fn report_error(line: usize, bytecode: &ByteCode, msg: &str) -> Result<(), InterpretError> {
-    println!("Error at line {line}, bytecode '{bytecode:?}': {msg}");
-    Err(InterpretError::Runtime)
+    /// Where `print` writes to, stdout by default
+    pub out: Box<dyn Write>,
+    /// Where `eprint` writes to, stderr by default
+    pub err: Box<dyn Write>,
+
+    /// Print each instruction and the stack before executing it to `out`
+    pub trace: bool,
+
+    /// How `==` treats operands of different types
+    pub equality: MixedEquality,
+
+    /// Count how often each opcode runs, printing a histogram to `err` after each run
+    pub profile: bool,
+    /// Executions during the last run of each opcode, indexed by its first byte
+    pub opcode_counts: [u64; 256],
+    /// Names of the counted opcodes, as shown in the disassembly
+    opcode_names: Vec<Option<String>>,
+
+    /// Fail the run once this passes
+    pub deadline: Option<Instant>,
+    /// Instructions left to run before the deadline is next checked
+    until_deadline_check: u32,
 }
 
-impl<'a> VM<'a> {
-    pub fn new(chunk: &Chunk) -> VM {
+impl Default for VM {
+    fn default() -> Self {
         VM {
-            _chunk: chunk,
             stack: vec![],
-            globals: vec![Value::Nil; chunk.global_slots as usize],
+            globals: native_globals().collect(),
+            global_bindings: GlobalBindings::default(),
+            constants: ConstantTable::default(),
+            frames: vec![],
+            handlers: vec![],
+            out: Box::new(io::stdout()),
+            err: Box::new(io::stderr()),
+            trace: false,
+            equality: MixedEquality::default(),
+            profile: false,
+            opcode_counts: [0; 256],
+            opcode_names: vec![None; 256],
+            deadline: None,
+            until_deadline_check: 0,
         }
     }
+}
+
+impl Debug for VM {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VM")
+            .field("stack", &self.stack)
+            .field("globals", &self.globals)
+            .field("frames", &self.frames)
+            .field("trace", &self.trace)
+            .field("profile", &self.profile)
+            .field("deadline", &self.deadline)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A function invocation. The callee sits just below `base` on the stack, followed by its
+/// arguments and locals starting at `base`.
+#[derive(Debug)]
+struct CallFrame {
+    func: Rc<FuncObj>,
+    ip: usize,
+    base: usize,
+    /// Set when running a generator, so that it can be suspended or marked done
+    generator: Option<Rc<RefCell<Generator>>>,
+}
 
-    pub fn interpret(chunk: &Chunk) -> Result<(), InterpretError> {
-        let mut vm = VM::new(chunk);
+/// Where a runtime error unwinds to, set up by a try block
+#[derive(Debug)]
+struct Handler {
+    /// Number of callers of the frame the try block is in
+    frames: usize,
+    stack_len: usize,
+    catch_ip: usize,
+}
+
+/// A runtime error on its way to a handler
+struct RaisedError {
+    /// Printed if no handler catches the error
+    report: String,
+    /// What the handler's catch block is given
+    message: String,
+    /// Natives don't know it, the VM fills it in once they return
+    line: Option<usize>,
+}
+
+thread_local! {
+    // Natives raise errors without access to the VM, so the error is kept here until the VM
+    // either catches or reports it
+    static RAISED_ERROR: RefCell<Option<RaisedError>> = const { RefCell::new(None) };
+}
+
+/// Fails with a runtime error, which is printed as `report` unless a try block catches it
+pub(crate) fn raise_error<T>(report: String, message: String) -> Result<T, InterpretError> {
+    RAISED_ERROR.set(Some(RaisedError {
+        report,
+        message,
+        line: None,
+    }));
+    Err(InterpretError::Runtime)
+}
 
-        let mut iterator = chunk.into_iter();
+/// Sets the line of a raised error that doesn't have one yet
+fn locate_raised_error(line: usize) {
+    RAISED_ERROR.with_borrow_mut(|raised| {
+        if let Some(raised) = raised {
+            raised.line.get_or_insert(line);
+        }
+    });
+}
+
+fn report_error<T>(line: usize, bytecode: &ByteCode, msg: &str) -> Result<T, InterpretError> {
+    RAISED_ERROR.set(Some(RaisedError {
+        report: format!("Error at line {line}, bytecode '{bytecode:?}': {msg}"),
+        message: msg.to_owned(),
+        line: Some(line),
+    }));
+    Err(InterpretError::Runtime)
+}
+
+/// Checks that a value can be used as a map key. NaN never equals itself, so an entry under it
+/// could never be found again, and infinities are rejected along with it. Keys are compared as
+/// numbers, so `1` and `1.0` are the same key, and -0 is stored as the 0 it equals.
+fn map_key(
+    key: Value,
+    chunk: &Chunk,
+    offset: usize,
+    bytecode: &ByteCode,
+) -> Result<Value, InterpretError> {
+    match key {
+        Value::Number(n) if !n.is_finite() => report_error(
+            chunk.get_line(offset),
+            bytecode,
+            &format!("Map keys must be finite numbers, found {n}"),
+        ),
+        // Matches -0 as well, since float patterns compare with ==
+        Value::Number(0.0) => Ok(Value::Number(0.0)),
+        key => Ok(key),
+    }
+}
+
+/// Resolves a relative jump, checking that it lands on an instruction of the chunk
+fn jump_target(
+    chunk: &Chunk,
+    offset: usize,
+    j_offset: i16,
+    bytecode: &ByteCode,
+) -> Result<usize, InterpretError> {
+    let target = offset as isize + j_offset as isize;
+    match usize::try_from(target) {
+        Ok(target) if chunk.is_instruction_start(target) => Ok(target),
+        _ => report_error(
+            chunk.get_line(offset),
+            bytecode,
+            &format!("Jump to {target} does not land on an instruction"),
+        ),
+    }
+}
+
+/// Converts a list index to a usize, if it is a whole number within bounds
+fn list_index(index: &Value, len: usize) -> Option<usize> {
+    match index {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && (*n as usize) < len => {
+            Some(*n as usize)
+        }
+        _ => None,
+    }
+}
+
+fn native_globals() -> impl Iterator<Item = Value> {
+    NATIVES.iter().map(Value::Native)
+}
+
+impl VM {
+    pub fn define_global(&mut self, slot: u32, value: Value) {
+        let slot = slot as usize;
+        if self.globals.len() <= slot {
+            self.globals.resize(slot + 1, Value::Uninit);
+        }
+        self.globals[slot] = value;
+    }
+
+    /// Forget all globals and anything left from the last run, keeping allocations and the
+    /// output sinks so the VM can be reused
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.globals.clear();
+        self.globals.extend(native_globals());
+        self.global_bindings = GlobalBindings::default();
+        self.constants = ConstantTable::default();
+    }
+
+    // Stack accessors. The compiler should never emit bytecode that underflows the stack, so
+    // underflows are reported with the offending instruction to help track such bugs down.
+
+    fn pop(
+        &mut self,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<Value, InterpretError> {
+        match self.stack.pop() {
+            Some(v) => Ok(v),
+            None => report_error(chunk.get_line(offset), bytecode, "Stack underflow"),
+        }
+    }
+
+    fn peek(
+        &self,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<Value, InterpretError> {
+        match self.stack.last() {
+            Some(v) => Ok(v.clone()),
+            None => report_error(chunk.get_line(offset), bytecode, "Stack underflow"),
+        }
+    }
+
+    /// Index of the bottom of the top `len` values
+    fn stack_start(
+        &self,
+        len: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<usize, InterpretError> {
+        match self.stack.len().checked_sub(len) {
+            Some(start) => Ok(start),
+            None => report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!(
+                    "Stack underflow, expected {len} values but found {}",
+                    self.stack.len()
+                ),
+            ),
+        }
+    }
+
+    /// `l == r`, treating operands of different types as `equality` says
+    fn equals(
+        &self,
+        l: &Value,
+        r: &Value,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<bool, InterpretError> {
+        let nil = matches!(l, Value::Nil) || matches!(r, Value::Nil);
+        if nil || l.type_name() == r.type_name() {
+            return Ok(l == r);
+        }
+        match self.equality {
+            MixedEquality::False => Ok(false),
+            MixedEquality::Coerce => Ok(l.coerced_eq(r)),
+            MixedEquality::Error => report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!(
+                    "Cannot compare {} '{l}' and {} '{r}' for equality",
+                    l.type_name(),
+                    r.type_name()
+                ),
+            ),
+        }
+    }
+
+    fn trace_instruction(
+        &mut self,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: ByteCode,
+    ) -> io::Result<()> {
+        let stack = self.stack.iter().map(|v| format!("[ {v} ]")).join("");
+        writeln!(self.out, "        {stack}")?;
+        writeln!(
+            self.out,
+            "{offset:#06x}: {}",
+            chunk.describe_instruction(bytecode, &[], &self.constants)
+        )
+    }
+
+    fn count_instruction(&mut self, chunk: &Chunk, offset: usize, bytecode: ByteCode) {
+        let opcode = chunk.bytecode[offset] as usize;
+        if self.opcode_counts[opcode] == 0 {
+            let name = format!("{bytecode:?}");
+            let name = name.split('(').next().unwrap_or_default();
+            self.opcode_names[opcode] = Some(name.to_owned());
+        }
+        self.opcode_counts[opcode] += 1;
+    }
+
+    /// Writes how often each opcode ran, most frequent first
+    fn write_profile(&mut self) -> io::Result<()> {
+        let counts = self
+            .opcode_counts
+            .iter()
+            .zip(&self.opcode_names)
+            .filter_map(|(&count, name)| Some((count, name.as_deref()?)))
+            .filter(|&(count, _)| count > 0)
+            .sorted_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        writeln!(self.err, "== PROFILE ==")?;
+        for (count, name) in counts {
+            writeln!(self.err, "{count:>10}  {name}")?;
+        }
+        writeln!(self.err, "=============")
+    }
+
+    /// Calls the value below the top `arg_count` values. Returns the frame to run if the callee
+    /// is a function, otherwise the call is complete with the result on the stack.
+    fn call(
+        &mut self,
+        arg_count: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<Option<CallFrame>, InterpretError> {
+        let base = self.stack_start(arg_count + 1, chunk, offset, bytecode)? + 1;
+
+        match &self.stack[base - 1] {
+            Value::Func(func) if func.accepts(arg_count) && func.is_generator => {
+                // Don't run the body yet, just capture the arguments
+                let generator = Generator {
+                    func: func.clone(),
+                    ip: func.entry_point(arg_count),
+                    stack: self.stack.split_off(base - 1),
+                    state: GeneratorState::Suspended,
+                };
+                self.stack
+                    .push(Value::Generator(Rc::new(RefCell::new(generator))));
+                Ok(None)
+            }
+            Value::Func(func) if func.accepts(arg_count) => {
+                if self.frames.len() >= MAX_FRAMES {
+                    return report_error(chunk.get_line(offset), bytecode, "Stack overflow");
+                }
+                Ok(Some(CallFrame {
+                    func: func.clone(),
+                    ip: func.entry_point(arg_count),
+                    base,
+                    generator: None,
+                }))
+            }
+            // Compiling needs the VM, which natives don't get
+            Value::Native(native) if native.name == "eval" && native.accepts(arg_count) => self
+                .eval(base, chunk, offset, bytecode)
+                .inspect_err(|_| locate_raised_error(chunk.get_line(offset)))
+                .map(Some),
+            Value::Native(native) if native.name == "next" && native.accepts(arg_count) => {
+                self.resume(base, chunk, offset, bytecode)
+            }
+            // Nothing to pop, so the result can replace the native in place
+            Value::Native(native) if arg_count == 0 && native.accepts(0) => {
+                self.stack[base - 1] = (native.func)(&[])
+                    .inspect_err(|_| locate_raised_error(chunk.get_line(offset)))?;
+                Ok(None)
+            }
+            Value::Native(native) if native.accepts(arg_count) => {
+                let result = (native.func)(&self.stack[base..])
+                    .inspect_err(|_| locate_raised_error(chunk.get_line(offset)))?;
+                self.stack.truncate(base - 1);
+                self.stack.push(result);
+                Ok(None)
+            }
+            Value::Func(func) => report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!(
+                    "Expected {} arguments but got {arg_count}",
+                    func.describe_arity()
+                ),
+            ),
+            Value::Native(native) => report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!(
+                    "Expected {} arguments but got {arg_count}",
+                    native.describe_arity()
+                ),
+            ),
+            v => report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!("Can only call functions, found {v:?}"),
+            ),
+        }
+    }
+
+    /// Replaces the given number of lists on top of the stack with their items, returning how
+    /// many there are
+    fn spread_arguments(
+        &mut self,
+        list_count: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<usize, InterpretError> {
+        let start = self.stack_start(list_count, chunk, offset, bytecode)?;
+        let mut args = vec![];
+        for list in self.stack.split_off(start) {
+            match &list {
+                Value::List(list) => args.extend(list.borrow().iter().cloned()),
+                v => {
+                    return report_error(
+                        chunk.get_line(offset),
+                        bytecode,
+                        &format!("Can only spread lists, found {} '{v}'", v.type_name()),
+                    )
+                }
+            }
+        }
+        let arg_count = args.len();
+        self.stack.append(&mut args);
+        Ok(arg_count)
+    }
+
+    /// Rearranges a call with named arguments, where the stack holds the callee, the positional
+    /// arguments and then a name and value for each named argument, into a positional call.
+    /// Returns the number of arguments.
+    fn bind_named_arguments(
+        &mut self,
+        positional: usize,
+        named: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<usize, InterpretError> {
+        let named_start = self.stack_start(2 * named, chunk, offset, bytecode)?;
+        let callee_idx = self.stack_start(2 * named + positional + 1, chunk, offset, bytecode)?;
+
+        let func = match &self.stack[callee_idx] {
+            Value::Func(func) => func.clone(),
+            v => {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    &format!("Only functions can take named arguments, found {v}"),
+                )
+            }
+        };
+        if positional > func.arity {
+            return report_error(
+                chunk.get_line(offset),
+                bytecode,
+                &format!(
+                    "Expected {} arguments but got {positional}",
+                    func.describe_arity()
+                ),
+            );
+        }
+
+        let mut args: Vec<Option<Value>> = vec![None; func.arity];
+        let named_args = self.stack.split_off(named_start);
+        for (i, arg) in self.stack.drain(callee_idx + 1..).enumerate() {
+            args[i] = Some(arg);
+        }
+        for (name, value) in named_args.into_iter().tuples() {
+            let Some(idx) = func
+                .params
+                .iter()
+                .position(|p| Value::Str(p.clone()) == name)
+            else {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    &format!("Unknown parameter '{name}' for {func}"),
+                );
+            };
+            if args[idx].is_some() {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    &format!("Argument '{name}' was given more than once"),
+                );
+            }
+            args[idx] = Some(value);
+        }
+
+        // Trailing parameters with defaults may be left out, but since defaults are evaluated by
+        // the prologue in order, one before a given argument can't be
+        let arg_count = args
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1)
+            .max(func.min_arity());
+        for (param, arg) in func.params.iter().zip(args).take(arg_count) {
+            let Some(arg) = arg else {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    &format!("Missing argument '{param}' for {func}"),
+                );
+            };
+            self.stack.push(arg);
+        }
+        Ok(arg_count)
+    }
+
+    /// Compiles the source passed to `eval`, returning the frame that runs it in place of the
+    /// call. Like a REPL line, the script's result is the value of its final expression if it
+    /// ends in one, and nil otherwise.
+    fn eval(
+        &mut self,
+        base: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<CallFrame, InterpretError> {
+        let source = self.stack[base].as_str()?;
+        // Compile against a copy of the bindings, so that a failed compile doesn't leave
+        // half-declared globals behind
+        let mut global_bindings = self.global_bindings.clone();
+        let mut compiler = Compiler::new(&source, &mut global_bindings, &mut self.constants);
+        compiler.allow_expression_result = true;
+        compiler.disassemble = false;
+        let Ok(script) = compiler.compile() else {
+            return report_native_error("eval", "The source failed to compile");
+        };
+        self.global_bindings = global_bindings;
+
+        if self.frames.len() >= MAX_FRAMES {
+            return report_error(chunk.get_line(offset), bytecode, "Stack overflow");
+        }
+        let global_slots = script.chunk.global_slots as usize;
+        if self.globals.len() < global_slots {
+            self.globals.resize(global_slots, Value::Uninit);
+        }
+        // The callee's slot is what's returned if the script leaves no result above it
+        self.stack.truncate(base);
+        self.stack[base - 1] = Value::Nil;
+        Ok(CallFrame {
+            func: Rc::new(script),
+            ip: 0,
+            base,
+            generator: None,
+        })
+    }
+
+    /// Resumes the generator passed to `next`, returning its frame to run. Once it has returned,
+    /// the call is complete with nil as the result.
+    fn resume(
+        &mut self,
+        base: usize,
+        chunk: &Chunk,
+        offset: usize,
+        bytecode: &ByteCode,
+    ) -> Result<Option<CallFrame>, InterpretError> {
+        let generator = match &self.stack[base] {
+            Value::Generator(generator) => Rc::clone(generator),
+            v => {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    &format!("Can only resume generators, found {v:?}"),
+                )
+            }
+        };
+        // The generator's result takes the place of `next` and its argument
+        self.stack.truncate(base - 1);
+
+        let mut state = generator.borrow_mut();
+        match state.state {
+            GeneratorState::Suspended => {}
+            GeneratorState::Running => {
+                return report_error(
+                    chunk.get_line(offset),
+                    bytecode,
+                    "Generator is already running",
+                )
+            }
+            GeneratorState::Done => {
+                self.stack.push(Value::Nil);
+                return Ok(None);
+            }
+        }
+        if self.frames.len() >= MAX_FRAMES {
+            return report_error(chunk.get_line(offset), bytecode, "Stack overflow");
+        }
+
+        state.state = GeneratorState::Running;
+        let base = self.stack.len() + 1;
+        self.stack.append(&mut state.stack);
+        Ok(Some(CallFrame {
+            func: state.func.clone(),
+            ip: state.ip,
+            base,
+            generator: Some(generator.clone()),
+        }))
+    }
+
+    /// Runs the script, keeping any globals set by previous runs. Returns the value the script
+    /// left on the stack, if any.
+    pub fn interpret(&mut self, script: Rc<FuncObj>) -> Result<Value, InterpretError> {
+        self.opcode_counts = [0; 256];
+        let result = self.run(script);
+        if self.profile {
+            if let Err(e) = self.write_profile() {
+                println!("Error: Failed to write profile: {e}");
+                return Err(InterpretError::Runtime);
+            }
+        }
+        // The sinks may be buffered, so make sure all output is written once the run is over
+        if let Err(e) = self.out.flush().and_then(|_| self.err.flush()) {
+            println!("Error: Failed to flush output: {e}");
+            return Err(InterpretError::Runtime);
+        }
+        result
+    }
+
+    fn run(&mut self, script: Rc<FuncObj>) -> Result<Value, InterpretError> {
+        self.stack.clear();
+        self.frames.clear();
+        self.handlers.clear();
+        let global_slots = script.chunk.global_slots as usize;
+        // Globals are only assigned once their declaration runs, even if it assigns nil
+        if self.globals.len() < global_slots {
+            self.globals.resize(global_slots, Value::Uninit);
+        }
+
+        let mut frame = CallFrame {
+            func: script,
+            ip: 0,
+            base: 0,
+            generator: None,
+        };
+
+        loop {
+            match self.execute(&mut frame) {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    let raised = RAISED_ERROR.take();
+                    match (raised, self.handlers.pop()) {
+                        (Some(raised), Some(handler)) => {
+                            self.unwind(&mut frame, handler, raised.message)
+                        }
+                        (raised, _) => {
+                            if let Some(raised) = raised {
+                                diagnostics::push(Severity::Error, raised.line, &raised.message);
+                                println!("{}", raised.report);
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Continues at the handler's catch block, abandoning any calls made since the try block
+    /// started
+    fn unwind(&mut self, frame: &mut CallFrame, handler: Handler, message: String) {
+        while self.frames.len() > handler.frames {
+            if let Some(generator) = &frame.generator {
+                generator.borrow_mut().state = GeneratorState::Done;
+            }
+            // Checked by the loop condition
+            *frame = self.frames.pop().unwrap();
+        }
+        frame.ip = handler.catch_ip;
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(message.into());
+    }
+
+    /// Whether there is a deadline and it has passed, only reading the clock every
+    /// `DEADLINE_CHECK_INTERVAL` calls
+    fn deadline_passed(&mut self) -> bool {
+        let Some(deadline) = self.deadline else {
+            return false;
+        };
+        if self.until_deadline_check > 0 {
+            self.until_deadline_check -= 1;
+            return false;
+        }
+        self.until_deadline_check = DEADLINE_CHECK_INTERVAL;
+        Instant::now() >= deadline
+    }
+
+    /// Drops the handlers of try blocks in frames deeper than `frames` callers
+    fn drop_handlers_above(&mut self, frames: usize) {
+        while self.handlers.last().is_some_and(|h| h.frames > frames) {
+            self.handlers.pop();
+        }
+    }
+
+    /// Runs until the script returns or a runtime error is raised
+    fn execute(&mut self, frame: &mut CallFrame) -> Result<Value, InterpretError> {
+        loop {
+            let mut iterator = frame.func.chunk.into_iter();
+            iterator.ptr = frame.ip;
+            let Some((offset, bytecode)) = iterator.next() else {
+                break;
+            };
+            frame.ip = iterator.ptr;
+            let chunk = &frame.func.chunk;
+
+            if self.trace {
+                if let Err(e) = self.trace_instruction(chunk, offset, bytecode) {
+                    return report_error(
+                        chunk.get_line(offset),
+                        &bytecode,
+                        &format!("Failed to write trace: {e}"),
+                    );
+                }
+            }
+
+            if self.profile {
+                self.count_instruction(chunk, offset, bytecode);
+            }
+
+            if self.deadline_passed() {
+                // Reported rather than raised, so that a try block can't keep the script running
+                let line = chunk.get_line(offset);
+                diagnostics::push(Severity::Error, Some(line), "Timed out");
+                println!("Error at line {line}, bytecode '{bytecode:?}': Timed out");
+                return Err(InterpretError::Runtime);
+            }
 
-        while let Some((offset, bytecode)) = iterator.next() {
             use ByteCode::*;
             match bytecode {
-                Return => break,
-                Constant(idx) => vm.stack.push(chunk.get_constant(idx as u32)),
-                ConstantLong(idx) => vm.stack.push(chunk.get_constant(idx)),
-                Nil => vm.stack.push(Value::Nil),
-                True => vm.stack.push(true.into()),
-                False => vm.stack.push(false.into()),
+                Return => {
+                    let Some(caller) = self.frames.pop() else {
+                        break;
+                    };
+                    // Returning from inside a try block leaves it
+                    self.drop_handlers_above(self.frames.len());
+                    let result = self.pop(chunk, offset, &bytecode)?;
+                    // Discard the callee along with its arguments and locals
+                    self.stack.truncate(frame.base - 1);
+                    self.stack.push(result);
+                    if let Some(generator) = &frame.generator {
+                        generator.borrow_mut().state = GeneratorState::Done;
+                    }
+                    *frame = caller;
+                }
+                Constant(idx) => self.stack.push(self.constants.get(idx as u32)),
+                ConstantLong(idx) => self.stack.push(self.constants.get(idx)),
+                Nil => self.stack.push(Value::Nil),
+                True => self.stack.push(true.into()),
+                False => self.stack.push(false.into()),
+                Uninit => self.stack.push(Value::Uninit),
                 Negate => {
-                    let val = match vm.stack.pop().ok_or(InterpretError::Runtime)? {
+                    let val = match self.pop(chunk, offset, &bytecode)? {
                         Value::Number(val) => -val,
                         v => {
                             return report_error(
                                 chunk.get_line(offset),
                                 &bytecode,
-                                &format!("Operand must be a number, found {v:?}"),
+                                &format!("Cannot negate {} '{v}'", v.type_name()),
                             )
                         }
                     };
 
-                    vm.stack.push(val.into());
+                    self.stack.push(val.into());
                 }
-                Add | Sub | Mul | Div => {
-                    let r = vm.stack.pop().ok_or(InterpretError::Runtime)?;
-                    let l = vm.stack.pop().ok_or(InterpretError::Runtime)?;
+                Add | Sub | Mul | Div | FloorDiv | AddConst(_) | SubConst(_) | AddConstLong(_)
+                | SubConstLong(_) => {
+                    // The fused variants take their right operand from the constants
+                    let (op, r) = match bytecode {
+                        AddConst(idx) => (Add, self.constants.get(idx as u32)),
+                        SubConst(idx) => (Sub, self.constants.get(idx as u32)),
+                        AddConstLong(idx) => (Add, self.constants.get(idx)),
+                        SubConstLong(idx) => (Sub, self.constants.get(idx)),
+                        op => (op, self.pop(chunk, offset, &bytecode)?),
+                    };
+                    let l = self.pop(chunk, offset, &bytecode)?;
 
-                    let res = match (bytecode, l, r) {
+                    let res = match (op, &l, &r) {
                         (Add, Value::Number(l), Value::Number(r)) => (l + r).into(),
                         (Sub, Value::Number(l), Value::Number(r)) => (l - r).into(),
                         (Mul, Value::Number(l), Value::Number(r)) => (l * r).into(),
                         (Div, Value::Number(l), Value::Number(r)) => (l / r).into(),
+                        (FloorDiv, Value::Number(l), Value::Number(r)) => (l / r).floor().into(),
                         (Add, Value::Str(l), r) => format!("{l}{r}").into(),
                         (Add, l, Value::Str(r)) => format!("{l}{r}").into(),
                         (Mul, Value::Str(l), Value::Number(r)) if r.fract() == 0.0 => {
-                            l.repeat(r as usize).into()
-                        },
-                        (_, l, r) => 
+                            l.repeat(*r as usize).into()
+                        }
+                        (op, l, r) => {
+                            let verb = match op {
+                                Add => "add",
+                                Sub => "subtract",
+                                Mul => "multiply",
+                                _ => "divide",
+                            };
                             return report_error(
                                 chunk.get_line(offset),
                                 &bytecode,
-                                &format!("Unsupported operands for operation {bytecode:?}, found {l:?}, {r:?}"),
-                            )
+                                &format!(
+                                    "Cannot {verb} {} '{l}' and {} '{r}'",
+                                    l.type_name(),
+                                    r.type_name()
+                                ),
+                            );
+                        }
                     };
 
-                    vm.stack.push(res);
+                    self.stack.push(res);
                 }
                 Not => {
-                    let val = !vm.stack.pop().ok_or(InterpretError::Runtime)?.is_truthy();
-                    vm.stack.push(val.into());
+                    let val = !self.pop(chunk, offset, &bytecode)?.is_truthy();
+                    self.stack.push(val.into());
+                }
+                Eq | NotEq => {
+                    let r = self.pop(chunk, offset, &bytecode)?;
+                    let l = self.pop(chunk, offset, &bytecode)?;
+                    let equal = self.equals(&l, &r, chunk, offset, &bytecode)?;
+                    self.stack.push((equal == matches!(bytecode, Eq)).into())
                 }
-                Eq => {
-                    let r = vm.stack.pop().ok_or(InterpretError::Runtime)?;
-                    let l = vm.stack.pop().ok_or(InterpretError::Runtime)?;
-                    vm.stack.push((r == l).into())
+                RefEq => {
+                    let r = self.pop(chunk, offset, &bytecode)?;
+                    let l = self.pop(chunk, offset, &bytecode)?;
+                    self.stack.push(l.is_same(&r).into())
+                }
+                EqTrue | EqFalse | EqNil => {
+                    let l = self.pop(chunk, offset, &bytecode)?;
+                    let r = match bytecode {
+                        EqTrue => Value::Bool(true),
+                        EqFalse => Value::Bool(false),
+                        _ => Value::Nil,
+                    };
+                    let equal = self.equals(&l, &r, chunk, offset, &bytecode)?;
+                    self.stack.push(equal.into())
                 }
-                Gt | Lt => {
-                    let r = vm.stack.pop().ok_or(InterpretError::Runtime)?;
-                    let l = vm.stack.pop().ok_or(InterpretError::Runtime)?;
+                Gt | Lt | NotGt | NotLt | Ge | Le => {
+                    let r = self.pop(chunk, offset, &bytecode)?;
+                    let l = self.pop(chunk, offset, &bytecode)?;
+                    // Every comparison with NaN is false, so only the negated ones are true
                     let res = match (l, r) {
                         (Value::Number(l), Value::Number(r)) => match bytecode {
                             Gt => l > r,
                             Lt => l < r,
-                            _ => unreachable!(),
+                            Ge => l >= r,
+                            Le => l <= r,
+                            NotGt => l.partial_cmp(&r) != Some(Ordering::Greater),
+                            _ => l.partial_cmp(&r) != Some(Ordering::Less),
                         },
                         (l, r) => {
                             return report_error(
@@ -109,49 +894,406 @@ impl<'a> VM<'a> {
                             )
                         }
                     };
-                    vm.stack.push(res.into())
+                    self.stack.push(res.into())
                 }
-                Print => {
-                    println!("{}", vm.stack.pop().ok_or(InterpretError::Runtime)?);
+                Print | PrintErr => {
+                    let val = self.pop(chunk, offset, &bytecode)?;
+                    let sink = match bytecode {
+                        Print => &mut self.out,
+                        _ => &mut self.err,
+                    };
+                    if let Err(e) = writeln!(sink, "{val}") {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            &format!("Failed to write output: {e}"),
+                        );
+                    }
                 }
                 SetGlobal(slot) => {
-                    let val = vm.stack.last().ok_or(InterpretError::Runtime)?.clone();
-                    vm.globals[slot as usize] = val;
+                    let val = self.peek(chunk, offset, &bytecode)?;
+                    self.globals[slot as usize] = val;
                 }
                 GetGlobal(slot) => {
-                    let val = vm.globals[slot as usize].clone();
-                    vm.stack.push(val);
-                }
-                SetLocal(idx) => {
-                    let val = vm.stack.last().ok_or(InterpretError::Runtime)?.clone();
-                    vm.stack[idx as usize] = val;
-                },
-                GetLocal(idx) => {
-                    let val = vm.stack[idx as usize].clone();
-                    vm.stack.push(val);
-                },
+                    let val = self.globals[slot as usize].clone();
+                    if let Value::Uninit = val {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            "Variable read before being assigned",
+                        );
+                    }
+                    self.stack.push(val);
+                }
+                SetLocal(_) | SetLocalByte(_) => {
+                    let idx = match bytecode {
+                        SetLocal(idx) => idx as usize,
+                        SetLocalByte(idx) => idx as usize,
+                        _ => unreachable!(),
+                    };
+                    let val = self.peek(chunk, offset, &bytecode)?;
+                    self.stack[frame.base + idx] = val;
+                }
+                GetLocal(_) | GetLocalByte(_) => {
+                    let idx = match bytecode {
+                        GetLocal(idx) => idx as usize,
+                        GetLocalByte(idx) => idx as usize,
+                        _ => unreachable!(),
+                    };
+                    let val = self.stack[frame.base + idx].clone();
+                    if let Value::Uninit = val {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            "Variable read before being assigned",
+                        );
+                    }
+                    self.stack.push(val);
+                }
+                GetCallee => self.stack.push(self.stack[frame.base - 1].clone()),
                 Pop => {
-                    vm.stack.pop().ok_or(InterpretError::Runtime)?;
-                },
+                    self.pop(chunk, offset, &bytecode)?;
+                }
                 Dup => {
-                    let v = vm.stack.last().ok_or(InterpretError::Runtime)?;
-                    vm.stack.push(v.clone());
-                },
+                    let v = self.peek(chunk, offset, &bytecode)?;
+                    self.stack.push(v);
+                }
+                DupAt(n) => {
+                    let Some(idx) = self.stack.len().checked_sub(n as usize + 1) else {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            &format!("Stack has only {} values", self.stack.len()),
+                        );
+                    };
+                    self.stack.push(self.stack[idx].clone());
+                }
                 JumpF(j_offset) => {
-                    let val = vm.stack.last().ok_or(InterpretError::Runtime)?.clone();
+                    let val = self.peek(chunk, offset, &bytecode)?;
                     if !val.is_truthy() {
-                        iterator.ptr = ((offset as isize) + j_offset as isize) as usize;
+                        frame.ip = jump_target(chunk, offset, j_offset, &bytecode)?;
                     }
-                },
+                }
                 JumpRelative(j_offset) => {
-                    iterator.ptr = ((offset as isize) + j_offset as isize) as usize;
-                },
+                    frame.ip = jump_target(chunk, offset, j_offset, &bytecode)?;
+                }
+                JumpTable(min, len) => {
+                    let entry = match self.peek(chunk, offset, &bytecode)? {
+                        Value::Number(n) => {
+                            let i = n - min as f64;
+                            if i.fract() == 0.0 && (0.0..len as f64).contains(&i) {
+                                i as usize
+                            } else {
+                                len as usize
+                            }
+                        }
+                        _ => len as usize + 1,
+                    };
+                    // Each entry is a JumpRelative, which is 3 bytes
+                    let target = frame.ip + 3 * entry;
+                    if !chunk.is_instruction_start(target) {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            &format!("Jump table entry {entry} is past the end of the chunk"),
+                        );
+                    }
+                    frame.ip = target;
+                }
+                IterNext(j_offset) => {
+                    let start = self.stack_start(2, chunk, offset, &bytecode)?;
+                    let Value::Number(position) = self.stack[start + 1] else {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            "Iteration position is not a number",
+                        );
+                    };
+                    let position = position as usize;
+                    // Strings are iterated by character, with the position as a byte offset
+                    let next = match &self.stack[start] {
+                        Value::List(items) => items
+                            .borrow()
+                            .get(position)
+                            .map(|v| (v.clone(), position + 1)),
+                        Value::Range(range) => (position < range.len())
+                            .then(|| (Value::Number(range.get(position)), position + 1)),
+                        Value::Str(s) => s[position..]
+                            .chars()
+                            .next()
+                            .map(|c| (Value::Str(c.to_string().into()), position + c.len_utf8())),
+                        v => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!("Cannot iterate over {} '{v}'", v.type_name()),
+                            )
+                        }
+                    };
+                    match next {
+                        Some((item, next_position)) => {
+                            self.stack[start + 1] = Value::Number(next_position as f64);
+                            self.stack.push(item);
+                        }
+                        None => frame.ip = jump_target(chunk, offset, j_offset, &bytecode)?,
+                    }
+                }
+                PushHandler(j_offset) => {
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        catch_ip: jump_target(chunk, offset, j_offset, &bytecode)?,
+                    });
+                }
+                PopHandler => {
+                    self.handlers.pop();
+                }
+                Nop => {}
+                PopJump(j_offset) => {
+                    self.pop(chunk, offset, &bytecode)?;
+                    frame.ip = jump_target(chunk, offset, j_offset, &bytecode)?;
+                }
+                Format(len) => {
+                    let start = self.stack_start(len as usize, chunk, offset, &bytecode)?;
+                    let formatted: String =
+                        self.stack.drain(start..).map(|v| v.to_string()).collect();
+                    self.stack.push(formatted.into());
+                }
+                MakeRange(inclusive) => {
+                    let end = self.pop(chunk, offset, &bytecode)?;
+                    let start = self.pop(chunk, offset, &bytecode)?;
+                    let range = match (start, end) {
+                        (Value::Number(start), Value::Number(end))
+                            if start.fract() == 0.0 && end.fract() == 0.0 =>
+                        {
+                            Range {
+                                start,
+                                end,
+                                inclusive,
+                            }
+                        }
+                        (start, end) => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!(
+                                    "Range bounds must be whole numbers, found {start:?}, {end:?}"
+                                ),
+                            )
+                        }
+                    };
+                    self.stack.push(Value::Range(range));
+                }
+                BuildList(len) => {
+                    let start = self.stack_start(len as usize, chunk, offset, &bytecode)?;
+                    let items = self.stack.split_off(start);
+                    self.stack.push(Value::new_list(items));
+                }
+                BuildMap(len) => {
+                    let start = self.stack_start(2 * len as usize, chunk, offset, &bytecode)?;
+                    let mut map = Map::default();
+                    for (k, v) in self.stack.split_off(start).into_iter().tuples() {
+                        map.insert(map_key(k, chunk, offset, &bytecode)?, v);
+                    }
+                    self.stack.push(Value::new_map(map));
+                }
+                Unpack(len) => {
+                    let items = match &self.pop(chunk, offset, &bytecode)? {
+                        Value::List(list) if list.borrow().len() == len as usize => {
+                            list.borrow().clone()
+                        }
+                        Value::List(list) => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!(
+                                    "Expected {len} values to unpack, found {}",
+                                    list.borrow().len()
+                                ),
+                            )
+                        }
+                        v => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!("Can only unpack a list, found {}", v.type_name()),
+                            )
+                        }
+                    };
+                    self.stack.extend(items);
+                }
+                GetIndex => {
+                    let index = self.pop(chunk, offset, &bytecode)?;
+                    let target = self.pop(chunk, offset, &bytecode)?;
+
+                    let val = match (&target, &index) {
+                        (Value::List(list), index) => {
+                            let list = list.borrow();
+                            match list_index(index, list.len()) {
+                                Some(i) => list[i].clone(),
+                                None => {
+                                    return report_error(
+                                        chunk.get_line(offset),
+                                        &bytecode,
+                                        &format!(
+                                            "Index {index} out of bounds for list of length {}",
+                                            list.len()
+                                        ),
+                                    )
+                                }
+                            }
+                        }
+                        (Value::Map(map), key) => {
+                            let key = map_key(key.clone(), chunk, offset, &bytecode)?;
+                            map.borrow().get(&key).cloned().unwrap_or(Value::Nil)
+                        }
+                        (Value::Range(range), index) => match list_index(index, range.len()) {
+                            Some(i) => range.get(i).into(),
+                            None => {
+                                return report_error(
+                                    chunk.get_line(offset),
+                                    &bytecode,
+                                    &format!(
+                                        "Index {index} out of bounds for range of length {}",
+                                        range.len()
+                                    ),
+                                )
+                            }
+                        },
+                        (target, _) => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!("Can only index lists, maps and ranges, found {target:?}"),
+                            )
+                        }
+                    };
+                    self.stack.push(val);
+                }
+                SetIndex => {
+                    let val = self.pop(chunk, offset, &bytecode)?;
+                    let index = self.pop(chunk, offset, &bytecode)?;
+                    let target = self.pop(chunk, offset, &bytecode)?;
+
+                    match (&target, index) {
+                        (Value::List(list), index) => {
+                            let mut list = list.borrow_mut();
+                            let len = list.len();
+                            match list_index(&index, len) {
+                                Some(i) => list[i] = val.clone(),
+                                None => {
+                                    return report_error(
+                                        chunk.get_line(offset),
+                                        &bytecode,
+                                        &format!(
+                                            "Index {index} out of bounds for list of length {len}"
+                                        ),
+                                    )
+                                }
+                            }
+                        }
+                        (Value::Map(map), key) => {
+                            let key = map_key(key, chunk, offset, &bytecode)?;
+                            map.borrow_mut().insert(key, val.clone())
+                        }
+                        (target, _) => {
+                            return report_error(
+                                chunk.get_line(offset),
+                                &bytecode,
+                                &format!("Can only index lists and maps, found {target:?}"),
+                            )
+                        }
+                    };
+                    // Like other assignments, leave the assigned value on the stack
+                    self.stack.push(val);
+                }
+                Yield => {
+                    let val = self.pop(chunk, offset, &bytecode)?;
+                    let Some(generator) = frame.generator.take() else {
+                        return report_error(
+                            chunk.get_line(offset),
+                            &bytecode,
+                            "Can only yield from a generator",
+                        );
+                    };
+                    // Set the generator's window aside until it is resumed
+                    let mut generator = generator.borrow_mut();
+                    generator.ip = frame.ip;
+                    generator.stack = self.stack.split_off(frame.base - 1);
+                    generator.state = GeneratorState::Suspended;
+                    self.stack.push(val);
+                    // Generators are never the script, so there is always a caller
+                    *frame = self.frames.pop().ok_or(InterpretError::Runtime)?;
+                }
+                CheckCounter => {
+                    let after = self.pop(chunk, offset, &bytecode)?;
+                    let before = self.pop(chunk, offset, &bytecode)?;
+                    if let (Value::Number(before), Value::Number(after)) = (before, after) {
+                        if before == after && before.abs() >= MAX_EXACT_INTEGER {
+                            let msg = format!(
+                                "For loop counter stopped changing at {before}, numbers this \
+                                 large are too imprecise for its increment"
+                            );
+                            return report_error(chunk.get_line(offset), &bytecode, &msg);
+                        }
+                    }
+                }
+                AssertFailed => {
+                    let msg = match self.pop(chunk, offset, &bytecode)? {
+                        Value::Nil => "Assertion failed".to_owned(),
+                        msg => format!("Assertion failed: {msg}"),
+                    };
+                    return report_error(chunk.get_line(offset), &bytecode, &msg);
+                }
+                Call(arg_count) => {
+                    if let Some(callee) = self.call(arg_count as usize, chunk, offset, &bytecode)? {
+                        self.frames.push(std::mem::replace(frame, callee));
+                    }
+                }
+                TailCall(arg_count) => {
+                    let arg_count = arg_count as usize;
+                    // Generators are suspended through their frame, so they can't give it up
+                    let replace_frame = frame.generator.is_none();
+                    if replace_frame {
+                        // Discard the caller and its locals, leaving the callee and arguments in
+                        // their place. If the callee is a native, its result is then returned by
+                        // the Return that follows.
+                        let start = self.stack_start(arg_count + 1, chunk, offset, &bytecode)?;
+                        self.stack.drain(frame.base - 1..start);
+                    }
+                    match self.call(arg_count, chunk, offset, &bytecode)? {
+                        Some(callee) if replace_frame => *frame = callee,
+                        Some(callee) => self.frames.push(std::mem::replace(frame, callee)),
+                        None => {}
+                    }
+                }
+                CallSpread(list_count) => {
+                    let arg_count =
+                        self.spread_arguments(list_count as usize, chunk, offset, &bytecode)?;
+                    if let Some(callee) = self.call(arg_count, chunk, offset, &bytecode)? {
+                        self.frames.push(std::mem::replace(frame, callee));
+                    }
+                }
+                CallNamed(positional, named) => {
+                    let arg_count = self.bind_named_arguments(
+                        positional as usize,
+                        named as usize,
+                        chunk,
+                        offset,
+                        &bytecode,
+                    )?;
+                    if let Some(callee) = self.call(arg_count, chunk, offset, &bytecode)? {
+                        self.frames.push(std::mem::replace(frame, callee));
+                    }
+                }
             }
         }
 
-        dbg!(vm.stack);
-        dbg!(vm.globals);
+        // The script's final expression, if any, is left on top of the stack. Anything else is
+        // discarded so that the stack is empty between runs.
+        let result = self.stack.pop().unwrap_or(Value::Nil);
+        self.stack.clear();
 
-        Ok(())
+        Ok(result)
     }
 }
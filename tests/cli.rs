@@ -0,0 +1,178 @@
+use std::{
+    fs,
+    process::{Command, Output},
+};
+
+/// Writes the source to a temporary file and runs it with the flags
+fn run_file(name: &str, flags: &[&str], source: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("lox-rs-cli-{}-{name}.lox", std::process::id()));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_lox-rs"))
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn oversized_sources_are_refused() {
+    let source = format!("print 1;{}", " ".repeat(92));
+    let output = run_file("oversized", &["--max-source-size=10"], &source);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("Error: Source is 100 bytes, which is over the limit of 10 bytes"),
+        "{stdout}"
+    );
+    assert!(!stdout.lines().any(|l| l == "1"));
+}
+
+#[test]
+fn sources_within_the_limit_run() {
+    let output = run_file("within", &["--max-source-size=100"], "print 1;");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().any(|l| l == "1"), "{stdout}");
+}
+
+#[test]
+fn invalid_source_size_is_an_error() {
+    let output = run_file("invalid", &["--max-source-size=lots"], "print 1;");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Invalid source size 'lots'"), "{stdout}");
+}
+
+#[test]
+fn buffered_output_is_all_flushed() {
+    let source = "for (var i = 0; i < 10000; i = i + 1) print i;";
+    let output = run_file("buffered", &["--buffer-output"], source);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed: Vec<_> = stdout
+        .lines()
+        .filter_map(|l| l.parse::<u32>().ok())
+        .collect();
+    assert_eq!(printed, (0..10000).collect::<Vec<_>>());
+}
+
+#[test]
+fn check_reports_every_error_without_running() {
+    let output = run_file(
+        "check_errors",
+        &["--check"],
+        "var = 1;\nprint 2;\nprint );\n",
+    );
+    assert!(!output.status.success());
+    let printed =
+        String::from_utf8(output.stdout).unwrap() + &String::from_utf8(output.stderr).unwrap();
+    assert!(
+        printed.contains("Error at line 1, token '=': Expected identifier after 'var'"),
+        "{printed}"
+    );
+    assert!(
+        printed.contains("Error at line 3, token ')': Expected expression here"),
+        "{printed}"
+    );
+    assert!(!printed.lines().any(|l| l == "2"), "{printed}");
+
+    let output = run_file("check_clean", &["--check"], "print 1;");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+}
+
+#[test]
+fn disassembly_includes_nested_functions() {
+    let source =
+        "fun outer() {\n  fun inner(a) { return a; }\n  return inner(1);\n}\nprint outer();\n";
+    let output = run_file("nested", &[], source);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    let outer = lines
+        .iter()
+        .position(|&l| l == "    == fn outer (arity 0) ==");
+    let inner = lines
+        .iter()
+        .position(|&l| l == "        == fn inner (arity 1) ==");
+    assert!(
+        matches!((outer, inner), (Some(o), Some(i)) if o < i),
+        "{stdout}"
+    );
+    assert!(
+        lines.contains(&"        0x0000: GetLocalByte(0)"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn dialect_and_warning_flags() {
+    let source = "var y = 2;\nif (y == 1) print 1; elif (y == 2) print 2;\n";
+    let output = run_file("elif", &["--allow-elif"], source);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .any(|l| l == "2"));
+    assert!(!run_file("no_elif", &[], source).status.success());
+
+    let source = "var x = 1;\nfun f() { var x = 2; }\n";
+    assert!(run_file("shadowing", &["--warnings-as-errors"], source)
+        .status
+        .success());
+    let output = run_file(
+        "shadowing_error",
+        &["--warn-shadowing", "--warnings-as-errors"],
+        source,
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Local 'x' shadows a global"));
+
+    let source = "fun g(a) { if (a) return 1; return; }\n";
+    assert!(!run_file(
+        "mixed_returns",
+        &["--warn-mixed-returns", "--warnings-as-errors"],
+        source
+    )
+    .status
+    .success());
+}
+
+#[test]
+fn mixed_equality_can_be_an_error() {
+    let message = "Cannot compare number '1' and string 'a' for equality";
+    for source in ["1 == \"a\";", "print 1 == \"a\";"] {
+        let output = run_file("equality", &["--equality=error"], source);
+        assert!(!output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains(message), "{stdout}");
+
+        let output = run_file("equality-default", &[], source);
+        assert!(output.status.success());
+    }
+}
+
+#[test]
+fn files_can_start_with_a_shebang() {
+    let source = "#!/usr/bin/env lox-rs\nprint 1 + 1;\n";
+    let output = run_file("shebang", &[], source);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().any(|l| l == "2"), "{stdout}");
+
+    // Anywhere else it's an error
+    let output = run_file("late-shebang", &[], "print 1;\n#!/usr/bin/env lox-rs\n");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Unexpected character '#'"), "{stdout}");
+    let output = run_file(
+        "indented-shebang",
+        &[],
+        " #!/usr/bin/env lox-rs\nprint 1;\n",
+    );
+    assert!(!output.status.success());
+}
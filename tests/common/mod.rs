@@ -0,0 +1,207 @@
+// Each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use lox_rs::{
+    chunk::{ByteCode, Chunk, ConstantTable},
+    compiler::{Compiler, GlobalBindings},
+    diagnostics::{Diagnostic, Severity},
+    pipeline::Pipeline,
+    value::{FuncObj, Value},
+    vm::{InterpretError, VM},
+};
+
+/// A script compiled on its own, along with the globals and constants it was compiled into
+pub struct Compiled {
+    pub script: FuncObj,
+    pub bindings: GlobalBindings,
+    pub constants: ConstantTable,
+}
+
+pub fn compile(source: &str) -> Compiled {
+    compile_with(source, |_| {}).expect("source should compile")
+}
+
+/// Compiles after letting `configure` set the compiler's options
+pub fn compile_with(
+    source: &str,
+    configure: impl FnOnce(&mut Compiler),
+) -> Result<Compiled, InterpretError> {
+    let mut bindings = GlobalBindings::default();
+    let mut constants = ConstantTable::default();
+    let mut compiler = Compiler::new(source, &mut bindings, &mut constants);
+    compiler.disassemble = false;
+    configure(&mut compiler);
+    let script = compiler.compile()?;
+    Ok(Compiled {
+        script,
+        bindings,
+        constants,
+    })
+}
+
+impl Compiled {
+    /// The script's instructions as the disassembly shows them
+    pub fn describe(&self) -> Vec<String> {
+        self.describe_chunk(&self.script.chunk)
+    }
+
+    pub fn describe_chunk(&self, chunk: &Chunk) -> Vec<String> {
+        let names = self.bindings.names_by_slot();
+        chunk
+            .into_iter()
+            .map(|(_, code)| chunk.describe_instruction(code, &names, &self.constants))
+            .collect()
+    }
+
+    /// The compiled function with the name, from the constants
+    pub fn function(&self, name: &str) -> Rc<FuncObj> {
+        self.constants
+            .values()
+            .iter()
+            .find_map(|value| match value {
+                Value::Func(func) if func.name.as_deref() == Some(name) => Some(Rc::clone(func)),
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("no function named '{name}'"))
+    }
+}
+
+/// The chunk's instructions, without their offsets
+pub fn instructions(chunk: &Chunk) -> Vec<ByteCode> {
+    chunk.into_iter().map(|(_, code)| code).collect()
+}
+
+/// Runs a hand-built chunk as a script in a new VM, returning the value left on top of the
+/// stack
+pub fn run_chunk(chunk: Chunk, constants: ConstantTable) -> Result<Value, InterpretError> {
+    let mut vm = VM::default();
+    vm.constants = constants;
+    vm.interpret(Rc::new(script(chunk)))
+}
+
+/// A script running the hand-built chunk
+pub fn script(chunk: Chunk) -> FuncObj {
+    FuncObj {
+        arity: 0,
+        chunk,
+        name: None,
+        def_line: 0,
+        params: vec![],
+        entry_points: vec![],
+        is_generator: false,
+    }
+}
+
+/// The values a script chunk has on its stack right before and right after calling the
+/// callee with the arguments, above a `nil` pushed first as a marker
+pub fn stack_around_call(
+    callee: Value,
+    args: &[Value],
+    mut constants: ConstantTable,
+) -> (Vec<Value>, Vec<Value>) {
+    let mut stack_after = |call: bool| {
+        let mut chunk = Chunk::default();
+        chunk.push(ByteCode::Nil, 1);
+        for value in std::iter::once(&callee).chain(args) {
+            let idx = constants.push(value.clone());
+            chunk.push(ByteCode::from_constant_index(idx), 1);
+        }
+        let mut len = args.len() as u32 + 2;
+        if call {
+            chunk.push(ByteCode::Call(args.len() as u8), 1);
+            len = 2;
+        }
+        chunk.push(ByteCode::BuildList(len), 1);
+        chunk.push(ByteCode::Return, 1);
+        match &run_chunk(chunk, constants.clone()) {
+            Ok(Value::List(list)) => list.borrow().clone(),
+            other => panic!("expected the stack as a list, got {other:?}"),
+        }
+    };
+    (stack_after(false), stack_after(true))
+}
+
+/// Collects what is written to it, shared by its clones so it can be read while a pipeline
+/// writes to another
+#[derive(Debug, Clone, Default)]
+pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    /// Everything written since the last call
+    pub fn take(&self) -> String {
+        String::from_utf8(self.0.take()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A pipeline printing to the returned output and collecting diagnostics, without printing
+/// disassembly
+pub fn pipeline() -> (Pipeline, Output) {
+    let mut pipeline = Pipeline::default();
+    let output = Output::default();
+    pipeline.set_output(output.clone());
+    pipeline.hide_disassembly = true;
+    pipeline.collect_diagnostics = true;
+    (pipeline, output)
+}
+
+/// Runs the source, returning what it printed, or the diagnostics if it fails
+pub fn run_in(
+    pipeline: &mut Pipeline,
+    output: &Output,
+    source: &str,
+) -> Result<String, Vec<Diagnostic>> {
+    output.take();
+    pipeline.take_diagnostics();
+    match pipeline.interpret_source(source) {
+        Ok(()) => Ok(output.take()),
+        Err(_) => Err(pipeline.take_diagnostics()),
+    }
+}
+
+/// Runs the source in a new pipeline, returning what it printed
+pub fn run(source: &str) -> String {
+    let (mut pipeline, output) = pipeline();
+    run_in(&mut pipeline, &output, source).unwrap_or_else(|e| panic!("failed with {e:?}"))
+}
+
+/// Runs the source in a new pipeline, expecting it to fail, returning the messages of the
+/// errors reported
+pub fn run_err(source: &str) -> Vec<String> {
+    let (mut pipeline, output) = pipeline();
+    match run_in(&mut pipeline, &output, source) {
+        Ok(out) => panic!("expected an error, printed {out:?}"),
+        Err(diagnostics) => diagnostics
+            .into_iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.message)
+            .collect(),
+    }
+}
+
+/// Compiles after letting `configure` set the compiler's options, returning the messages of
+/// the warnings reported
+pub fn compile_warnings(source: &str, configure: impl FnOnce(&mut Compiler)) -> Vec<String> {
+    let (_, diagnostics) = lox_rs::diagnostics::collect(|| compile_with(source, configure));
+    diagnostics
+        .into_iter()
+        .filter(|d| d.severity == Severity::Warning)
+        .map(|d| d.message)
+        .collect()
+}
@@ -0,0 +1,534 @@
+mod common;
+
+use common::{compile, compile_warnings, compile_with, run, run_chunk, run_err};
+use lox_rs::{
+    chunk::{ByteCode, Chunk, ConstantTable},
+    value::{FuncObj, Value},
+};
+
+#[test]
+fn deeply_nested_expressions_fail_gracefully() {
+    let source = format!("print {}1{};", "(".repeat(100_000), ")".repeat(100_000));
+    assert_eq!(
+        run_err(&source),
+        ["Expression nested too deeply (limit is 256)"]
+    );
+
+    let source = format!("print {}1;", "-".repeat(100_000));
+    assert_eq!(
+        run_err(&source),
+        ["Expression nested too deeply (limit is 256)"]
+    );
+}
+
+#[test]
+fn nesting_below_the_limit_compiles() {
+    let source = format!("print {}1{};", "(".repeat(100), ")".repeat(100));
+    assert_eq!(run(&source), "1\n");
+}
+
+#[test]
+fn expression_depth_limit_is_configurable() {
+    let source = "print ((((1))));";
+    assert!(compile_with(source, |c| c.max_expression_depth = 3).is_err());
+    assert!(compile_with(source, |c| c.max_expression_depth = 10).is_ok());
+}
+
+#[test]
+fn shadowing_warns_only_when_enabled() {
+    let source = "{ var x = 1; { var x = 2; } }";
+    assert_eq!(
+        compile_warnings(source, |c| c.warn_shadowing = true),
+        ["Local 'x' shadows a local declared on line 1"]
+    );
+    assert!(compile_warnings(source, |_| {}).is_empty());
+}
+
+#[test]
+fn shadowing_a_global_warns() {
+    let source = "var x = 1; { var x = 2; }";
+    assert_eq!(
+        compile_warnings(source, |c| c.warn_shadowing = true),
+        ["Local 'x' shadows a global"]
+    );
+    assert!(compile_warnings(source, |_| {}).is_empty());
+}
+
+#[test]
+fn imprecise_integer_literals_warn() {
+    assert_eq!(
+        compile_warnings("print 9007199254740993;", |_| {}),
+        ["Integer literal can't be represented exactly, it is 9007199254740992"]
+    );
+    assert_eq!(
+        compile_warnings("print -9007199254740993;", |_| {}),
+        ["Integer literal can't be represented exactly, it is 9007199254740992"]
+    );
+}
+
+#[test]
+fn imprecise_constant_operands_warn_once() {
+    let warning = "Integer literal can't be represented exactly, it is 9007199254740992";
+    let source = "var i = 0; i = i + 9007199254740993;";
+    assert_eq!(compile_warnings(source, |_| {}), [warning]);
+
+    // Past a byte of constants the operand isn't fused, and is compiled the regular way
+    let source = print_numbers(0..300) + source;
+    assert_eq!(compile_warnings(&source, |_| {}), [warning]);
+}
+
+#[test]
+fn exact_integer_literals_dont_warn() {
+    let source = "print 9007199254740992; print 0; print 007; print 0.1;";
+    assert!(compile_warnings(source, |_| {}).is_empty());
+    assert_eq!(run(source), "9007199254740992\n0\n7\n0.1\n");
+}
+
+/// A program printing each of the numbers, each a distinct constant
+fn print_numbers(numbers: std::ops::Range<usize>) -> String {
+    numbers.map(|n| format!("print {n}.5;\n")).collect()
+}
+
+#[test]
+fn too_many_constants_is_a_compile_error() {
+    let source = print_numbers(0..20);
+    let errors = lox_rs::diagnostics::collect(|| {
+        assert!(compile_with(&source, |c| c.max_constants = 10).is_err());
+    })
+    .1;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].message,
+        "Too many constants in one chunk (limit is 10)"
+    );
+    assert_eq!(errors[0].line, Some(11));
+}
+
+#[test]
+fn constant_limit_applies_to_each_function() {
+    // Each function uses 8 constants, while the table holds all 16 and the functions
+    let functions: String = (0..2)
+        .map(|f| {
+            let prints: String = (0..8).map(|n| format!("print {f}{n}.5;")).collect();
+            format!("fun f{f}() {{ {prints} }}\n")
+        })
+        .collect();
+    let compiled = compile_with(&functions, |c| c.max_constants = 10).unwrap();
+    assert!(compiled.constants.len() > 10);
+
+    // Constants already in the table still count against the function using them
+    let shared = format!("{functions}fun g() {{ {} }}", print_numbers(0..11));
+    assert!(compile_with(&shared, |c| c.max_constants = 10).is_err());
+    let reused = format!("{functions}fun g() {{ {} }}", "print 1.5; ".repeat(20));
+    assert!(compile_with(&reused, |c| c.max_constants = 10).is_ok());
+}
+
+#[test]
+fn constants_past_a_byte_are_used() {
+    let source = print_numbers(0..300);
+    let expected: String = (0..300).map(|n| format!("{n}.5\n")).collect();
+    assert_eq!(run(&source), expected);
+}
+
+#[test]
+fn constants_past_a_byte_are_still_fused() {
+    let source = format!(
+        "{}fun f(i) {{ return i + 0.25 - 0.125; }}\nprint f(1);",
+        print_numbers(0..300)
+    );
+    let compiled = compile(&source);
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert!(
+        described.iter().any(|d| d.starts_with("AddConstLong"))
+            && described.iter().any(|d| d.starts_with("SubConstLong")),
+        "{described:?}"
+    );
+    assert!(run(&source).ends_with("299.5\n1.125\n"));
+}
+
+#[test]
+fn fused_constants_count_against_the_limit() {
+    let source = format!("{}var i = 1;\nprint i + 20.5;", print_numbers(0..9));
+    let errors = lox_rs::diagnostics::collect(|| {
+        assert!(compile_with(&source, |c| c.max_constants = 10).is_err());
+    })
+    .1;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].message,
+        "Too many constants in one chunk (limit is 10)"
+    );
+
+    // An operand already in the chunk takes no more room
+    let source = format!("{}var i = 1;\nprint i + 8.5;", print_numbers(0..9));
+    assert!(compile_with(&source, |c| c.max_constants = 10).is_ok());
+}
+
+#[test]
+fn elif_compiles_like_else_if() {
+    let chain = |elif: &str| {
+        let source = format!(
+            "var x = 2; if (x == 1) print 1; {elif} (x == 2) print 2; {elif} (x == 3) print 3; else print 4;"
+        );
+        compile_with(&source, |c| c.allow_elif = true)
+            .unwrap()
+            .describe()
+    };
+    assert_eq!(chain("elif"), chain("else if"));
+}
+
+#[test]
+fn elif_is_an_identifier_by_default() {
+    assert!(compile_with("if (true) print 1; elif (true) print 2;", |_| {}).is_err());
+    assert_eq!(run("var elif = 3; print elif;"), "3\n");
+}
+
+#[test]
+fn repeated_match_patterns_share_constants() {
+    let arms: String = ["a", "b", "a", "c", "b", "a"]
+        .iter()
+        .map(|s| format!("\"{s}\" => print 1;\n"))
+        .collect();
+    let compiled = compile(&format!("match (\"b\") {{\n{arms}}}"));
+    let strings: Vec<String> = compiled
+        .constants
+        .values()
+        .iter()
+        .filter(|v| matches!(v, Value::Str(_)))
+        .map(|v| v.to_string())
+        .collect();
+    assert_eq!(strings, ["b", "a", "c"]);
+}
+
+#[test]
+fn missing_semicolon_at_the_end_of_the_file() {
+    assert_eq!(
+        run_err("var x = 1"),
+        ["Expected ';' after variable declaration, but the file ended"]
+    );
+    assert_eq!(
+        run_err("print 1"),
+        ["Expected ';' after value, but the file ended"]
+    );
+    assert_eq!(
+        run_err("var x = 1\nprint x;")[0],
+        "Expected ';' after variable declaration"
+    );
+    assert_eq!(run_err("print 1\nprint 2;")[0], "Expected ';' after value");
+}
+
+fn many_locals(count: usize) -> String {
+    let locals: String = (0..count).map(|n| format!("var v{n} = {n};\n")).collect();
+    format!("fun f() {{\n{locals}print v0;\n}}\nf();")
+}
+
+#[test]
+fn too_many_locals_is_a_compile_error() {
+    let errors = run_err(&many_locals(300));
+    assert_eq!(
+        errors[0],
+        "Too many locals, 'v256' would be local number 257 of a function limited to 256. \
+         Consider splitting it into smaller functions."
+    );
+}
+
+#[test]
+fn locals_limit_is_configurable() {
+    assert!(compile_with(&many_locals(256), |_| {}).is_ok());
+    assert!(compile_with(&many_locals(10), |c| c.max_locals = 5).is_err());
+}
+
+#[test]
+fn assignment_conditions_warn() {
+    let warning = "Assignment used as a condition, did you mean '=='? Wrap it in parentheses \
+                   if the assignment is intended";
+    assert_eq!(
+        compile_warnings("var x = 1; if (x = 5) print x;", |_| {}),
+        [warning]
+    );
+    assert_eq!(
+        compile_warnings("var x = 1; while (x = nil) print x;", |_| {}),
+        [warning]
+    );
+    assert!(compile_warnings("var x = 1; if ((x = 5)) print x;", |_| {}).is_empty());
+    assert!(compile_warnings("var x = 1; if (x == 5) print x;", |_| {}).is_empty());
+}
+
+#[test]
+fn errors_inside_loops_in_functions_are_recovered_from() {
+    let source = "fun f() {\nfor (var i = 0; i < 1; i = i + 1) print 1 2;\nprint );\n}";
+    assert_eq!(
+        run_err(source),
+        ["Expected ';' after value", "Expected expression here"]
+    );
+}
+
+#[test]
+fn constant_conditions_warn() {
+    assert_eq!(
+        compile_warnings("if (false) print 1;", |_| {}),
+        ["Condition 'false' is always false"]
+    );
+    assert_eq!(
+        compile_warnings("if (0) print 1;", |_| {}),
+        ["Condition '0' is always true"]
+    );
+    assert_eq!(
+        compile_warnings("while (nil) print 1;", |_| {}),
+        ["Condition 'nil' is always false"]
+    );
+    assert!(compile_warnings("while (true) print 1;", |_| {}).is_empty());
+    assert!(compile_warnings("var x = 1; if (x) print 1;", |_| {}).is_empty());
+    assert!(compile_warnings("if (false or true) print 1;", |_| {}).is_empty());
+}
+
+#[test]
+fn unclosed_and_empty_interpolations() {
+    assert_eq!(
+        run_err("var a = 1; print \"${a"),
+        ["Expected '}' to close the interpolation opened on line 1"]
+    );
+    assert_eq!(
+        run_err("var a = 1;\nprint \"x ${a\n;\nprint 2;"),
+        ["Expected '}' to close the interpolation opened on line 2"]
+    );
+    assert_eq!(
+        run_err("print \"${}\";"),
+        ["Expected an expression inside '${}'"]
+    );
+    assert_eq!(run("var x = 1; print \"${ \"${x}\" }\";"), "1\n");
+}
+
+#[test]
+fn constants_can_be_inspected_by_index() {
+    let compiled = compile("print 1.5;\nprint \"two\";\nfun f() { return 3.5; }");
+    assert_eq!(compiled.constants.get(0), Value::Number(1.5));
+    assert_eq!(compiled.constants.get(1), Value::from("two".to_owned()));
+    // A function's constants are added while compiling it, before the function itself
+    assert_eq!(compiled.function("f").chunk.used_constants(), [2]);
+    assert_eq!(compiled.constants.get(2), Value::Number(3.5));
+    assert_eq!(compiled.script.chunk.used_constants(), [0, 1, 3]);
+    assert_eq!(compiled.constants.len(), 4);
+}
+
+/// Offsets of the jumps in the chunk that land on an unconditional jump
+fn jumps_to_jumps(chunk: &Chunk) -> Vec<usize> {
+    let codes: Vec<_> = chunk.into_iter().collect();
+    codes
+        .iter()
+        .filter_map(|&(offset, code)| match code {
+            ByteCode::JumpF(j) | ByteCode::JumpRelative(j) | ByteCode::PopJump(j) => {
+                let target = (offset as isize + j as isize) as usize;
+                codes
+                    .iter()
+                    .any(|&(at, code)| at == target && matches!(code, ByteCode::JumpRelative(_)))
+                    .then_some(offset)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn jump_chains_are_threaded() {
+    let source = "
+        fun f(a, b) {
+            if (a) { if (b) print 1; else print 2; } else print 3;
+            match (a) { 1 => match (b) { 2 => print 4; else => print 5; } else => print 6; }
+        }
+        f(true, false); f(1, 2); f(1, 3); f(false, 0);
+    ";
+    let compiled = compile(source);
+    assert_eq!(
+        jumps_to_jumps(&compiled.function("f").chunk),
+        [] as [usize; 0]
+    );
+    assert_eq!(run(source), "2\n6\n1\n4\n1\n5\n3\n6\n");
+
+    // A jump to a jump to a jump ends up going straight to the end
+    let mut chunk = Chunk::default();
+    let [first, second, end] = [(); 3].map(|_| chunk.allocate_new_label());
+    chunk.push_monkey_patch(ByteCode::JumpRelative(0), 1, first);
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push_label(first);
+    chunk.push_monkey_patch(ByteCode::JumpRelative(0), 1, second);
+    chunk.push_label(second);
+    chunk.push_monkey_patch(ByteCode::JumpRelative(0), 1, end);
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push_label(end);
+    chunk.push(ByteCode::True, 1);
+    chunk.push(ByteCode::Return, 1);
+    chunk.resolve_monkey_patches();
+    let (_, ByteCode::JumpRelative(j)) = chunk.into_iter().next().unwrap() else {
+        panic!("the first jump is gone");
+    };
+    assert_eq!(j as usize, chunk.size() - 2);
+    assert!(matches!(
+        run_chunk(chunk, Default::default()),
+        Ok(Value::Bool(true))
+    ));
+}
+
+#[test]
+fn cyclic_jumps_are_left_alone() {
+    let mut chunk = Chunk::default();
+    let (top, bottom) = (chunk.allocate_new_label(), chunk.allocate_new_label());
+    chunk.push_label(top);
+    chunk.push_monkey_patch(ByteCode::JumpRelative(0), 1, bottom);
+    chunk.push_label(bottom);
+    chunk.push_monkey_patch(ByteCode::JumpRelative(0), 1, top);
+    // Resolving must finish
+    chunk.resolve_monkey_patches();
+    assert_eq!(jumps_to_jumps(&chunk).len(), 2);
+}
+
+#[test]
+fn pure_expression_statements_compile_to_nothing() {
+    assert_eq!(compile("1 + 2;").describe(), ["Return"]);
+    assert_eq!(
+        compile("[1, {\"k\": 2}]; -3 * 4 < 5;").describe(),
+        ["Return"]
+    );
+    assert_eq!(
+        compile("1 == 2; \"a\" == nil; 1 === \"a\";").describe(),
+        ["Return"]
+    );
+    // Equality on mixed types fails with `MixedEquality::Error`
+    assert!(compile("1 == \"a\";").describe().len() > 1);
+    // Computed numeric map keys might be NaN or infinite
+    assert_eq!(
+        compile("[{1: 1 / 0, \"k\": 0 / 0}];").describe(),
+        ["Return"]
+    );
+    assert!(compile("[{1 / 0: 1}];").describe().len() > 1);
+
+    let kept = compile("fun f() {} f(); var x = 1; x = 2; x;").describe();
+    assert!(kept.contains(&"Call(0)".to_string()), "{kept:?}");
+    assert!(kept.iter().any(|code| code.starts_with("SetGlobal")));
+    assert!(kept.ends_with(&[
+        "GetGlobal(30) (x)".to_string(),
+        "Pop".into(),
+        "Return".into()
+    ]));
+    // Arithmetic on something that might not be a number could fail
+    assert!(compile("\"a\" - 1;").describe().len() > 1);
+    assert_eq!(
+        run_err("\"a\" - 1;"),
+        ["Cannot subtract string 'a' and number '1'"]
+    );
+}
+
+#[test]
+fn mixing_value_and_bare_returns_warns_when_enabled() {
+    let mixed = "fun f(a) {\nif (a) return 1;\nreturn;\n}";
+    assert_eq!(
+        compile_warnings(mixed, |c| c.warn_mixed_returns = true),
+        ["Function 'f' returns nothing here but returns a value on line 2"]
+    );
+    assert!(compile_warnings(mixed, |_| {}).is_empty());
+
+    // Only the first mismatch in each function is reported
+    let reversed = "fun g(a) {\nif (a) return;\nreturn 1;\nreturn 2;\nreturn;\n}";
+    assert_eq!(
+        compile_warnings(reversed, |c| c.warn_mixed_returns = true),
+        ["Function 'g' returns a value here but returns nothing on line 2"]
+    );
+
+    let consistent = "fun h(a) { if (a) return 1; return 2; } fun k(a) { if (a) return; }";
+    assert!(compile_warnings(consistent, |c| c.warn_mixed_returns = true).is_empty());
+}
+
+#[test]
+fn stripped_assertions_compile_to_nothing() {
+    let source = "var x = 1;\nassert(x == 1 and x > 0, \"m\");\nprint x;";
+    let kept = compile(source).describe();
+    assert!(kept.contains(&"AssertFailed".to_owned()), "{kept:?}");
+
+    let stripped = compile_with(source, |c| c.strip_assertions = true)
+        .unwrap()
+        .describe();
+    assert_eq!(
+        stripped,
+        [
+            "Constant(0) (1)",
+            "SetGlobal(29) (x)",
+            "Pop",
+            "GetGlobal(29) (x)",
+            "Print",
+            "Return",
+        ]
+    );
+
+    // The condition is never evaluated, but is still checked
+    let (mut pipeline, output) = common::pipeline();
+    pipeline.strip_assertions = true;
+    let side_effect = "fun f() { print \"ran\"; return false; } assert(f()); print \"end\";";
+    assert_eq!(
+        common::run_in(&mut pipeline, &output, side_effect).unwrap(),
+        "end\n"
+    );
+    assert!(compile_with("assert(missing);", |c| c.strip_assertions = true).is_err());
+    assert_eq!(run_err("assert(1 == 2);").len(), 1);
+}
+
+#[test]
+fn functions_share_constant_table_entries() {
+    let source = "fun f() { return \"hello\"; }\nfun g() { return \"hello\" + 1.5; }\nprint 1.5;";
+    let compiled = compile(source);
+    let values = compiled.constants.values();
+    let count = |wanted: &Value| values.iter().filter(|v| *v == wanted).count();
+    assert_eq!(count(&Value::from("hello".to_owned())), 1);
+    assert_eq!(count(&Value::Number(1.5)), 1);
+    assert_eq!(run(source), "1.5\n");
+}
+
+#[test]
+fn identical_literals_share_one_slot() {
+    let mut table = ConstantTable::default();
+    let zero = table.push(Value::Number(0.0));
+    let nan = table.push(Value::Number(f64::NAN));
+    let text = table.push(Value::from("text".to_owned()));
+    let yes = table.push(Value::Bool(true));
+    assert_eq!(table.push(Value::Number(0.0)), zero);
+    assert_ne!(table.push(Value::Number(-0.0)), zero);
+    assert_eq!(table.push(Value::Number(f64::NAN)), nan);
+    assert_eq!(table.push(Value::from("text".to_owned())), text);
+    assert_eq!(table.push(Value::Bool(true)), yes);
+    assert_ne!(table.push(Value::Symbol("text".into())), text);
+    let func = Value::Func(std::rc::Rc::new(FuncObj::default()));
+    assert_ne!(table.push(func.clone()), table.push(func));
+    assert_eq!(table.len(), 8);
+
+    // Read tables keep duplicates where they are, and share the first of them
+    let mut table = ConstantTable::from_values(vec![Value::Nil, Value::Nil, Value::Number(1.0)]);
+    assert_eq!(table.push(Value::Nil), 0);
+    assert_eq!(table.push(Value::Number(1.0)), 2);
+    assert_eq!(table.len(), 3);
+}
+
+#[test]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+fn bench_compiling_many_constants() {
+    for count in [10_000, 20_000, 40_000] {
+        let source = print_numbers(0..count);
+        let start = std::time::Instant::now();
+        compile_with(&source, |c| c.max_constants = usize::MAX).unwrap();
+        println!("{count} constants: {:?}", start.elapsed());
+    }
+}
+
+#[test]
+fn released_slots_are_filled_again() {
+    let mut table = ConstantTable::default();
+    let one = table.push(Value::Number(1.0));
+    let text = table.push(Value::from("text".to_owned()));
+    table.release(one);
+    assert_eq!(table.get(one), Value::Nil);
+
+    let mark = table.mark();
+    assert_eq!(table.push(Value::Number(2.0)), one);
+    assert_eq!(table.push(Value::from("text".to_owned())), text);
+    assert_eq!(table.push(Value::Number(1.0)), 2);
+    assert_eq!(table.filled_since(mark).collect::<Vec<_>>(), [one, 2]);
+    assert_eq!(table.len(), 3);
+}
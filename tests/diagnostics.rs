@@ -0,0 +1,75 @@
+mod common;
+
+use common::{compile_with, pipeline, run_in};
+use lox_rs::diagnostics::{self, Diagnostic, Severity};
+
+fn error(line: Option<usize>, message: &str) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        line,
+        message: message.to_owned(),
+    }
+}
+
+fn warning(line: usize, message: &str) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Warning,
+        line: Some(line),
+        message: message.to_owned(),
+    }
+}
+
+#[test]
+fn compile_errors_are_collected_with_their_lines() {
+    let source = "var a = ;\nprint 1;\nvar b = ;\n";
+    let (result, collected) = diagnostics::collect(|| compile_with(source, |_| {}).is_err());
+    assert!(result);
+    assert_eq!(
+        collected,
+        [
+            error(Some(1), "Expected expression here"),
+            error(Some(3), "Expected expression here"),
+        ]
+    );
+
+    let (_, collected) = diagnostics::collect(|| compile_with("print 1;\nprint (1", |_| {}));
+    assert_eq!(collected, [error(None, "Expected ')' after expression")]);
+}
+
+#[test]
+fn nothing_is_collected_outside_collect() {
+    assert!(compile_with("var a = ;", |_| {}).is_err());
+    let (_, collected) = diagnostics::collect(|| compile_with("print 1;", |_| {}));
+    assert!(collected.is_empty());
+}
+
+#[test]
+fn nested_collects_also_report_to_the_outer_one() {
+    let (inner, outer) = diagnostics::collect(|| {
+        let _ = compile_with("var a = ;", |_| {});
+        diagnostics::collect(|| compile_with("\nvar b = ;", |_| {})).1
+    });
+    assert_eq!(inner, [error(Some(2), "Expected expression here")]);
+    assert_eq!(
+        outer,
+        [
+            error(Some(1), "Expected expression here"),
+            error(Some(2), "Expected expression here"),
+        ]
+    );
+}
+
+#[test]
+fn pipelines_return_warnings_and_runtime_errors_in_order() {
+    let (mut pipeline, output) = pipeline();
+    pipeline.warn_shadowing = true;
+    let source = "var x = 1;\nfun f() {\n  var x = 2;\n}\nprint nil + 1;\n";
+    assert_eq!(
+        run_in(&mut pipeline, &output, source).unwrap_err(),
+        [
+            warning(3, "Local 'x' shadows a global"),
+            error(Some(5), "Cannot add nil 'nil' and number '1'"),
+        ]
+    );
+    assert!(pipeline.take_diagnostics().is_empty());
+}
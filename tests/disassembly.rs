@@ -0,0 +1,276 @@
+mod common;
+
+use common::compile;
+use lox_rs::chunk::{ByteCode, Chunk};
+
+#[test]
+fn globals_are_shown_by_name() {
+    let compiled = compile("var x = 1;\nprint x;");
+    let slot = compiled.bindings.global_slots["x"];
+    let get = format!("GetGlobal({slot}) (x)");
+    assert!(
+        compiled.describe().contains(&get),
+        "{:?}",
+        compiled.describe()
+    );
+}
+
+#[test]
+fn constants_are_shown_by_value() {
+    let compiled = compile("print \"hi\";\nprint 2.5;");
+    let described = compiled.describe();
+    assert!(described.iter().any(|d| d.ends_with("(\"hi\")")));
+    assert!(described.iter().any(|d| d.ends_with("(2.5)")));
+}
+
+#[test]
+fn interpolation_skips_empty_pieces() {
+    let compiled = compile("var a = 1;\nvar b = 2;\nprint \"${a}${b}\";");
+    let described = compiled.describe();
+    assert!(
+        !described.iter().any(|d| d.ends_with("(\"\")")),
+        "{described:?}"
+    );
+    assert!(described.contains(&"Format(2)".to_owned()), "{described:?}");
+}
+
+#[test]
+fn locals_use_byte_wide_slots() {
+    let compiled = compile("fun f(a) {\nvar b = a;\nb = a + b;\nreturn b;\n}");
+    let f = compiled.function("f");
+    let described = compiled.describe_chunk(&f.chunk);
+    assert!(
+        described.contains(&"GetLocalByte(1)".to_owned()),
+        "{described:?}"
+    );
+    assert!(
+        described.contains(&"SetLocalByte(1)".to_owned()),
+        "{described:?}"
+    );
+    assert!(
+        !described
+            .iter()
+            .any(|d| d.starts_with("GetLocal(") || d.starts_with("SetLocal(")),
+        "{described:?}"
+    );
+
+    let size = |code: ByteCode| {
+        let mut chunk = Chunk::default();
+        chunk.push(code, 1);
+        chunk.size()
+    };
+    assert_eq!(size(ByteCode::get_local(1)), 2);
+    assert_eq!(size(ByteCode::GetLocal(1)), 5);
+    assert_eq!(size(ByteCode::set_local(300)), 5);
+}
+
+#[test]
+fn comparisons_against_literals_are_fused() {
+    for (literal, fused) in [("true", "EqTrue"), ("false", "EqFalse"), ("nil", "EqNil")] {
+        for (op, not) in [("==", vec![]), ("!=", vec!["Not"])] {
+            let compiled = compile(&format!("fun f(x) {{ return x {op} {literal}; }}"));
+            let described = compiled.describe_chunk(&compiled.function("f").chunk);
+            let expected: Vec<_> = ["GetLocalByte(0)", fused]
+                .into_iter()
+                .chain(not)
+                .chain(["Return"])
+                .collect();
+            assert_eq!(described[..expected.len()], expected, "{op} {literal}");
+        }
+    }
+}
+
+#[test]
+fn literals_in_larger_operands_are_not_fused() {
+    let compiled = compile("fun f(x) { return x == nil + 1; }");
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert!(described.contains(&"Eq".to_owned()), "{described:?}");
+    assert!(!described.contains(&"EqNil".to_owned()), "{described:?}");
+
+    let compiled = compile("fun f(x) { return x == true and false; }");
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert!(described.contains(&"EqTrue".to_owned()), "{described:?}");
+}
+
+#[test]
+fn adding_a_constant_is_fused() {
+    let compiled = compile("fun f(i) {\ni = i + 1;\ni = i - 2;\n}");
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert_eq!(
+        described[..6],
+        [
+            "GetLocalByte(0)",
+            "AddConst(0) (1)",
+            "SetLocalByte(0)",
+            "Pop",
+            "GetLocalByte(0)",
+            "SubConst(1) (2)",
+        ]
+    );
+}
+
+#[test]
+fn functions_record_their_declaration_line() {
+    let compiled = compile("var x = 1;\n\nfun f() {\n  fun g() {}\n}\n");
+    assert_eq!(compiled.function("f").def_line, 3);
+    assert_eq!(compiled.function("g").def_line, 4);
+    assert_eq!(compiled.script.def_line, 0);
+    assert!(
+        compiled
+            .describe()
+            .iter()
+            .any(|d| d.ends_with(", declared on line 3)")),
+        "{:?}",
+        compiled.describe()
+    );
+}
+
+/// Whether the function's calls were compiled as tail calls, in order
+fn tail_calls(source: &str, name: &str) -> Vec<bool> {
+    let compiled = compile(source);
+    compiled
+        .describe_chunk(&compiled.function(name).chunk)
+        .iter()
+        .filter_map(|d| match d.split('(').next() {
+            Some("Call") => Some(false),
+            Some("TailCall") => Some(true),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn only_returned_calls_are_tail_calls() {
+    let g = "fun g() { return 1; }\n";
+    assert_eq!(
+        tail_calls(&format!("{g}fun f() {{ return g(); }}"), "f"),
+        [true]
+    );
+    assert_eq!(
+        tail_calls(&format!("{g}fun f() {{ return g() + 1; }}"), "f"),
+        [false]
+    );
+    assert_eq!(
+        tail_calls(&format!("{g}fun f() {{ g(); return 1; }}"), "f"),
+        [false]
+    );
+    assert_eq!(
+        tail_calls(&format!("{g}fun f() {{ var x = g(); return g(); }}"), "f"),
+        [false, true]
+    );
+}
+
+#[test]
+fn tail_position_is_the_last_instruction_being_a_call() {
+    let mut chunk = Chunk::default();
+    assert!(!chunk.is_tail_position());
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push(ByteCode::Call(0), 1);
+    assert!(chunk.is_tail_position());
+    chunk.push(ByteCode::Pop, 1);
+    assert!(!chunk.is_tail_position());
+}
+
+#[test]
+fn literal_global_vals_are_inlined() {
+    let compiled = compile("val K = 10;\nprint K + K;");
+    let described = compiled.describe();
+    assert!(
+        !described.iter().any(|d| d.starts_with("GetGlobal")),
+        "{described:?}"
+    );
+    assert!(
+        described.iter().any(|d| d.ends_with("(10)")),
+        "{described:?}"
+    );
+}
+
+#[test]
+fn literal_local_vals_are_inlined() {
+    let compiled = compile("fun f() {\nval k = 2;\nprint k * k;\n}");
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert_eq!(
+        described[..4],
+        ["Constant(0) (2)", "Constant(0) (2)", "Mul", "Print"]
+    );
+
+    // Other vals, and vars, still live in a slot
+    let compiled = compile("fun g(a) {\nval k = a;\nvar v = 2;\nprint k * v;\n}");
+    let described = compiled.describe_chunk(&compiled.function("g").chunk);
+    assert!(
+        described.iter().any(|d| d.starts_with("GetLocal")),
+        "{described:?}"
+    );
+    let shadowed = "val k = 3;\nfun f() { val k = 2; { val k = \"in\"; print k; } print k * k; }\nf();\nprint k;";
+    assert_eq!(common::run(shadowed), "in\n4\n3\n");
+}
+
+#[test]
+fn match_arms_pop_and_jump_in_one_instruction() {
+    let source = "fun f(x) {\nmatch (x) {\n1 => print \"one\";\n\"a\" => print \"a\";\nnil => print \"nil\";\nelse => print \"other\";\n}\n}";
+    let compiled = compile(source);
+    let described = compiled.describe_chunk(&compiled.function("f").chunk);
+    assert_eq!(
+        described
+            .iter()
+            .filter(|d| d.starts_with("PopJump("))
+            .count(),
+        3,
+        "{described:?}"
+    );
+}
+
+#[test]
+fn repeated_global_reads_are_duplicated() {
+    let described = compile("var x = 2;\nprint x + x;").describe();
+    assert_eq!(
+        described[3..],
+        ["GetGlobal(29) (x)", "Dup", "Add", "Print", "Return"]
+    );
+
+    // Only reads right after each other are merged
+    let apart = compile("var x = 2;\nvar y = 3;\nprint x + y + x;").describe();
+    assert_eq!(
+        apart.iter().filter(|d| *d == "GetGlobal(29) (x)").count(),
+        2
+    );
+    assert!(!apart.contains(&"Dup".to_owned()), "{apart:?}");
+
+    // The right operand of ?: is jumped to, so it reads the global again
+    let jumped = compile("var x;\nprint x ?: x;").describe();
+    assert_eq!(
+        jumped.iter().filter(|d| *d == "GetGlobal(29) (x)").count(),
+        2
+    );
+    assert_eq!(
+        common::run("var x = 2;\nprint x + x;\nprint x * x;"),
+        "4\n4\n"
+    );
+}
+
+#[test]
+fn only_assigned_for_counters_are_checked() {
+    let checks = |source: &str| {
+        let compiled = compile(source);
+        let described = compiled.describe_chunk(&compiled.function("f").chunk);
+        described.iter().filter(|d| *d == "CheckCounter").count()
+    };
+    assert_eq!(
+        checks("fun f() { for (var i = 0; i < 3; i = i + 1) {} }"),
+        1
+    );
+    assert_eq!(checks("fun f() { for (var i = 0; i < 3; i += 1) {} }"), 1);
+    assert_eq!(
+        checks("fun f() { var j = 0; for (var i = 0; i < 3; j = j + 1) {} }"),
+        0
+    );
+    assert_eq!(checks("fun f() { for (var i = 0; i < 3; i + 1) {} }"), 0);
+    assert_eq!(
+        checks("fun f() { for (var i = 0; i < 3;) { i = i + 1; } }"),
+        0
+    );
+    assert_eq!(
+        checks("fun f() { var i = 0; for (; i < 3; i = i + 1) {} }"),
+        0
+    );
+}
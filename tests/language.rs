@@ -0,0 +1,1176 @@
+mod common;
+
+use common::{compile, compile_with, instructions, pipeline, run, run_err, run_in};
+use lox_rs::{chunk::ByteCode, value::MixedEquality};
+
+#[test]
+fn negative_literals_match() {
+    let source = "
+        fun describe(x) {
+            match (x) {
+                -1 => print \"minus one\";
+                1 => print \"one\";
+                else => print \"other\";
+            }
+        }
+        describe(-1);
+        describe(1);
+        describe(-2);
+    ";
+    assert_eq!(run(source), "minus one\none\nother\n");
+}
+
+#[test]
+fn negative_literals_are_single_constants() {
+    let compiled = compile("print -2.5;");
+    let code = instructions(&compiled.script.chunk);
+    assert!(!code.iter().any(|c| matches!(c, ByteCode::Negate)));
+    assert!(
+        compiled
+            .describe()
+            .contains(&"Constant(0) (-2.5)".to_owned()),
+        "{:?}",
+        compiled.describe()
+    );
+}
+
+#[test]
+fn negating_a_variable_still_negates() {
+    assert_eq!(run("var x = 3;\nprint -x;\nprint - -x;"), "-3\n3\n");
+}
+
+#[test]
+fn functions_can_recurse() {
+    let source = "
+        fun fact(n) { if (n < 2) return 1; return n * fact(n - 1); }
+        print fact(5);
+    ";
+    assert_eq!(run(source), "120\n");
+}
+
+#[test]
+fn local_functions_can_recurse() {
+    let source = "
+        fun outer() {
+            fun fact(n) { if (n < 2) return 1; return n * fact(n - 1); }
+            return fact(5);
+        }
+        print outer();
+        {
+            fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            print fib(10);
+        }
+    ";
+    assert_eq!(run(source), "120\n55\n");
+}
+
+#[test]
+fn local_function_names_cant_be_assigned_in_their_body() {
+    let errors = run_err("{ fun f() { f = 1; } }");
+    assert_eq!(errors, ["Variable f is not mutable"]);
+}
+
+#[test]
+fn bare_question_mark_is_an_error() {
+    assert_eq!(
+        run_err("print 1 ? 2;"),
+        ["The conditional operator '?' is not supported, did you mean '?:'?"]
+    );
+}
+
+#[test]
+fn not_equal_is_an_operator() {
+    assert_eq!(run("print 1 != 2; print 1 != 1;"), "true\nfalse\n");
+}
+
+#[test]
+fn elvis_binds_looser_than_arithmetic() {
+    assert_eq!(run("print 1 + 2 ?: 3;"), "3\n");
+    assert_eq!(run("print nil ?: 1 + 2;"), "3\n");
+    assert_eq!(run("print nil ?: nil ?: 4;"), "4\n");
+}
+
+#[test]
+fn elvis_binds_tighter_than_comparisons() {
+    assert_eq!(run("print nil ?: 2 == 2;"), "true\n");
+    assert_eq!(run("print 1 ?: 2 == 2;"), "false\n");
+}
+
+/// A function matching its argument against each of `cases`, printing the arm it took
+fn integer_match(cases: impl Iterator<Item = i32>) -> String {
+    let arms: String = cases
+        .map(|n| format!("{n} => print \"arm {n}\";\n"))
+        .collect();
+    format!("fun pick(x) {{ match (x) {{\n{arms}else => print \"else\";\n}} }}\n")
+}
+
+#[test]
+fn integer_matches_use_a_jump_table() {
+    let source = integer_match(0..100);
+    let compiled = compile(&source);
+    let code = instructions(&compiled.function("pick").chunk);
+    assert!(code
+        .iter()
+        .any(|c| matches!(c, ByteCode::JumpTable(0, 100))));
+
+    let calls: String = (-2..102).map(|n| format!("pick({n});\n")).collect();
+    let expected: String = (-2..102)
+        .map(|n| match n {
+            0..100 => format!("arm {n}\n"),
+            _ => "else\n".to_owned(),
+        })
+        .collect();
+    assert_eq!(run(&(source + &calls)), expected);
+}
+
+#[test]
+fn jump_tables_take_the_first_matching_arm() {
+    let source = "
+        fun pick(x) {
+            match (x) {
+                -1 | 1 => print \"odd\";
+                0 | 2 => print \"even\";
+                1 => print \"unreachable\";
+                3 => print \"three\";
+            }
+            print \"done\";
+        }
+        pick(-1); pick(1); pick(2); pick(3); pick(4); pick(1.5); pick(\"1\"); pick(nil);
+    ";
+    assert_eq!(
+        run(source),
+        "odd\ndone\nodd\ndone\neven\ndone\nthree\ndone\n".to_owned() + &"done\n".repeat(4)
+    );
+}
+
+#[test]
+fn sparse_or_mixed_matches_are_checked_in_order() {
+    let sparse = compile(&integer_match([1, 1000, 2000, 3000].into_iter()));
+    let mixed = compile(
+        "fun pick(x) { match (x) { 1 => print 1; 2 => print 2; 3 => print 3; \"4\" => print 4; } }",
+    );
+    for compiled in [sparse, mixed] {
+        let code = instructions(&compiled.function("pick").chunk);
+        assert!(!code.iter().any(|c| matches!(c, ByteCode::JumpTable(..))));
+    }
+}
+
+#[test]
+fn jump_tables_leave_other_values_to_equality() {
+    let (mut pipeline, output) = pipeline();
+    pipeline.equality = MixedEquality::Coerce;
+    let source = integer_match(0..10) + "pick(\"3\"); pick(true);";
+    let printed = common::run_in(&mut pipeline, &output, &source).unwrap();
+    assert_eq!(printed, "arm 3\narm 1\n");
+}
+
+#[test]
+fn else_must_be_the_last_match_arm() {
+    let source = "match (1) { else => print 0; 1 => print 1; }";
+    assert_eq!(
+        run_err(source),
+        ["The 'else' arm must be the last arm of a match"]
+    );
+    assert_eq!(run("match (2) { 1 => print 1; else => print 0; }"), "0\n");
+}
+
+#[test]
+fn generators_resume_where_they_yielded() {
+    let source = "
+        fun count(n) {
+            var i = 1;
+            while (i <= n) {
+                yield i;
+                i = i + 1;
+            }
+        }
+        var a = count(3);
+        var b = count(3);
+        print next(a);
+        print next(a);
+        print next(b);
+        print next(a);
+        print next(a);
+        print next(b);
+    ";
+    assert_eq!(run(source), "1\n2\n1\n3\nnil\n2\n");
+}
+
+#[test]
+fn next_is_an_ordinary_name() {
+    assert_eq!(run("var next = 1;\nprint next + 1;"), "2\n");
+    assert_eq!(
+        run("fun f() { var next = 1; return next; }\nprint f();"),
+        "1\n"
+    );
+    assert_eq!(
+        run_err("var next = 1;\nvar next = 2;"),
+        ["Variable 'next' already declared"]
+    );
+    let source = "
+        fun ones() { while (true) yield 1; }
+        fun take(next, gen) { return next(gen); }
+        print take(next, ones());
+        fun step(gen) { return next(gen); }
+        print step(ones());
+        var resume = next;
+        print resume(ones());
+    ";
+    assert_eq!(run(source), "1\n1\n1\n");
+    let errors = run_err("next(1);");
+    assert!(
+        errors[0].starts_with("Can only resume generators"),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn yield_outside_a_function_is_an_error() {
+    assert_eq!(run_err("yield 1;"), ["Cannot yield from top-level code"]);
+}
+
+#[test]
+fn assert_messages_are_only_evaluated_on_failure() {
+    let source = "
+        var evaluated = 0;
+        fun message() {
+            evaluated = evaluated + 1;
+            return \"failed\";
+        }
+        assert(1 == 1, message());
+        print evaluated;
+    ";
+    assert_eq!(run(source), "0\n");
+    assert_eq!(
+        run_err("assert(1 == 2, \"bad \" + \"thing\");"),
+        ["Assertion failed: bad thing"]
+    );
+    assert_eq!(run_err("assert(false);"), ["Assertion failed"]);
+}
+
+#[test]
+fn named_arguments_bind_by_name() {
+    let source = "
+        fun divide(a, b) { print a / b; }
+        divide(b: 2, a: 10);
+        divide(10, b: 5);
+    ";
+    assert_eq!(run(source), "5\n2\n");
+}
+
+#[test]
+fn bad_named_arguments_are_errors() {
+    let declare = "fun divide(a, b) { print a / b; }\n";
+    assert_eq!(
+        run_err(&format!("{declare}divide(a: 1, c: 2);")),
+        ["Unknown parameter 'c' for function 'divide'"]
+    );
+    assert_eq!(
+        run_err(&format!("{declare}divide(a: 1, a: 2);")),
+        ["Argument 'a' was given more than once"]
+    );
+    assert_eq!(
+        run_err(&format!("{declare}divide(a: 1, 2);")),
+        ["Positional arguments cannot follow named arguments"]
+    );
+}
+
+#[test]
+fn unknown_named_arguments_are_found_when_compiling() {
+    // Calls before the declaration are checked too, and every unknown name is reported
+    let source = "
+        fun early() { divide(c: 1, a: 2, d: 3); }
+        fun divide(a, b) { print a / b; }
+    ";
+    let diagnostics = lox_rs::diagnostics::collect(|| {
+        assert!(compile_with(source, |_| {}).is_err());
+    })
+    .1;
+    let errors: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.line))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            ("Unknown parameter 'c' for function 'divide'", Some(2)),
+            ("Unknown parameter 'd' for function 'divide'", Some(2)),
+        ]
+    );
+}
+
+#[test]
+fn unknown_named_arguments_of_unknown_callees_are_found_when_called() {
+    // Locals, and globals that may have been assigned another function, are only known at
+    // runtime
+    let local = "fun f() { fun g(a) {} g(b: 1); }\nf();";
+    let reassigned = "
+        fun g(a) {}
+        fun h(b) {}
+        fun call() { g(b: 1); }
+        g = h;
+        call();
+        fun h2(c) {}
+        g = h2;
+        g(b: 1);
+    ";
+    for source in [local, reassigned] {
+        let errors = run_err(source);
+        assert!(
+            errors[0].starts_with("Unknown parameter 'b' for <fn"),
+            "{errors:?}"
+        );
+    }
+    let fine = "
+        fun g(a) {}
+        fun h(b) { print b; }
+        fun call() { g(b: 1); }
+        g = h;
+        call();
+    ";
+    assert_eq!(run(fine), "1\n");
+}
+
+#[test]
+fn default_parameters_fill_omitted_arguments() {
+    let source = "
+        fun greet(name, greeting = \"Hello\") { print greeting + \", \" + name; }
+        greet(\"Bob\");
+        greet(\"Bob\", \"Hi\");
+        greet(greeting: \"Hey\", name: \"Al\");
+    ";
+    assert_eq!(run(source), "Hello, Bob\nHi, Bob\nHey, Al\n");
+}
+
+#[test]
+fn defaults_can_use_earlier_parameters() {
+    let source = "
+        fun area(width, height = width) { return width * height; }
+        print area(3);
+        print area(3, 4);
+    ";
+    assert_eq!(run(source), "9\n12\n");
+}
+
+#[test]
+fn defaults_widen_the_accepted_arity() {
+    let declare = "fun f(a, b = 1) {}\n";
+    assert_eq!(
+        run_err(&format!("{declare}f();")),
+        ["Expected 1 to 2 arguments but got 0"]
+    );
+    assert_eq!(
+        run_err(&format!("{declare}f(1, 2, 3);")),
+        ["Expected 1 to 2 arguments but got 3"]
+    );
+}
+
+#[test]
+fn top_level_functions_can_be_mutually_recursive() {
+    let source = "
+        fun even(n) { if (n == 0) return true; return odd(n - 1); }
+        fun odd(n) { if (n == 0) return false; return even(n - 1); }
+        print even(10);
+        print odd(7);
+        print even(3);
+    ";
+    assert_eq!(run(source), "true\ntrue\nfalse\n");
+}
+
+#[test]
+fn globals_that_are_never_declared_are_errors() {
+    let errors = run_err("fun f() { return missing; } print 1;");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("'missing'"), "{errors:?}");
+}
+
+#[test]
+fn or_evaluates_the_right_operand_only_when_needed() {
+    let source = r#"
+        var calls = 0;
+        fun side(value) {
+            calls = calls + 1;
+            return value;
+        }
+        print true or side(1);
+        print calls;
+        print false or side(2);
+        print nil or side(nil);
+        print calls;
+        print false or nil or side(3);
+        print (false or side(false)) or side(4);
+        print side(5) or side(6);
+        print calls;
+    "#;
+    assert_eq!(run(source), "true\n0\n2\nnil\n2\n3\n4\n5\n6\n");
+}
+
+#[test]
+fn or_leaves_only_its_result_on_the_stack() {
+    // A stray or missing value would shift the locals declared after the expressions
+    let source = r#"
+        fun f(x) {
+            var a = x or 1;
+            var b = nil or x;
+            var c = (x or false) or (nil or 2);
+            var d = "d";
+            print a;
+            print b;
+            print c;
+            print d;
+        }
+        f(nil);
+        f(7);
+    "#;
+    assert_eq!(run(source), "1\nnil\n2\nd\n7\n7\n7\nd\n");
+}
+
+#[test]
+fn while_binds_the_condition_value() {
+    let source = "
+        var n = 0;
+        fun step() {
+            n = n + 1;
+            if (n <= 3) return n * 10;
+            return nil;
+        }
+        while (var v = step()) print v;
+        print n;
+    ";
+    assert_eq!(run(source), "10\n20\n30\n4\n");
+}
+
+#[test]
+fn while_bindings_are_scoped_to_the_loop() {
+    let source = "
+        fun f() {
+            while (var v = false) print v;
+            print v;
+        }
+    ";
+    assert!(!run_err(source).is_empty());
+    assert_eq!(
+        run("fun f() { while (val v = nil) print v; var v = 1; print v; } f();"),
+        "1\n"
+    );
+}
+
+#[test]
+fn elvis_assignment_assigns_only_nil() {
+    let source = r#"
+        fun fallback(value) {
+            print "fallback";
+            return value;
+        }
+        var x;
+        x ?:= fallback(1);
+        print x;
+        x ?:= fallback(2);
+        print x;
+        fun f() {
+            var y = false;
+            y ?:= fallback(3);
+            print y;
+            var z = nil;
+            print z ?:= 4;
+        }
+        f();
+    "#;
+    assert_eq!(run(source), "fallback\n1\n1\nfalse\n4\n");
+}
+
+#[test]
+fn elvis_assignment_respects_mutability() {
+    assert_eq!(
+        run_err("val x = nil; x ?:= 1;"),
+        ["Variable x is not mutable"]
+    );
+}
+
+#[test]
+fn enum_members_are_symbols() {
+    let source = r#"
+        enum Color { Red, Green, Blue }
+        var c = Color.Green;
+        print c;
+        print c == Color.Green;
+        print c == Color.Red;
+        print c == "Color.Green";
+        fun name(color) {
+            match (color) {
+                Color.Red => return "red";
+                Color.Green => return "green";
+                else => return "other";
+            }
+        }
+        print name(c);
+        print name(Color.Red);
+        print name(Color.Blue);
+    "#;
+    assert_eq!(
+        run(source),
+        "Color.Green\ntrue\nfalse\nfalse\ngreen\nred\nother\n"
+    );
+}
+
+#[test]
+fn bad_enums_are_errors() {
+    assert_eq!(
+        run_err("enum E { A } print E.B;"),
+        ["Enum 'E' has no member 'B'"]
+    );
+    assert_eq!(
+        run_err("enum E { A, A }")[0],
+        "Duplicate member 'A' in enum 'E'"
+    );
+    assert_eq!(
+        run_err("fun f() { enum E { A } }"),
+        ["Enums must be declared at the top level"]
+    );
+}
+
+#[test]
+fn functions_without_a_value_return_nil() {
+    let source = "
+        fun fall_through(x) { var y = x + 1; }
+        fun empty_return(x) { if (x) return; print \"unreachable\"; }
+        fun empty() {}
+        print fall_through(1);
+        print empty_return(true);
+        print empty();
+        print fall_through(1) == nil;
+    ";
+    assert_eq!(run(source), "nil\nnil\nnil\ntrue\n");
+}
+
+#[test]
+fn underscore_can_be_redeclared() {
+    let source = "
+        fun f() {
+            var _ = 1;
+            var _ = 2;
+            _ = 3;
+            print \"ok\";
+        }
+        f();
+    ";
+    assert_eq!(run(source), "ok\n");
+}
+
+#[test]
+fn underscore_cant_be_read() {
+    assert_eq!(
+        run_err("fun f() { var _ = 1; print _; }"),
+        ["'_' can only be assigned to, not read"]
+    );
+    assert_eq!(
+        run_err("var _ = 1; print _ + 1;"),
+        ["'_' can only be assigned to, not read"]
+    );
+}
+
+#[test]
+fn interpolation_matches_concatenation() {
+    let source = r#"
+        var n = 3;
+        var s = "x";
+        var l = [1, "a"];
+        print "n=${n}, s=${s}, l=${l}, sum=${n + 1}${nil}${true}!";
+        print "n=" + n + ", s=" + s + ", l=" + l + ", sum=" + (n + 1) + nil + true + "!";
+        print "${n}";
+        print "" + n;
+    "#;
+    let printed = run(source);
+    let lines: Vec<_> = printed.lines().collect();
+    assert_eq!(lines[0], r#"n=3, s=x, l=[1, "a"], sum=4niltrue!"#);
+    assert_eq!(lines[0], lines[1]);
+    assert_eq!(lines[2], lines[3]);
+}
+
+#[test]
+fn val_globals_cant_be_assigned() {
+    for assignment in ["G = 2;", "G += 1;", "G -= 1;", "G *= 2;", "G ?:= 2;"] {
+        assert_eq!(
+            run_err(&format!("val G = 1; {assignment}")),
+            ["Variable G is not mutable"],
+            "{assignment}"
+        );
+        assert_eq!(
+            run_err(&format!("val G = 1; fun f() {{ {assignment} }}")),
+            ["Variable G is not mutable"],
+            "{assignment} in a function"
+        );
+    }
+    // There is no increment operator to get around it
+    assert!(!run_err("val G = 1; ++G;").is_empty());
+}
+
+#[test]
+fn compound_index_assignment_evaluates_the_index_once() {
+    let source = "
+        var calls = 0;
+        fun index() {
+            calls = calls + 1;
+            return 1;
+        }
+        var xs = [10, 20, 30];
+        xs[index()] += 5;
+        xs[index()] -= 1;
+        xs[index()] *= 2;
+        xs[index()] /= 4;
+        print xs;
+        print calls;
+        var x = 1;
+        x += 2;
+        print x;
+    ";
+    assert_eq!(run(source), "[10, 12, 30]\n4\n3\n");
+}
+
+#[test]
+fn tail_calls_dont_grow_the_call_stack() {
+    let source = "
+        fun count_down(n) {
+            if (n == 0) return \"done\";
+            return count_down(n - 1);
+        }
+        print count_down(1000000);
+    ";
+    assert_eq!(run(source), "done\n");
+}
+
+#[test]
+fn arithmetic_errors_name_the_operands() {
+    assert_eq!(
+        run_err("print nil + 1;"),
+        ["Cannot add nil 'nil' and number '1'"]
+    );
+    assert_eq!(
+        run_err("print true - \"a\";"),
+        ["Cannot subtract bool 'true' and string 'a'"]
+    );
+    assert_eq!(
+        run_err("print [1] * abs;"),
+        ["Cannot multiply list '[1]' and function '<native fn abs#1>'"]
+    );
+    let (mut pipeline, output) = pipeline();
+    let errors = run_in(&mut pipeline, &output, "var x = 1;\nprint x / nil;").unwrap_err();
+    assert_eq!(errors[0].message, "Cannot divide number '1' and nil 'nil'");
+    assert_eq!(errors[0].line, Some(2));
+}
+
+#[test]
+fn literal_global_vals_keep_their_value() {
+    assert_eq!(
+        run(
+            "val K = 10; val S = \"s\"; fun f() { return K * 2; } print K + K; print f(); print S;"
+        ),
+        "20\n20\ns\n"
+    );
+}
+
+#[test]
+fn ranges_can_be_iterated_and_queried() {
+    let source = r#"
+        fun show(r) {
+            var items = "";
+            for (var i = 0; i < len(r); i = i + 1) items = items + r[i] + ";";
+            print items;
+        }
+        var r = 0..5;
+        var s = 0..=5;
+        print r;
+        print s;
+        show(r);
+        show(s);
+        show(3..1);
+        print contains(r, 5);
+        print contains(s, 5);
+        print contains(r, 0);
+        print contains(r, -1);
+    "#;
+    assert_eq!(
+        run(source),
+        "0..5\n0..=5\n0;1;2;3;4;\n0;1;2;3;4;5;\n\nfalse\ntrue\ntrue\nfalse\n"
+    );
+}
+
+#[test]
+fn range_bounds_must_be_whole_numbers() {
+    assert_eq!(
+        run_err("print 0.5..2;"),
+        ["Range bounds must be whole numbers, found Number(0.5), Number(2.0)"]
+    );
+}
+
+#[test]
+fn spread_arguments_are_unpacked() {
+    let source = "
+        fun f(a, b, c) { return a + b * c; }
+        print f(...[1, 2, 3]);
+        print f(1, ...[2], 3);
+        print f(...[], 1, ...[2, 3]);
+        print abs(...[-2]);
+    ";
+    assert_eq!(run(source), "7\n7\n7\n2\n");
+}
+
+#[test]
+fn spread_arguments_are_checked() {
+    assert_eq!(
+        run_err("fun f(a, b, c) {} f(...[1, 2]);"),
+        ["Expected 3 arguments but got 2"]
+    );
+    assert_eq!(
+        run_err("fun f(a) {} f(...3);"),
+        ["Can only spread lists, found number '3'"]
+    );
+    assert_eq!(
+        run_err("fun f(a) {} f(...[1], a: 2);"),
+        ["Cannot mix named and spread arguments"]
+    );
+}
+
+#[test]
+fn floor_division_floors() {
+    assert_eq!(
+        run("print 7 / 2; print 7 ~/ 2; print -7 ~/ 2; print 7 ~/ -2; print 6 ~/ 3; print 7.5 ~/ 2;"),
+        "3.5\n3\n-4\n-4\n2\n3\n"
+    );
+    assert_eq!(run("print 1 + 7 ~/ 2 * 2;"), "7\n");
+    assert_eq!(
+        run_err("print 1 ~/ nil;"),
+        ["Cannot divide number '1' and nil 'nil'"]
+    );
+}
+
+#[test]
+fn match_arms_fall_through_to_the_next() {
+    let source = r#"
+        fun f(x) {
+            match (x) {
+                1 => print "one";
+                "a" => print "a";
+                nil => print "nil";
+                else => print "other";
+            }
+        }
+        f(1);
+        f("a");
+        f(nil);
+        f(2);
+        f([]);
+    "#;
+    assert_eq!(run(source), "one\na\nnil\nother\nother\n");
+}
+
+#[test]
+fn identity_differs_from_equality() {
+    let source = r#"
+        var a = [1, 2];
+        var b = [1, 2];
+        var c = a;
+        print a == b;
+        print a === b;
+        print a !== b;
+        print a === c;
+        print {"k": 1} === {"k": 1};
+        print abs === abs;
+        print 1 === 1.0;
+        print nil === nil;
+        print nil !== false;
+    "#;
+    assert_eq!(
+        run(source),
+        "true\nfalse\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\ntrue\n"
+    );
+}
+
+#[test]
+fn caught_errors_continue_in_the_catch_block() {
+    // Dividing by zero gives inf, so a type error stands in for a failing operation
+    let source = r#"
+        fun fail() { return [] - 1; }
+        fun f(x) {
+            var before = "before";
+            try {
+                var inside = 1;
+                print x + nil;
+                print "unreachable";
+            } catch (e) {
+                print "caught: " + e;
+            }
+            try { fail(); } catch (e) { print e; }
+            try { print "fine"; } catch (e) { print "unreachable"; }
+            print before;
+        }
+        f(1);
+        print "after";
+    "#;
+    assert_eq!(
+        run(source),
+        "caught: Cannot add number '1' and nil 'nil'\n\
+         Cannot subtract list '[]' and number '1'\n\
+         fine\nbefore\nafter\n"
+    );
+}
+
+#[test]
+fn errors_in_catch_blocks_reach_the_outer_handler() {
+    let source = r#"
+        try {
+            try { nil(); } catch (e) { print "inner"; [] + nil; }
+        } catch (e) {
+            print "outer: " + e;
+        }
+    "#;
+    assert_eq!(
+        run(source),
+        "inner\nouter: Cannot add list '[]' and nil 'nil'\n"
+    );
+    assert_eq!(
+        run_err("try { print 1; } catch (e) {} print nil + 1;"),
+        ["Cannot add nil 'nil' and number '1'"]
+    );
+}
+
+#[test]
+fn negation_flips_the_sign_of_zero_too() {
+    let source = "
+        var five = 5;
+        var five_point_oh = 5.0;
+        var zero = 0;
+        var min = -9223372036854775808;
+        print -five;
+        print -five_point_oh;
+        print -zero;
+        print -0;
+        print 1 / -zero;
+        print -min == 9223372036854775808;
+        print -(-2.5);
+    ";
+    assert_eq!(run(source), "-5\n-5\n-0\n-0\n-inf\ntrue\n2.5\n");
+}
+
+#[test]
+fn negating_a_non_number_is_an_error() {
+    assert_eq!(
+        run_err("var s = \"a\"; print -s;"),
+        ["Cannot negate string 'a'"]
+    );
+    assert_eq!(run_err("print -nil;"), ["Cannot negate nil 'nil'"]);
+}
+
+#[test]
+fn optimized_negation_matches() {
+    let (mut pipeline, output) = pipeline();
+    pipeline.optimize = true;
+    let source = "print -(0); print -(5); print -(2.5); print 1 / -0;";
+    assert_eq!(
+        run_in(&mut pipeline, &output, source).unwrap(),
+        "-0\n-5\n-2.5\n-inf\n"
+    );
+}
+
+#[test]
+fn functions_match_by_identity() {
+    let source = "
+        fun f() {}
+        fun g() {}
+        fun pick(h) {
+            match (h) {
+                f => print \"f\";
+                g => print \"g\";
+                else => print \"other\";
+            }
+        }
+        pick(f); pick(g); pick(len);
+        print f == f; print f == g;
+        {
+            fun f() {}
+            pick(f);
+            print f == f;
+        }
+    ";
+    assert_eq!(run(source), "f\ng\nother\ntrue\nfalse\nother\ntrue\n");
+}
+
+#[test]
+fn mixed_type_equality_follows_the_dialect() {
+    let source = "print 1 == \"1\"; print 0 == false; print \"a\" != 1; print 1 == nil;";
+    let mut printed = vec![];
+    for equality in [MixedEquality::False, MixedEquality::Coerce] {
+        let (mut pipeline, output) = pipeline();
+        pipeline.equality = equality;
+        printed.push(common::run_in(&mut pipeline, &output, source).unwrap());
+    }
+    assert_eq!(
+        printed,
+        ["false\nfalse\ntrue\nfalse\n", "true\ntrue\ntrue\nfalse\n"]
+    );
+
+    let (mut pipeline, output) = pipeline();
+    pipeline.equality = MixedEquality::Error;
+    let run_strict = |pipeline: &mut _, source| {
+        common::run_in(pipeline, &output, source)
+            .map_err(|diagnostics| diagnostics[0].message.clone())
+    };
+    assert_eq!(
+        run_strict(&mut pipeline, "print 1 == nil; print 1 == 2;"),
+        Ok("false\nfalse\n".to_owned())
+    );
+    let message = "Cannot compare number '1' and string 'a' for equality";
+    for source in [
+        "1 == \"a\";",
+        "print 1 == \"a\";",
+        "match (1) { \"a\" => print 1; }",
+    ] {
+        assert_eq!(run_strict(&mut pipeline, source), Err(message.to_owned()));
+    }
+    assert_eq!(
+        run_strict(&mut pipeline, "1 == true;"),
+        Err("Cannot compare number '1' and bool 'true' for equality".to_owned())
+    );
+}
+
+#[test]
+fn for_in_iterates_characters_lists_and_ranges() {
+    let source = "
+        for (c in \"aé→b\") print c + \"|\";
+        for (c in \"\") print \"never\";
+        for (x in [1, \"two\"]) print x;
+        for (i in 1..=3) print i;
+        var count = 0;
+        for (x in []) count = count + 1;
+        print count;
+    ";
+    assert_eq!(run(source), "a|\né|\n→|\nb|\n1\ntwo\n1\n2\n3\n0\n");
+    assert_eq!(
+        run("var lens = \"\"; for (c in \"é→\") lens = lens + len(c); print lens;"),
+        "11\n"
+    );
+}
+
+#[test]
+fn for_in_over_a_number_is_an_error() {
+    assert_eq!(
+        run_err("for (x in 5) print x;"),
+        ["Cannot iterate over number '5'"]
+    );
+}
+
+#[test]
+fn labeled_break_exits_both_loops() {
+    let source = "
+        var i = 0;
+        outer: while (i < 3) {
+            for (var j = 0; j < 3; j = j + 1) {
+                if (j == 1) continue;
+                if (i == 1) break outer;
+                print \"${i} ${j}\";
+            }
+            i = i + 1;
+        }
+        print \"done ${i}\";
+    ";
+    assert_eq!(run(source), "0 0\n0 2\ndone 1\n");
+}
+
+#[test]
+fn labeled_continue_skips_the_outer_iteration() {
+    let source = "
+        rows: for (var i = 0; i < 3; i = i + 1) {
+            var row = \"row\";
+            for (var j = 0; j < 3; j = j + 1) {
+                if (j > i) continue rows;
+                print \"${i} ${j}\";
+            }
+        }
+    ";
+    assert_eq!(run(source), "0 0\n1 0\n1 1\n2 0\n2 1\n2 2\n");
+}
+
+#[test]
+fn invalid_break_and_continue_targets_are_errors() {
+    assert_eq!(
+        run_err("while (true) { break missing; }"),
+        ["No enclosing loop is labeled 'missing'"]
+    );
+    assert_eq!(
+        run_err("a: while (true) { fun f() { while (true) { break a; } } }"),
+        ["No enclosing loop is labeled 'a'"]
+    );
+    assert_eq!(run_err("x: print 1;"), ["Expected a loop after label 'x'"]);
+    assert_eq!(
+        run_err("continue;"),
+        ["Can't use 'continue' outside of a loop"]
+    );
+    assert_eq!(
+        run_err("a: while (true) { a: while (true) {} }"),
+        ["Label 'a' is already used by an enclosing loop"]
+    );
+}
+
+#[test]
+fn for_counters_too_large_to_increment_are_errors() {
+    let source = "
+        for (var i = 9007199254740990; i < 9007199254740996; i = i + 1) print i;
+    ";
+    let (mut pipeline, output) = pipeline();
+    let errors = common::run_in(&mut pipeline, &output, source).unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "For loop counter stopped changing at 9007199254740992, numbers this large are too \
+         imprecise for its increment"
+    );
+    assert_eq!(
+        output.take(),
+        "9007199254740990\n9007199254740991\n9007199254740992\n"
+    );
+
+    let compound = "fun f() { for (var i = 9007199254740992; i >= 0; i += 1) {} } f();";
+    assert_eq!(run_err(compound).len(), 1);
+}
+
+#[test]
+fn for_counters_are_only_checked_when_incremented() {
+    // The increment doesn't assign the counter, so it isn't checked
+    let source = "
+        var j = 0;
+        for (var i = 9007199254740992; j < 2; j = j + 1) print i;
+        for (var k = 0; k < 2; k = k) { print k; k = k + 1; }
+    ";
+    assert_eq!(run(source), "9007199254740992\n9007199254740992\n0\n1\n");
+}
+
+#[test]
+fn multiple_return_values_are_unpacked() {
+    let source = "
+        fun divmod(a, b) { return a ~/ b, a - b * (a ~/ b); }
+        fun f() { var q, r = divmod(7, 2); val s, t = divmod(8, 3); print q; print r; print s + t; }
+        f();
+        var a, b = divmod(9, 4);
+        print a; print b;
+        print divmod(1, 1);
+        fun one() { return 1; }
+        print one();
+    ";
+    assert_eq!(run(source), "3\n1\n4\n2\n1\n[1, 0]\n1\n");
+}
+
+#[test]
+fn unpacking_mismatches_are_errors() {
+    assert_eq!(
+        run_err("var x, y = [1];"),
+        ["Expected 2 values to unpack, found 1"]
+    );
+    assert_eq!(
+        run_err("fun f() { var x, y = 5; } f();"),
+        ["Can only unpack a list, found number"]
+    );
+    assert_eq!(
+        run_err("val p, q = [1, 2];"),
+        ["Global vals can't be unpacked, as they must be initialized with a literal"]
+    );
+}
+
+#[test]
+fn whole_number_keys_match_however_they_are_written() {
+    let source = "var m = {}; m[1] = \"a\"; print m[1.0]; m[-0] = \"z\"; print m[0]; print m;";
+    assert_eq!(run(source), "a\nz\n{1: \"a\", 0: \"z\"}\n");
+}
+
+#[test]
+fn non_finite_map_keys_are_errors() {
+    let nan = "Map keys must be finite numbers, found NaN";
+    assert_eq!(run_err("var m = {}; m[0/0] = 1;"), [nan]);
+    assert_eq!(run_err("var m = {0/0: 1};"), [nan]);
+    assert_eq!(
+        run_err("var m = {}; print m[1/0];"),
+        ["Map keys must be finite numbers, found inf"]
+    );
+    // Even when the map is never used
+    assert_eq!(run_err("[{0/0: 1}];"), [nan]);
+    assert_eq!(
+        run_err("[{-1/0: 1}];"),
+        ["Map keys must be finite numbers, found -inf"]
+    );
+}
+
+#[test]
+fn line_and_function_pseudo_variables() {
+    let source = "\n\n\n\n\n\nprint __line__;\nfun f() {\n    print __func__ + \":\" + __line__;\n}\nf();\nprint __func__;";
+    assert_eq!(run(source), "7\nf:9\n<script>\n");
+    assert_eq!(
+        run_err("__line__ = 3;"),
+        ["Variable __line__ is not mutable"]
+    );
+}
+
+#[test]
+fn raw_strings_are_taken_as_written() {
+    assert_eq!(
+        run(r"print `C:\path\no\escapes`;"),
+        "C:\\path\\no\\escapes\n"
+    );
+    let source = "var a = 1;\nprint `one \"${a}\"\ntwo`;\nprint -nil;";
+    let (mut pipeline, output) = pipeline();
+    let errors = common::run_in(&mut pipeline, &output, source).unwrap_err();
+    assert_eq!(output.take(), "one \"${a}\"\ntwo\n");
+    // Lines after a multi-line raw string are counted right
+    assert_eq!(errors[0].line, Some(4));
+    assert_eq!(run("val RAW = `x`; print RAW + `` + RAW;"), "xx\n");
+    assert_eq!(run_err("print `open;")[0], "Unterminated string");
+}
+
+#[test]
+fn assignment_chains_right_to_left() {
+    let source = "
+        var a; var b;
+        print a = b = 5;
+        print a; print b;
+        fun f() { var c; var d; var e = c = d = 7; print c + d + e; }
+        f();
+        var m = [0, 0];
+        var x = m[0] = m[1] = 3;
+        print x; print m;
+        a += b = 2;
+        print a; print b;
+    ";
+    assert_eq!(run(source), "5\n5\n5\n21\n3\n[3, 3]\n7\n2\n");
+    assert_eq!(
+        run_err("var a; var b; a + b = 5;")[0],
+        "Left hand side of the assignment is not assignable"
+    );
+}
+
+#[test]
+fn nan_compares_false_and_matches_nothing() {
+    let source = "
+        var n = 0/0;
+        print n < 1; print n > 1; print n <= 1; print n >= 1;
+        print 1 < n; print 1 >= n;
+        print n == n; print n != n;
+        fun describe(x) {
+            match (x) {
+                0 => print \"zero\";
+                1 => print \"one\";
+                2 => print \"two\";
+                else => print \"other\";
+            }
+        }
+        describe(n);
+        match (n) { n => print \"itself\"; else => print \"not itself\"; }
+    ";
+    let expected = "false\nfalse\nfalse\nfalse\nfalse\nfalse\nfalse\ntrue\nother\nnot itself\n";
+    assert_eq!(run(source), expected);
+
+    let (mut pipeline, output) = pipeline();
+    pipeline.optimize = true;
+    assert_eq!(run_in(&mut pipeline, &output, source).unwrap(), expected);
+    let folded = "print 0/0 < 1; print 0/0 >= 1; print 0/0 == 0/0;";
+    assert_eq!(
+        run_in(&mut pipeline, &output, folded).unwrap(),
+        "false\nfalse\nfalse\n"
+    );
+}
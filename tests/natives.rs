@@ -0,0 +1,429 @@
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+
+use common::{pipeline, run, run_err, run_in};
+use lox_rs::{native::NATIVES, value::Value};
+
+#[test]
+fn abs_sign_and_clamp() {
+    assert_eq!(run("print abs(-2.5); print abs(3);"), "2.5\n3\n");
+    assert_eq!(
+        run("print sign(-3); print sign(0); print sign(7);"),
+        "-1\n0\n1\n"
+    );
+    assert_eq!(
+        run("print clamp(5, 0, 3); print clamp(-1, 0, 3); print clamp(2, 0, 3);"),
+        "3\n0\n2\n"
+    );
+}
+
+#[test]
+fn clamp_rejects_crossed_bounds() {
+    assert_eq!(
+        run_err("clamp(1, 3, 0);"),
+        ["clamp: Lower bound 3 is above upper bound 0"]
+    );
+    assert_eq!(
+        run_err("clamp(1, 0, 0/0);"),
+        ["clamp: Lower bound 0 is above upper bound NaN"]
+    );
+}
+
+#[test]
+fn casing_round_trips() {
+    assert_eq!(
+        run("print upper(\"Hello\"); print lower(\"HeLLo\"); print lower(upper(\"abc\"));"),
+        "HELLO\nhello\nabc\n"
+    );
+    assert_eq!(run("print upper(\"straße\");"), "STRASSE\n");
+}
+
+#[test]
+fn equality_ignoring_case() {
+    assert_eq!(
+        run("print eq_ignore_case(\"Lox\", \"lOX\"); print eq_ignore_case(\"Lox\", \"Lax\");"),
+        "true\nfalse\n"
+    );
+    assert_eq!(run("print \"Lox\" == \"lox\";"), "false\n");
+}
+
+/// Runs the source with the filesystem natives declared
+fn run_with_fs(source: &str) -> Result<String, Vec<String>> {
+    let (mut pipeline, output) = pipeline();
+    pipeline.allow_filesystem = true;
+    run_in(&mut pipeline, &output, source)
+        .map_err(|diagnostics| diagnostics.into_iter().map(|d| d.message).collect())
+}
+
+#[test]
+fn write_file_then_read_file() {
+    let path = std::env::temp_dir().join(format!("lox-rs-natives-{}.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    let printed = run_with_fs(&format!(
+        r#"write_file("{path}", "hello, file"); print read_file("{path}");"#
+    ));
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(printed.unwrap(), "hello, file\n");
+}
+
+#[test]
+fn read_file_reports_the_os_error() {
+    let path = std::env::temp_dir().join("lox-rs-natives-missing/none.txt");
+    let path = path.to_str().unwrap();
+    let errors = run_with_fs(&format!(r#"print read_file("{path}");"#)).unwrap_err();
+    assert!(
+        errors[0].starts_with(&format!("read_file: Could not read '{path}': ")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn filesystem_natives_need_the_flag() {
+    assert!(!run_err(r#"print read_file("x");"#).is_empty());
+}
+
+#[test]
+fn round_to_rounds_half_to_even() {
+    // 2.675 scales to exactly 267.5, which goes to the even 268
+    assert_eq!(run("print round_to(2.675, 2);"), "2.68\n");
+    assert_eq!(
+        run("print round_to(0.125, 2); print round_to(2.5, 0); print round_to(3.5, 0);"),
+        "0.12\n2\n4\n"
+    );
+    assert_eq!(
+        run("print round_to(12345, -2); print round_to(250, -2);"),
+        "12300\n200\n"
+    );
+}
+
+#[test]
+fn round_to_needs_integer_digits() {
+    assert_eq!(
+        run_err("round_to(1, 0.5);"),
+        ["round_to: Digits must be an integer, found 0.5"]
+    );
+    assert!(!run_err("round_to(1, \"2\");").is_empty());
+}
+
+#[test]
+fn integer_predicates() {
+    assert_eq!(
+        run("print is_integer(2.0); print is_integer(2.5); print is_integer(1/0);"),
+        "true\nfalse\nfalse\n"
+    );
+    assert_eq!(
+        run("print is_even(4); print is_even(-3); print is_odd(-3); print is_odd(0);"),
+        "true\nfalse\ntrue\nfalse\n"
+    );
+}
+
+#[test]
+fn parity_needs_an_integer() {
+    assert_eq!(
+        run_err("is_even(2.5);"),
+        ["is_even: Expected a whole number, found 2.5"]
+    );
+    assert_eq!(
+        run_err("is_odd(0/0);"),
+        ["is_odd: Expected a whole number, found NaN"]
+    );
+}
+
+#[test]
+fn json_round_trips_nested_values() {
+    let source = r#"
+        var v = {"a": [1, 2.5, {"b": nil}], "c": "x", "d": [true, false, []]};
+        var j = to_json(v);
+        print j;
+        var back = from_json(j);
+        print back;
+        print to_json(back) == j;
+    "#;
+    assert_eq!(
+        run(source),
+        concat!(
+            r#"{"a":[1,2.5,{"b":null}],"c":"x","d":[true,false,[]]}"#,
+            "\n",
+            r#"{"a": [1, 2.5, {"b": nil}], "c": "x", "d": [true, false, []]}"#,
+            "\ntrue\n"
+        )
+    );
+}
+
+#[test]
+fn json_errors() {
+    assert_eq!(
+        run_err("to_json([abs]);"),
+        ["to_json: Can't convert function '<native fn abs#1>' to JSON"]
+    );
+    assert_eq!(
+        run_err("to_json(0/0);"),
+        ["to_json: NaN can't be represented in JSON"]
+    );
+    assert_eq!(
+        run_err(r#"from_json("[1,");"#),
+        ["from_json: Unexpected end of JSON"]
+    );
+}
+
+#[test]
+fn padding_strings() {
+    assert_eq!(
+        run(
+            r#"print "[" + pad_left("ab", 5, ".") + "]"; print "[" + pad_right("ab", 5, "é") + "]";"#
+        ),
+        "[...ab]\n[abééé]\n"
+    );
+    assert_eq!(
+        run(r#"print "[" + pad_left("ab", 4) + "]"; print "[" + pad_right("ab", 4) + "]";"#),
+        "[  ab]\n[ab  ]\n"
+    );
+    assert_eq!(
+        run(r#"print pad_left("abcdef", 3, "."); print pad_right("héllo", 5, ".");"#),
+        "abcdef\nhéllo\n"
+    );
+}
+
+#[test]
+fn padding_rejects_bad_arguments() {
+    assert_eq!(
+        run_err(r#"pad_left("a", 3, "ab");"#),
+        ["pad_left: Fill must be a single character, found 'ab'"]
+    );
+    assert_eq!(
+        run_err(r#"pad_right("a", -1);"#),
+        ["pad_right: Width must be a non-negative integer, found -1"]
+    );
+    assert_eq!(
+        run_err(r#"pad_left("a", 10000000000000000000, " ");"#),
+        ["pad_left: Width must be at most 1000000, found 10000000000000000000"]
+    );
+    assert!(!run_err(r#"pad_left(1, 3);"#).is_empty());
+}
+
+#[test]
+fn replace_and_trim() {
+    assert_eq!(
+        run(r#"print replace("a-b-c", "-", "+"); print replace("héllo héllo", "é", "e");"#),
+        "a+b+c\nhello hello\n"
+    );
+    assert_eq!(run(r#"print replace("abc", "x", "y");"#), "abc\n");
+    assert_eq!(
+        run("print \"[\" + trim(\"  padded \t\n\") + \"]\"; print \"[\" + trim(\"\") + \"]\";"),
+        "[padded]\n[]\n"
+    );
+    assert_eq!(
+        run_err(r#"replace("abc", "", "x");"#),
+        ["replace: Can't replace an empty string"]
+    );
+}
+
+#[test]
+fn parse_int_across_radices() {
+    assert_eq!(
+        run(
+            r#"print parse_int("ff", 16); print parse_int("-101", 2); print parse_int("+z", 36); print parse_int("042", 10);"#
+        ),
+        "255\n-5\n35\n42\n"
+    );
+    assert_eq!(
+        run(
+            r#"print parse_int(" 1", 10); print parse_int("12a", 10); print parse_int("", 10); print parse_int("2", 2);"#
+        ),
+        "nil\nnil\nnil\nnil\n"
+    );
+    assert_eq!(
+        run_err(r#"parse_int("1", 1);"#),
+        ["parse_int: Radix must be an integer from 2 to 36, found 1"]
+    );
+    assert_eq!(
+        run_err(r#"parse_int("1", 2.5);"#),
+        ["parse_int: Radix must be an integer from 2 to 36, found 2.5"]
+    );
+}
+
+#[test]
+fn parse_float_rejects_non_decimals() {
+    assert_eq!(
+        run(r#"print parse_float("1.5"); print parse_float("-2e3"); print parse_float("1.");"#),
+        "1.5\n-2000\n1\n"
+    );
+    assert_eq!(
+        run(
+            r#"print parse_float("inf"); print parse_float("NaN"); print parse_float(" 1"); print parse_float("1,5");"#
+        ),
+        "nil\nnil\nnil\nnil\n"
+    );
+}
+
+#[test]
+fn gc_frees_cyclic_lists() {
+    let gc = NATIVES.iter().find(|native| native.name == "gc").unwrap();
+    let sentinel = Rc::new(RefCell::new(vec![]));
+    {
+        // A list holding the sentinel and itself, dropped as soon as it's made
+        let cycle = Value::new_list(vec![Value::List(Rc::clone(&sentinel))]);
+        let Value::List(list) = &cycle else {
+            unreachable!()
+        };
+        list.borrow_mut().push(cycle.clone());
+    }
+    assert_eq!(Rc::strong_count(&sentinel), 2);
+    assert!(matches!((gc.func)(&[]), Ok(Value::Number(n)) if n >= 1.0));
+    assert_eq!(Rc::strong_count(&sentinel), 1);
+}
+
+#[test]
+fn gc_keeps_reachable_cycles() {
+    let source = "
+        fun leak() { var a = []; var m = {}; a = [a, m]; m[\"self\"] = m; m[\"a\"] = a; }
+        var kept = {\"n\": 2};
+        kept[\"self\"] = kept;
+        gc();
+        leak();
+        print gc() > 0;
+        print kept[\"self\"][\"n\"];
+    ";
+    assert_eq!(run(source), "true\n2\n");
+}
+
+#[test]
+fn approx_eq_compares_within_a_tolerance() {
+    assert_eq!(run("print 0.1 + 0.2 == 0.3;"), "false\n");
+    assert_eq!(run("print approx_eq(0.1 + 0.2, 0.3);"), "true\n");
+    assert_eq!(run("print approx_eq(1, 1.001);"), "false\n");
+    assert_eq!(run("print approx_eq(1, 1.001, 0.01);"), "true\n");
+    assert_eq!(run("print approx_eq(1, 2, 0);"), "false\n");
+    assert_eq!(run("print approx_eq(1 / 0, 1 / 0);"), "true\n");
+    assert_eq!(run("print approx_eq(1 / 0, -1 / 0, 1000000);"), "false\n");
+    assert_eq!(
+        run_err("print approx_eq(1, 2, -1);"),
+        ["approx_eq: Tolerance must not be negative, found -1"]
+    );
+    assert_eq!(
+        run_err("print approx_eq(\"a\", 1);"),
+        ["Expected a number, found string 'a'"]
+    );
+    assert_eq!(
+        run_err("print approx_eq(1);"),
+        ["Expected 2 to 3 arguments but got 1"]
+    );
+}
+
+#[test]
+fn format_substitutes_arguments_in_order() {
+    assert_eq!(
+        run("print format(\"{} + {} = {}\", 1, 2.5, \"3.5\");"),
+        "1 + 2.5 = 3.5\n"
+    );
+    assert_eq!(run("print format(\"{}\", [1, \"a\"]);"), "[1, \"a\"]\n");
+    assert_eq!(run("print format(\"none\");"), "none\n");
+    assert_eq!(run("print format(\"{{}} {{{}}}\", 7);"), "{} {7}\n");
+}
+
+#[test]
+fn format_checks_placeholders() {
+    assert_eq!(
+        run_err("format(\"{} {}\", 1);"),
+        ["format: Format string has 2 placeholders but 1 arguments were given"]
+    );
+    assert_eq!(
+        run_err("format(\"{}\", 1, 2);"),
+        ["format: Format string has 1 placeholders but 2 arguments were given"]
+    );
+    assert_eq!(
+        run_err("format(\"a } b\");"),
+        ["format: Unmatched '}' in format string, use '}}' for a literal one"]
+    );
+    assert_eq!(
+        run_err("format(1);"),
+        ["Expected a string, found number '1'"]
+    );
+}
+
+#[test]
+fn sort_and_sorted_order_numbers_and_strings() {
+    assert_eq!(
+        run("var l = [3, -1, 2.5, 0]; var s = sorted(l); print s; print l;"),
+        "[-1, 0, 2.5, 3]\n[3, -1, 2.5, 0]\n"
+    );
+    assert_eq!(
+        run("var l = [\"pear\", \"Apple\", \"apple\"]; print sort(l); print l;"),
+        "nil\n[\"Apple\", \"apple\", \"pear\"]\n"
+    );
+    assert_eq!(run("print sorted([]);"), "[]\n");
+}
+
+#[test]
+fn sorting_mixed_types_is_an_error() {
+    assert_eq!(
+        run_err("sorted([1, \"a\"]);"),
+        ["sorted: Can't compare number '1' with string 'a'"]
+    );
+    assert_eq!(
+        run_err("sort([\"a\", 1]);"),
+        ["sort: Can't compare string 'a' with number '1'"]
+    );
+}
+
+#[test]
+fn binary_search_finds_indices() {
+    let source = "
+        var l = [1, 3, 5, 7];
+        print binary_search(l, 1);
+        print binary_search(l, 7);
+        print binary_search(l, 4);
+        print binary_search([], 1);
+    ";
+    assert_eq!(run(source), "0\n3\n-1\n-1\n");
+    assert_eq!(run("print binary_search([\"a\", \"c\"], \"c\");"), "1\n");
+}
+
+#[test]
+fn eval_returns_the_final_expression() {
+    assert_eq!(run("print eval(\"1 + 2\");"), "3\n");
+    assert_eq!(run("print eval(\"print 1;\");"), "1\nnil\n");
+    assert_eq!(
+        run("var inner = \"3 * 3\"; print eval(\"eval(inner)\");"),
+        "9\n"
+    );
+}
+
+#[test]
+fn eval_shares_globals() {
+    let source = "
+        eval(\"var g = 10;\");
+        print eval(\"g * 2\");
+        var x = 1;
+        eval(\"x = 5;\");
+        print x;
+    ";
+    assert_eq!(run(source), "20\n5\n");
+
+    let (mut pipeline, _) = pipeline();
+    pipeline
+        .interpret_repl_line("eval(\"var later = 4;\");")
+        .unwrap();
+    assert_eq!(
+        pipeline.interpret_repl_line("later * 2").unwrap(),
+        Some(Value::Number(8.0))
+    );
+}
+
+#[test]
+fn eval_errors_are_runtime_errors() {
+    let source = "
+        try { eval(\"1 +\"); } catch (e) { print \"caught \" + e; }
+        try { eval(\"nil + 1\"); } catch (e) { print \"caught \" + e; }
+    ";
+    let (mut pipeline, output) = pipeline();
+    assert_eq!(
+        run_in(&mut pipeline, &output, source).unwrap(),
+        "caught eval: The source failed to compile\ncaught Cannot add nil 'nil' and number '1'\n"
+    );
+    assert_eq!(
+        run_err("eval(\"1 +\");").last().unwrap(),
+        "eval: The source failed to compile"
+    );
+}
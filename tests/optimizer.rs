@@ -0,0 +1,176 @@
+mod common;
+
+use common::{compile, instructions, pipeline, run, run_chunk, run_in, script};
+use lox_rs::{
+    chunk::{ByteCode, Chunk, ConstantTable},
+    optimizer::Optimizer,
+    value::Value,
+};
+
+/// An optimizer that runs none of its passes
+const NO_PASSES: Optimizer = Optimizer {
+    dead_pops: false,
+    fold_constants: false,
+    fuse_not: false,
+    thread_jumps: false,
+};
+
+/// The script's instructions after running the optimizer over it, and those of the named
+/// function if given
+fn optimized(source: &str, optimizer: Optimizer, function: Option<&str>) -> Vec<String> {
+    let mut compiled = compile(source);
+    let script = optimizer.optimize_function(&compiled.script, &mut compiled.constants);
+    match function {
+        Some(name) => {
+            let func = compiled.function(name);
+            compiled.describe_chunk(&func.chunk)
+        }
+        None => compiled.describe_chunk(&script.chunk),
+    }
+}
+
+fn unoptimized(source: &str, function: Option<&str>) -> Vec<String> {
+    optimized(source, NO_PASSES, function)
+}
+
+#[test]
+fn no_passes_leaves_the_chunk_alone() {
+    let source = "var x = 2 * 3; print !(x == 6);";
+    assert_eq!(unoptimized(source, None), compile(source).describe());
+}
+
+#[test]
+fn dead_pops_are_removed() {
+    use ByteCode::*;
+    let mut chunk = Chunk::default();
+    let mut constants = ConstantTable::default();
+    let one = constants.push(Value::Number(1.0));
+    for code in [Constant(one as u8), Pop, True, Dup, Pop, Pop, Nil, Return] {
+        chunk.push(code, 1);
+    }
+    let only_dead_pops = Optimizer {
+        dead_pops: true,
+        ..NO_PASSES
+    };
+    let func = only_dead_pops.optimize_function(&script(chunk), &mut constants);
+    let codes = instructions(&func.chunk);
+    assert!(matches!(codes[..], [Nil, Return]), "{codes:?}");
+}
+
+#[test]
+fn constants_are_folded() {
+    let source = "var x = 2 * 3 + 1;";
+    let only_folding = Optimizer {
+        fold_constants: true,
+        ..NO_PASSES
+    };
+    assert_eq!(
+        unoptimized(source, None)[..4],
+        [
+            "Constant(0) (2)",
+            "Constant(1) (3)",
+            "Mul",
+            "AddConst(2) (1)"
+        ]
+    );
+    let after = optimized(source, only_folding, None);
+    assert_eq!(after[0], "Constant(4) (7)");
+    assert_eq!(after[1], "SetGlobal(29) (x)");
+}
+
+#[test]
+fn comparisons_are_fused_with_not() {
+    let source = "var a = 1; print !(a == 2); print !(a > 2); print !(a < 2);";
+    let only_fusing = Optimizer {
+        fuse_not: true,
+        ..NO_PASSES
+    };
+    let before = unoptimized(source, None);
+    let after = optimized(source, only_fusing, None);
+    assert_eq!(before.iter().filter(|code| *code == "Not").count(), 3);
+    assert!(!after.contains(&"Not".to_string()));
+    for fused in ["NotEq", "NotGt", "NotLt"] {
+        assert!(after.contains(&fused.to_string()), "{after:?}");
+    }
+    assert_eq!(after.len(), before.len() - 3);
+}
+
+#[test]
+fn jumps_to_jumps_are_threaded() {
+    use ByteCode::*;
+    // Jumps to a jump back to the instruction right after the first jump
+    let chain = || {
+        let mut chunk = Chunk::default();
+        for code in [JumpRelative(5), Nil, Return, JumpRelative(-2)] {
+            chunk.push(code, 1);
+        }
+        chunk
+    };
+    let only_threading = Optimizer {
+        thread_jumps: true,
+        ..NO_PASSES
+    };
+    let mut constants = ConstantTable::default();
+    let func = only_threading.optimize_function(&script(chain()), &mut constants);
+    let codes = instructions(&func.chunk);
+    assert!(matches!(codes[..2], [Nil, Return]), "{codes:?}");
+    assert!(matches!(
+        run_chunk(func.chunk, ConstantTable::default()),
+        Ok(Value::Nil)
+    ));
+    assert!(matches!(
+        run_chunk(chain(), ConstantTable::default()),
+        Ok(Value::Nil)
+    ));
+}
+
+#[test]
+fn optimized_programs_print_the_same() {
+    let source = "
+        fun f(n) { var a = n; a; return !(a == 2 * 3); }
+        var i = 0;
+        while (i < 3) { if (i > 0) { if (f(i)) print i; else print -i; } i = i + 1; }
+        print f(6);
+    ";
+    let (mut optimizing, output) = pipeline();
+    optimizing.optimize = true;
+    assert_eq!(
+        run_in(&mut optimizing, &output, source).unwrap(),
+        run(source)
+    );
+}
+
+#[test]
+fn jumps_to_the_next_instruction_are_padded_then_dropped() {
+    let source = "var x = 1; match (x) { 1 => {} else => {} } print x;";
+    let mut compiled = compile(source);
+    let padded = compiled.describe();
+    assert!(
+        padded.windows(3).any(|w| w == ["Nop", "Nop", "Nop"]),
+        "{padded:?}"
+    );
+
+    let optimized =
+        Optimizer::default().optimize_function(&compiled.script, &mut compiled.constants);
+    let shrunk = compiled.describe_chunk(&optimized.chunk);
+    assert!(!shrunk.contains(&"Nop".to_owned()), "{shrunk:?}");
+    assert!(optimized.chunk.size() < compiled.script.chunk.size());
+
+    let (mut optimizing, output) = pipeline();
+    optimizing.optimize = true;
+    assert_eq!(run_in(&mut optimizing, &output, source).unwrap(), "1\n");
+    assert_eq!(run(source), "1\n");
+}
+
+#[test]
+fn nops_do_nothing() {
+    use ByteCode::*;
+    let mut chunk = Chunk::default();
+    for code in [True, Nop, Nop, Return] {
+        chunk.push(code, 1);
+    }
+    assert!(matches!(
+        run_chunk(chunk, ConstantTable::default()),
+        Ok(Value::Bool(true))
+    ));
+}
@@ -0,0 +1,332 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{pipeline, run_in, Output};
+use lox_rs::{
+    diagnostics::Severity,
+    native::{report_native_error, NativeResult},
+    pipeline::Pipeline,
+    value::Value,
+    vm::InterpretError,
+};
+
+#[test]
+fn repl_lines_return_trailing_expressions() {
+    let (mut pipeline, output) = pipeline();
+    let value = pipeline.interpret_repl_line("1+2").unwrap();
+    assert_eq!(value, Some(Value::Number(3.0)));
+    assert_eq!(output.take(), "");
+}
+
+#[test]
+fn repl_lines_without_an_expression_return_nothing() {
+    let (mut pipeline, output) = pipeline();
+    assert_eq!(pipeline.interpret_repl_line("var x=1;").unwrap(), None);
+    assert_eq!(output.take(), "");
+    assert_eq!(
+        pipeline.interpret_repl_line("print x; x + 1").unwrap(),
+        Some(Value::Number(2.0))
+    );
+    assert_eq!(output.take(), "1\n");
+}
+
+#[test]
+fn eprint_writes_to_the_error_output() {
+    let (mut pipeline, output) = pipeline();
+    let errors = Output::default();
+    pipeline.set_error_output(errors.clone());
+    let printed = run_in(
+        &mut pipeline,
+        &output,
+        "print 1; eprint 2; eprint \"three\";",
+    );
+    assert_eq!(printed.unwrap(), "1\n");
+    assert_eq!(errors.take(), "2\nthree\n");
+}
+
+#[test]
+fn strict_mode_rejects_reading_unassigned_variables() {
+    let source = "var x; print x; { var y; print y; }";
+    let (mut normal, output) = pipeline();
+    assert_eq!(run_in(&mut normal, &output, source).unwrap(), "nil\nnil\n");
+
+    let (mut strict, output) = pipeline();
+    strict.strict = true;
+    let errors = run_in(&mut strict, &output, source).unwrap_err();
+    assert_eq!(errors[0].message, "Variable read before being assigned");
+    assert_eq!(errors[0].line, Some(1));
+
+    let (mut strict, output) = pipeline();
+    strict.strict = true;
+    let errors = run_in(&mut strict, &output, "{ var y; print y; }").unwrap_err();
+    assert_eq!(errors[0].message, "Variable read before being assigned");
+}
+
+#[test]
+fn globals_read_before_their_declaration_runs_are_errors() {
+    let (mut pipeline, output) = pipeline();
+    let source = "fun f() { print x; } f(); var x;";
+    let errors = run_in(&mut pipeline, &output, source).unwrap_err();
+    assert_eq!(errors[0].message, "Variable read before being assigned");
+
+    let source = "fun g() { print y; } var y; g();";
+    assert_eq!(run_in(&mut pipeline, &output, source).unwrap(), "nil\n");
+}
+
+#[test]
+fn trace_shows_each_instruction_in_order() {
+    let (mut pipeline, output) = pipeline();
+    pipeline.trace = true;
+    let traced = run_in(&mut pipeline, &output, "print 1 + 2;").unwrap();
+    let opcodes: Vec<&str> = traced
+        .lines()
+        .filter_map(|line| line.split_once(": ").map(|(_, code)| code))
+        .collect();
+    assert_eq!(
+        opcodes,
+        ["Constant(0) (1)", "AddConst(1) (2)", "Print", "Return"]
+    );
+    assert!(traced.contains("        [ 1 ]\n"), "{traced}");
+    assert!(traced.contains("        [ 3 ]\n"), "{traced}");
+    assert!(traced.contains("\n3\n"), "{traced}");
+}
+
+#[test]
+fn compiled_scripts_can_run_many_times() {
+    let (mut pipeline, output) = pipeline();
+    run_in(&mut pipeline, &output, "var count = 0;").unwrap();
+    let script = pipeline.compile("count = count + 1; print count;").unwrap();
+
+    pipeline.run(&script).unwrap();
+    pipeline.run(&script).unwrap();
+    assert_eq!(output.take(), "1\n2\n");
+
+    run_in(&mut pipeline, &output, "count = 10;").unwrap();
+    pipeline.run(&script).unwrap();
+    assert_eq!(output.take(), "11\n");
+}
+
+/// The messages of the warnings the pipeline reported since they were last taken
+fn warnings(pipeline: &mut Pipeline) -> Vec<String> {
+    pipeline
+        .take_diagnostics()
+        .into_iter()
+        .filter(|d| d.severity == Severity::Warning)
+        .map(|d| d.message)
+        .collect()
+}
+
+#[test]
+fn compiler_options_are_passed_on() {
+    let shadowing = "var x = 1; fun f() { var x = 2; return x; } print f();";
+    let mixed = "fun g(a) { if (a) return 1; return; } print g(true);";
+    let elif = "var y = 2; if (y == 1) print 1; elif (y == 2) print 2;";
+
+    let (mut plain, output) = pipeline();
+    assert_eq!(run_in(&mut plain, &output, shadowing).unwrap(), "2\n");
+    assert!(warnings(&mut plain).is_empty());
+    assert!(run_in(&mut plain, &output, elif).is_err());
+
+    let (mut pipeline, output) = pipeline();
+    pipeline.warn_shadowing = true;
+    pipeline.warn_mixed_returns = true;
+    pipeline.allow_elif = true;
+    assert_eq!(run_in(&mut pipeline, &output, shadowing).unwrap(), "2\n");
+    assert_eq!(warnings(&mut pipeline), ["Local 'x' shadows a global"]);
+    assert_eq!(run_in(&mut pipeline, &output, mixed).unwrap(), "1\n");
+    assert_eq!(warnings(&mut pipeline).len(), 1);
+    assert_eq!(run_in(&mut pipeline, &output, elif).unwrap(), "2\n");
+}
+
+#[test]
+fn compiler_options_apply_to_incremental_compiles() {
+    let (mut pipeline, _) = pipeline();
+    pipeline.warn_shadowing = true;
+    pipeline.allow_elif = true;
+    let source = "var x = 1;\nfun f() { var x = 2; if (x == 1) print 1; elif (x == 2) print 2; }\n";
+    assert!(pipeline.compile_incremental(source).is_ok());
+    assert_eq!(warnings(&mut pipeline), ["Local 'x' shadows a global"]);
+
+    pipeline.warnings_as_errors = true;
+    assert!(pipeline
+        .compile_incremental("var z = 1;\nfun g() { var z = 2; }\n")
+        .is_err());
+}
+
+#[test]
+fn profile_counts_each_opcode() {
+    let (mut pipeline, output) = pipeline();
+    let errors = Output::default();
+    pipeline.set_error_output(errors.clone());
+    pipeline.profile = true;
+    let source =
+        "fun f() { var s = 0; for (var i = 0; i < 25; i = i + 1) s = s + i; return s; } print f();";
+    assert_eq!(run_in(&mut pipeline, &output, source).unwrap(), "300\n");
+
+    let profile = errors.take();
+    let count = |name: &str| {
+        profile
+            .lines()
+            .find_map(|l| match l.split_whitespace().collect::<Vec<_>>()[..] {
+                [count, n] if n == name => count.parse::<u64>().ok(),
+                _ => None,
+            })
+    };
+    assert!(profile.starts_with("== PROFILE ==\n"), "{profile}");
+    assert_eq!(count("Add"), Some(25), "{profile}");
+    assert_eq!(count("Print"), Some(1), "{profile}");
+}
+
+fn double(args: &[Value]) -> NativeResult {
+    match args[0] {
+        Value::Number(n) => Ok(Value::Number(n * 2.0)),
+        _ => report_native_error("double", "Expected a number"),
+    }
+}
+
+#[test]
+fn registered_natives_are_callable() {
+    let (mut pipeline, output) = pipeline();
+    assert!(pipeline.register_native("double", 1, double));
+    assert_eq!(
+        run_in(&mut pipeline, &output, "print double(21); print double;").unwrap(),
+        "42\n<native fn double#1>\n"
+    );
+    let errors = run_in(&mut pipeline, &output, "double(\"x\");").unwrap_err();
+    assert_eq!(errors[0].message, "double: Expected a number");
+    let errors = run_in(&mut pipeline, &output, "double(1, 2);").unwrap_err();
+    assert_eq!(errors[0].message, "Expected 1 arguments but got 2");
+
+    // They survive a reset, and names can't be taken twice
+    pipeline.reset();
+    assert_eq!(
+        run_in(&mut pipeline, &output, "print double(2);").unwrap(),
+        "4\n"
+    );
+    assert!(!pipeline.register_native("double", 1, double));
+    assert!(!pipeline.register_native("abs", 1, double));
+}
+
+#[test]
+fn declarations_of_the_last_script_are_reported() {
+    let (mut pipeline, _) = pipeline();
+    pipeline
+        .interpret_repl_line("var x = 5; val k = \"a\"; fun f() {}")
+        .unwrap();
+    let declared: Vec<_> = pipeline
+        .declared_by_last_script()
+        .into_iter()
+        .map(|(name, value)| format!("{name} = {value}"))
+        .collect();
+    assert_eq!(declared.len(), 3);
+    assert_eq!(declared[..2], ["x = 5", "f = <fn f#0 (size: 2)>"]);
+    assert_eq!(declared[2], "k = a");
+
+    pipeline.interpret_repl_line("x = 6; var y;").unwrap();
+    let declared = pipeline.declared_by_last_script();
+    assert_eq!(declared, [("y", Value::Nil)]);
+}
+
+#[test]
+fn infinite_loops_time_out() {
+    let (mut pipeline, output) = pipeline();
+    let source = "var n = 0; while (true) { try { n = n + 1; } catch (e) {} }";
+    let script = pipeline.compile(source).unwrap();
+    let start = Instant::now();
+    let result = pipeline.run_with_timeout(&script, Duration::from_millis(50));
+    assert!(matches!(result, Err(InterpretError::Runtime)));
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+    let errors = pipeline.take_diagnostics();
+    assert_eq!(errors.last().unwrap().message, "Timed out");
+    assert_eq!(output.take(), "");
+
+    // The deadline only applies to that run
+    let script = pipeline.compile("print 1;").unwrap();
+    assert!(pipeline.run(&script).is_ok());
+    assert!(pipeline
+        .run_with_timeout(&script, Duration::from_secs(60))
+        .is_ok());
+    assert_eq!(output.take(), "1\n1\n");
+}
+
+#[test]
+fn incremental_compiles_reuse_unchanged_declarations() {
+    let (mut pipeline, output) = pipeline();
+    pipeline.hide_disassembly = true;
+    let before = "fun a() { return 1; }\nfun b() { return 2; }\nfun c() { return 3; }\n";
+    let after = "fun a() { return 1; }\nfun b() { return 20; }\nfun c() { return 3; }\n";
+    let main = "print a() + b() + c();\n";
+
+    let scripts = pipeline
+        .compile_incremental(&(before.to_owned() + main))
+        .unwrap();
+    assert_eq!(scripts.len(), 4);
+    assert_eq!(pipeline.last_recompiled(), 4);
+
+    assert!(pipeline
+        .compile_incremental(&(before.to_owned() + main))
+        .is_ok());
+    assert_eq!(pipeline.last_recompiled(), 0);
+
+    let scripts = pipeline
+        .compile_incremental(&(after.to_owned() + main))
+        .unwrap();
+    assert_eq!(pipeline.last_recompiled(), 1);
+    for script in &scripts {
+        pipeline.run(script).unwrap();
+    }
+    assert_eq!(output.take(), "24\n");
+}
+
+#[test]
+fn incremental_compiles_report_lines_in_the_whole_source() {
+    let (mut pipeline, output) = pipeline();
+    let source = "var x = 1;\n\nfun f() {\n  return x +;\n}\nprint 1 +;\n";
+    assert!(pipeline.compile_incremental(source).is_err());
+    let lines: Vec<_> = pipeline.take_diagnostics().iter().map(|d| d.line).collect();
+    assert_eq!(lines, [Some(4), Some(6)]);
+
+    let scripts = pipeline
+        .compile_incremental("var y = 1;\n\n\nprint y + nil;\n")
+        .unwrap();
+    for script in &scripts[..1] {
+        pipeline.run(script).unwrap();
+    }
+    assert!(pipeline.run(&scripts[1]).is_err());
+    assert_eq!(pipeline.take_diagnostics()[0].line, Some(4));
+    assert_eq!(output.take(), "");
+}
+
+#[test]
+fn incremental_edits_reuse_the_slots_of_replaced_functions() {
+    let (mut pipeline, output) = pipeline();
+    let version = |n: usize| {
+        format!(
+            "fun f() {{ fun g() {{ return {}; }} return g(); }}\nprint f();\n",
+            n % 2
+        )
+    };
+    let compile_and_run = |pipeline: &mut Pipeline, source: &str| {
+        for script in pipeline.compile_incremental(source).unwrap() {
+            pipeline.run(&script).unwrap();
+        }
+    };
+
+    compile_and_run(&mut pipeline, &version(0));
+    compile_and_run(&mut pipeline, &version(1));
+    let size = pipeline.constants().len();
+    for n in 2..50 {
+        compile_and_run(&mut pipeline, &version(n));
+        // Failed compiles don't keep their functions either
+        assert!(pipeline
+            .compile_incremental("fun f() { fun g() { return 1 +; } }\n")
+            .is_err());
+    }
+    assert_eq!(pipeline.constants().len(), size);
+    assert_eq!(output.take().lines().last(), Some("1"));
+}
@@ -0,0 +1,84 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Feeds the input to the REPL, returning what it printed
+fn repl(flags: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lox-rs"))
+        .args(flags)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn has_line(output: &str, line: &str) -> bool {
+    output.lines().any(|l| l == line)
+}
+
+#[test]
+fn env_lists_declared_globals() {
+    let output = repl(&[], "var x = 5;\n:env\n");
+    assert!(has_line(&output, "x = 5"));
+    assert!(has_line(&output, "abs = <native fn abs#1>"));
+}
+
+#[test]
+fn clear_forgets_globals() {
+    let output = repl(&[], "var x = 5;\n:clear\n:env\nprint x;\n");
+    assert!(!has_line(&output, "x = 5"));
+    assert!(output.contains("'x'"));
+}
+
+#[test]
+fn globals_persist_across_lines() {
+    let output = repl(&[], "var x = 5;\nprint x + 1;\n");
+    assert!(has_line(&output, "6"));
+}
+
+#[test]
+fn help_and_unknown_commands() {
+    let output = repl(&[], ":help\n:nope\n");
+    assert!(has_line(
+        &output,
+        ":env    List all globals and their values"
+    ));
+    assert!(has_line(&output, "Unknown command ':nope', try :help"));
+}
+
+#[test]
+fn expressions_are_echoed() {
+    let output = repl(&[], "1+2\nvar x=1;\nx\n");
+    assert!(has_line(&output, "=> 3"));
+    assert!(has_line(&output, "=> 1"));
+    assert_eq!(output.matches("=> ").count(), 2);
+}
+
+#[test]
+fn declarations_are_echoed_with_echo_on() {
+    let input =
+        "var x = 5;\nfun f() {}\nval k = 2;\nvar y = x + 1; y * 2\nprint nil + 1; var z = 1;\n";
+    let output = repl(&["--echo"], input);
+    for line in ["x = 5", "<fn f>", "k = 2", "y = 6", "=> 12"] {
+        assert!(has_line(&output, line), "{output}");
+    }
+    // Declarations echo before the line's result, and not at all if the line failed
+    assert!(output.find("y = 6").unwrap() < output.find("=> 12").unwrap());
+    assert!(!has_line(&output, "z = 1"));
+
+    let output = repl(&[], "var x = 5;\n:echo\nvar y = 6;\n:echo\nvar z = 7;\n");
+    assert!(!has_line(&output, "x = 5"));
+    assert!(has_line(&output, "Echoing declarations is on"));
+    assert!(has_line(&output, "y = 6"));
+    assert!(has_line(&output, "Echoing declarations is off"));
+    assert!(!has_line(&output, "z = 7"));
+}
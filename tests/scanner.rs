@@ -0,0 +1,228 @@
+mod common;
+
+use common::{compile_with, run, run_err};
+use lox_rs::scanner::{ScanErrorKind, TokenScanner, TokenType};
+
+#[test]
+fn scan_all_returns_tokens() {
+    let (tokens, errors) = TokenScanner::scan_all("var x = 1;");
+    let types: Vec<_> = tokens.iter().map(|t| t.ttype).collect();
+    assert_eq!(
+        types,
+        [
+            TokenType::Var,
+            TokenType::Ident,
+            TokenType::Equal,
+            TokenType::Number,
+            TokenType::Semi,
+        ]
+    );
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn scan_all_collects_errors() {
+    let (_, errors) = TokenScanner::scan_all("var x = @;\nprint \"open");
+    let kinds: Vec<_> = errors.iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            ScanErrorKind::UnexpectedCharacter,
+            ScanErrorKind::UnterminatedString,
+        ]
+    );
+    assert_eq!(errors[0].token.lexeme, "@");
+    assert_eq!(errors[0].token.line, 1);
+    assert_eq!(errors[0].to_string(), "Unexpected character '@'");
+    assert_eq!(errors[1].token.line, 2);
+}
+
+#[test]
+fn stray_closing_brace_is_scanned() {
+    let (tokens, errors) = TokenScanner::scan_all("}");
+    assert_eq!(tokens[0].ttype, TokenType::RBrace);
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn stray_closing_brace_is_a_compile_error() {
+    assert!(compile_with("print 1; }", |_| {}).is_err());
+    assert!(!run_err("print 1; }").is_empty());
+}
+
+#[test]
+fn spans_slice_the_source() {
+    let source = "var name = \"héllo\"; // done\nprint name;";
+    let (tokens, _) = TokenScanner::scan_all(source);
+    for token in &tokens {
+        assert_eq!(token.span.text(source), token.lexeme);
+    }
+    assert_eq!(tokens[3].span.text(source), "héllo");
+    let print = tokens.iter().find(|t| t.ttype == TokenType::Print).unwrap();
+    assert_eq!(print.span.text_with_trivia(source), " // done\nprint");
+    let from_name = &source[tokens[1].span.start..tokens[3].span.end];
+    assert_eq!(from_name, "name = \"héllo");
+}
+
+#[test]
+fn scan_errors_fail_the_compile() {
+    assert!(compile_with("print @;", |_| {}).is_err());
+    assert!(run_err("print @;")
+        .iter()
+        .any(|e| e.contains("Unexpected character '@'")));
+}
+
+/// The types and lexemes of the tokens scanned from the source
+fn scan(source: &str) -> Vec<(TokenType, &str)> {
+    let (tokens, errors) = TokenScanner::scan_all(source);
+    assert!(errors.is_empty(), "{errors:?}");
+    tokens.iter().map(|t| (t.ttype, t.lexeme)).collect()
+}
+
+#[test]
+fn numbers_can_start_or_end_with_a_dot() {
+    assert_eq!(scan(".5"), [(TokenType::Number, ".5")]);
+    assert_eq!(scan("5."), [(TokenType::Number, "5.")]);
+    assert_eq!(run("print .5 + 5.;"), "5.5\n");
+}
+
+#[test]
+fn dots_before_names_are_still_dots() {
+    assert_eq!(
+        scan("obj.x"),
+        [
+            (TokenType::Ident, "obj"),
+            (TokenType::Dot, "."),
+            (TokenType::Ident, "x"),
+        ]
+    );
+}
+
+#[test]
+fn single_character_tokens_scan_to_their_types() {
+    assert_eq!(
+        scan("( ) [ ] ; , | : { }"),
+        [
+            (TokenType::LParen, "("),
+            (TokenType::RParen, ")"),
+            (TokenType::LBracket, "["),
+            (TokenType::RBracket, "]"),
+            (TokenType::Semi, ";"),
+            (TokenType::Comma, ","),
+            (TokenType::Bar, "|"),
+            (TokenType::Colon, ":"),
+            (TokenType::LBrace, "{"),
+            (TokenType::RBrace, "}"),
+        ]
+    );
+    // Characters that can start a longer token still go through the match
+    assert_eq!(
+        scan("+ - * / . ! = < > ?"),
+        [
+            (TokenType::Plus, "+"),
+            (TokenType::Minus, "-"),
+            (TokenType::Star, "*"),
+            (TokenType::Slash, "/"),
+            (TokenType::Dot, "."),
+            (TokenType::Bang, "!"),
+            (TokenType::Equal, "="),
+            (TokenType::Less, "<"),
+            (TokenType::Greater, ">"),
+            (TokenType::Question, "?"),
+        ]
+    );
+    assert_eq!(
+        scan("(a,b);"),
+        [
+            (TokenType::LParen, "("),
+            (TokenType::Ident, "a"),
+            (TokenType::Comma, ","),
+            (TokenType::Ident, "b"),
+            (TokenType::RParen, ")"),
+            (TokenType::Semi, ";"),
+        ]
+    );
+}
+
+#[test]
+fn comments_are_kept_as_tokens_on_request() {
+    let source = "#!/usr/bin/env lox\nvar x = 1; // one\n//\n// whole line\nprint x;";
+    let mut scanner = TokenScanner::from_source(source);
+    scanner.keep_comments = true;
+    let comments: Vec<_> = scanner
+        .filter(|t| t.ttype == TokenType::Comment)
+        .map(|t| (t.lexeme, t.line))
+        .collect();
+    assert_eq!(
+        comments,
+        [
+            ("#!/usr/bin/env lox", 1),
+            ("// one", 2),
+            ("//", 3),
+            ("// whole line", 4),
+        ]
+    );
+
+    // They are skipped by default
+    let (tokens, _) = TokenScanner::scan_all(source);
+    assert!(tokens.iter().all(|t| t.ttype != TokenType::Comment));
+    assert_eq!(tokens.last().unwrap().line, 5);
+    assert_eq!(run(source), "1\n");
+}
+
+#[test]
+fn every_keyword_scans_to_its_type() {
+    use TokenType::*;
+    let keywords = [
+        ("and", And),
+        ("assert", Assert),
+        ("break", Break),
+        ("class", Class),
+        ("catch", Catch),
+        ("continue", Continue),
+        ("else", Else),
+        ("eprint", EPrint),
+        ("enum", Enum),
+        ("false", False),
+        ("for", For),
+        ("fun", Fun),
+        ("if", If),
+        ("match", Match),
+        ("nil", Nil),
+        ("or", Or),
+        ("print", Print),
+        ("return", Return),
+        ("super", Super),
+        ("this", This),
+        ("true", True),
+        ("try", Try),
+        ("var", Var),
+        ("val", Val),
+        ("while", While),
+        ("yield", Yield),
+    ];
+    for (keyword, ttype) in keywords {
+        assert_eq!(scan(keyword), [(ttype, keyword)]);
+        // Anything that only starts or ends like a keyword is an identifier
+        let extended = format!("{keyword}e");
+        let prefixed = format!("_{keyword}");
+        let upper = keyword.to_uppercase();
+        let truncated = &keyword[..keyword.len() - 1];
+        for ident in [&extended, &prefixed, &upper, truncated] {
+            assert_eq!(scan(ident), [(Ident, ident)]);
+        }
+    }
+    for ident in ["forge", "fo", "iff", "nill", "x", "in", "z"] {
+        assert_eq!(scan(ident), [(Ident, ident)]);
+    }
+}
+
+#[test]
+fn a_leading_shebang_is_skipped() {
+    assert_eq!(
+        scan("#!/usr/bin/env lox\nprint"),
+        [(TokenType::Print, "print")]
+    );
+    let (_, errors) = TokenScanner::scan_all("print 1;\n#!/usr/bin/env lox");
+    assert_eq!(errors[0].to_string(), "Unexpected character '#'");
+}
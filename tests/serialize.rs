@@ -0,0 +1,133 @@
+mod common;
+
+use std::rc::Rc;
+
+use common::{compile_with, run_chunk};
+use lox_rs::{
+    chunk::{ByteCode, Chunk, ConstantTable, SerializeError},
+    value::{FuncObj, Value},
+};
+
+/// Serializes a chunk made of the instructions, with the constants
+fn serialize(code: &[ByteCode], constants: &[Value]) -> Vec<u8> {
+    let mut table = ConstantTable::default();
+    for value in constants {
+        table.push(value.clone());
+    }
+    let mut chunk = Chunk::default();
+    for &bytecode in code {
+        chunk.push(bytecode, 1);
+    }
+    chunk.to_bytes(&table).unwrap()
+}
+
+fn read_error(bytes: &[u8]) -> SerializeError {
+    Chunk::from_bytes(bytes).expect_err("bytes should be rejected")
+}
+
+#[test]
+fn programs_with_functions_round_trip() {
+    let source = "
+        fun double(x, y = 1) { return x * 2 * y; }
+        var items = [double(1), double(2, 3)];
+        double(items[0] + items[1])
+    ";
+    let compiled = compile_with(source, |c| c.allow_expression_result = true).unwrap();
+    let bytes = compiled.script.chunk.to_bytes(&compiled.constants).unwrap();
+    let (chunk, constants) = Chunk::from_bytes(&bytes).unwrap();
+    assert_eq!(chunk.to_bytes(&constants).unwrap(), bytes);
+    assert_eq!(run_chunk(chunk, constants).unwrap(), Value::Number(28.0));
+}
+
+#[test]
+fn chunks_start_with_a_header() {
+    let bytes = serialize(&[ByteCode::Nil, ByteCode::Return], &[Value::Number(1.5)]);
+    assert_eq!(bytes[..4], *b"LOXC");
+    let (chunk, constants) = Chunk::from_bytes(&bytes).unwrap();
+    assert_eq!(chunk.to_bytes(&constants).unwrap(), bytes);
+    assert_eq!(constants.values(), [Value::Number(1.5)]);
+}
+
+#[test]
+fn wrong_magic_is_rejected() {
+    let mut bytes = serialize(&[ByteCode::Return], &[]);
+    bytes[0] = b'J';
+    assert_eq!(read_error(&bytes), SerializeError::InvalidMagic);
+    assert_eq!(read_error(b"LOX"), SerializeError::InvalidMagic);
+    assert_eq!(read_error(b""), SerializeError::InvalidMagic);
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let mut bytes = serialize(&[ByteCode::Return], &[]);
+    let version = bytes[4];
+    bytes[4] = version + 1;
+    assert_eq!(
+        read_error(&bytes),
+        SerializeError::UnsupportedVersion(version + 1)
+    );
+    bytes[4] = 0;
+    assert_eq!(read_error(&bytes), SerializeError::UnsupportedVersion(0));
+    assert_eq!(read_error(b"LOXC"), SerializeError::UnexpectedEnd);
+}
+
+#[test]
+fn unknown_opcodes_are_rejected() {
+    let mut bytes = serialize(&[ByteCode::Nil, ByteCode::Return], &[]);
+    *bytes.last_mut().unwrap() = 0xFF;
+    assert_eq!(read_error(&bytes), SerializeError::InvalidOpcode(0xFF));
+}
+
+#[test]
+fn missing_operands_are_rejected() {
+    let mut bytes = serialize(&[ByteCode::Return, ByteCode::Return], &[]);
+    // A Constant without its index
+    *bytes.last_mut().unwrap() = 0x01;
+    assert_eq!(read_error(&bytes), SerializeError::UnexpectedEnd);
+}
+
+#[test]
+fn missing_constants_are_rejected() {
+    let bytes = serialize(&[ByteCode::Constant(1)], &[Value::Nil]);
+    assert_eq!(read_error(&bytes), SerializeError::ConstantOutOfRange(1));
+    let bytes = serialize(&[ByteCode::ConstantLong(7)], &[]);
+    assert_eq!(read_error(&bytes), SerializeError::ConstantOutOfRange(7));
+}
+
+#[test]
+fn missing_globals_are_rejected() {
+    let bytes = serialize(&[ByteCode::GetGlobal(3)], &[]);
+    assert_eq!(read_error(&bytes), SerializeError::GlobalOutOfRange(3));
+}
+
+#[test]
+fn jumps_must_land_on_instructions() {
+    let bytes = serialize(&[ByteCode::Nil, ByteCode::JumpRelative(100)], &[]);
+    assert_eq!(read_error(&bytes), SerializeError::InvalidJump(1));
+    // Into the middle of the ConstantLong
+    let bytes = serialize(
+        &[ByteCode::ConstantLong(0), ByteCode::JumpF(-3)],
+        &[Value::Nil],
+    );
+    assert_eq!(read_error(&bytes), SerializeError::InvalidJump(5));
+    let bytes = serialize(&[ByteCode::Nil, ByteCode::JumpTable(0, 1)], &[]);
+    assert_eq!(read_error(&bytes), SerializeError::InvalidJump(1));
+}
+
+#[test]
+fn entry_points_must_be_instructions() {
+    let mut chunk = Chunk::default();
+    chunk.push(ByteCode::ConstantLong(0), 1);
+    chunk.push(ByteCode::Return, 1);
+    let func = FuncObj {
+        arity: 1,
+        chunk,
+        name: Some("f".into()),
+        def_line: 1,
+        params: vec!["x".into()],
+        entry_points: vec![0, 2],
+        is_generator: false,
+    };
+    let bytes = serialize(&[], &[Value::Func(Rc::new(func))]);
+    assert_eq!(read_error(&bytes), SerializeError::InvalidEntryPoint(2));
+}
@@ -0,0 +1,16 @@
+use lox_rs::util::PrevPeekable;
+
+#[test]
+fn peeking_ahead_does_not_advance() {
+    let mut items = PrevPeekable::from(1..=4);
+    assert_eq!(items.peek_second(), Some(&2));
+    assert_eq!(items.peek_nth(2), Some(&3));
+    assert_eq!(items.peek_nth(4), None);
+    assert_eq!(items.peek(), Some(&1));
+    assert_eq!(items.prev(), None);
+
+    assert_eq!(items.next(), Some(1));
+    assert_eq!(items.prev(), Some(&1));
+    assert_eq!(items.peek_second(), Some(&3));
+    assert_eq!(items.collect::<Vec<_>>(), [2, 3, 4]);
+}
@@ -0,0 +1,190 @@
+mod common;
+
+use std::rc::Rc;
+
+use common::{compile, run, run_err};
+use lox_rs::{value::Value, vm::InterpretError};
+
+#[test]
+fn nested_lists_and_maps_print() {
+    assert_eq!(
+        run(r#"print [[1, [2]], {"x": [3]}, "s"];"#),
+        "[[1, [2]], {\"x\": [3]}, \"s\"]\n"
+    );
+}
+
+#[test]
+fn self_containing_list_prints() {
+    assert_eq!(
+        run(r#"var a = [1, "s"]; a[0] = a; print a;"#),
+        "[[...], \"s\"]\n"
+    );
+}
+
+#[test]
+fn self_containing_map_prints() {
+    assert_eq!(
+        run(r#"var m = {"k": 1}; m["k"] = m; print m;"#),
+        "{\"k\": {...}}\n"
+    );
+}
+
+#[test]
+fn shared_items_are_not_repeats() {
+    assert_eq!(run("var a = [1]; print [a, a];"), "[[1], [1]]\n");
+}
+
+#[test]
+fn self_containing_list_pretty_prints() {
+    assert_eq!(
+        run("var a = [1]; a[0] = a; print pretty(a, 2);"),
+        "[\n  [...]\n]\n"
+    );
+}
+
+#[test]
+fn self_containing_values_can_be_compared() {
+    let source = "
+        var a = [1]; a[0] = a;
+        var b = [1]; b[0] = b;
+        var c = [1, 2]; c[1] = c;
+        print a == a; print a == b; print a == [a]; print a == c;
+        var m = {}; m[a] = 1; m[b] = 2;
+        print m[a]; print len(m); print contains([a], b);
+        var n = {}; n[\"n\"] = n;
+        print n == {\"n\": n};
+    ";
+    assert_eq!(run(source), "true\ntrue\ntrue\nfalse\n2\n1\ntrue\ntrue\n");
+}
+
+#[test]
+fn deeply_nested_values_can_be_dropped() {
+    let source = "
+        var l = [];
+        var m = {};
+        for (var i = 0; i < 100000; i = i + 1) { l = [l]; m = {\"m\": m}; }
+        l = nil; m = nil;
+        print \"dropped\";
+    ";
+    assert_eq!(run(source), "dropped\n");
+}
+
+#[test]
+fn nested_maps_pretty_print_indented() {
+    assert_eq!(
+        run("print pretty([{\"a\": [1, 2], \"b\": {}}], 4);"),
+        "[\n    {\n        \"a\": [\n            1,\n            2\n        ],\n        \"b\": {}\n    }\n]\n"
+    );
+    assert_eq!(run("print pretty(1, 16); print pretty([]);"), "1\n[]\n");
+}
+
+#[test]
+fn pretty_indents_are_capped() {
+    assert_eq!(
+        run_err("pretty([1], 17);"),
+        ["pretty: Indent must be at most 16, found 17"]
+    );
+    assert_eq!(
+        run_err("pretty([1], 10000000000000000000);")[0],
+        "pretty: Indent must be at most 16, found 10000000000000000000"
+    );
+    assert_eq!(Value::new_list(vec![Value::Nil]).pretty(usize::MAX), None);
+    let nested = Value::new_list(vec![Value::new_list(vec![Value::Nil])]);
+    assert_eq!(nested.pretty(usize::MAX / 2 + 1), None);
+}
+
+#[test]
+fn argument_helpers_accept_their_type() {
+    assert_eq!(Value::Number(2.5).as_number().unwrap(), 2.5);
+    assert_eq!(&*Value::Str("hi".into()).as_str().unwrap(), "hi");
+    let list = Value::new_list(vec![Value::Nil]);
+    assert_eq!(list.as_list().unwrap().borrow().len(), 1);
+}
+
+#[test]
+fn argument_helpers_reject_other_types() {
+    let s = Value::Str("hi".into());
+    assert!(matches!(s.as_number(), Err(InterpretError::Runtime)));
+    assert!(matches!(
+        Value::Number(1.0).as_str(),
+        Err(InterpretError::Runtime)
+    ));
+    assert!(matches!(s.as_list(), Err(InterpretError::Runtime)));
+}
+
+#[test]
+fn argument_helper_errors_name_the_types() {
+    assert_eq!(
+        run_err("abs(\"x\");"),
+        ["Expected a number, found string 'x'"]
+    );
+}
+
+#[test]
+fn functions_print_their_name_arity_and_size() {
+    let source = "fun add(a, b) { return a + b; }";
+    let size = compile(source).function("add").chunk.size();
+    assert_eq!(
+        run(&format!("{source} print add; print abs;")),
+        format!("<fn add#2 (size: {size})>\n<native fn abs#1>\n")
+    );
+}
+
+/// The string constant in a program compiled on its own
+fn string_constant(source: &str) -> Rc<str> {
+    compile(source)
+        .constants
+        .values()
+        .iter()
+        .find_map(|value| match value {
+            Value::Str(s) => Some(Rc::clone(s)),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+fn empty_and_single_character_strings_are_shared() {
+    for s in ["", "a", "}"] {
+        let (Value::Str(first), Value::Str(second)) =
+            (&Value::from(s.to_owned()), &Value::from(s.to_owned()))
+        else {
+            panic!("expected strings");
+        };
+        assert!(Rc::ptr_eq(first, second), "{s:?}");
+    }
+    assert!(Rc::ptr_eq(
+        &string_constant("print \"\";"),
+        &string_constant("var x = \"\";"),
+    ));
+
+    let (Value::Str(first), Value::Str(second)) =
+        (&Value::from("ab".to_owned()), &Value::from("ab".to_owned()))
+    else {
+        panic!("expected strings");
+    };
+    assert!(!Rc::ptr_eq(first, second));
+}
+
+#[test]
+fn function_values_print_wherever_they_end_up() {
+    let source = "
+        fun f() {}
+        var g = f;
+        print g;
+        print [f, len];
+        print \"${f}\";
+        fun outer() { fun inner(a) { return a; } print inner; }
+        outer();
+    ";
+    let compiled = compile(source);
+    let f = format!("<fn f#0 (size: {})>", compiled.function("f").chunk.size());
+    let inner = format!(
+        "<fn inner#1 (size: {})>",
+        compiled.function("inner").chunk.size()
+    );
+    assert_eq!(
+        run(source),
+        format!("{f}\n[{f}, <native fn len#1>]\n{f}\n{inner}\n")
+    );
+}
@@ -0,0 +1,308 @@
+mod common;
+
+use std::{process::Command, rc::Rc};
+
+use common::{compile, compile_with, pipeline, run_chunk, run_in, script, stack_around_call};
+use lox_rs::{
+    chunk::{ByteCode, Chunk, ConstantTable},
+    diagnostics,
+    native::{report_native_error, NativeFn, NativeResult, NATIVES},
+    pipeline::Pipeline,
+    value::Value,
+    vm::VM,
+};
+
+#[test]
+fn dup_at_copies_a_deeper_value() {
+    let mut constants = ConstantTable::default();
+    let mut chunk = Chunk::default();
+    for n in [1.0, 2.0, 3.0] {
+        let idx = constants.push(Value::Number(n));
+        chunk.push(ByteCode::from_constant_index(idx), 1);
+    }
+    chunk.push(ByteCode::DupAt(2), 1);
+    chunk.push(ByteCode::Return, 1);
+    assert_eq!(run_chunk(chunk, constants).unwrap(), Value::Number(1.0));
+}
+
+#[test]
+fn dup_at_past_the_bottom_is_an_error() {
+    let mut chunk = Chunk::default();
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push(ByteCode::DupAt(1), 1);
+    chunk.push(ByteCode::Return, 1);
+    let (result, diagnostics) = diagnostics::collect(|| run_chunk(chunk, ConstantTable::default()));
+    assert!(result.is_err());
+    assert_eq!(diagnostics[0].message, "Stack has only 1 values");
+}
+
+#[test]
+fn underflow_is_reported_at_the_instruction() {
+    let mut chunk = Chunk::default();
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push(ByteCode::Pop, 2);
+    chunk.push(ByteCode::Pop, 3);
+    chunk.push(ByteCode::Return, 4);
+    let (result, diagnostics) = diagnostics::collect(|| run_chunk(chunk, ConstantTable::default()));
+    assert!(result.is_err());
+    assert_eq!(diagnostics[0].message, "Stack underflow");
+    assert_eq!(diagnostics[0].line, Some(3));
+}
+
+#[test]
+fn lines_are_found_for_any_offset() {
+    let mut chunk = Chunk::default();
+    assert_eq!(chunk.get_line(0), 0);
+    chunk.push(ByteCode::Nil, 3);
+    chunk.push(ByteCode::Nil, 3);
+    chunk.push(ByteCode::Add, 5);
+    chunk.push(ByteCode::Return, 8);
+    let lines: Vec<_> = (0..=chunk.size() + 10)
+        .map(|offset| chunk.get_line(offset))
+        .collect();
+    assert_eq!(lines[..5], [3, 3, 5, 8, 8]);
+    assert!(lines[4..].iter().all(|&line| line == 8));
+
+    let mut one_line = Chunk::default();
+    for _ in 0..4 {
+        one_line.push(ByteCode::Nil, 1);
+    }
+    assert!((0..10).all(|offset| one_line.get_line(offset) == 1));
+}
+
+#[test]
+fn runtime_errors_report_source_lines() {
+    let (mut pipeline, output) = common::pipeline();
+    let source = "var x = 1;\n\nprint x;\nprint -nil;";
+    let diagnostics = common::run_in(&mut pipeline, &output, source).unwrap_err();
+    assert_eq!(diagnostics[0].message, "Cannot negate nil 'nil'");
+    assert_eq!(diagnostics[0].line, Some(4));
+}
+
+/// Set when the test runs itself to see what a failing chunk prints
+const PRINT_UNDERFLOW: &str = "LOX_TEST_PRINT_UNDERFLOW";
+
+#[test]
+fn underflow_report_names_the_opcode() {
+    if std::env::var_os(PRINT_UNDERFLOW).is_some() {
+        let mut chunk = Chunk::default();
+        chunk.push(ByteCode::Add, 7);
+        let _ = run_chunk(chunk, ConstantTable::default());
+        return;
+    }
+    // The report is printed to stdout, which is only seen by running the test again
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "underflow_report_names_the_opcode",
+            "--exact",
+            "--nocapture",
+        ])
+        .env(PRINT_UNDERFLOW, "1")
+        .output()
+        .unwrap();
+    let printed = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        printed.contains("Error at line 7, bytecode 'Add': Stack underflow"),
+        "{printed}"
+    );
+}
+
+#[test]
+fn reset_vm_runs_programs_independently() {
+    let first = compile_with("var x = 40; x + 2", |c| c.allow_expression_result = true).unwrap();
+    let second = compile_with("var y; y", |c| c.allow_expression_result = true).unwrap();
+
+    let mut vm = VM::default();
+    vm.constants = first.constants;
+    assert_eq!(
+        vm.interpret(Rc::new(first.script)).unwrap(),
+        Value::Number(42.0)
+    );
+    assert!(vm.globals.len() > NATIVES.len());
+
+    vm.reset();
+    assert_eq!(vm.globals.len(), NATIVES.len());
+    // `y` gets the slot `x` had, which no longer holds its value
+    vm.constants = second.constants;
+    assert_eq!(vm.interpret(Rc::new(second.script)).unwrap(), Value::Nil);
+}
+
+#[test]
+fn call_replaces_the_callee_and_arguments_with_the_result() {
+    let program = compile("fun add(a, b) { return a + b; }");
+    let args = [Value::Number(2.0), Value::Number(3.0)];
+    let callee = Value::Func(program.function("add"));
+    let (before, after) = stack_around_call(callee.clone(), &args, program.constants);
+    assert_eq!(
+        before,
+        [Value::Nil, callee, args[0].clone(), args[1].clone()]
+    );
+    assert_eq!(after, [Value::Nil, Value::Number(5.0)]);
+
+    let abs = NATIVES.iter().find(|native| native.name == "abs").unwrap();
+    let (before, after) = stack_around_call(
+        Value::Native(abs),
+        &[Value::Number(-4.0)],
+        ConstantTable::default(),
+    );
+    assert_eq!(before.len(), 3);
+    assert_eq!(after, [Value::Nil, Value::Number(4.0)]);
+}
+
+fn answer(_: &[Value]) -> NativeResult {
+    Ok(Value::Number(42.0))
+}
+
+fn fail(_: &[Value]) -> NativeResult {
+    report_native_error("fail", "Always fails")
+}
+
+fn identity(args: &[Value]) -> NativeResult {
+    Ok(args[0].clone())
+}
+
+static ANSWER: NativeFn = NativeFn {
+    name: "answer",
+    min_arity: 0,
+    arity: 0,
+    func: answer,
+};
+
+#[test]
+fn zero_argument_natives_replace_themselves_with_the_result() {
+    let (before, after) = stack_around_call(Value::Native(&ANSWER), &[], ConstantTable::default());
+    assert_eq!(before, [Value::Nil, Value::Native(&ANSWER)]);
+    assert_eq!(after, [Value::Nil, Value::Number(42.0)]);
+
+    let (mut pipeline, output) = pipeline();
+    pipeline.register_native("answer", 0, answer);
+    pipeline.register_native("fail", 0, fail);
+    let source = "var a = [answer(), answer()]; print a; print answer() + answer();";
+    assert_eq!(
+        run_in(&mut pipeline, &output, source).unwrap(),
+        "[42, 42]\n84\n"
+    );
+    let errors = run_in(&mut pipeline, &output, "print 1;\nfail();").unwrap_err();
+    assert_eq!(errors[0].message, "fail: Always fails");
+    assert_eq!(errors[0].line, Some(2));
+    let errors = run_in(&mut pipeline, &output, "answer(1);").unwrap_err();
+    assert_eq!(errors[0].message, "Expected 0 arguments but got 1");
+}
+
+#[test]
+fn jumps_outside_the_chunk_are_errors() {
+    for (j_offset, target) in [(100, 100), (-5, -5)] {
+        let mut chunk = Chunk::default();
+        chunk.push(ByteCode::JumpRelative(j_offset), 1);
+        chunk.push(ByteCode::Nil, 1);
+        chunk.push(ByteCode::Return, 1);
+        let (result, diagnostics) =
+            diagnostics::collect(|| run_chunk(chunk, ConstantTable::default()));
+        assert!(result.is_err());
+        assert_eq!(
+            diagnostics[0].message,
+            format!("Jump to {target} does not land on an instruction")
+        );
+    }
+}
+
+#[test]
+fn jumps_into_an_instruction_are_errors() {
+    let mut constants = ConstantTable::default();
+    let idx = constants.push(Value::Number(1.0));
+    let mut chunk = Chunk::default();
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push(ByteCode::JumpF(2), 1);
+    chunk.push(ByteCode::from_constant_index(idx), 1);
+    chunk.push(ByteCode::Return, 1);
+    let (result, diagnostics) = diagnostics::collect(|| run_chunk(chunk, constants));
+    assert!(result.is_err());
+    assert_eq!(
+        diagnostics[0].message,
+        "Jump to 3 does not land on an instruction"
+    );
+}
+
+#[test]
+fn stack_is_empty_after_a_run() {
+    let program = compile("fun f(a) { var b = a; return; } var x = 1; { var y = 2; f(y); }");
+    let mut vm = VM::default();
+    vm.constants = program.constants;
+    assert_eq!(vm.interpret(Rc::new(program.script)).unwrap(), Value::Nil);
+    assert!(vm.stack.is_empty());
+
+    let mut chunk = Chunk::default();
+    chunk.push(ByteCode::Nil, 1);
+    chunk.push(ByteCode::True, 1);
+    chunk.push(ByteCode::Return, 1);
+    let mut vm = VM::default();
+    assert_eq!(
+        vm.interpret(Rc::new(script(chunk))).unwrap(),
+        Value::Bool(true)
+    );
+    assert!(vm.stack.is_empty());
+}
+
+/// Times a loop counting to a million, with the step given by `step`
+fn time_counting(step: &str) -> std::time::Duration {
+    let program = compile(&format!(
+        "var one = 1; fun count() {{ var i = 0; while (i < 1000000) i = i + {step}; }} count();"
+    ));
+    let mut vm = VM::default();
+    vm.constants = program.constants;
+    let start = std::time::Instant::now();
+    vm.interpret(Rc::new(program.script)).unwrap();
+    start.elapsed()
+}
+
+#[test]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+fn bench_fused_constant_addition() {
+    let fused = time_counting("1");
+    let unfused = time_counting("one");
+    println!("i + 1: {fused:?}, i + one: {unfused:?}");
+}
+
+/// Times building a string from two values a hundred thousand times
+fn time_building(expression: &str) -> std::time::Duration {
+    let program = compile(&format!(
+        "fun build() {{ var a = 1; var b = \"b\"; for (var i = 0; i < 100000; i = i + 1) {{ var s = {expression}; }} }} build();"
+    ));
+    let mut vm = VM::default();
+    vm.constants = program.constants;
+    let start = std::time::Instant::now();
+    vm.interpret(Rc::new(program.script)).unwrap();
+    start.elapsed()
+}
+
+#[test]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+fn bench_interpolation() {
+    let format = time_building(r#""a=${a}, b=${b}.""#);
+    let add = time_building(r#""a=" + a + ", b=" + b + ".""#);
+    println!("Format: {format:?}, Add: {add:?}");
+}
+
+/// Times a million calls of the registered natives made by `call`
+fn time_native_calls(call: &str) -> std::time::Duration {
+    let mut pipeline = Pipeline::default();
+    pipeline.hide_disassembly = true;
+    pipeline.register_native("answer", 0, answer);
+    pipeline.register_native("identity", 1, identity);
+    let script = pipeline
+        .compile(&format!(
+            "fun calls() {{ for (var i = 0; i < 1000000; i = i + 1) {call}; }} calls();"
+        ))
+        .unwrap();
+    let start = std::time::Instant::now();
+    pipeline.run(&script).unwrap();
+    start.elapsed()
+}
+
+#[test]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+fn bench_zero_argument_natives() {
+    let zero = time_native_calls("answer()");
+    let one = time_native_calls("identity(1)");
+    println!("answer(): {zero:?}, identity(1): {one:?}");
+}